@@ -0,0 +1,68 @@
+//! Reads a large file from one node to another over loopback TCP and prints
+//! the throughput. Run with `cargo bench --bench loopback`; the amount moved
+//! can be changed with `PUPYNET_BENCH_BYTES` (default 2 GiB).
+
+use std::path::Path;
+use std::time::Duration;
+use std::time::Instant;
+
+use pupynet_core::Compression;
+use pupynet_core::Pupynet;
+
+const DEFAULT_BYTES: u64 = 2 << 30;
+/// Size of each `read_file` request.
+const CHUNK: u64 = 64 << 20;
+
+/// A node keeping its data in `dir`, sending files as they are so the
+/// transport is measured rather than the codec.
+fn node(dir: &Path, name: &str) -> pupynet_core::PupynetBuilder {
+	Pupynet::builder()
+		.name(name)
+		.data_dir(dir.join(name))
+		.no_discovery()
+		.compression(Compression::None)
+		.listen("tcp://127.0.0.1:0")
+}
+
+#[tokio::main]
+async fn main() {
+	let total = std::env::var("PUPYNET_BENCH_BYTES").ok()
+		.and_then(|s| s.parse().ok())
+		.unwrap_or(DEFAULT_BYTES);
+
+	let dir = tempfile::tempdir().unwrap();
+	let share = dir.path().join("share");
+	std::fs::create_dir_all(&share).unwrap();
+	let path = share.join("big.bin");
+	// Sparse, so the disk stays out of the measurement.
+	std::fs::File::create(&path).unwrap().set_len(total).unwrap();
+	let path = path.to_str().unwrap().to_string();
+
+	let server = node(dir.path(), "server").share(&share).build();
+	let server_info = server.info().await.unwrap();
+	let client = node(dir.path(), "client").peer(&server_info.listen_addrs[0]).build();
+
+	let connected = async {
+		while !client.peers().await.unwrap().iter().any(|p| p.id == server_info.id && p.hops.is_some()) {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+	};
+	tokio::time::timeout(Duration::from_secs(10), connected).await.expect("nodes did not connect");
+	client.read_file(&server_info.id, &path, 0, 1).await.unwrap();
+
+	let start = Instant::now();
+	let mut offset = 0;
+	while offset < total {
+		let length = CHUNK.min(total - offset);
+		let data = client.read_file(&server_info.id, &path, offset, length).await.unwrap();
+		assert_eq!(data.len() as u64, length);
+		offset += length;
+	}
+	let elapsed = start.elapsed();
+
+	let mib = total as f64 / (1 << 20) as f64;
+	println!("read {:.0} MiB over loopback in {:.2?}: {:.1} MiB/s", mib, elapsed, mib / elapsed.as_secs_f64());
+
+	client.shutdown().await;
+	server.shutdown().await;
+}
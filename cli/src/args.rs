@@ -1,4 +1,7 @@
+use std::path::PathBuf;
+
 use clap::Parser;
+use clap::ValueEnum;
 use clap::Subcommand;
 
 
@@ -9,15 +12,102 @@ pub struct Args {
     pub peer: Vec<String>,
     #[clap(long)]
     pub bind: Vec<String>,
-    #[clap(long, default_value = "127.0.0.1:8832")]
-    pub ui_bind: String,
+	/// Config file, defaults to ~/.pupynet/config.toml
+	#[clap(long, global = true)]
+	pub config: Option<PathBuf>,
+	#[clap(long)]
+	pub name: Option<String>,
+	#[clap(long)]
+	pub owner: Option<String>,
+	/// Folder to share with other nodes, can be repeated
+	#[clap(long)]
+	pub share: Vec<PathBuf>,
+	#[clap(long)]
+	pub no_discovery: bool,
+	/// Control socket of the daemon, defaults to ~/.pupynet/control.sock
+	#[clap(long, global = true)]
+	pub socket: Option<PathBuf>,
+	/// Print results as JSON
+	#[clap(long, global = true)]
+	pub json: bool,
 	#[clap(subcommand)]
 	pub cmd: Option<Command>,
 }
 
 #[derive(Debug, Parser)]
 pub enum Command {
-	Copy { src: String, dest: String },
+	/// Runs the node and serves the control socket for the other commands
+	Daemon,
+	/// Shows the id, name and listen addresses of the daemon's node
+	Info,
+	/// Lists known peers
+	Peers,
+	/// Lists a folder on a node, e.g. `pupynet ls nas:/srv/media`
+	Ls {
+		location: String,
+		#[clap(long, short)]
+		recursive: bool,
+	},
+	/// Copies a file, either side may be `node:/path` or a local path
+	Copy {
+		src: String,
+		dest: String,
+		#[clap(long, value_enum, default_value = "normal")]
+		priority: Priority,
+	},
+	/// Prints a remote file
+	Cat { location: String },
+	/// Removes a remote file, or a folder with `-r`
+	Rm {
+		location: String,
+		#[clap(long, short)]
+		recursive: bool,
+	},
+	/// Creates a remote folder and its parents
+	Mkdir { location: String },
+	/// Moves or renames a remote file or folder on the same node
+	Mv { src: String, dest: String },
+	/// Sets how much a peer is trusted
+	Trust {
+		node: String,
+		#[clap(value_enum, default_value = "trusted")]
+		state: Trust,
+	},
+	/// Removes a peer from the peer store
+	Forget {
+		node: String,
+		/// Also ask the trusted nodes of the same owner to forget it
+		#[clap(long)]
+		propagate: bool,
+	},
+	/// Lists ongoing and recently finished transfers
+	Transfers,
+	/// Pauses a transfer, see `pupynet transfers` for ids
+	Pause { id: u64 },
+	/// Resumes a paused transfer
+	Resume { id: u64 },
+	/// Cancels a transfer
+	Cancel { id: u64 },
+	/// Changes the priority of a queued transfer
+	Prioritize {
+		id: u64,
+		#[clap(value_enum)]
+		priority: Priority,
+	},
 	Update,
 	Verify { bin: String, sig: String },
 }
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Trust {
+	Trusted,
+	Blocked,
+	Unknown,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Priority {
+	Low,
+	Normal,
+	High,
+}
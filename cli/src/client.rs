@@ -0,0 +1,174 @@
+use std::path::PathBuf;
+
+use anyhow::bail;
+use pupynet_core::FolderEntry;
+use pupynet_core::NodeInfo;
+use pupynet_core::PeerInfo;
+use pupynet_core::Priority;
+use pupynet_core::TransferInfo;
+use pupynet_core::TrustState;
+use serde_json::Value;
+
+use crate::args;
+use crate::args::Command;
+use crate::args::Trust;
+use crate::control;
+use crate::control::Location;
+use crate::control::Request;
+
+fn print_json(value: &Value) -> anyhow::Result<()> {
+	println!("{}", serde_json::to_string_pretty(value)?);
+	Ok(())
+}
+
+fn print_peers(peers: &[PeerInfo]) {
+	println!("ID           NAME             OWNER        ADDRESS                       RTT  STATE");
+	for peer in peers {
+		let state = match (peer.connected, peer.hops) {
+			(true, _) => "connected".to_string(),
+			(false, Some(hops)) => format!("{} hops", hops),
+			(false, None) => "offline".to_string(),
+		};
+		let rtt = peer.rtt_ms.map(|ms| format!("{}ms", ms)).unwrap_or_else(|| "-".to_string());
+		println!(
+			"{:<12} {:<16} {:<12} {:<24} {:>8}  {}",
+			peer.id.get(..12).unwrap_or(&peer.id),
+			peer.name,
+			peer.owner.as_deref().unwrap_or("-"),
+			peer.addrs.first().map(String::as_str).unwrap_or("-"),
+			rtt,
+			state,
+		);
+	}
+}
+
+fn print_entries(entries: &[FolderEntry]) {
+	for entry in entries {
+		let kind = if entry.is_dir { "d" } else { "-" };
+		println!("{} {:>12} {}", kind, entry.size, entry.path);
+	}
+}
+
+fn print_transfers(transfers: &[TransferInfo]) {
+	println!("   ID KIND     STATE      PRIORITY                 DONE       RATE    ETA  PATH");
+	for transfer in transfers {
+		let done = match transfer.total {
+			Some(total) => format!("{}/{}", transfer.done, total),
+			None => transfer.done.to_string(),
+		};
+		let rate = format!("{}/s", transfer.rate);
+		let eta = transfer.eta_secs.map(|secs| format!("{}s", secs)).unwrap_or_else(|| "-".to_string());
+		let path = match &transfer.node_id {
+			Some(node_id) => format!("{}:{}", node_id.get(..12).unwrap_or(node_id), transfer.path),
+			None => transfer.path.clone(),
+		};
+		println!(
+			"{:>5} {:<8} {:<10} {:<8} {:>20} {:>10} {:>6}  {}",
+			transfer.id,
+			format!("{:?}", transfer.kind).to_lowercase(),
+			format!("{:?}", transfer.state).to_lowercase(),
+			format!("{:?}", transfer.priority).to_lowercase(),
+			done,
+			rate,
+			eta,
+			path,
+		);
+		if let Some(error) = &transfer.error {
+			println!("      {}", error);
+		}
+	}
+}
+
+fn priority(priority: args::Priority) -> Priority {
+	match priority {
+		args::Priority::Low => Priority::Low,
+		args::Priority::Normal => Priority::Normal,
+		args::Priority::High => Priority::High,
+	}
+}
+
+/// Runs a command against the daemon listening on `socket`.
+pub async fn run(socket: Option<PathBuf>, json: bool, cmd: Command) -> anyhow::Result<()> {
+	let socket = socket.unwrap_or_else(control::default_path);
+	let req = match cmd {
+		Command::Info => Request::Info,
+		Command::Peers => Request::Peers,
+		Command::Ls { location, recursive } => {
+			let (node, path) = Location::remote(&location)?;
+			Request::Ls { node, path, recursive }
+		},
+		Command::Copy { src, dest, priority: level } => Request::Copy {
+			src: control::absolute(&src)?,
+			dest: control::absolute(&dest)?,
+			priority: priority(level),
+		},
+		Command::Cat { location } => {
+			let (node, path) = Location::remote(&location)?;
+			if !json {
+				control::cat(&socket, node, path, &mut std::io::stdout().lock()).await?;
+				return Ok(());
+			}
+			let mut data = Vec::new();
+			let bytes = control::cat(&socket, node, path.clone(), &mut data).await?;
+			let text = match String::from_utf8(data) {
+				Ok(text) => text,
+				Err(_) => bail!("{} is not text, leave out --json to get its bytes", path),
+			};
+			return print_json(&serde_json::json!({ "path": path, "bytes": bytes, "text": text }));
+		},
+		Command::Rm { location, recursive } => {
+			let (node, path) = Location::remote(&location)?;
+			Request::Rm { node, path, recursive }
+		},
+		Command::Mkdir { location } => {
+			let (node, path) = Location::remote(&location)?;
+			Request::Mkdir { node, path }
+		},
+		Command::Mv { src, dest } => {
+			let (node, path) = Location::remote(&src)?;
+			let new_path = match Location::parse(&dest) {
+				Location::Remote { node: dest_node, path } if dest_node == node => path,
+				Location::Remote { .. } => bail!("mv only works within one node, use copy"),
+				// A bare name renames in place.
+				Location::Local(path) => path.to_string_lossy().to_string(),
+			};
+			Request::Mv { node, path, new_path }
+		},
+		Command::Trust { node, state } => {
+			let trust = match state {
+				Trust::Trusted => TrustState::Trusted,
+				Trust::Blocked => TrustState::Blocked,
+				Trust::Unknown => TrustState::Unknown,
+			};
+			Request::Trust { node, trust }
+		},
+		Command::Forget { node, propagate } => Request::Forget { node, propagate },
+		Command::Transfers => Request::Transfers,
+		Command::Pause { id } => Request::Pause { id },
+		Command::Resume { id } => Request::Resume { id },
+		Command::Cancel { id } => Request::Cancel { id },
+		Command::Prioritize { id, priority: level } => Request::Prioritize { id, priority: priority(level) },
+		Command::Daemon | Command::Update | Command::Verify { .. } => unreachable!("handled in main"),
+	};
+	let value = control::request(&socket, &req).await?;
+	if json {
+		return print_json(&value);
+	}
+	match req {
+		Request::Info => {
+			let info: NodeInfo = serde_json::from_value(value)?;
+			println!("id:     {}", info.id);
+			println!("name:   {}", info.name);
+			println!("owner:  {}", info.owner.as_deref().unwrap_or("-"));
+			for addr in info.listen_addrs {
+				println!("listen: {}", addr);
+			}
+		},
+		Request::Peers => print_peers(&serde_json::from_value::<Vec<PeerInfo>>(value)?),
+		Request::Ls { .. } => print_entries(&serde_json::from_value::<Vec<FolderEntry>>(value)?),
+		Request::Transfers => print_transfers(&serde_json::from_value::<Vec<TransferInfo>>(value)?),
+		Request::Copy { .. } => println!("copied {} bytes, sent {}", value["bytes"], value["sent"]),
+		_ => {},
+	}
+	Ok(())
+}
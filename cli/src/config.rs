@@ -0,0 +1,223 @@
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use pupynet_core::PupynetBuilder;
+use pupynet_core::RateLimit;
+use serde::Deserialize;
+use serde::Deserializer;
+
+use crate::args::Args;
+
+/// Contents of `~/.pupynet/config.toml`.
+///
+/// ```toml
+/// name = "nas"
+/// owner = "alice"
+/// listen = ["tcp://0.0.0.0:7765"]
+/// peers = ["tcp://server.example.com:7765"]
+///
+/// [[shares]]
+/// path = "/srv/media"
+/// read_only = true
+///
+/// [discovery]
+/// enabled = true
+/// port = 7764
+/// interval_secs = 5
+///
+/// # Bytes per second of stream data, unlimited when left out.
+/// [rate_limit]
+/// upload = 1000000
+///
+/// [[rate_limit.peers]]
+/// node = "<node id>"
+/// download = 500000
+///
+/// # Times are UTC.
+/// [[rate_limit.schedules]]
+/// from = "22:00"
+/// to = "06:00"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub name: Option<String>,
+	pub owner: Option<String>,
+	pub listen: Vec<String>,
+	pub peers: Vec<String>,
+	pub shares: Vec<ShareConfig>,
+	pub discovery: DiscoveryConfig,
+	pub rate_limit: RateLimitConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShareConfig {
+	pub path: PathBuf,
+	#[serde(default)]
+	pub read_only: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+	pub enabled: bool,
+	pub port: Option<u16>,
+	pub interval_secs: Option<u64>,
+}
+
+impl Default for DiscoveryConfig {
+	fn default() -> Self {
+		DiscoveryConfig {
+			enabled: true,
+			port: None,
+			interval_secs: None,
+		}
+	}
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+	pub upload: Option<u64>,
+	pub download: Option<u64>,
+	pub peers: Vec<PeerRateLimit>,
+	pub schedules: Vec<RateSchedule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerRateLimit {
+	pub node: String,
+	pub upload: Option<u64>,
+	pub download: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateSchedule {
+	#[serde(deserialize_with = "time_of_day")]
+	pub from: Duration,
+	#[serde(deserialize_with = "time_of_day")]
+	pub to: Duration,
+	pub upload: Option<u64>,
+	pub download: Option<u64>,
+}
+
+/// Parses `HH:MM` into the time since midnight.
+fn time_of_day<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+	let text = String::deserialize(deserializer)?;
+	let parsed = text.split_once(':').and_then(|(hours, minutes)| {
+		let (hours, minutes) = (hours.parse::<u64>().ok()?, minutes.parse::<u64>().ok()?);
+		(hours < 24 && minutes < 60).then(|| Duration::from_secs(hours * 3600 + minutes * 60))
+	});
+	parsed.ok_or_else(|| serde::de::Error::custom(format!("expected a time like 22:30, got {}", text)))
+}
+
+pub fn default_path() -> PathBuf {
+	crate::app_dir().join("config.toml")
+}
+
+/// Loads the config file. A missing file is the same as an empty one.
+pub fn load(path: &Path) -> anyhow::Result<Config> {
+	let text = match std::fs::read_to_string(path) {
+		Ok(text) => text,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+		Err(err) => return Err(err.into()),
+	};
+	Ok(toml::from_str(&text)?)
+}
+
+/// Builds the node settings from the config file, with command line flags
+/// taking precedence over and adding to what the file says.
+pub fn builder(config: Config, args: &Args) -> PupynetBuilder {
+	let mut builder = PupynetBuilder::new();
+	if let Some(name) = args.name.clone().or(config.name) {
+		builder = builder.name(name);
+	}
+	if let Some(owner) = args.owner.clone().or(config.owner) {
+		builder = builder.owner(owner);
+	}
+	for addr in config.listen.into_iter().chain(args.bind.iter().cloned()) {
+		builder = builder.listen(addr);
+	}
+	for addr in config.peers.into_iter().chain(args.peer.iter().cloned()) {
+		builder = builder.peer(addr);
+	}
+	for share in config.shares {
+		builder = match share.read_only {
+			true => builder.share_read_only(share.path),
+			false => builder.share(share.path),
+		};
+	}
+	for path in &args.share {
+		builder = builder.share(path);
+	}
+	if !config.discovery.enabled || args.no_discovery {
+		builder = builder.no_discovery();
+	} else if let Some(port) = config.discovery.port {
+		builder = builder.discovery_port(port);
+	}
+	if let Some(secs) = config.discovery.interval_secs {
+		builder = builder.discovery_interval(Duration::from_secs(secs));
+	}
+	let rate_limit = config.rate_limit;
+	builder = builder.rate_limit(RateLimit { upload: rate_limit.upload, download: rate_limit.download });
+	for peer in rate_limit.peers {
+		builder = builder.peer_rate_limit(peer.node, RateLimit { upload: peer.upload, download: peer.download });
+	}
+	for schedule in rate_limit.schedules {
+		builder = builder.rate_schedule(schedule.from, schedule.to, RateLimit { upload: schedule.upload, download: schedule.download });
+	}
+	builder
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_config() {
+		let config: Config = toml::from_str(r#"
+			name = "nas"
+			listen = ["tcp://0.0.0.0:7765"]
+
+			[[shares]]
+			path = "/srv/media"
+			read_only = true
+
+			[discovery]
+			enabled = false
+		"#).unwrap();
+		assert_eq!(config.name.as_deref(), Some("nas"));
+		assert_eq!(config.listen, vec!["tcp://0.0.0.0:7765".to_string()]);
+		assert!(config.shares[0].read_only);
+		assert!(!config.discovery.enabled);
+		assert!(config.peers.is_empty());
+	}
+
+	#[test]
+	fn test_parse_rate_limits() {
+		let config: Config = toml::from_str(r#"
+			[rate_limit]
+			upload = 1000000
+
+			[[rate_limit.peers]]
+			node = "abc"
+			download = 500
+
+			[[rate_limit.schedules]]
+			from = "22:30"
+			to = "06:00"
+		"#).unwrap();
+		let rate_limit = config.rate_limit;
+		assert_eq!(rate_limit.upload, Some(1000000));
+		assert_eq!(rate_limit.download, None);
+		assert_eq!(rate_limit.peers[0].download, Some(500));
+		assert_eq!(rate_limit.schedules[0].from, Duration::from_secs(22 * 3600 + 30 * 60));
+		assert_eq!(rate_limit.schedules[0].upload, None);
+
+		assert!(toml::from_str::<Config>("[[rate_limit.schedules]]\nfrom = \"25:00\"\nto = \"06:00\"").is_err());
+	}
+}
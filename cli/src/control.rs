@@ -0,0 +1,413 @@
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use pupynet_core::Priority;
+use pupynet_core::Pupynet;
+use pupynet_core::TrustState;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncSeekExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::UnixListener;
+use tokio::net::UnixStream;
+
+/// One request per line on the control socket, answered by one `Response`
+/// line. A successful `Cat` is followed by the file in frames of a `u32`
+/// length and that many bytes, ended by an empty frame.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+	Info,
+	Peers,
+	Ls { node: String, path: String, recursive: bool },
+	Copy {
+		src: String,
+		dest: String,
+		#[serde(default)]
+		priority: Priority,
+	},
+	Cat { node: String, path: String },
+	Rm { node: String, path: String, recursive: bool },
+	Mkdir { node: String, path: String },
+	Mv { node: String, path: String, new_path: String },
+	Trust { node: String, trust: TrustState },
+	Forget { node: String, propagate: bool },
+	Transfers,
+	Pause { id: u64 },
+	Resume { id: u64 },
+	Cancel { id: u64 },
+	Prioritize { id: u64, priority: Priority },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+	Ok(Value),
+	Error(String),
+}
+
+/// Files are moved in ranges of this size, so copying one to this machine or
+/// printing it never holds all of it in memory.
+const RANGE: u64 = 4 * 1024 * 1024;
+
+pub fn default_path() -> PathBuf {
+	crate::app_dir().join("control.sock")
+}
+
+/// A file on a node (`node:/path`) or on this machine.
+#[derive(Debug, PartialEq)]
+pub enum Location {
+	Remote { node: String, path: String },
+	Local(PathBuf),
+}
+
+impl Location {
+	/// Parses `node:/path`, failing on local paths.
+	pub fn remote(s: &str) -> anyhow::Result<(String, String)> {
+		match Location::parse(s) {
+			Location::Remote { node, path } => Ok((node, path)),
+			Location::Local(_) => bail!("expected node:/path, got {}", s),
+		}
+	}
+
+	pub fn parse(s: &str) -> Location {
+		match s.split_once(':') {
+			Some((node, path)) if !node.is_empty() && !node.contains('/') => Location::Remote {
+				node: node.to_string(),
+				path: path.to_string(),
+			},
+			_ => Location::Local(PathBuf::from(s)),
+		}
+	}
+}
+
+/// Makes local paths absolute so the daemon resolves them like the caller would.
+pub fn absolute(location: &str) -> anyhow::Result<String> {
+	match Location::parse(location) {
+		Location::Local(path) if path.is_relative() => {
+			Ok(std::env::current_dir()?.join(path).to_string_lossy().to_string())
+		},
+		_ => Ok(location.to_string()),
+	}
+}
+
+/// Finds a peer by id, name or unique id prefix.
+async fn resolve_node(pupynet: &Pupynet, node: &str) -> anyhow::Result<String> {
+	let peers = pupynet.peers().await?;
+	if let Some(peer) = peers.iter().find(|p| p.id == node) {
+		return Ok(peer.id.clone());
+	}
+	let matches: Vec<_> = peers.iter()
+		.filter(|p| p.name == node || p.id.starts_with(node))
+		.collect();
+	match matches.as_slice() {
+		[peer] => Ok(peer.id.clone()),
+		[] => bail!("unknown node {}", node),
+		_ => bail!("{} matches {} nodes, use the node id", node, matches.len()),
+	}
+}
+
+/// Replaces the node of a remote location by its id.
+async fn resolve(pupynet: &Pupynet, location: Location) -> anyhow::Result<Location> {
+	match location {
+		Location::Remote { node, path } => Ok(Location::Remote { node: resolve_node(pupynet, &node).await?, path }),
+		local => Ok(local),
+	}
+}
+
+/// Reads `RANGE` bytes at `offset`, fewer at the end of the file. Remote
+/// locations have to be resolved.
+async fn read_range(pupynet: &Pupynet, location: &Location, offset: u64) -> anyhow::Result<Vec<u8>> {
+	match location {
+		Location::Remote { node, path } => pupynet.read_file(node, path, offset, RANGE).await,
+		Location::Local(path) => {
+			let mut file = tokio::fs::File::open(path).await?;
+			file.seek(SeekFrom::Start(offset)).await?;
+			let mut data = Vec::new();
+			file.take(RANGE).read_to_end(&mut data).await?;
+			Ok(data)
+		},
+	}
+}
+
+/// Copies `src` range by range, returning its size and how many bytes had
+/// to be sent.
+async fn copy(pupynet: &Pupynet, src: &Location, dest: Location) -> anyhow::Result<(u64, u64)> {
+	match dest {
+		Location::Remote { node, path } => {
+			// `sync_file` takes the whole file to find what the node lacks.
+			let mut data = Vec::new();
+			loop {
+				let range = read_range(pupynet, src, data.len() as u64).await?;
+				let last = (range.len() as u64) < RANGE;
+				data.extend_from_slice(&range);
+				if last {
+					break;
+				}
+			}
+			let id = resolve_node(pupynet, &node).await?;
+			let len = data.len() as u64;
+			Ok((len, pupynet.sync_file(&id, &path, data).await?.sent))
+		},
+		Location::Local(path) => {
+			// Assembled next to the destination, which is only replaced once complete.
+			let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+			let temp = path.with_file_name(format!(".{}.part", name));
+			let res = copy_to_file(pupynet, src, &temp).await;
+			if res.is_err() {
+				let _ = tokio::fs::remove_file(&temp).await;
+			}
+			let len = res?;
+			tokio::fs::rename(&temp, &path).await?;
+			Ok((len, len))
+		},
+	}
+}
+
+async fn copy_to_file(pupynet: &Pupynet, src: &Location, path: &Path) -> anyhow::Result<u64> {
+	let mut file = tokio::fs::File::create(path).await?;
+	let mut len = 0;
+	loop {
+		let range = read_range(pupynet, src, len).await?;
+		file.write_all(&range).await?;
+		len += range.len() as u64;
+		if (range.len() as u64) < RANGE {
+			break;
+		}
+	}
+	file.sync_all().await?;
+	Ok(len)
+}
+
+/// Answers `req`, except for `Cat` which `send_file` answers.
+async fn handle(pupynet: &Pupynet, req: Request) -> anyhow::Result<Value> {
+	let value = match req {
+		Request::Info => serde_json::to_value(pupynet.info().await?)?,
+		Request::Peers => serde_json::to_value(pupynet.peers().await?)?,
+		Request::Ls { node, path, recursive } => {
+			let id = resolve_node(pupynet, &node).await?;
+			let entries = pupynet.list_folder(&id, &path, 0, 0, recursive).await?;
+			serde_json::to_value(entries)?
+		},
+		Request::Copy { src, dest, priority } => {
+			let pupynet = pupynet.with_priority(priority);
+			let src = resolve(&pupynet, Location::parse(&src)).await?;
+			let (bytes, sent) = copy(&pupynet, &src, Location::parse(&dest)).await?;
+			serde_json::json!({ "bytes": bytes, "sent": sent })
+		},
+		Request::Cat { .. } => bail!("cat is answered with the file"),
+		Request::Rm { node, path, recursive } => {
+			let id = resolve_node(pupynet, &node).await?;
+			match recursive {
+				true => pupynet.remove_folder(&id, &path).await?,
+				false => pupynet.remove_file(&id, &path).await?,
+			}
+			serde_json::json!({})
+		},
+		Request::Mkdir { node, path } => {
+			let id = resolve_node(pupynet, &node).await?;
+			pupynet.create_folder(&id, &path).await?;
+			serde_json::json!({})
+		},
+		Request::Mv { node, path, new_path } => {
+			let id = resolve_node(pupynet, &node).await?;
+			pupynet.rename(&id, &path, &new_path).await?;
+			serde_json::json!({})
+		},
+		Request::Trust { node, trust } => {
+			let id = resolve_node(pupynet, &node).await?;
+			pupynet.set_trust(id.clone(), trust)?;
+			serde_json::json!({ "id": id, "trust": trust })
+		},
+		Request::Forget { node, propagate } => {
+			let id = resolve_node(pupynet, &node).await?;
+			pupynet.forget_peer(id.clone(), propagate)?;
+			serde_json::json!({ "id": id })
+		},
+		Request::Transfers => serde_json::to_value(pupynet.transfers())?,
+		Request::Pause { id } => {
+			pupynet.pause_transfer(id)?;
+			serde_json::json!({ "id": id })
+		},
+		Request::Resume { id } => {
+			pupynet.resume_transfer(id)?;
+			serde_json::json!({ "id": id })
+		},
+		Request::Cancel { id } => {
+			pupynet.cancel_transfer(id)?;
+			serde_json::json!({ "id": id })
+		},
+		Request::Prioritize { id, priority } => {
+			pupynet.set_transfer_priority(id, priority)?;
+			serde_json::json!({ "id": id, "priority": priority })
+		},
+	};
+	Ok(value)
+}
+
+async fn respond(writer: &mut OwnedWriteHalf, res: anyhow::Result<Value>) -> anyhow::Result<()> {
+	let res = match res {
+		Ok(value) => Response::Ok(value),
+		Err(err) => Response::Error(format!("{:#}", err)),
+	};
+	let mut data = serde_json::to_vec(&res)?;
+	data.push(b'\n');
+	writer.write_all(&data).await?;
+	Ok(())
+}
+
+/// Answers a `Cat` with the file, range by range. Errors before the first
+/// range are answered, later ones close the connection.
+async fn send_file(pupynet: &Pupynet, node: &str, path: String, writer: &mut OwnedWriteHalf) -> anyhow::Result<()> {
+	let first = async {
+		let location = Location::Remote { node: resolve_node(pupynet, node).await?, path };
+		let data = read_range(pupynet, &location, 0).await?;
+		anyhow::Ok((location, data))
+	};
+	let (location, mut data) = match first.await {
+		Ok(first) => first,
+		Err(err) => return respond(writer, Err(err)).await,
+	};
+	respond(writer, Ok(serde_json::json!({}))).await?;
+	let mut offset = 0;
+	loop {
+		writer.write_all(&(data.len() as u32).to_le_bytes()).await?;
+		if data.is_empty() {
+			return Ok(());
+		}
+		writer.write_all(&data).await?;
+		offset += data.len() as u64;
+		data = match (data.len() as u64) < RANGE {
+			true => Vec::new(),
+			false => read_range(pupynet, &location, offset).await?,
+		};
+	}
+}
+
+async fn handle_conn(pupynet: Pupynet, conn: UnixStream) -> anyhow::Result<()> {
+	let (reader, mut writer) = conn.into_split();
+	let mut lines = BufReader::new(reader).lines();
+	while let Some(line) = lines.next_line().await? {
+		match serde_json::from_str::<Request>(&line) {
+			Ok(Request::Cat { node, path }) => send_file(&pupynet, &node, path, &mut writer).await?,
+			Ok(req) => respond(&mut writer, handle(&pupynet, req).await).await?,
+			Err(err) => respond(&mut writer, Err(err.into())).await?,
+		}
+	}
+	Ok(())
+}
+
+/// Binds the control socket, refusing to take over one a running daemon owns.
+pub async fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+	if path.exists() {
+		if UnixStream::connect(path).await.is_ok() {
+			bail!("a daemon is already listening on {}", path.display());
+		}
+		// Left behind by a daemon that did not shut down cleanly.
+		std::fs::remove_file(path)?;
+	}
+	let listener = UnixListener::bind(path)?;
+	log::info!("control socket at {}", path.display());
+	Ok(listener)
+}
+
+/// Accepts CLI connections for as long as the daemon runs.
+pub async fn serve(listener: UnixListener, pupynet: Pupynet) -> anyhow::Result<()> {
+	loop {
+		let (conn, _) = listener.accept().await?;
+		let pupynet = pupynet.clone();
+		tokio::spawn(async move {
+			if let Err(err) = handle_conn(pupynet, conn).await {
+				log::error!("control connection failed: {}", err);
+			}
+		});
+	}
+}
+
+/// Sends one request to the running daemon and returns its answer.
+pub async fn request(path: &Path, req: &Request) -> anyhow::Result<Value> {
+	let (value, _) = send(path, req).await?;
+	Ok(value)
+}
+
+/// Fetches a remote file through the daemon into `out`, returning its size.
+pub async fn cat(path: &Path, node: String, file: String, out: &mut impl std::io::Write) -> anyhow::Result<u64> {
+	let (_, mut reader) = send(path, &Request::Cat { node, path: file }).await?;
+	let mut len = 0;
+	let mut data = Vec::new();
+	loop {
+		let frame = match reader.read_u32_le().await {
+			Ok(0) => return Ok(len),
+			Ok(frame) => frame,
+			Err(_) => bail!("the daemon stopped sending the file, see its log"),
+		};
+		data.resize(frame as usize, 0);
+		if reader.read_exact(&mut data).await.is_err() {
+			bail!("the daemon stopped sending the file, see its log");
+		}
+		out.write_all(&data)?;
+		len += frame as u64;
+	}
+}
+
+async fn send(path: &Path, req: &Request) -> anyhow::Result<(Value, BufReader<tokio::net::unix::OwnedReadHalf>)> {
+	let conn = match UnixStream::connect(path).await {
+		Ok(conn) => conn,
+		Err(err) => bail!("cannot reach the daemon at {} ({}), start it with `pupynet daemon`", path.display(), err),
+	};
+	let (reader, mut writer) = conn.into_split();
+	let mut data = serde_json::to_vec(req)?;
+	data.push(b'\n');
+	writer.write_all(&data).await?;
+	let mut reader = BufReader::new(reader);
+	let mut line = String::new();
+	if reader.read_line(&mut line).await? == 0 {
+		bail!("daemon closed the connection");
+	}
+	match serde_json::from_str(&line)? {
+		Response::Ok(value) => Ok((value, reader)),
+		Response::Error(err) => bail!(err),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_location() {
+		assert_eq!(Location::parse("nas:/srv/a.txt"), Location::Remote {
+			node: "nas".to_string(),
+			path: "/srv/a.txt".to_string(),
+		});
+		assert_eq!(Location::parse("/tmp/a:b"), Location::Local(PathBuf::from("/tmp/a:b")));
+		assert_eq!(Location::parse("a.txt"), Location::Local(PathBuf::from("a.txt")));
+	}
+
+	#[tokio::test]
+	async fn test_copy_in_ranges() {
+		let dir = tempfile::tempdir().unwrap();
+		let pupynet = Pupynet::builder().data_dir(dir.path().join("node")).no_discovery().build();
+		let data: Vec<u8> = (0..2 * RANGE as usize + 1000).map(|i| (i * 7 % 251) as u8).collect();
+		let (src, dest) = (dir.path().join("src.bin"), dir.path().join("dest.bin"));
+		std::fs::write(&src, &data).unwrap();
+		std::fs::write(&dest, b"old contents").unwrap();
+
+		let (bytes, _) = copy(&pupynet, &Location::Local(src), Location::Local(dest.clone())).await.unwrap();
+		assert_eq!(bytes, data.len() as u64);
+		assert!(std::fs::read(&dest).unwrap() == data);
+		// Only the node's folder and the two files are left.
+		assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+
+		let missing = Location::Local(dir.path().join("missing.bin"));
+		assert!(copy(&pupynet, &missing, Location::Local(dest.clone())).await.is_err());
+		assert!(std::fs::read(&dest).unwrap() == data);
+	}
+}
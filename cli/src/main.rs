@@ -7,7 +7,6 @@ use anyhow::bail;
 use args::Args;
 use args::Command;
 use flate2::bufread::GzDecoder;
-use pupynet_core::Pupynet;
 use clap::Parser;
 use reqwest::header;
 use reqwest::Url;
@@ -22,9 +21,13 @@ use tar::Archive;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 use rsa::signature::Verifier;
-use tokio::sync::mpsc;
 
 mod args;
+mod config;
+#[cfg(unix)]
+mod client;
+#[cfg(unix)]
+mod control;
 
 pub const PUBLIC_KEY: &str = include_str!("../../public_key.pem");
 
@@ -173,13 +176,107 @@ async fn update_bin() -> anyhow::Result<()> {
 	Ok(())
 }
 
+#[cfg(unix)]
+fn listen_signal(kind: tokio::signal::unix::SignalKind) -> Option<tokio::signal::unix::Signal> {
+	match tokio::signal::unix::signal(kind) {
+		Ok(signal) => Some(signal),
+		Err(err) => {
+			log::warn!("cannot listen for signal {:?}: {}", kind, err);
+			None
+		}
+	}
+}
+
+/// Resolves when the signal arrives. Never resolves when it could not be
+/// listened for or on platforms without Unix signals.
+#[cfg(unix)]
+async fn recv_signal(signal: &mut Option<tokio::signal::unix::Signal>) {
+	match signal {
+		Some(signal) => {
+			signal.recv().await;
+		},
+		None => std::future::pending().await,
+	}
+}
+
+#[cfg(not(unix))]
+async fn recv_signal(_: &mut Option<()>) {
+	std::future::pending().await
+}
+
+async fn run(args: Args) -> anyhow::Result<()> {
+	let config_path = args.config.clone().unwrap_or_else(config::default_path);
+	let file = config::load(&config_path)?;
+	#[cfg(unix)]
+	let socket = args.socket.clone().unwrap_or_else(control::default_path);
+	#[cfg(unix)]
+	let listener = control::bind(&socket).await?;
+	let mut pupynet = config::builder(file, &args).build();
+
+	#[cfg(unix)]
+	{
+		let pupynet = pupynet.clone();
+		tokio::spawn(async move {
+			if let Err(err) = control::serve(listener, pupynet).await {
+				log::error!("control socket stopped: {}", err);
+			}
+		});
+	}
+
+	#[cfg(unix)]
+	let (mut sighup, mut sigterm) = (
+		listen_signal(tokio::signal::unix::SignalKind::hangup()),
+		listen_signal(tokio::signal::unix::SignalKind::terminate()),
+	);
+	#[cfg(not(unix))]
+	let (mut sighup, mut sigterm) = (None, None);
+
+	loop {
+		tokio::select! {
+			event = pupynet.next() => {
+				match event {
+					Some(event) => log::info!("{:?}", event),
+					None => break,
+				}
+			}
+			_ = tokio::signal::ctrl_c() => break,
+			_ = recv_signal(&mut sigterm) => break,
+			_ = recv_signal(&mut sighup) => {
+				log::info!("reloading {}", config_path.display());
+				match config::load(&config_path) {
+					Ok(file) => if let Err(err) = pupynet.reload(config::builder(file, &args)) {
+						log::error!("reload failed: {}", err);
+					},
+					Err(err) => log::error!("keeping old config, {} is invalid: {}", config_path.display(), err),
+				}
+			}
+		}
+	}
+	pupynet.shutdown().await;
+	#[cfg(unix)]
+	let _ = std::fs::remove_file(&socket);
+	Ok(())
+}
+
+#[cfg(not(unix))]
+mod client {
+	pub async fn run(_: Option<std::path::PathBuf>, _: bool, _: crate::args::Command) -> anyhow::Result<()> {
+		anyhow::bail!("talking to the daemon needs Unix domain sockets")
+	}
+}
+
 #[tokio::main]
 async fn main() {
-	simple_logger::init_with_level(log::Level::Info).unwrap();
-	log::info!("Pupynet version: {}", get_version());
-
 	let args = Args::parse();
 
+	// Commands talking to the daemon print their own output, keep logs out of it.
+	let level = match args.cmd {
+		None | Some(Command::Daemon) | Some(Command::Update) | Some(Command::Verify { .. }) => log::Level::Info,
+		Some(_) => log::Level::Warn,
+	};
+	simple_logger::init_with_level(level).unwrap();
+	log::info!("Pupynet version: {}", get_version());
+
 	match args.cmd {
 		Some(Command::Update) => {
 			update_bin().await.unwrap();
@@ -193,21 +290,17 @@ async fn main() {
 				log::error!("Signature verification failed");
 			}
 		}
-		_ => {
-			
-		}
+		Some(Command::Daemon) | None => {
+			if let Err(err) = run(args).await {
+				log::error!("{}", err);
+				std::process::exit(1);
+			}
+		},
+		Some(cmd) => {
+			if let Err(err) = client::run(args.socket, args.json, cmd).await {
+				eprintln!("error: {:#}", err);
+				std::process::exit(1);
+			}
+		},
 	}
-
-	let (tx, mut rx) = mpsc::channel::<[u8; 1024]>(10); // 10 slots for fixed-size arrays
-
-    tokio::spawn(async move {
-        for i in 0..20 {
-            let data = [i as u8; 1024]; // Reuse static allocation
-            tx.send(data).await.unwrap();
-        }
-    });
-
-    while let Some(data) = rx.recv().await {
-        println!("Received: {:?}", data[0]); // Prints first byte of each message
-    }
 }
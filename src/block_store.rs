@@ -0,0 +1,494 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncSeekExt;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::watch;
+use tokio::sync::Mutex;
+use tokio::sync::RwLock;
+
+use crate::builder::Share;
+use crate::chunk;
+use crate::chunk::Hash;
+use crate::chunk::MAX_CHUNK;
+use crate::delta;
+use crate::protocol::PeerCmd;
+use crate::protocol::ERR_ACCESS_DENIED;
+use crate::stream;
+use crate::stream::Stream;
+use crate::DeltaStats;
+
+/// How often chunks no received file is made of any more are removed.
+pub const GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Ends the receiver's list of wanted chunks.
+const WANT_END: u32 = u32::MAX;
+/// Recipes and want lists are batched into writes of about this size.
+const BATCH: usize = 64 * 1024;
+
+pub(crate) fn hex(hash: &Hash) -> String {
+	hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex(s: &str) -> Option<Hash> {
+	if s.len() != 64 || !s.is_ascii() {
+		return None;
+	}
+	let mut hash = Hash::default();
+	for (i, byte) in hash.iter_mut().enumerate() {
+		*byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+	}
+	Some(hash)
+}
+
+/// Size and modification time in milliseconds, to tell whether a file
+/// changed since we wrote it.
+async fn stamp(path: &Path) -> Option<(u64, u64)> {
+	let metadata = tokio::fs::metadata(path).await.ok()?;
+	let modified = metadata.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?;
+	Some((metadata.len(), modified.as_millis() as u64))
+}
+
+/// The chunks a received file is made of.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Manifest {
+	size: u64,
+	modified: u64,
+	chunks: Vec<Hash>,
+	/// Of the whole file; missing in manifests written by older builds.
+	#[serde(default)]
+	hash: Option<Hash>,
+}
+
+/// The chunks of the files we received, by hash, so no chunk we have is
+/// transferred to us again, whichever file it is part of.
+#[derive(Debug)]
+pub struct BlockStore {
+	dir: PathBuf,
+	/// Held by transfers counting on chunks to stay, and exclusively by
+	/// garbage collection.
+	in_use: RwLock<()>,
+	/// Serializes updates of `manifests.json`.
+	manifests: Mutex<()>,
+}
+
+impl BlockStore {
+	pub fn new(dir: PathBuf) -> BlockStore {
+		BlockStore { dir, in_use: RwLock::new(()), manifests: Mutex::new(()) }
+	}
+
+	fn chunk_path(&self, hash: &Hash) -> PathBuf {
+		let name = hex(hash);
+		self.dir.join(&name[..2]).join(name)
+	}
+
+	fn manifests_path(&self) -> PathBuf {
+		self.dir.join("manifests.json")
+	}
+
+	pub async fn contains(&self, hash: &Hash) -> bool {
+		tokio::fs::try_exists(self.chunk_path(hash)).await.unwrap_or(false)
+	}
+
+	/// Reads a chunk, `None` when we lack it or it no longer matches its hash.
+	pub async fn get(&self, hash: &Hash) -> Option<Vec<u8>> {
+		let data = tokio::fs::read(self.chunk_path(hash)).await.ok()?;
+		(chunk::hash(&data) == *hash).then_some(data)
+	}
+
+	pub async fn put(&self, hash: &Hash, data: &[u8]) -> std::io::Result<()> {
+		let path = self.chunk_path(hash);
+		if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+			return Ok(());
+		}
+		if let Some(parent) = path.parent() {
+			tokio::fs::create_dir_all(parent).await?;
+		}
+		let temp = delta::temp_path(&path);
+		let res = async {
+			tokio::fs::write(&temp, data).await?;
+			tokio::fs::rename(&temp, &path).await
+		}.await;
+		if res.is_err() {
+			let _ = tokio::fs::remove_file(&temp).await;
+		}
+		res
+	}
+
+	async fn load_manifests(&self) -> anyhow::Result<HashMap<String, Manifest>> {
+		match tokio::fs::read(self.manifests_path()).await {
+			Ok(data) => Ok(serde_json::from_slice(&data)?),
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+			Err(err) => Err(err.into()),
+		}
+	}
+
+	async fn save_manifests(&self, manifests: &HashMap<String, Manifest>) -> anyhow::Result<()> {
+		tokio::fs::create_dir_all(&self.dir).await?;
+		let path = self.manifests_path();
+		let temp = delta::temp_path(&path);
+		tokio::fs::write(&temp, serde_json::to_vec(manifests)?).await?;
+		tokio::fs::rename(&temp, &path).await?;
+		Ok(())
+	}
+
+	/// Records that `path` is made of `chunks` and hashes to `hash`, which
+	/// keeps the chunks for as long as the file stays as we wrote it.
+	async fn add_manifest(&self, path: &Path, chunks: Vec<Hash>, hash: Hash) -> anyhow::Result<()> {
+		let (size, modified) = match stamp(path).await {
+			Some(stamp) => stamp,
+			None => anyhow::bail!("{} vanished after writing it", path.display()),
+		};
+		let _guard = self.manifests.lock().await;
+		let mut manifests = self.load_manifests().await?;
+		manifests.insert(path.to_string_lossy().to_string(), Manifest { size, modified, chunks, hash: Some(hash) });
+		self.save_manifests(&manifests).await
+	}
+
+	/// Received files that hash to `hash`, as far as they did not change
+	/// since.
+	pub async fn files_with(&self, hash: &Hash) -> Vec<PathBuf> {
+		let manifests = match self.load_manifests().await {
+			Ok(manifests) => manifests,
+			Err(err) => {
+				log::warn!("could not load manifests: {:#}", err);
+				return Vec::new();
+			},
+		};
+		let mut files = Vec::new();
+		for (path, manifest) in manifests {
+			if manifest.hash.as_ref() == Some(hash) && stamp(Path::new(&path)).await == Some((manifest.size, manifest.modified)) {
+				files.push(PathBuf::from(path));
+			}
+		}
+		files
+	}
+
+	/// Removes the chunks no unchanged received file is made of, and the
+	/// manifests of files that changed. Returns how many chunks went.
+	pub async fn collect_garbage(&self) -> anyhow::Result<usize> {
+		let _in_use = self.in_use.write().await;
+		let _guard = self.manifests.lock().await;
+		let mut live = HashSet::new();
+		let mut kept = HashMap::new();
+		for (path, manifest) in self.load_manifests().await? {
+			if stamp(Path::new(&path)).await == Some((manifest.size, manifest.modified)) {
+				live.extend(manifest.chunks.iter().copied());
+				kept.insert(path, manifest);
+			}
+		}
+		self.save_manifests(&kept).await?;
+
+		let mut removed = 0;
+		let mut dirs = tokio::fs::read_dir(&self.dir).await?;
+		while let Some(dir) = dirs.next_entry().await? {
+			if !dir.file_type().await?.is_dir() {
+				continue;
+			}
+			let mut entries = tokio::fs::read_dir(dir.path()).await?;
+			while let Some(entry) = entries.next_entry().await? {
+				// Anything else is a chunk whose write was cut short.
+				if parse_hex(&entry.file_name().to_string_lossy()).is_some_and(|hash| live.contains(&hash)) {
+					continue;
+				}
+				tokio::fs::remove_file(entry.path()).await?;
+				removed += 1;
+			}
+		}
+		Ok(removed)
+	}
+}
+
+/// Collects garbage in `store` every `interval` until the node shuts down.
+pub async fn collect_garbage_every(store: std::sync::Arc<BlockStore>, interval: Duration, mut shutdown: watch::Receiver<bool>) {
+	let mut timer = tokio::time::interval(interval);
+	loop {
+		tokio::select! {
+			_ = timer.tick() => {},
+			_ = shutdown.changed() => return,
+		}
+		match store.collect_garbage().await {
+			Ok(0) => {},
+			Ok(removed) => log::info!("removed {} unused chunks", removed),
+			Err(err) => log::warn!("chunk garbage collection failed: {:#}", err),
+		}
+	}
+}
+
+/// A chunk of the file being sent.
+pub struct Part {
+	offset: usize,
+	len: usize,
+	hash: Hash,
+}
+
+/// Cuts `data` into the parts `send` sends, and hashes it whole.
+pub fn recipe(data: &[u8]) -> (Vec<Part>, Hash) {
+	let mut offset = 0;
+	let parts = chunk::chunks(data).map(|c| {
+		let part = Part { offset, len: c.len(), hash: chunk::hash(c) };
+		offset += c.len();
+		part
+	}).collect();
+	(parts, chunk::hash(data))
+}
+
+/// Sender side of `WriteBlocks`: offers the parts of `data`, then sends
+/// the ones the receiver asks for.
+pub async fn send(stream: &mut Stream, data: &[u8], parts: &[Part]) -> anyhow::Result<DeltaStats> {
+	let mut batch = Vec::with_capacity(BATCH + 36);
+	for part in parts {
+		batch.extend_from_slice(&(part.len as u32).to_le_bytes());
+		batch.extend_from_slice(&part.hash);
+		if batch.len() >= BATCH {
+			stream.write_bytes(&batch).await?;
+			batch.clear();
+		}
+	}
+	batch.extend_from_slice(&0u32.to_le_bytes());
+	stream.write_bytes(&batch).await?;
+	stream.read_status().await?;
+
+	let mut wanted = Vec::new();
+	loop {
+		let index = stream.read_u32().await?;
+		if index == WANT_END {
+			break;
+		}
+		match parts.get(index as usize) {
+			Some(part) => wanted.push(part),
+			None => anyhow::bail!("receiver wants unknown chunk {}", index),
+		}
+	}
+	let mut stats = DeltaStats { sent: 0, reused: data.len() as u64 };
+	for part in wanted {
+		stream.write_bytes(&data[part.offset..part.offset + part.len]).await?;
+		stats.sent += part.len as u64;
+		stats.reused -= part.len as u64;
+	}
+	Ok(stats)
+}
+
+/// Where the receiver takes a chunk from.
+enum Source {
+	Stream,
+	/// Offset in our current copy.
+	Old(u64),
+	Store,
+}
+
+pub(crate) async fn read_recipe(stream: &mut Stream, size: u64) -> anyhow::Result<Vec<(u32, Hash)>> {
+	let mut parts = Vec::new();
+	let mut total = 0;
+	loop {
+		let len = stream.read_u32().await?;
+		if len == 0 {
+			break;
+		}
+		if len as usize > MAX_CHUNK {
+			anyhow::bail!("chunk of {} bytes", len);
+		}
+		let hash: Hash = stream.read_exact(32).await?[..].try_into()?;
+		parts.push((len, hash));
+		total += len as u64;
+	}
+	if total != size {
+		anyhow::bail!("chunks add up to {} bytes instead of {}", total, size);
+	}
+	Ok(parts)
+}
+
+/// Decides where each part comes from: our current copy, the store or the
+/// sender.
+async fn plan(path: &Path, parts: &[(u32, Hash)], store: Option<&BlockStore>) -> anyhow::Result<Vec<Source>> {
+	let old = {
+		let path = path.to_path_buf();
+		tokio::task::spawn_blocking(move || delta::signatures(&path)).await??
+	};
+	let old: HashMap<Hash, u64> = old.iter().map(|sig| (sig.hash, sig.offset)).collect();
+	let mut coming = HashSet::new();
+	let mut sources = Vec::with_capacity(parts.len());
+	for (_, hash) in parts {
+		let source = match old.get(hash) {
+			Some(offset) => Source::Old(*offset),
+			// Parts sent earlier in this transfer are in the store by the
+			// time they are needed again.
+			None if store.is_some() && coming.contains(hash) => Source::Store,
+			None => match store {
+				Some(store) if store.contains(hash).await => Source::Store,
+				_ => {
+					coming.insert(*hash);
+					Source::Stream
+				},
+			},
+		};
+		sources.push(source);
+	}
+	Ok(sources)
+}
+
+/// Writes the new file to `temp` part by part, then moves it into place.
+async fn assemble(stream: &mut Stream, path: &Path, temp: &Path, hash: Hash, parts: &[(u32, Hash)], sources: &[Source], store: Option<&BlockStore>) -> anyhow::Result<()> {
+	let mut old = match sources.iter().any(|s| matches!(s, Source::Old(_))) {
+		true => Some(tokio::fs::File::open(path).await?),
+		false => None,
+	};
+	let mut out = tokio::io::BufWriter::new(tokio::fs::File::create(temp).await?);
+	let mut hasher = Sha256::new();
+	for ((len, part_hash), source) in parts.iter().zip(sources) {
+		let data = match (source, old.as_mut()) {
+			(Source::Stream, _) => stream.read_exact(*len as usize).await?.to_vec(),
+			(Source::Old(offset), Some(old)) => {
+				let mut buf = vec![0; *len as usize];
+				old.seek(std::io::SeekFrom::Start(*offset)).await?;
+				old.read_exact(&mut buf).await?;
+				buf
+			},
+			(Source::Store, _) => match store {
+				Some(store) => store.get(part_hash).await.unwrap_or_default(),
+				None => Vec::new(),
+			},
+			(Source::Old(_), None) => Vec::new(),
+		};
+		if chunk::hash(&data) != *part_hash {
+			anyhow::bail!("chunk of {} does not match its hash", path.display());
+		}
+		if let Some(store) = store {
+			store.put(part_hash, &data).await?;
+		}
+		hasher.update(&data);
+		out.write_all(&data).await?;
+	}
+	out.flush().await?;
+	if <Hash>::from(hasher.finalize()) != hash {
+		anyhow::bail!("{} does not match its hash", path.display());
+	}
+	let file = out.into_inner();
+	if let Ok(metadata) = tokio::fs::metadata(path).await {
+		file.set_permissions(metadata.permissions()).await?;
+	}
+	file.sync_all().await?;
+	tokio::fs::rename(temp, path).await?;
+	Ok(())
+}
+
+async fn receive(stream: &mut Stream, path: &Path, size: u64, hash: Hash, store: Option<&BlockStore>) -> anyhow::Result<()> {
+	let parts = read_recipe(stream, size).await?;
+	let sources = match plan(path, &parts, store).await {
+		Ok(sources) => sources,
+		Err(err) => return stream.write_status(Err(err)).await,
+	};
+	stream.write_status(Ok(())).await?;
+	let mut batch = Vec::with_capacity(BATCH + 4);
+	for (index, source) in sources.iter().enumerate() {
+		if matches!(source, Source::Stream) {
+			batch.extend_from_slice(&(index as u32).to_le_bytes());
+		}
+		if batch.len() >= BATCH {
+			stream.write_bytes(&batch).await?;
+			batch.clear();
+		}
+	}
+	batch.extend_from_slice(&WANT_END.to_le_bytes());
+	stream.write_bytes(&batch).await?;
+
+	let temp = delta::temp_path(path);
+	let mut res = assemble(stream, path, &temp, hash, &parts, &sources, store).await;
+	if res.is_err() {
+		let _ = tokio::fs::remove_file(&temp).await;
+	}
+	if let (Ok(()), Some(store)) = (&res, store) {
+		res = store.add_manifest(path, parts.into_iter().map(|(_, hash)| hash).collect(), hash).await;
+	}
+	stream.write_status(res).await
+}
+
+/// Serves a `WriteBlocks`, refusing paths outside writable shares.
+pub async fn handle_stream(mut stream: Stream, cmd: PeerCmd, shares: &[Share], store: Option<&BlockStore>) -> anyhow::Result<()> {
+	let (path, size, hash) = match cmd {
+		PeerCmd::WriteBlocks { path, size, hash, .. } => (PathBuf::from(path), size, hash),
+		_ => anyhow::bail!("not a block write"),
+	};
+	if !stream::is_shared(shares, &path, true).await {
+		stream::reject(stream, ERR_ACCESS_DENIED, "path is not shared".to_string()).await;
+		return Ok(());
+	}
+	{
+		let _in_use = match store {
+			Some(store) => Some(store.in_use.read().await),
+			None => None,
+		};
+		receive(&mut stream, &path, size, hash, store).await?;
+	}
+	stream.finish()?;
+	while stream.read_bytes().await?.is_some() {}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::folder;
+	use crate::testing::node;
+	use crate::testing::wait_for_peer;
+
+	#[tokio::test]
+	async fn test_garbage_collection() {
+		let dir = tempfile::tempdir().unwrap();
+		let store = BlockStore::new(dir.path().join("blocks"));
+		let (kept, dropped) = (b"kept".to_vec(), b"dropped".to_vec());
+		for data in [&kept, &dropped] {
+			store.put(&chunk::hash(data), data).await.unwrap();
+		}
+		assert_eq!(store.get(&chunk::hash(&kept)).await, Some(kept.clone()));
+
+		let file = dir.path().join("file");
+		std::fs::write(&file, &kept).unwrap();
+		store.add_manifest(&file, vec![chunk::hash(&kept)], chunk::hash(&kept)).await.unwrap();
+		assert_eq!(store.files_with(&chunk::hash(&kept)).await, vec![file.clone()]);
+		assert_eq!(store.collect_garbage().await.unwrap(), 1);
+		assert!(store.contains(&chunk::hash(&kept)).await);
+		assert!(!store.contains(&chunk::hash(&dropped)).await);
+
+		// Once the file changes its chunks are no longer needed.
+		std::fs::write(&file, b"changed").unwrap();
+		assert_eq!(store.collect_garbage().await.unwrap(), 1);
+		assert!(store.load_manifests().await.unwrap().is_empty());
+		assert!(store.files_with(&chunk::hash(&kept)).await.is_empty());
+	}
+
+	#[test]
+	fn test_hex() {
+		let hash = chunk::hash(b"hello");
+		assert_eq!(parse_hex(&hex(&hash)), Some(hash));
+		assert_eq!(parse_hex("manifests.json"), None);
+	}
+
+	#[tokio::test]
+	async fn test_block_store_dedups() {
+		let dir = tempfile::tempdir().unwrap();
+		let (docs, backup) = (folder(dir.path(), "docs"), folder(dir.path(), "backup"));
+		let a = node(dir.path(), "a").share(&docs).share(&backup).build();
+		let a_info = a.info().await.unwrap();
+		let b = node(dir.path(), "b").peer(&a_info.listen_addrs[0]).build();
+		wait_for_peer(&b, &a_info.id).await;
+		let data: Vec<u8> = (0..1 << 20).map(|_| rand::random()).collect();
+		let first = docs.join("report.bin");
+		let stats = b.sync_file(&a_info.id, first.to_str().unwrap(), data.clone()).await.unwrap();
+		assert_eq!((stats.sent, stats.reused), (data.len() as u64, 0));
+
+		// The same bytes under another name only cost the recipe.
+		let second = backup.join("report-copy.bin");
+		let stats = b.sync_file(&a_info.id, second.to_str().unwrap(), data.clone()).await.unwrap();
+		assert_eq!((stats.sent, stats.reused), (0, data.len() as u64));
+		assert_eq!(std::fs::read(&second).unwrap(), data);
+		assert_eq!(std::fs::read_dir(&backup).unwrap().count(), 1);
+	}
+}
@@ -0,0 +1,334 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+
+use crate::compress::Compression;
+use crate::folder_sync::Engine;
+use crate::folder_sync::SYNC_INTERVAL;
+use crate::multiplex::MAX_WIDE_FRAME;
+use crate::protocol::MAX_HOPS;
+use crate::rate::RateConfig;
+use crate::rate::RateLimit;
+use crate::rate::RateSchedule;
+use crate::storage;
+use crate::transfer::Priority;
+use crate::transfer::TransferManager;
+use crate::transfer::MAX_TRANSFERS;
+use crate::udp::DISCOVERY_PORT;
+use crate::worker::Worker;
+use crate::worker::DISCOVERY_INTERVAL;
+use crate::worker::KEEPALIVE_INTERVAL;
+use crate::worker::KEEPALIVE_TIMEOUT;
+use crate::worker::PEER_BUFFER;
+use crate::worker::QUEUE_CAPACITY;
+use crate::worker::SHUTDOWN_TIMEOUT;
+use crate::Pupynet;
+
+/// A folder remote nodes may access.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+	pub path: PathBuf,
+	/// Remote nodes may only read and list inside a read-only share.
+	pub read_only: bool,
+}
+
+/// A folder kept identical on every node of our owner that syncs a folder
+/// with the same `id`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncFolder {
+	pub id: String,
+	pub path: PathBuf,
+}
+
+/// Everything a node is started with, collected by `PupynetBuilder`.
+#[derive(Debug, Clone)]
+pub struct Config {
+	pub name: String,
+	pub owner: Option<String>,
+	/// Where the peer store lives, `~/.pupynet` when unset.
+	pub data_dir: Option<PathBuf>,
+	/// File holding the node id, `<data_dir>/node_id` when unset.
+	pub identity_path: Option<PathBuf>,
+	/// UDP port for LAN discovery, `None` turns discovery off.
+	pub discovery_port: Option<u16>,
+	pub discovery_interval: Duration,
+	/// Folders remote nodes may access. Empty means none.
+	pub shares: Vec<Share>,
+	pub listen: Vec<String>,
+	pub peers: Vec<String>,
+	pub keepalive_interval: Duration,
+	pub keepalive_timeout: Duration,
+	pub max_connections: usize,
+	pub max_hops: u8,
+	/// How long `shutdown` waits for in-flight transfers.
+	pub shutdown_timeout: Duration,
+	/// Commands and transport events that may wait for the worker.
+	pub queue_capacity: usize,
+	/// Bytes a connection may queue for sending and for stream readers.
+	pub peer_buffer: usize,
+	/// Largest frame we accept, advertised to peers when connecting.
+	pub max_frame_size: u32,
+	/// Codec we ask for when reading files from peers that support it.
+	pub compression: Compression,
+	pub sync_folders: Vec<SyncFolder>,
+	/// How often synced folders are rescanned and compared with peers.
+	pub sync_interval: Duration,
+	/// Keep the chunks of received files in `<data_dir>/blocks`, so the
+	/// same data is never received twice.
+	pub block_store: bool,
+	/// Transfers running at once; the others wait in a queue.
+	pub max_transfers: usize,
+	pub rate_limits: RateConfig,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Config {
+			name: String::new(),
+			owner: None,
+			data_dir: None,
+			identity_path: None,
+			discovery_port: Some(DISCOVERY_PORT),
+			discovery_interval: DISCOVERY_INTERVAL,
+			shares: Vec::new(),
+			listen: Vec::new(),
+			peers: Vec::new(),
+			keepalive_interval: KEEPALIVE_INTERVAL,
+			keepalive_timeout: KEEPALIVE_TIMEOUT,
+			max_connections: 256,
+			max_hops: MAX_HOPS,
+			shutdown_timeout: SHUTDOWN_TIMEOUT,
+			queue_capacity: QUEUE_CAPACITY,
+			peer_buffer: PEER_BUFFER,
+			max_frame_size: MAX_WIDE_FRAME,
+			compression: Compression::Zstd,
+			sync_folders: Vec::new(),
+			sync_interval: SYNC_INTERVAL,
+			block_store: true,
+			max_transfers: MAX_TRANSFERS,
+			rate_limits: RateConfig::default(),
+		}
+	}
+}
+
+/// Configures and starts a `Pupynet` node.
+///
+/// ```no_run
+/// # async fn example() {
+/// let node = pupynet_core::Pupynet::builder()
+///     .name("nas")
+///     .listen("tcp://0.0.0.0:7765")
+///     .share("/srv/media")
+///     .build();
+/// # }
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct PupynetBuilder {
+	pub(crate) config: Config,
+}
+
+impl PupynetBuilder {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn name(mut self, name: impl Into<String>) -> Self {
+		self.config.name = name.into();
+		self
+	}
+
+	/// Who the node belongs to. Peers tell us their owner themselves and it
+	/// is not authenticated, so a peer only counts as one of our own nodes
+	/// once it is also trusted with `Pupynet::set_trust`.
+	pub fn owner(mut self, owner: impl Into<String>) -> Self {
+		self.config.owner = Some(owner.into());
+		self
+	}
+
+	pub fn data_dir(mut self, path: impl Into<PathBuf>) -> Self {
+		self.config.data_dir = Some(path.into());
+		self
+	}
+
+	pub fn identity_path(mut self, path: impl Into<PathBuf>) -> Self {
+		self.config.identity_path = Some(path.into());
+		self
+	}
+
+	pub fn discovery_port(mut self, port: u16) -> Self {
+		self.config.discovery_port = Some(port);
+		self
+	}
+
+	/// Disables UDP discovery; peers are then only reached through `peer`/`connect`.
+	pub fn no_discovery(mut self) -> Self {
+		self.config.discovery_port = None;
+		self
+	}
+
+	pub fn discovery_interval(mut self, interval: Duration) -> Self {
+		self.config.discovery_interval = interval;
+		self
+	}
+
+	/// Adds a folder remote nodes may access. Requests for paths outside
+	/// all shares are refused.
+	pub fn share(mut self, path: impl Into<PathBuf>) -> Self {
+		self.config.shares.push(Share { path: path.into(), read_only: false });
+		self
+	}
+
+	/// Like `share`, but remote nodes may not modify anything inside it.
+	pub fn share_read_only(mut self, path: impl Into<PathBuf>) -> Self {
+		self.config.shares.push(Share { path: path.into(), read_only: true });
+		self
+	}
+
+	/// Adds an address to listen on, e.g. `tcp://0.0.0.0:7765`.
+	pub fn listen(mut self, addr: impl Into<String>) -> Self {
+		self.config.listen.push(addr.into());
+		self
+	}
+
+	/// Adds a peer to keep connected to from startup.
+	pub fn peer(mut self, addr: impl Into<String>) -> Self {
+		self.config.peers.push(addr.into());
+		self
+	}
+
+	pub fn keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+		self.config.keepalive_interval = interval;
+		self.config.keepalive_timeout = timeout;
+		self
+	}
+
+	/// Connections beyond this are closed right after they are accepted.
+	pub fn max_connections(mut self, max: usize) -> Self {
+		self.config.max_connections = max;
+		self
+	}
+
+	/// How many nodes our streams may pass through before they are dropped.
+	pub fn max_hops(mut self, hops: u8) -> Self {
+		self.config.max_hops = hops;
+		self
+	}
+
+	pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+		self.config.shutdown_timeout = timeout;
+		self
+	}
+
+	/// How many commands and transport events may queue up for the worker
+	/// before senders have to wait.
+	pub fn queue_capacity(mut self, capacity: usize) -> Self {
+		self.config.queue_capacity = capacity.max(1);
+		self
+	}
+
+	/// Caps the data a connection may queue for the socket, and what a peer
+	/// may write to each stream before it is read. Once full, writers wait.
+	/// Peers without `FEATURE_WINDOW` share one such budget across streams
+	/// and we stop reading their socket while it is full.
+	pub fn peer_buffer(mut self, bytes: usize) -> Self {
+		self.config.peer_buffer = bytes;
+		self
+	}
+
+	/// Largest frame peers may send us. Both sides of a connection use the
+	/// smaller of their limits; larger frames mean less header overhead for
+	/// bulk transfers.
+	pub fn max_frame_size(mut self, bytes: u32) -> Self {
+		self.config.max_frame_size = bytes;
+		self
+	}
+
+	/// Codec for files we read from other nodes, zstd by default. Files
+	/// that are compressed already are always sent as they are.
+	pub fn compression(mut self, codec: Compression) -> Self {
+		self.config.compression = codec;
+		self
+	}
+
+	/// Keeps `path` in sync with the folders of the same `id` on the other
+	/// nodes of our owner that we trust. Changes made on both sides are kept
+	/// as conflict copies.
+	pub fn sync_folder(mut self, id: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+		self.config.sync_folders.push(SyncFolder { id: id.into(), path: path.into() });
+		self
+	}
+
+	pub fn sync_interval(mut self, interval: Duration) -> Self {
+		self.config.sync_interval = interval;
+		self
+	}
+
+	/// Stops keeping the chunks of received files. Saves the disk space
+	/// they take, at the cost of receiving data again that we already had.
+	pub fn no_block_store(mut self) -> Self {
+		self.config.block_store = false;
+		self
+	}
+
+	/// How many transfers run at once, 4 by default. Later ones wait,
+	/// highest priority first.
+	pub fn max_transfers(mut self, max: usize) -> Self {
+		self.config.max_transfers = max.max(1);
+		self
+	}
+
+	/// Caps how fast stream data is sent and received over all connections
+	/// together. Control messages are never held back.
+	pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+		self.config.rate_limits.global = limit;
+		self
+	}
+
+	/// Caps the stream data sent to and received from a directly connected
+	/// node, on top of the global limit.
+	pub fn peer_rate_limit(mut self, node_id: impl Into<String>, limit: RateLimit) -> Self {
+		self.config.rate_limits.peers.insert(node_id.into(), limit);
+		self
+	}
+
+	/// Uses `limit` instead of the global limit from `from` to `to`, both
+	/// times after midnight UTC, e.g. to lift the limit at night.
+	pub fn rate_schedule(mut self, from: Duration, to: Duration, limit: RateLimit) -> Self {
+		self.config.rate_limits.schedules.push(RateSchedule { from, to, limit });
+		self
+	}
+
+	/// Starts the node. Must be called inside a tokio runtime.
+	pub fn build(self) -> Pupynet {
+		let (event_tx, event_rx) = broadcast::channel(1024);
+		let (tx, rx) = mpsc::channel(self.config.queue_capacity);
+		let config = self.config;
+		let sync = (config.sync_folders.clone(), storage::data_dir(&config), config.sync_interval);
+		let transfers = Arc::new(TransferManager::new(config.max_transfers, event_tx.clone()));
+		{
+			let event_tx = event_tx.clone();
+			tokio::spawn(async move {
+				Worker::new(config, rx, event_tx).await.run().await;
+			});
+		}
+
+		let pupynet = Pupynet {
+			tx,
+			transfers,
+			event_tx,
+			event_rx,
+			priority: Priority::default(),
+		};
+		match sync {
+			(folders, _, _) if folders.is_empty() => {},
+			(folders, Ok(data_dir), interval) => {
+				tokio::spawn(Engine::run(pupynet.clone(), folders, data_dir, interval));
+			},
+			(_, Err(err), _) => log::error!("folder sync disabled: {}", err),
+		}
+		pupynet
+	}
+}
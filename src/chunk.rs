@@ -0,0 +1,140 @@
+use std::io::Read;
+
+use sha2::Digest;
+use sha2::Sha256;
+
+/// Chunks are cut where the bytes before them hash to a pattern, so an
+/// insert or delete only changes the chunks around it and everything after
+/// lines up again.
+pub const MIN_CHUNK: usize = 2 * 1024;
+pub const MAX_CHUNK: usize = 64 * 1024;
+/// Cut when the top 13 bits of the hash are zero, about every 8 KiB.
+const CUT_SHIFT: u32 = 64 - 13;
+
+pub type Hash = [u8; 32];
+
+pub fn hash(data: &[u8]) -> Hash {
+	Sha256::digest(data).into()
+}
+
+/// Random values per byte for the gear hash, fixed so every node cuts the
+/// same data the same way.
+const GEAR: [u64; 256] = {
+	let mut table = [0u64; 256];
+	let mut state = 0x9e37_79b9_7f4a_7c15u64;
+	let mut i = 0;
+	while i < 256 {
+		// splitmix64
+		state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		let mut z = state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		table[i] = z ^ (z >> 31);
+		i += 1;
+	}
+	table
+};
+
+/// Length of the chunk at the start of `data`. Unless `data` is the end of
+/// the input it must hold at least `MAX_CHUNK` bytes.
+pub fn boundary(data: &[u8]) -> usize {
+	if data.len() <= MIN_CHUNK {
+		return data.len();
+	}
+	let end = data.len().min(MAX_CHUNK);
+	let mut h = 0u64;
+	for (i, byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK) {
+		h = (h << 1).wrapping_add(GEAR[*byte as usize]);
+		if h >> CUT_SHIFT == 0 {
+			return i + 1;
+		}
+	}
+	end
+}
+
+/// Splits `data` into chunks.
+pub fn chunks(mut data: &[u8]) -> impl Iterator<Item = &[u8]> {
+	std::iter::from_fn(move || {
+		if data.is_empty() {
+			return None;
+		}
+		let (chunk, rest) = data.split_at(boundary(data));
+		data = rest;
+		Some(chunk)
+	})
+}
+
+/// Cuts a reader into the same chunks `chunks` would cut its contents into.
+pub struct Chunker<R> {
+	reader: R,
+	buffer: Vec<u8>,
+	eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+	pub fn new(reader: R) -> Chunker<R> {
+		Chunker {
+			reader,
+			buffer: Vec::with_capacity(2 * MAX_CHUNK),
+			eof: false,
+		}
+	}
+
+	pub fn next_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+		while !self.eof && self.buffer.len() < MAX_CHUNK {
+			let len = self.buffer.len();
+			self.buffer.resize(len + MAX_CHUNK, 0);
+			let res = self.reader.read(&mut self.buffer[len..]);
+			let read = *res.as_ref().unwrap_or(&0);
+			self.buffer.truncate(len + read);
+			match res {
+				Ok(read) => self.eof = read == 0,
+				Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {},
+				Err(err) => return Err(err),
+			}
+		}
+		if self.buffer.is_empty() {
+			return Ok(None);
+		}
+		let len = boundary(&self.buffer);
+		let rest = self.buffer.split_off(len);
+		Ok(Some(std::mem::replace(&mut self.buffer, rest)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn random(len: usize) -> Vec<u8> {
+		(0..len).map(|_| rand::random()).collect()
+	}
+
+	#[test]
+	fn test_insert_only_changes_nearby_chunks() {
+		let data = random(1 << 20);
+		let before: Vec<Hash> = chunks(&data).map(hash).collect();
+		assert!(before.len() > 50 && before.len() < 500);
+		assert!(chunks(&data).all(|c| c.len() <= MAX_CHUNK));
+
+		let mut edited = data[..300_000].to_vec();
+		edited.extend_from_slice(b"a few new bytes");
+		edited.extend_from_slice(&data[300_000..]);
+		let after: Vec<Hash> = chunks(&edited).map(hash).collect();
+		let changed = after.iter().filter(|h| !before.contains(h)).count();
+		assert!(changed <= 2, "{} chunks changed", changed);
+	}
+
+	#[test]
+	fn test_chunker_matches_chunks() {
+		let data = random(300_000);
+		// A reader handing out odd sized pieces.
+		let mut chunker = Chunker::new(std::io::Read::chain(&data[..1000], &data[1000..]));
+		let mut read = Vec::new();
+		while let Some(chunk) = chunker.next_chunk().unwrap() {
+			read.push(chunk);
+		}
+		let expected: Vec<&[u8]> = chunks(&data).collect();
+		assert_eq!(read, expected);
+	}
+}
@@ -0,0 +1,161 @@
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Codec a file transfer is compressed with. Peers advertising
+/// `FEATURE_COMPRESSION` understand all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Compression {
+	#[default]
+	None,
+	/// Fast, for links where the CPU would otherwise be the bottleneck.
+	Lz4,
+	/// Smaller output, for slow links.
+	Zstd,
+}
+
+impl Compression {
+	pub fn from_u8(value: u8) -> anyhow::Result<Compression> {
+		match value {
+			0 => Ok(Compression::None),
+			1 => Ok(Compression::Lz4),
+			2 => Ok(Compression::Zstd),
+			_ => anyhow::bail!("unknown compression {}", value),
+		}
+	}
+
+	pub fn as_u8(self) -> u8 {
+		match self {
+			Compression::None => 0,
+			Compression::Lz4 => 1,
+			Compression::Zstd => 2,
+		}
+	}
+}
+
+/// Compressed data travels in blocks, each preceded by its raw and stored
+/// length as `u32`s. A block stored at its raw length is not compressed.
+pub const BLOCK_HEADER_LEN: usize = 8;
+/// Largest block we decode, so a peer cannot make us allocate at will.
+pub const MAX_BLOCK_LEN: usize = 4 << 20;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Extensions of formats that are compressed already.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+	"7z", "avif", "br", "bz2", "flac", "gif", "gz", "heic", "jpeg", "jpg", "lz4", "mkv", "mov",
+	"mp3", "mp4", "ogg", "opus", "png", "rar", "tgz", "webm", "webp", "xz", "zip", "zst",
+];
+
+/// The codec to use for `path`, skipping files that would not shrink.
+pub fn for_path(codec: Compression, path: &Path) -> Compression {
+	let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default().to_ascii_lowercase();
+	if COMPRESSED_EXTENSIONS.contains(&ext.as_str()) {
+		return Compression::None;
+	}
+	codec
+}
+
+/// Encodes `data` as one block, header included.
+pub fn encode_block(codec: Compression, data: &[u8]) -> Vec<u8> {
+	let compressed = match codec {
+		Compression::None => None,
+		Compression::Lz4 => Some(lz4_flex::block::compress(data)),
+		Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok(),
+	};
+	let stored = match &compressed {
+		Some(compressed) if compressed.len() < data.len() => compressed,
+		_ => data,
+	};
+	let mut block = Vec::with_capacity(BLOCK_HEADER_LEN + stored.len());
+	block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+	block.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+	block.extend_from_slice(stored);
+	block
+}
+
+/// Decodes the `stored` bytes of a block that was `raw_len` bytes long.
+pub fn decode_block(codec: Compression, raw_len: usize, stored: &[u8]) -> anyhow::Result<Vec<u8>> {
+	if raw_len > MAX_BLOCK_LEN {
+		anyhow::bail!("block of {} bytes exceeds the {} byte limit", raw_len, MAX_BLOCK_LEN);
+	}
+	if stored.len() == raw_len {
+		return Ok(stored.to_vec());
+	}
+	let data = match codec {
+		Compression::None => anyhow::bail!("compressed block in an uncompressed transfer"),
+		Compression::Lz4 => lz4_flex::block::decompress(stored, raw_len)?,
+		Compression::Zstd => zstd::bulk::decompress(stored, raw_len)?,
+	};
+	if data.len() != raw_len {
+		anyhow::bail!("block decoded to {} bytes, expected {}", data.len(), raw_len);
+	}
+	Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::folder;
+	use crate::testing::node;
+	use crate::testing::wait_for_peer;
+
+	fn decode(codec: Compression, block: &[u8]) -> Vec<u8> {
+		let raw_len = u32::from_le_bytes(block[0..4].try_into().unwrap()) as usize;
+		let stored_len = u32::from_le_bytes(block[4..8].try_into().unwrap()) as usize;
+		assert_eq!(block.len(), BLOCK_HEADER_LEN + stored_len);
+		decode_block(codec, raw_len, &block[BLOCK_HEADER_LEN..]).unwrap()
+	}
+
+	#[test]
+	fn test_block_roundtrip() {
+		let text = "fn main() { println!(\"hello\"); }\n".repeat(1000).into_bytes();
+		for codec in [Compression::Lz4, Compression::Zstd] {
+			let block = encode_block(codec, &text);
+			assert!(block.len() < text.len() / 4);
+			assert_eq!(decode(codec, &block), text);
+		}
+
+		// Data that does not shrink is stored as is.
+		let noise: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+		let block = encode_block(Compression::Zstd, &noise);
+		assert_eq!(block.len(), BLOCK_HEADER_LEN + noise.len());
+		assert_eq!(decode(Compression::Zstd, &block), noise);
+
+		assert!(decode_block(Compression::Zstd, MAX_BLOCK_LEN + 1, &[0; 16]).is_err());
+	}
+
+	#[test]
+	fn test_skips_compressed_formats() {
+		assert_eq!(for_path(Compression::Zstd, Path::new("/src/main.rs")), Compression::Zstd);
+		assert_eq!(for_path(Compression::Zstd, Path::new("/photos/IMG_1.JPG")), Compression::None);
+		assert_eq!(for_path(Compression::Lz4, Path::new("/backup.tar.gz")), Compression::None);
+		assert_eq!(for_path(Compression::Lz4, Path::new("/Makefile")), Compression::Lz4);
+	}
+
+	#[tokio::test]
+	async fn test_compressed_reads() {
+		let dir = tempfile::tempdir().unwrap();
+		let share = folder(dir.path(), "share");
+		let text = "let answer = 42;\n".repeat(100_000).into_bytes();
+		std::fs::write(share.join("main.rs"), &text).unwrap();
+		let noise: Vec<u8> = (0..300_000).map(|_| rand::random()).collect();
+		std::fs::write(share.join("photo.jpg"), &noise).unwrap();
+
+		let a = node(dir.path(), "a").share(&share).build();
+		let a_info = a.info().await.unwrap();
+		for codec in [Compression::Zstd, Compression::Lz4, Compression::None] {
+			let b = node(dir.path(), "b").compression(codec).peer(&a_info.listen_addrs[0]).build();
+			// Compression is only asked for once a's features are known.
+			wait_for_peer(&b, &a_info.id).await;
+			let path = share.join("main.rs");
+			// Start mid-file so the range is honoured before compression.
+			let data = b.read_file(&a_info.id, path.to_str().unwrap(), 7, u64::MAX).await.unwrap();
+			assert_eq!(data, text[7..]);
+			let path = share.join("photo.jpg");
+			let data = b.read_file(&a_info.id, path.to_str().unwrap(), 0, u64::MAX).await.unwrap();
+			assert_eq!(data, noise);
+			b.shutdown().await;
+		}
+	}
+}
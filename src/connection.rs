@@ -1,41 +1,953 @@
+use std::collections::HashMap;
+use std::sync::Weak;
+use std::time::Duration;
+
+use bytes::Buf;
+use bytes::BufMut;
+use bytes::Bytes;
+use bytes::BytesMut;
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
 use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
 
+use crate::flow::Budget;
+use crate::flow::Credit;
+use crate::multiplex::frames;
+use crate::multiplex::window_frame;
+use crate::multiplex::FrameFormat;
 use crate::multiplex::Multiplexer;
 use crate::multiplex::MultiplexerEvent;
+use crate::multiplex::Scheduler;
+use crate::multiplex::CONTROL_STREAM;
+use crate::protocol::PeerCmd;
+use crate::protocol::StreamHeader;
+use crate::protocol::FEATURE_PAUSE;
+use crate::protocol::FEATURE_WINDOW;
+use crate::protocol::INITIAL_WINDOW;
+use crate::protocol::MAX_COMMAND_SIZE;
+use crate::protocol::STREAM_CONTINUE;
+use crate::protocol::STREAM_DIED;
+use crate::protocol::STREAM_END;
+use crate::protocol::STREAM_PAUSE;
+use crate::protocol::STREAM_PULL;
+use crate::protocol::STREAM_START;
+use crate::rate::Direction;
+use crate::stream::Hold;
+use crate::stream::Stream;
+use crate::stream::StreamMsg;
 use crate::types::Context;
+use crate::types::InternalEvent;
+use crate::types::PeerConnCmd;
+use crate::types::PeerTx;
+
+/// Bounds for how much is read from the socket at once. Without windows
+/// each read is reserved from the inbound budget first, so a full budget
+/// stops reading.
+const MIN_READ_SIZE: usize = 4 * 1024;
+const MAX_READ_SIZE: usize = 256 * 1024;
+
+/// How much of commands and stream headers still arriving a peer may make
+/// us hold. A few large ones may arrive interleaved.
+const MAX_PARTIAL: usize = 4 * MAX_COMMAND_SIZE;
+
+/// Grows the read size while reads fill it and shrinks it again when they
+/// come back mostly empty.
+fn next_read_size(size: usize, read: usize) -> usize {
+	if read == size {
+		(size * 2).min(MAX_READ_SIZE)
+	} else if read < size / 4 {
+		(size / 2).max(MIN_READ_SIZE)
+	} else {
+		size
+	}
+}
+
+enum Event {
+	Started(u64),
+	Data(u64, Bytes),
+	Ended(u64),
+	Died(u64),
+	Paused(u64, bool),
+	Window(u64, u32),
+}
+
+/// Where the data of a stream goes.
+struct Inbound {
+	tx: mpsc::UnboundedSender<StreamMsg>,
+	/// What the remote may write before we grant more, `None` when the
+	/// stream shares the connection's inbound budget.
+	window: Option<Budget>,
+	/// Read but not granted back yet.
+	ungranted: usize,
+}
 
 pub struct Connection<T: AsyncRead + AsyncWrite> {
 	conn: T,
+	addr: String,
+	outbound: bool,
 	ctx: Context,
-	multiplexer: Multiplexer
+	multiplexer: Multiplexer,
+	/// How we write frames, legacy until the peer advertises wide frames.
+	format: FrameFormat,
+	control: Vec<u8>,
+	/// Open substreams and where their incoming data goes.
+	streams: HashMap<u64, Inbound>,
+	/// Streams opened by the remote whose header has not fully arrived yet.
+	pending: HashMap<u64, Vec<u8>>,
+	/// Write holds of our streams. The remote may pause a stream after
+	/// ending its side, so they are kept until the stream is dropped.
+	holds: HashMap<u64, Weak<watch::Sender<Hold>>>,
+	/// `FEATURE_*` bits both ends support, none until the peer introduced
+	/// itself.
+	features: u64,
+	/// Both ends keep to the window of each stream. Set as soon as the
+	/// introduction is read, ahead of any stream the peer opens after it.
+	windowed: bool,
+	/// Node id of the peer, for its rate limit.
+	peer: Option<String>,
+	/// Stream frames waiting for their turn or the upload limit. They keep
+	/// their credit, so writers stall once it is used up.
+	scheduler: Scheduler<(Vec<u8>, Bytes, Credit)>,
 }
 
 impl<T: AsyncRead + AsyncWrite + std::marker::Unpin> Connection<T> {
-	pub fn new(conn: T, ctx: Context) -> Connection<T> {
+	pub fn new(conn: T, addr: String, outbound: bool, ctx: Context) -> Connection<T> {
 		Connection {
 			conn,
+			addr,
+			outbound,
+			multiplexer: Multiplexer::with_max_frame(ctx.max_frame),
 			ctx,
-			multiplexer: Multiplexer::new()
+			format: FrameFormat::Legacy,
+			control: Vec::new(),
+			streams: HashMap::new(),
+			pending: HashMap::new(),
+			holds: HashMap::new(),
+			features: 0,
+			windowed: false,
+			peer: None,
+			scheduler: Scheduler::default(),
+		}
+	}
+
+	/// Lets writes to the stream start, within `INITIAL_WINDOW` on windowed
+	/// connections.
+	fn add_hold(&mut self, stream_id: u64, hold: Weak<watch::Sender<Hold>>) {
+		self.holds.retain(|_, hold| hold.strong_count() > 0);
+		if let Some(hold) = hold.upgrade() {
+			let window = self.windowed.then_some(INITIAL_WINDOW as u64);
+			hold.send_modify(|hold| hold.window = window);
+		}
+		self.holds.insert(stream_id, hold);
+	}
+
+	/// Releases a hold the remote put on a stream, so writers waiting on it
+	/// find out the stream is gone.
+	fn release(&mut self, stream_id: u64) {
+		if let Some(hold) = self.holds.remove(&stream_id).and_then(|hold| hold.upgrade()) {
+			hold.send_modify(|hold| {
+				hold.remote = false;
+				hold.window = None;
+			});
+		}
+	}
+
+	/// How much a peer may write to each stream before we read it. Grants
+	/// are held back until half of it was read, the other half leaves room
+	/// for the pieces writers wait for.
+	fn window_size(&self) -> usize {
+		self.ctx.peer_buffer.clamp(2 * INITIAL_WINDOW, u32::MAX as usize)
+	}
+
+	/// Receive window for a new stream and the frame widening the
+	/// `INITIAL_WINDOW` both ends start with to it. Neither without windows.
+	fn open_window(&self, stream_id: u64, tx: &PeerTx) -> (Option<Budget>, Vec<(Vec<u8>, Bytes)>) {
+		if !self.windowed {
+			return (None, Vec::new());
+		}
+		let size = self.window_size();
+		let frames = match size - INITIAL_WINDOW {
+			0 => Vec::new(),
+			extra => vec![(window_frame(stream_id, extra as u32), Bytes::new())],
+		};
+		(Some(tx.window(size)), frames)
+	}
+
+	/// Charges data for a stream to its window, failing when the peer wrote
+	/// more than it was granted.
+	fn charge(&self, window: &Budget, stream_id: u64, len: usize) -> anyhow::Result<Credit> {
+		match window.try_take(len) {
+			Some(credit) if len <= self.window_size() => Ok(credit),
+			_ => anyhow::bail!("peer overran the window of stream {}", stream_id),
+		}
+	}
+
+	/// Handles bytes read from the socket. `credit` covers them; the part
+	/// handed to streams travels with the data, the rest returns on drop.
+	/// Stream payloads are passed on as slices of `data`.
+	async fn handle_data(&mut self, data: Bytes, mut credit: Credit, tx: &PeerTx) -> anyhow::Result<()> {
+		let mut events = Vec::new();
+		let mut error = None;
+		self.multiplexer.handle_bytes(data, |event| match event {
+			MultiplexerEvent::StreamStarted { stream_id } => events.push(Event::Started(stream_id)),
+			MultiplexerEvent::DataPointer { stream_id, data } => events.push(Event::Data(stream_id, data)),
+			MultiplexerEvent::StreamEnded { stream_id } => events.push(Event::Ended(stream_id)),
+			MultiplexerEvent::StreamDied { stream_id } => events.push(Event::Died(stream_id)),
+			MultiplexerEvent::StreamPaused { stream_id, paused } => events.push(Event::Paused(stream_id, paused)),
+			MultiplexerEvent::StreamWindow { stream_id, bytes } => events.push(Event::Window(stream_id, bytes)),
+			MultiplexerEvent::Error(err) => {
+				error.get_or_insert(err);
+			},
+		});
+		// Framing is lost after a bad header, so nothing after it can be trusted.
+		if let Some(err) = error {
+			anyhow::bail!("multiplexer error: {}", err);
+		}
+
+		let received: usize = events.iter()
+			.map(|event| match event {
+				Event::Data(stream_id, data) if *stream_id != CONTROL_STREAM => data.len(),
+				_ => 0,
+			})
+			.sum();
+		self.ctx.limiter.charge(Direction::Download, self.peer.as_deref(), received);
+
+		for event in events {
+			match event {
+				Event::Started(stream_id) => {
+					self.pending.insert(stream_id, Vec::new());
+				},
+				Event::Data(CONTROL_STREAM, data) => {
+					// Commands are handled in order with the frames around
+					// them, so an introduction applies to what follows it.
+					self.control.extend_from_slice(&data);
+					self.handle_control().await?;
+				},
+				Event::Data(stream_id, data) => {
+					if let Some(inbound) = self.streams.get(&stream_id) {
+						let credit = match &inbound.window {
+							Some(window) => self.charge(window, stream_id, data.len())?,
+							None => credit.split(data.len()),
+						};
+						if inbound.tx.send(StreamMsg::Data(data, credit)).is_err() {
+							self.streams.remove(&stream_id);
+						}
+					} else if let Some(buffer) = self.pending.get_mut(&stream_id) {
+						buffer.extend_from_slice(&data);
+						self.open_pending(stream_id, tx).await?;
+					}
+				},
+				Event::Ended(stream_id) => {
+					if let Some(inbound) = self.streams.remove(&stream_id) {
+						let _ = inbound.tx.send(StreamMsg::End);
+					}
+					self.pending.remove(&stream_id);
+				},
+				Event::Died(stream_id) => {
+					if let Some(inbound) = self.streams.remove(&stream_id) {
+						let _ = inbound.tx.send(StreamMsg::Died);
+					}
+					self.pending.remove(&stream_id);
+					self.scheduler.close(stream_id);
+					self.release(stream_id);
+				},
+				Event::Paused(stream_id, paused) => {
+					if let Some(hold) = self.holds.get(&stream_id).and_then(|hold| hold.upgrade()) {
+						hold.send_modify(|hold| hold.remote = paused);
+					}
+				},
+				Event::Window(stream_id, bytes) => {
+					if !self.windowed {
+						anyhow::bail!("peer sent a window without FEATURE_WINDOW");
+					}
+					if let Some(hold) = self.holds.get(&stream_id).and_then(|hold| hold.upgrade()) {
+						hold.send_modify(|hold| if let Some(window) = &mut hold.window {
+							*window += bytes as u64;
+						});
+					}
+				},
+			}
 		}
+
+		let partial = self.control.len() + self.pending.values().map(Vec::len).sum::<usize>();
+		if partial > MAX_PARTIAL {
+			anyhow::bail!("peer sent {} bytes of unfinished commands", partial);
+		}
+		Ok(())
+	}
+
+	/// Passes complete commands on to the worker.
+	async fn handle_control(&mut self) -> anyhow::Result<()> {
+		let mut used = 0;
+		while let Some((cmd, n)) = PeerCmd::parse(&self.control[used..])? {
+			used += n;
+			if let PeerCmd::Introduce(introduce) = &cmd {
+				self.windowed = introduce.features & FEATURE_WINDOW != 0;
+			}
+			self.ctx.internal_event_tx.send(InternalEvent::PeerCmd { addr: self.addr.clone(), cmd }).await?;
+		}
+		self.control.drain(..used);
+		Ok(())
+	}
+
+	/// Hands a remotely opened stream to the worker once its header is complete.
+	async fn open_pending(&mut self, stream_id: u64, tx: &PeerTx) -> anyhow::Result<()> {
+		let buffer = match self.pending.get_mut(&stream_id) {
+			Some(buffer) => buffer,
+			None => return Ok(()),
+		};
+		let (header, used) = match StreamHeader::parse(buffer)? {
+			Some(res) => res,
+			None => return Ok(()),
+		};
+		let rest = buffer.split_off(used);
+		self.pending.remove(&stream_id);
+		let (stream_tx, stream_rx) = mpsc::unbounded_channel();
+		let (window, frames) = self.open_window(stream_id, tx);
+		if !rest.is_empty() {
+			let credit = match &window {
+				Some(window) => self.charge(window, stream_id, rest.len())?,
+				None => Credit::default(),
+			};
+			let _ = stream_tx.send(StreamMsg::Data(rest.into(), credit));
+		}
+		self.streams.insert(stream_id, Inbound { tx: stream_tx, window, ungranted: 0 });
+		self.scheduler.open(stream_id, header.cmd.stream_class());
+		let stream = Stream::new(stream_id, stream_rx, tx.clone());
+		self.add_hold(stream_id, stream.hold());
+		self.write(frames).await?;
+		self.ctx.internal_event_tx.send(InternalEvent::StreamOpened { addr: self.addr.clone(), header, stream }).await?;
+		Ok(())
+	}
+
+	async fn handle_cmd(&mut self, cmd: PeerConnCmd, mut credit: Credit, tx: &PeerTx) -> anyhow::Result<bool> {
+		let frames = match cmd {
+			PeerConnCmd::Send(data) => frames(CONTROL_STREAM, STREAM_CONTINUE, data.into(), self.format),
+			PeerConnCmd::OpenStream { stream_id, header, data_tx, hold, class } => {
+				let (window, grant) = self.open_window(stream_id, tx);
+				self.streams.insert(stream_id, Inbound { tx: data_tx, window, ungranted: 0 });
+				self.add_hold(stream_id, hold);
+				self.scheduler.open(stream_id, class);
+				let mut frames = frames(stream_id, STREAM_START, header.into(), self.format);
+				frames.extend(grant);
+				frames
+			},
+			PeerConnCmd::StreamData { stream_id, stage, data } => {
+				if stage == STREAM_DIED {
+					self.streams.remove(&stream_id);
+					self.holds.remove(&stream_id);
+				}
+				if matches!(stage, STREAM_PAUSE | STREAM_PULL) {
+					if self.features & FEATURE_PAUSE == 0 {
+						// The peer would close the connection on a stage it does not know.
+						return Ok(true);
+					}
+					frames(stream_id, stage, data, self.format)
+				} else {
+					if matches!(stage, STREAM_END | STREAM_DIED) {
+						self.scheduler.close(stream_id);
+					}
+					for (header, payload) in frames(stream_id, stage, data, self.format) {
+						let credit = credit.split(payload.len());
+						self.scheduler.push(stream_id, payload.len(), (header, payload, credit));
+					}
+					return Ok(true);
+				}
+			},
+			PeerConnCmd::Grant { stream_id, bytes } => {
+				// Every frame the remote writes waits for its turn, so it
+				// gets a good part of the window at once rather than slivers.
+				let step = self.window_size() / 2;
+				let inbound = match self.streams.get_mut(&stream_id) {
+					Some(inbound) if inbound.window.is_some() => inbound,
+					// Pointless once the remote has finished writing.
+					_ => return Ok(true),
+				};
+				inbound.ungranted += bytes as usize;
+				if inbound.ungranted < step {
+					return Ok(true);
+				}
+				let bytes = std::mem::take(&mut inbound.ungranted) as u32;
+				vec![(window_frame(stream_id, bytes), Bytes::new())]
+			},
+			PeerConnCmd::SetFrameFormat(format) => {
+				self.format = format;
+				return Ok(true);
+			},
+			PeerConnCmd::SetFeatures(features) => {
+				self.features = features;
+				return Ok(true);
+			},
+			PeerConnCmd::SetPeer(peer) => {
+				self.peer = Some(peer);
+				return Ok(true);
+			},
+			PeerConnCmd::Close => {
+				self.flush().await?;
+				return Ok(false);
+			},
+		};
+		self.write(frames).await?;
+		Ok(true)
+	}
+
+	async fn write(&mut self, frames: Vec<(Vec<u8>, Bytes)>) -> anyhow::Result<()> {
+		for (header, payload) in frames {
+			// Header and payload go out in one vectored write where supported.
+			self.conn.write_all_buf(&mut Buf::chain(&header[..], payload)).await?;
+		}
+		Ok(())
+	}
+
+	/// How long stream frames have to wait for the upload limit, `None`
+	/// when none are waiting.
+	fn upload_delay(&self) -> Option<Duration> {
+		if self.scheduler.is_empty() {
+			return None;
+		}
+		Some(self.ctx.limiter.delay(Direction::Upload, self.peer.as_deref()).unwrap_or_default())
+	}
+
+	/// Writes the next stream frame if the upload limit lets it through.
+	async fn write_next(&mut self) -> anyhow::Result<()> {
+		if self.upload_delay() != Some(Duration::ZERO) {
+			return Ok(());
+		}
+		if let Some((_, (header, payload, _credit))) = self.scheduler.pop() {
+			self.ctx.limiter.charge(Direction::Upload, self.peer.as_deref(), payload.len());
+			self.write(vec![(header, payload)]).await?;
+		}
+		Ok(())
+	}
+
+	/// Writes every queued stream frame, regardless of the upload limit.
+	async fn flush(&mut self) -> anyhow::Result<()> {
+		while let Some((_, (header, payload, _credit))) = self.scheduler.pop() {
+			self.ctx.limiter.charge(Direction::Upload, self.peer.as_deref(), payload.len());
+			self.write(vec![(header, payload)]).await?;
+		}
+		Ok(())
 	}
 
 	pub async fn run(mut self) {
-		let mut buffer = [0u8; 1024];
+		let (tx, mut rx) = PeerTx::new(self.ctx.peer_buffer);
+		let event = InternalEvent::PeerConnected {
+			addr: self.addr.clone(),
+			outbound: self.outbound,
+			tx: tx.clone()
+		};
+		if let Err(err) = self.ctx.internal_event_tx.send(event).await {
+			log::error!("error sending event: {}", err);
+			return;
+		}
+
+		let mut buffer = BytesMut::new();
+		let mut read_size = MIN_READ_SIZE;
+		let mut reserved = None;
+		let mut error = None;
 		loop {
-			let n = self.conn.read(&mut buffer).await.unwrap();
-			if n == 0 {
-				break;
+			// Small reads under a download limit, so control messages behind
+			// the data are not held back for long.
+			let mut size = read_size;
+			if let Some(rate) = self.ctx.limiter.rate(Direction::Download, self.peer.as_deref()) {
+				size = size.min(rate as usize / 8).max(MIN_READ_SIZE);
 			}
-			self.multiplexer.handle_data(&buffer[0..n], |event| match event {
-				MultiplexerEvent::StreamStarted { stream_id, length } => {
-					
-				},
-				MultiplexerEvent::DataPointer { stream_id, data } => {},
-				MultiplexerEvent::StreamEnded { stream_id } => {},
-				MultiplexerEvent::Error(_) => {},
+			// Never read more than the budget could ever hold.
+			let size = size.min(self.ctx.peer_buffer);
+			let upload_delay = self.upload_delay();
+			let download_delay = self.ctx.limiter.delay(Direction::Download, self.peer.as_deref());
+			tokio::select! {
+				credit = tx.inbound.take(size), if reserved.is_none() && !self.windowed => {
+					reserved = Some(credit);
+				}
+				_ = tokio::time::sleep(download_delay.unwrap_or_default()), if download_delay.is_some() => {}
+				_ = tokio::time::sleep(upload_delay.unwrap_or_default()), if upload_delay.is_some() => {
+					// Everything queued by now competes for the next frame.
+					let mut res = Ok(true);
+					while let Ok((cmd, credit)) = rx.try_recv() {
+						res = self.handle_cmd(cmd, credit, &tx).await;
+						if !matches!(res, Ok(true)) {
+							break;
+						}
+					}
+					if let Ok(true) = res {
+						res = self.write_next().await.map(|_| true);
+					}
+					match res {
+						Ok(true) => {},
+						Ok(false) => break,
+						Err(err) => {
+							error = Some(err.context("write failed"));
+							break;
+						}
+					}
+				}
+				// Windows keep each stream in check, so reads go on regardless.
+				res = async {
+					buffer.reserve(size);
+					self.conn.read_buf(&mut (&mut buffer).limit(size)).await
+				}, if (reserved.is_some() || self.windowed) && download_delay.is_none() => {
+					let n = match res {
+						Ok(0) => break,
+						Ok(n) => n,
+						Err(err) => {
+							error = Some(anyhow::Error::new(err).context("read failed"));
+							break;
+						}
+					};
+					tx.mark_received();
+					read_size = next_read_size(size, n);
+					let credit = reserved.take().unwrap_or_default();
+					// Slices of this chunk go straight to the stream readers.
+					let data = buffer.split().freeze();
+					if let Err(err) = self.handle_data(data, credit, &tx).await {
+						error = Some(err.context("closing connection"));
+						break;
+					}
+				}
+				cmd = rx.recv() => {
+					// `tx` is held above, so the channel never closes here.
+					let (cmd, credit) = match cmd {
+						Some(cmd) => cmd,
+						None => break,
+					};
+					match self.handle_cmd(cmd, credit, &tx).await {
+						Ok(true) => {},
+						Ok(false) => break,
+						Err(err) => {
+							error = Some(err.context("write failed"));
+							break;
+						}
+					}
+				}
+			}
+		}
+
+		for (_, inbound) in self.streams.drain() {
+			let _ = inbound.tx.send(StreamMsg::Died);
+		}
+		let ids: Vec<u64> = self.holds.keys().copied().collect();
+		for stream_id in ids {
+			self.release(stream_id);
+		}
+		if let Some(err) = error {
+			self.ctx.report(Some(&self.addr), err);
+		}
+		if let Err(err) = self.ctx.internal_event_tx.send(InternalEvent::PeerDisconnected { addr: self.addr }).await {
+			log::error!("error sending event: {}", err);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::compress::Compression;
+	use crate::multiplex::encode_frames;
+	use crate::multiplex::HEADER_LEN;
+	use crate::multiplex::MAX_FRAME_PAYLOAD;
+	use crate::multiplex::MAX_WIDE_FRAME;
+	use crate::multiplex::StreamClass;
+	use crate::multiplex::WIDE_FRAME;
+	use crate::multiplex::WIDE_HEADER_LEN;
+	use crate::protocol::Introduce;
+	use crate::protocol::CMD_WRITE_FILE;
+	use crate::protocol::PROTOCOL_VERSION;
+	use crate::rate::RateConfig;
+	use crate::rate::RateLimit;
+	use crate::rate::RateLimiter;
+	use crate::types::PeerConn;
+	use std::pin::Pin;
+	use std::sync::atomic::AtomicUsize;
+	use std::sync::atomic::Ordering;
+	use std::sync::Arc;
+	use std::task::Poll;
+	use tokio::sync::watch;
+
+	fn ctx(peer_buffer: usize) -> (Context, mpsc::Receiver<InternalEvent>, watch::Sender<bool>) {
+		limited_ctx(peer_buffer, RateConfig::default())
+	}
+
+	fn limited_ctx(peer_buffer: usize, rate: RateConfig) -> (Context, mpsc::Receiver<InternalEvent>, watch::Sender<bool>) {
+		let (internal_event_tx, rx) = mpsc::channel(16);
+		let (shutdown_tx, shutdown) = watch::channel(false);
+		let limiter = Arc::new(RateLimiter::new(rate));
+		(Context { internal_event_tx, shutdown, peer_buffer, max_frame: MAX_WIDE_FRAME, limiter }, rx, shutdown_tx)
+	}
+
+	/// Collects events up to the disconnect, which must come.
+	async fn events_until_disconnect(rx: &mut mpsc::Receiver<InternalEvent>) -> Vec<InternalEvent> {
+		let mut events = Vec::new();
+		loop {
+			let event = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+			if matches!(event, InternalEvent::PeerDisconnected { .. }) {
+				return events;
+			}
+			events.push(event);
+		}
+	}
+
+	/// The control frame a peer introducing itself with `features` sends.
+	fn introduce(features: u64) -> Vec<u8> {
+		let cmd = PeerCmd::Introduce(Introduce {
+			id: "peer".to_string(),
+			name: "peer".to_string(),
+			owner: String::new(),
+			ports: Vec::new(),
+			max_frame: 0,
+			version: PROTOCOL_VERSION,
+			min_version: PROTOCOL_VERSION,
+			features,
+		});
+		encode_frames(CONTROL_STREAM, STREAM_CONTINUE, &cmd.serialize())
+	}
+
+	fn read_file_header() -> StreamHeader {
+		StreamHeader {
+			ttl: 1,
+			cmd: PeerCmd::ReadFile { node_id: "me".to_string(), path: "/a".to_string(), offset: 0, length: 0, compression: Compression::None },
+		}
+	}
+
+	/// Payload bytes for `stream_id` that arrive until the connection falls
+	/// silent.
+	async fn received_until_silent(remote: &mut tokio::io::DuplexStream, multiplexer: &mut Multiplexer, stream_id: u64) -> usize {
+		let mut received = 0;
+		let mut buffer = vec![0; 64 * 1024];
+		while let Ok(Ok(n @ 1..)) = tokio::time::timeout(Duration::from_millis(200), remote.read(&mut buffer)).await {
+			multiplexer.handle_data(&buffer[..n], |event| match event {
+				MultiplexerEvent::DataPointer { stream_id: id, data } if id == stream_id => received += data.len(),
+				_ => {},
 			});
 		}
+		received
+	}
+
+	/// Reads the next frame, which must grant a window.
+	async fn read_window(remote: &mut tokio::io::DuplexStream) -> (u64, usize) {
+		let mut header = vec![0; WIDE_HEADER_LEN];
+		remote.read_exact(&mut header).await.unwrap();
+		let mut window = None;
+		Multiplexer::new().handle_data(&header, |event| {
+			if let MultiplexerEvent::StreamWindow { stream_id, bytes } = event {
+				window = Some((stream_id, bytes as usize));
+			}
+		});
+		window.expect("expected a window")
+	}
+
+	/// A socket whose reads fail and whose writes go nowhere.
+	struct BrokenIo;
+
+	impl AsyncRead for BrokenIo {
+		fn poll_read(self: Pin<&mut Self>, _: &mut std::task::Context<'_>, _: &mut tokio::io::ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+			Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+		}
+	}
+
+	impl AsyncWrite for BrokenIo {
+		fn poll_write(self: Pin<&mut Self>, _: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+			Poll::Ready(Ok(buf.len()))
+		}
+
+		fn poll_flush(self: Pin<&mut Self>, _: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+
+		fn poll_shutdown(self: Pin<&mut Self>, _: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+	}
+
+	#[test]
+	fn test_read_size_adapts() {
+		assert_eq!(next_read_size(MIN_READ_SIZE, MIN_READ_SIZE), MIN_READ_SIZE * 2);
+		assert_eq!(next_read_size(MAX_READ_SIZE, MAX_READ_SIZE), MAX_READ_SIZE);
+		assert_eq!(next_read_size(64 * 1024, 40 * 1024), 64 * 1024);
+		assert_eq!(next_read_size(64 * 1024, 100), 32 * 1024);
+		assert_eq!(next_read_size(MIN_READ_SIZE, 1), MIN_READ_SIZE);
+	}
+
+	#[tokio::test]
+	async fn test_garbage_is_reported() {
+		let (ctx, mut rx, _shutdown) = ctx(1 << 20);
+		let (local, mut remote) = tokio::io::duplex(1024);
+		tokio::spawn(Connection::new(local, "tcp://peer".to_string(), false, ctx).run());
+		// An invalid frame stage.
+		remote.write_all(&[0xf0; 16]).await.unwrap();
+
+		let events = events_until_disconnect(&mut rx).await;
+		assert!(matches!(events[0], InternalEvent::PeerConnected { .. }));
+		match &events[1] {
+			InternalEvent::Error { addr, error } => {
+				assert_eq!(addr.as_deref(), Some("tcp://peer"));
+				assert!(format!("{:#}", error).contains("multiplexer error"));
+			},
+			_ => panic!("expected an error"),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_oversized_command_closes() {
+		let (ctx, mut rx, _shutdown) = ctx(1 << 20);
+		let (local, mut remote) = tokio::io::duplex(1024);
+		tokio::spawn(Connection::new(local, "tcp://peer".to_string(), false, ctx).run());
+		// A stream header claiming more than a command may hold.
+		let mut header = vec![1];
+		header.extend_from_slice(&CMD_WRITE_FILE.to_le_bytes());
+		header.extend_from_slice(&(MAX_COMMAND_SIZE as u32 + 1).to_le_bytes());
+		remote.write_all(&encode_frames(2, STREAM_START, &header)).await.unwrap();
+
+		let events = events_until_disconnect(&mut rx).await;
+		assert!(matches!(&events[1], InternalEvent::Error { error, .. } if format!("{:#}", error).contains("too large")));
+	}
+
+	#[tokio::test]
+	async fn test_read_error_is_reported() {
+		let (ctx, mut rx, _shutdown) = ctx(1 << 20);
+		tokio::spawn(Connection::new(BrokenIo, "tcp://peer".to_string(), true, ctx).run());
+
+		let events = events_until_disconnect(&mut rx).await;
+		assert_eq!(events.len(), 2);
+		assert!(matches!(&events[1], InternalEvent::Error { error, .. } if format!("{:#}", error).contains("read failed")));
+	}
+
+	#[tokio::test]
+	async fn test_clean_close_is_not_an_error() {
+		let (ctx, mut rx, _shutdown) = ctx(1 << 20);
+		let (local, remote) = tokio::io::duplex(1024);
+		tokio::spawn(Connection::new(local, "tcp://peer".to_string(), false, ctx).run());
+		drop(remote);
+
+		let events = events_until_disconnect(&mut rx).await;
+		assert_eq!(events.len(), 1);
+	}
+
+	/// Peers without windows can only be held back by the socket.
+	#[tokio::test]
+	async fn test_slow_reader_stops_the_socket() {
+		let (ctx, mut rx, _shutdown) = ctx(4096);
+		let (local, mut remote) = tokio::io::duplex(16 * 1024);
+		tokio::spawn(Connection::new(local, "tcp://peer".to_string(), false, ctx).run());
+		let tx = match rx.recv().await.unwrap() {
+			InternalEvent::PeerConnected { tx, .. } => tx,
+			_ => panic!("expected a connection"),
+		};
+
+		let mut frames = encode_frames(2, STREAM_START, &read_file_header().serialize());
+		frames.extend(encode_frames(2, STREAM_CONTINUE, &vec![7; 256 * 1024]));
+		let writer = tokio::spawn(async move {
+			remote.write_all(&frames).await.unwrap();
+			remote
+		});
+		let mut stream = match rx.recv().await.unwrap() {
+			InternalEvent::StreamOpened { stream, .. } => stream,
+			_ => panic!("expected a stream"),
+		};
+
+		// Nobody reads the stream, so the writer stalls once the budget and
+		// the pipe are full.
+		tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+		assert!(!writer.is_finished());
+		assert!(tx.inbound.used() <= 4096);
+
+		let mut received = 0;
+		while received < 256 * 1024 {
+			received += stream.read_bytes().await.unwrap().unwrap().len();
+		}
+		let _remote = writer.await.unwrap();
+		assert_eq!(received, 256 * 1024);
+	}
+
+	#[tokio::test]
+	async fn test_stalled_stream_spares_control() {
+		let (ctx, mut rx, _shutdown) = ctx(4096);
+		let (local, mut remote) = tokio::io::duplex(64 * 1024);
+		tokio::spawn(Connection::new(local, "tcp://peer".to_string(), false, ctx).run());
+		let tx = match rx.recv().await.unwrap() {
+			InternalEvent::PeerConnected { tx, .. } => tx,
+			_ => panic!("expected a connection"),
+		};
+
+		let hello = |seq| encode_frames(CONTROL_STREAM, STREAM_CONTINUE, &PeerCmd::Hello { seq, reply: false }.serialize());
+		let mut frames = introduce(FEATURE_WINDOW);
+		frames.extend(encode_frames(2, STREAM_START, &read_file_header().serialize()));
+		frames.extend(encode_frames(2, STREAM_CONTINUE, &vec![7; INITIAL_WINDOW]));
+		remote.write_all(&frames).await.unwrap();
+		assert!(matches!(rx.recv().await.unwrap(), InternalEvent::PeerCmd { cmd: PeerCmd::Introduce(_), .. }));
+		let mut stream = match rx.recv().await.unwrap() {
+			InternalEvent::StreamOpened { stream, .. } => stream,
+			_ => panic!("expected a stream"),
+		};
+		// The window starts small and grows to what the buffer allows.
+		assert_eq!(read_window(&mut remote).await, (2, INITIAL_WINDOW));
+
+		// Nobody reads the stream, which holds a full window, yet the
+		// keepalive behind it still arrives.
+		let mut frames = encode_frames(2, STREAM_CONTINUE, &vec![7; INITIAL_WINDOW]);
+		frames.extend(hello(1));
+		remote.write_all(&frames).await.unwrap();
+		let event = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+		assert!(matches!(event, InternalEvent::PeerCmd { cmd: PeerCmd::Hello { seq: 1, .. }, .. }));
+		assert_eq!(tx.unread(), 2 * INITIAL_WINDOW);
+
+		// Reading grants the window back.
+		stream.read_exact(2 * INITIAL_WINDOW).await.unwrap();
+		let (stream_id, granted) = read_window(&mut remote).await;
+		assert_eq!(stream_id, 2);
+		assert!(granted >= INITIAL_WINDOW);
+		assert_eq!(tx.unread(), 0);
+
+		// Writing more than the window closes the connection.
+		remote.write_all(&encode_frames(2, STREAM_CONTINUE, &vec![7; 2 * INITIAL_WINDOW + 1])).await.unwrap();
+		let events = events_until_disconnect(&mut rx).await;
+		assert!(events.iter().any(|event| matches!(event, InternalEvent::Error { error, .. } if format!("{:#}", error).contains("overran the window"))));
+	}
+
+	#[tokio::test]
+	async fn test_writes_keep_to_the_window() {
+		let (ctx, mut rx, _shutdown) = ctx(4096);
+		let (local, mut remote) = tokio::io::duplex(1 << 20);
+		tokio::spawn(Connection::new(local, "tcp://peer".to_string(), true, ctx).run());
+		let tx = match rx.recv().await.unwrap() {
+			InternalEvent::PeerConnected { tx, .. } => tx,
+			_ => panic!("expected a connection"),
+		};
+		remote.write_all(&introduce(FEATURE_WINDOW)).await.unwrap();
+		assert!(matches!(rx.recv().await.unwrap(), InternalEvent::PeerCmd { cmd: PeerCmd::Introduce(_), .. }));
+
+		let mut stream = PeerConn::new(tx, true).open_stream(&read_file_header()).unwrap();
+		let stream_id = stream.id();
+		tokio::spawn(async move {
+			stream.write_chunk(Bytes::from(vec![1; INITIAL_WINDOW + 1000])).await.unwrap();
+			stream
+		});
+
+		// The stream header and a window of data, the rest waits for a grant.
+		let mut multiplexer = Multiplexer::new();
+		let header_len = read_file_header().serialize().len();
+		let received = received_until_silent(&mut remote, &mut multiplexer, stream_id).await;
+		assert_eq!(received, header_len + INITIAL_WINDOW);
+
+		remote.write_all(&window_frame(stream_id, 1000)).await.unwrap();
+		assert_eq!(received_until_silent(&mut remote, &mut multiplexer, stream_id).await, 1000);
 	}
-}
\ No newline at end of file
+
+	#[tokio::test]
+	async fn test_wide_frames_after_negotiation() {
+		let (ctx, mut rx, _shutdown) = ctx(1 << 20);
+		let (local, mut remote) = tokio::io::duplex(1 << 20);
+		tokio::spawn(Connection::new(local, "tcp://peer".to_string(), true, ctx).run());
+		let tx = match rx.recv().await.unwrap() {
+			InternalEvent::PeerConnected { tx, .. } => tx,
+			_ => panic!("expected a connection"),
+		};
+
+		tx.send(PeerConnCmd::Send(vec![1; 100])).unwrap();
+		tx.send(PeerConnCmd::SetFrameFormat(FrameFormat::Wide { max: MAX_WIDE_FRAME })).unwrap();
+		tx.send_data(3, Bytes::from(vec![2; 200 * 1024])).await.unwrap();
+
+		let mut legacy = vec![0; HEADER_LEN + 100];
+		remote.read_exact(&mut legacy).await.unwrap();
+		assert_eq!(legacy[0] & WIDE_FRAME, 0);
+		let mut wide = vec![0; WIDE_HEADER_LEN];
+		remote.read_exact(&mut wide).await.unwrap();
+		assert_eq!(wide[0] & WIDE_FRAME, WIDE_FRAME);
+		assert_eq!(u32::from_le_bytes([wide[8], wide[9], wide[10], wide[11]]), 200 * 1024);
+	}
+
+	#[tokio::test]
+	async fn test_upload_limit_spares_control() {
+		let rate = RateConfig {
+			global: RateLimit { upload: Some(64 * 1024), download: None },
+			..RateConfig::default()
+		};
+		let (ctx, mut rx, _shutdown) = limited_ctx(1 << 20, rate);
+		let (local, mut remote) = tokio::io::duplex(1 << 20);
+		tokio::spawn(Connection::new(local, "tcp://peer".to_string(), true, ctx).run());
+		let tx = match rx.recv().await.unwrap() {
+			InternalEvent::PeerConnected { tx, .. } => tx,
+			_ => panic!("expected a connection"),
+		};
+
+		// The first two frames use up the burst and about a second more.
+		tx.send_data(3, Bytes::from(vec![2; 3 * MAX_FRAME_PAYLOAD])).await.unwrap();
+		let mut data = vec![0; 2 * (HEADER_LEN + MAX_FRAME_PAYLOAD)];
+		remote.read_exact(&mut data).await.unwrap();
+
+		tx.send(PeerConnCmd::Send(vec![1; 100])).unwrap();
+		let mut control = vec![0; HEADER_LEN + 100];
+		tokio::time::timeout(Duration::from_millis(500), remote.read_exact(&mut control)).await.unwrap().unwrap();
+		assert_eq!(control, encode_frames(CONTROL_STREAM, STREAM_CONTINUE, &[1; 100]));
+
+		// The last frame waits for the limit.
+		let mut rest = vec![0; HEADER_LEN + MAX_FRAME_PAYLOAD];
+		assert!(tokio::time::timeout(Duration::from_millis(500), remote.read_exact(&mut rest)).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn test_interactive_stream_overtakes_bulk() {
+		let (ctx, mut rx, _shutdown) = ctx(1 << 20);
+		let (local, mut remote) = tokio::io::duplex(64 * 1024);
+		tokio::spawn(Connection::new(local, "tcp://peer".to_string(), true, ctx).run());
+		let tx = match rx.recv().await.unwrap() {
+			InternalEvent::PeerConnected { tx, .. } => tx,
+			_ => panic!("expected a connection"),
+		};
+		for (stream_id, class) in [(3, StreamClass::Bulk), (5, StreamClass::Interactive)] {
+			tx.send(PeerConnCmd::OpenStream {
+				stream_id,
+				header: Vec::new(),
+				data_tx: mpsc::unbounded_channel().0,
+				hold: Weak::new(),
+				class,
+			}).unwrap();
+		}
+
+		// A slow reader counting the bulk bytes that arrive before the reply.
+		let bulk = Arc::new(AtomicUsize::new(0));
+		let reader = tokio::spawn({
+			let bulk = bulk.clone();
+			async move {
+				let mut multiplexer = Multiplexer::new();
+				let mut buffer = vec![0; 16 * 1024];
+				loop {
+					let n = remote.read(&mut buffer).await.unwrap();
+					let mut replied = false;
+					multiplexer.handle_data(&buffer[..n], |event| match event {
+						MultiplexerEvent::DataPointer { stream_id: 3, data } => {
+							bulk.fetch_add(data.len(), Ordering::SeqCst);
+						},
+						MultiplexerEvent::DataPointer { stream_id: 5, .. } => replied = true,
+						_ => {},
+					});
+					if replied {
+						return bulk.load(Ordering::SeqCst);
+					}
+					tokio::time::sleep(Duration::from_millis(1)).await;
+				}
+			}
+		});
+		tokio::spawn({
+			let tx = tx.clone();
+			async move {
+				for _ in 0..64 {
+					tx.send_data(3, Bytes::from(vec![2; 60_000])).await.unwrap();
+				}
+			}
+		});
+
+		while bulk.load(Ordering::SeqCst) < 256 * 1024 {
+			tokio::time::sleep(Duration::from_millis(1)).await;
+		}
+		let before = bulk.load(Ordering::SeqCst);
+		tx.send_data(5, Bytes::from(vec![9; 100])).await.unwrap();
+		let after = reader.await.unwrap();
+		// Without scheduling the whole send buffer of bulk data would go first.
+		assert!(after - before < 400 * 1024, "{} bulk bytes went first", after - before);
+	}
+}
+
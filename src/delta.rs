@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncSeekExt;
+use tokio::io::AsyncWriteExt;
+
+use crate::chunk;
+use crate::chunk::Hash;
+use crate::chunk::MAX_CHUNK;
+use crate::stream::Stream;
+use crate::DeltaStats;
+
+/// Sender ops: copy chunk `u32` of the receiver's old copy, or take `u32`
+/// bytes of new data that follow.
+const OP_COPY: u8 = 1;
+const OP_DATA: u8 = 2;
+const SIGNATURE_LEN: usize = 4 + 32;
+/// Signatures and copy ops are batched into writes of about this size.
+const BATCH: usize = 64 * 1024;
+
+/// A chunk of the receiver's current copy.
+pub(crate) struct Signature {
+	pub offset: u64,
+	pub len: u32,
+	pub hash: Hash,
+}
+
+/// The chunks of `path`, none when it does not exist.
+pub(crate) fn signatures(path: &Path) -> std::io::Result<Vec<Signature>> {
+	let file = match std::fs::File::open(path) {
+		Ok(file) => file,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(err) => return Err(err),
+	};
+	let mut chunker = chunk::Chunker::new(std::io::BufReader::new(file));
+	let mut res = Vec::new();
+	let mut offset = 0;
+	while let Some(chunk) = chunker.next_chunk()? {
+		res.push(Signature { offset, len: chunk.len() as u32, hash: chunk::hash(&chunk) });
+		offset += chunk.len() as u64;
+	}
+	Ok(res)
+}
+
+/// Where a file is assembled before it replaces `path`.
+pub fn temp_path(path: &Path) -> PathBuf {
+	let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+	path.with_file_name(format!(".{}.pupynet-{:08x}", name, rand::random::<u32>()))
+}
+
+/// Receiving side of a delta write: sends the signatures of our copy of
+/// `path`, then rebuilds it from the sender's ops next to the original and
+/// renames it into place once complete.
+pub async fn handle_write_delta(stream: &mut Stream, path: &str) -> anyhow::Result<()> {
+	let path = PathBuf::from(path);
+	let sigs = {
+		let path = path.clone();
+		tokio::task::spawn_blocking(move || signatures(&path)).await?
+	};
+	let sigs = match sigs {
+		Ok(sigs) => sigs,
+		Err(err) => return stream.write_status(Err(err.into())).await,
+	};
+	stream.write_status(Ok(())).await?;
+	let mut batch = Vec::with_capacity(BATCH + SIGNATURE_LEN);
+	for sig in &sigs {
+		batch.extend_from_slice(&sig.len.to_le_bytes());
+		batch.extend_from_slice(&sig.hash);
+		if batch.len() >= BATCH {
+			stream.write_bytes(&batch).await?;
+			batch.clear();
+		}
+	}
+	batch.extend_from_slice(&0u32.to_le_bytes());
+	stream.write_bytes(&batch).await?;
+
+	let temp = temp_path(&path);
+	let res = apply(stream, &path, &temp, &sigs).await;
+	if res.is_err() {
+		let _ = tokio::fs::remove_file(&temp).await;
+	}
+	stream.write_status(res).await
+}
+
+async fn apply(stream: &mut Stream, path: &Path, temp: &Path, sigs: &[Signature]) -> anyhow::Result<()> {
+	let mut base = match sigs.is_empty() {
+		true => None,
+		false => Some(tokio::fs::File::open(path).await?),
+	};
+	let mut out = tokio::io::BufWriter::new(tokio::fs::File::create(temp).await?);
+	let mut buf = vec![0; MAX_CHUNK];
+	while let Some(op) = stream.try_read_u8().await? {
+		match op {
+			OP_COPY => {
+				let index = stream.read_u32().await? as usize;
+				let (sig, base) = match (sigs.get(index), base.as_mut()) {
+					(Some(sig), Some(base)) => (sig, base),
+					_ => anyhow::bail!("copy of unknown chunk {}", index),
+				};
+				let buf = &mut buf[..sig.len as usize];
+				base.seek(std::io::SeekFrom::Start(sig.offset)).await?;
+				base.read_exact(buf).await?;
+				out.write_all(buf).await?;
+			},
+			OP_DATA => {
+				let len = stream.read_u32().await? as usize;
+				if len > MAX_CHUNK {
+					anyhow::bail!("data op of {} bytes", len);
+				}
+				out.write_all(&stream.read_exact(len).await?).await?;
+			},
+			_ => anyhow::bail!("unknown delta op {}", op),
+		}
+	}
+	out.flush().await?;
+	let file = out.into_inner();
+	if let Ok(metadata) = tokio::fs::metadata(path).await {
+		file.set_permissions(metadata.permissions()).await?;
+	}
+	file.sync_all().await?;
+	tokio::fs::rename(temp, path).await?;
+	Ok(())
+}
+
+/// Reads the receiver's signatures: where each chunk it has can be found.
+pub async fn read_signatures(stream: &mut Stream) -> anyhow::Result<HashMap<Hash, u32>> {
+	let mut known = HashMap::new();
+	let mut index = 0;
+	loop {
+		let len = stream.read_u32().await?;
+		if len == 0 {
+			return Ok(known);
+		}
+		let hash: Hash = stream.read_exact(32).await?[..].try_into()?;
+		known.entry(hash).or_insert(index);
+		index += 1;
+	}
+}
+
+/// Sends `data` as ops against the receiver's chunks in `known`.
+pub async fn send_delta(stream: &mut Stream, data: Vec<u8>, known: HashMap<Hash, u32>) -> anyhow::Result<DeltaStats> {
+	let (data, ops) = tokio::task::spawn_blocking(move || {
+		let ops: Vec<(usize, Option<u32>)> = chunk::chunks(&data)
+			.map(|c| (c.len(), known.get(&chunk::hash(c)).copied()))
+			.collect();
+		(data, ops)
+	}).await?;
+
+	let mut stats = DeltaStats::default();
+	let mut batch = Vec::with_capacity(BATCH + MAX_CHUNK);
+	let mut offset = 0;
+	for (len, index) in ops {
+		match index {
+			Some(index) => {
+				batch.push(OP_COPY);
+				batch.extend_from_slice(&index.to_le_bytes());
+				stats.reused += len as u64;
+			},
+			None => {
+				batch.push(OP_DATA);
+				batch.extend_from_slice(&(len as u32).to_le_bytes());
+				batch.extend_from_slice(&data[offset..offset + len]);
+				stats.sent += len as u64;
+			},
+		}
+		offset += len;
+		if batch.len() >= BATCH {
+			stream.write_bytes(&batch).await?;
+			batch.clear();
+		}
+	}
+	if !batch.is_empty() {
+		stream.write_bytes(&batch).await?;
+	}
+	Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::testing::folder;
+	use crate::testing::node;
+	use crate::testing::wait_for_peer;
+
+	#[tokio::test]
+	async fn test_delta_sync() {
+		let dir = tempfile::tempdir().unwrap();
+		let share = folder(dir.path(), "share");
+		let a = node(dir.path(), "a").share(&share).build();
+		let a_info = a.info().await.unwrap();
+		let b = node(dir.path(), "b").peer(&a_info.listen_addrs[0]).build();
+		wait_for_peer(&b, &a_info.id).await;
+		let path = share.join("disk.img");
+		let remote = path.to_str().unwrap();
+
+		let mut data: Vec<u8> = (0..1 << 20).map(|_| rand::random()).collect();
+		let stats = b.sync_file(&a_info.id, remote, data.clone()).await.unwrap();
+		assert_eq!((stats.sent, stats.reused), (data.len() as u64, 0));
+
+		data[500_000..500_010].copy_from_slice(b"0123456789");
+		let stats = b.sync_file(&a_info.id, remote, data.clone()).await.unwrap();
+		assert!(stats.sent <= 2 * crate::chunk::MAX_CHUNK as u64, "sent {}", stats.sent);
+		assert_eq!(stats.sent + stats.reused, data.len() as u64);
+		assert_eq!(std::fs::read(&path).unwrap(), data);
+
+		// A shorter file replaces the old one entirely.
+		data.truncate(1000);
+		b.sync_file(&a_info.id, remote, data.clone()).await.unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), data);
+		assert_eq!(std::fs::read_dir(&share).unwrap().count(), 1);
+	}
+}
@@ -0,0 +1,138 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+
+/// Caps the bytes waiting in a queue. Senders take credit for what they
+/// queue and it flows back once the receiver has consumed the data.
+#[derive(Debug, Clone)]
+pub struct Budget {
+	sem: Arc<Semaphore>,
+	size: usize,
+	/// Also counts the credit out, for a total over several budgets.
+	meter: Option<Arc<AtomicUsize>>,
+}
+
+impl Budget {
+	pub fn new(size: usize) -> Budget {
+		let size = size.clamp(1, u32::MAX as usize);
+		Budget {
+			sem: Arc::new(Semaphore::new(size)),
+			size,
+			meter: None,
+		}
+	}
+
+	/// Like `new`, adding the credit taken to `meter` until it returns.
+	pub fn metered(size: usize, meter: Arc<AtomicUsize>) -> Budget {
+		Budget { meter: Some(meter), ..Budget::new(size) }
+	}
+
+	fn credit(&self, n: u32) -> Credit {
+		if let Some(meter) = &self.meter {
+			meter.fetch_add(n as usize, Ordering::Relaxed);
+		}
+		Credit { sem: Some(self.sem.clone()), n, meter: self.meter.clone() }
+	}
+
+	/// Messages larger than the whole budget are charged the whole budget,
+	/// so they wait for the queue to drain instead of forever.
+	fn amount(&self, len: usize) -> u32 {
+		len.clamp(1, self.size) as u32
+	}
+
+	/// Waits until `len` bytes fit.
+	pub async fn take(&self, len: usize) -> Credit {
+		let n = self.amount(len);
+		match self.sem.acquire_many(n).await {
+			Ok(permit) => permit.forget(),
+			// The semaphore is never closed.
+			Err(_) => return Credit::default(),
+		}
+		self.credit(n)
+	}
+
+	/// Takes credit for `len` bytes if they fit right now.
+	pub fn try_take(&self, len: usize) -> Option<Credit> {
+		let n = self.amount(len);
+		self.sem.try_acquire_many(n).ok()?.forget();
+		Some(self.credit(n))
+	}
+
+	/// Bytes currently queued.
+	pub fn used(&self) -> usize {
+		self.size - self.sem.available_permits()
+	}
+}
+
+/// Queue space held by a message, handed back to its budget on drop. The
+/// default credit belongs to no budget.
+#[derive(Debug, Default)]
+pub struct Credit {
+	sem: Option<Arc<Semaphore>>,
+	n: u32,
+	meter: Option<Arc<AtomicUsize>>,
+}
+
+impl Credit {
+	/// Moves up to `len` bytes of this credit into a new one.
+	pub fn split(&mut self, len: usize) -> Credit {
+		let n = self.n.min(len.min(u32::MAX as usize) as u32);
+		self.n -= n;
+		Credit { sem: self.sem.clone(), n, meter: self.meter.clone() }
+	}
+}
+
+impl Drop for Credit {
+	fn drop(&mut self) {
+		if let (Some(sem), true) = (&self.sem, self.n > 0) {
+			sem.add_permits(self.n as usize);
+		}
+		if let Some(meter) = &self.meter {
+			meter.fetch_sub(self.n as usize, Ordering::Relaxed);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_credit_returns_on_drop() {
+		let budget = Budget::new(100);
+		let mut credit = budget.take(60).await;
+		assert_eq!(budget.used(), 60);
+		assert!(budget.try_take(50).is_none());
+
+		let part = credit.split(20);
+		drop(credit);
+		assert_eq!(budget.used(), 20);
+		drop(part);
+		assert_eq!(budget.used(), 0);
+
+		// Oversized messages take everything rather than never fitting.
+		let all = budget.try_take(1000).unwrap();
+		assert_eq!(budget.used(), 100);
+		drop(all);
+		assert!(budget.try_take(1).is_some());
+	}
+
+	#[tokio::test]
+	async fn test_meter_sums_budgets() {
+		let meter = Arc::new(AtomicUsize::new(0));
+		let a = Budget::metered(100, meter.clone());
+		let b = Budget::metered(100, meter.clone());
+		let mut credit = a.take(60).await;
+		let other = b.try_take(30).unwrap();
+		assert_eq!(meter.load(Ordering::Relaxed), 90);
+
+		let part = credit.split(20);
+		drop(credit);
+		assert_eq!(meter.load(Ordering::Relaxed), 50);
+		drop(part);
+		drop(other);
+		assert_eq!(meter.load(Ordering::Relaxed), 0);
+	}
+}
@@ -0,0 +1,778 @@
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::io::AsyncWriteExt;
+
+use crate::builder::SyncFolder;
+use crate::chunk::Hash;
+use crate::delta;
+use crate::protocol::put_string;
+use crate::protocol::PeerCmd;
+use crate::protocol::FEATURE_SYNC;
+use crate::protocol::ERR_ACCESS_DENIED;
+use crate::remote_fs;
+use crate::storage;
+use crate::stream;
+use crate::stream::Stream;
+use crate::types::TrustState;
+use crate::Pupynet;
+use crate::PupynetEvent;
+use crate::WeakPupynet;
+
+/// How often synced folders are rescanned and compared with peers.
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+/// Index entries are sent in writes of about this size.
+const BATCH: usize = 64 * 1024;
+
+/// Per node count of the changes it made to a file. Comparing two tells
+/// whether one version descends from the other or both changed it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+	Equal,
+	Newer,
+	Older,
+	Concurrent,
+}
+
+impl VersionVector {
+	fn get(&self, node: &str) -> u64 {
+		self.0.get(node).copied().unwrap_or_default()
+	}
+
+	pub fn bump(&mut self, node: &str) {
+		*self.0.entry(node.to_string()).or_default() += 1;
+	}
+
+	pub fn merge(&mut self, other: &VersionVector) {
+		for (node, count) in &other.0 {
+			let own = self.0.entry(node.clone()).or_default();
+			*own = (*own).max(*count);
+		}
+	}
+
+	/// How `self` relates to `other`.
+	pub fn compare(&self, other: &VersionVector) -> Causality {
+		let mut newer = false;
+		let mut older = false;
+		for node in self.0.keys().chain(other.0.keys()) {
+			newer |= self.get(node) > other.get(node);
+			older |= self.get(node) < other.get(node);
+		}
+		match (newer, older) {
+			(false, false) => Causality::Equal,
+			(true, false) => Causality::Newer,
+			(false, true) => Causality::Older,
+			(true, true) => Causality::Concurrent,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntry {
+	pub size: u64,
+	/// Unix timestamp in milliseconds, of the deletion for deleted files.
+	pub modified: u64,
+	pub hash: Hash,
+	/// Kept so the deletion reaches the other nodes.
+	pub deleted: bool,
+	pub version: VersionVector,
+	/// Node that made the latest change.
+	pub modified_by: String,
+}
+
+impl FileEntry {
+	fn same_content(&self, other: &FileEntry) -> bool {
+		self.deleted == other.deleted && (self.deleted || self.hash == other.hash)
+	}
+
+	/// Which of two concurrent versions keeps the name; the other becomes a
+	/// conflict copy. Every node picks the same one.
+	fn wins_over(&self, other: &FileEntry) -> bool {
+		(self.modified, &self.modified_by) > (other.modified, &other.modified_by)
+	}
+}
+
+/// Every file of a synced folder by its path relative to the folder,
+/// separated by `/`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Index {
+	pub files: BTreeMap<String, FileEntry>,
+}
+
+pub(crate) async fn load_index(path: &Path) -> anyhow::Result<Index> {
+	match tokio::fs::read(path).await {
+		Ok(data) => Ok(serde_json::from_slice(&data)?),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Index::default()),
+		Err(err) => Err(err.into()),
+	}
+}
+
+async fn save_index(path: &Path, index: &Index) -> anyhow::Result<()> {
+	if let Some(parent) = path.parent() {
+		tokio::fs::create_dir_all(parent).await?;
+	}
+	let tmp = path.with_extension("json.tmp");
+	tokio::fs::write(&tmp, serde_json::to_vec(index)?).await?;
+	tokio::fs::rename(&tmp, path).await?;
+	Ok(())
+}
+
+fn unix_millis(time: std::time::SystemTime) -> u64 {
+	time.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+/// Files we are still writing, see `delta::temp_path`.
+fn is_temp(name: &str) -> bool {
+	name.starts_with('.') && name.contains(".pupynet-")
+}
+
+fn hash_file(path: &Path) -> std::io::Result<Hash> {
+	let mut file = std::fs::File::open(path)?;
+	let mut hasher = <sha2::Sha256 as sha2::Digest>::new();
+	std::io::copy(&mut file, &mut hasher)?;
+	Ok(sha2::Digest::finalize(hasher).into())
+}
+
+/// Size and modification time of the file at `path`, `None` if there is none.
+fn stat(path: &Path) -> Option<(u64, u64)> {
+	let metadata = std::fs::metadata(path).ok().filter(|m| m.is_file())?;
+	Some((metadata.len(), unix_millis(metadata.modified().ok()?)))
+}
+
+/// Brings `index` up to date with the files under `root`, recording changes
+/// as made by `me`. Returns the paths that changed.
+pub fn scan(root: &Path, index: &mut Index, me: &str) -> anyhow::Result<Vec<String>> {
+	let mut changed = Vec::new();
+	let mut seen = HashSet::new();
+	let mut dirs = vec![root.to_path_buf()];
+	while let Some(dir) = dirs.pop() {
+		for entry in std::fs::read_dir(&dir)? {
+			let entry = entry?;
+			let file_type = entry.file_type()?;
+			let path = entry.path();
+			if file_type.is_dir() {
+				dirs.push(path);
+				continue;
+			}
+			if !file_type.is_file() || is_temp(&entry.file_name().to_string_lossy()) {
+				continue;
+			}
+			let rel = relative(root, &path);
+			seen.insert(rel.clone());
+			let (size, modified) = match stat(&path) {
+				Some(stat) => stat,
+				None => continue,
+			};
+			if let Some(known) = index.files.get(&rel) {
+				if !known.deleted && known.size == size && known.modified == modified {
+					continue;
+				}
+			}
+			let hash = match hash_file(&path) {
+				Ok(hash) => hash,
+				Err(err) => {
+					log::warn!("skipping {}: {}", path.display(), err);
+					continue;
+				}
+			};
+			let entry = index.files.entry(rel.clone()).or_insert_with(|| FileEntry {
+				size,
+				modified,
+				hash,
+				deleted: true,
+				version: VersionVector::default(),
+				modified_by: String::new(),
+			});
+			if entry.deleted || entry.hash != hash {
+				entry.version.bump(me);
+				entry.modified_by = me.to_string();
+				changed.push(rel);
+			}
+			entry.size = size;
+			entry.modified = modified;
+			entry.hash = hash;
+			entry.deleted = false;
+		}
+	}
+	let now = unix_millis(std::time::SystemTime::now());
+	for (rel, entry) in index.files.iter_mut() {
+		if !entry.deleted && !seen.contains(rel) {
+			entry.deleted = true;
+			entry.size = 0;
+			entry.modified = now;
+			entry.hash = Hash::default();
+			entry.version.bump(me);
+			entry.modified_by = me.to_string();
+			changed.push(rel.clone());
+		}
+	}
+	Ok(changed)
+}
+
+fn relative(root: &Path, path: &Path) -> String {
+	let rel = path.strip_prefix(root).unwrap_or(path);
+	rel.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+/// `rel` below `root`, refusing anything that could leave it.
+pub(crate) fn resolve(root: &Path, rel: &str) -> anyhow::Result<PathBuf> {
+	let path = Path::new(rel);
+	if rel.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+		anyhow::bail!("invalid path {:?}", rel);
+	}
+	Ok(root.join(path))
+}
+
+/// Name the losing side of a conflict is kept under, next to the original.
+pub fn conflict_name(rel: &str, node: &str) -> String {
+	let (dir, name) = match rel.rsplit_once('/') {
+		Some((dir, name)) => (format!("{}/", dir), name),
+		None => (String::new(), rel),
+	};
+	let node = &node[..node.len().min(8)];
+	match name.rsplit_once('.') {
+		Some((stem, ext)) if !stem.is_empty() => format!("{}{}.sync-conflict-{}.{}", dir, stem, node, ext),
+		_ => format!("{}{}.sync-conflict-{}", dir, name, node),
+	}
+}
+
+/// What to do about a file a peer has a different version of.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+	/// Take the peer's version vector; the content is already the same.
+	Adopt,
+	Fetch,
+	Delete,
+	/// Both changed the file and the peer's version wins: move ours aside,
+	/// then take theirs.
+	Conflict,
+}
+
+pub fn plan(local: Option<&FileEntry>, remote: &FileEntry) -> Option<Action> {
+	let local = match local {
+		Some(local) => local,
+		None if remote.deleted => return Some(Action::Adopt),
+		None => return Some(Action::Fetch),
+	};
+	match remote.version.compare(&local.version) {
+		Causality::Equal | Causality::Older => None,
+		_ if local.same_content(remote) => Some(Action::Adopt),
+		Causality::Newer if remote.deleted => Some(Action::Delete),
+		Causality::Newer => Some(Action::Fetch),
+		Causality::Concurrent if local.wins_over(remote) => None,
+		Causality::Concurrent => Some(Action::Conflict),
+	}
+}
+
+fn put_entry(buf: &mut Vec<u8>, path: &str, entry: &FileEntry) {
+	put_string(buf, path);
+	buf.extend_from_slice(&entry.size.to_le_bytes());
+	buf.extend_from_slice(&entry.modified.to_le_bytes());
+	buf.push(entry.deleted as u8);
+	buf.extend_from_slice(&entry.hash);
+	put_string(buf, &entry.modified_by);
+	buf.extend_from_slice(&(entry.version.0.len() as u16).to_le_bytes());
+	for (node, count) in &entry.version.0 {
+		put_string(buf, node);
+		buf.extend_from_slice(&count.to_le_bytes());
+	}
+}
+
+/// Reads an index sent by `handle_index`.
+async fn read_index(stream: &mut Stream) -> anyhow::Result<BTreeMap<String, FileEntry>> {
+	let mut files = BTreeMap::new();
+	loop {
+		let path = stream.read_str().await?;
+		if path.is_empty() {
+			return Ok(files);
+		}
+		let size = stream.read_u64().await?;
+		let modified = stream.read_u64().await?;
+		let deleted = stream.read_u8().await? != 0;
+		let hash: Hash = stream.read_exact(32).await?[..].try_into()?;
+		let modified_by = stream.read_str().await?;
+		let mut version = VersionVector::default();
+		for _ in 0..stream.read_u16().await? {
+			let node = stream.read_str().await?;
+			version.0.insert(node, stream.read_u64().await?);
+		}
+		files.insert(path, FileEntry { size, modified, hash, deleted, version, modified_by });
+	}
+}
+
+async fn handle_index(stream: &mut Stream, index_path: &Path) -> anyhow::Result<()> {
+	let index = match load_index(index_path).await {
+		Ok(index) => index,
+		Err(err) => return stream.write_status(Err(err)).await,
+	};
+	stream.write_status(Ok(())).await?;
+	let mut batch = Vec::with_capacity(2 * BATCH);
+	for (path, entry) in &index.files {
+		put_entry(&mut batch, path, entry);
+		if batch.len() >= BATCH {
+			stream.write_bytes(&batch).await?;
+			batch.clear();
+		}
+	}
+	put_string(&mut batch, "");
+	stream.write_bytes(&batch).await
+}
+
+/// Serves `SyncIndex` and `SyncFetch` for the folders we sync.
+pub async fn handle_stream(mut stream: Stream, cmd: PeerCmd, folders: &[SyncFolder], data_dir: Option<&Path>) -> anyhow::Result<()> {
+	let (folder, data_dir) = match &cmd {
+		PeerCmd::SyncIndex { folder, .. } | PeerCmd::SyncFetch { folder, .. } => {
+			match (folders.iter().find(|f| f.id == *folder), data_dir) {
+				(Some(folder), Some(data_dir)) => (folder, data_dir),
+				_ => {
+					stream::reject(stream, ERR_ACCESS_DENIED, format!("folder {} is not synced here", folder)).await;
+					return Ok(());
+				}
+			}
+		},
+		_ => anyhow::bail!("not a sync command"),
+	};
+	match cmd {
+		PeerCmd::SyncIndex { .. } => handle_index(&mut stream, &storage::sync_index_path(data_dir, &folder.id)).await?,
+		PeerCmd::SyncFetch { path, compression, .. } => match resolve(&folder.path, &path) {
+			Ok(path) => stream::handle_read_file(&mut stream, &path.to_string_lossy(), 0, u64::MAX, compression).await?,
+			Err(err) => stream.write_status(Err(err)).await?,
+		},
+		_ => {},
+	}
+	stream.finish()?;
+	while stream.read_bytes().await?.is_some() {}
+	Ok(())
+}
+
+async fn fetch_index(pupynet: &Pupynet, node_id: &str, folder: &str) -> anyhow::Result<BTreeMap<String, FileEntry>> {
+	let mut stream = pupynet.request(PeerCmd::SyncIndex {
+		node_id: node_id.to_string(),
+		folder: folder.to_string(),
+	}).await?;
+	stream.finish()?;
+	stream.read_status().await?;
+	let files = read_index(&mut stream).await?;
+	stream.read_to_end().await?;
+	Ok(files)
+}
+
+struct Folder {
+	config: SyncFolder,
+	index: Index,
+	index_path: PathBuf,
+}
+
+/// Keeps the configured folders in sync with the trusted nodes of our
+/// owner that sync folders of the same id. Holds no handle between rounds,
+/// so it does not keep the node running, and ends at the first tick after
+/// the node shut down or its last handle was dropped.
+pub struct Engine {
+	node: WeakPupynet,
+	folders: Vec<Folder>,
+	me: String,
+	owner: Option<String>,
+}
+
+impl Engine {
+	pub async fn run(pupynet: Pupynet, folders: Vec<SyncFolder>, data_dir: PathBuf, interval: Duration) {
+		let info = match pupynet.info().await {
+			Ok(info) => info,
+			Err(_) => return,
+		};
+		let mut engine = Engine { node: pupynet.downgrade(), folders: Vec::new(), me: info.id, owner: info.owner };
+		drop(pupynet);
+		for config in folders {
+			let index_path = storage::sync_index_path(&data_dir, &config.id);
+			let index = match load_index(&index_path).await {
+				Ok(index) => index,
+				Err(err) => {
+					engine.report(anyhow::anyhow!("sync of {} disabled: cannot load its index: {}", config.id, err));
+					continue;
+				}
+			};
+			engine.folders.push(Folder { config, index, index_path });
+		}
+		let mut timer = tokio::time::interval(interval);
+		loop {
+			timer.tick().await;
+			let Some(pupynet) = engine.node.upgrade() else { return };
+			engine.round(&pupynet).await;
+		}
+	}
+
+	fn report(&self, err: anyhow::Error) {
+		log::error!("{:#}", err);
+		self.node.emit(PupynetEvent::Error { addr: None, message: format!("{:#}", err) });
+	}
+
+	fn emit(&self, event: PupynetEvent) {
+		self.node.emit(event);
+	}
+
+	async fn round(&mut self, pupynet: &Pupynet) {
+		for i in 0..self.folders.len() {
+			self.rescan(i).await;
+		}
+		let peers = match pupynet.peers().await {
+			Ok(peers) => peers,
+			Err(_) => return,
+		};
+		for peer in peers {
+			if !peer.connected || self.owner.is_none() || peer.owner != self.owner || peer.trust != TrustState::Trusted {
+				continue;
+			}
+			match pupynet.features(&peer.id).await {
+				Ok(features) if features & FEATURE_SYNC != 0 => {},
+				_ => continue,
+			}
+			for i in 0..self.folders.len() {
+				let id = self.folders[i].config.id.clone();
+				match fetch_index(pupynet, &peer.id, &id).await {
+					Ok(remote) => self.reconcile(pupynet, i, &peer.id, remote).await,
+					Err(err) => log::info!("no index of {} from {}: {}", id, peer.id, err),
+				}
+			}
+		}
+	}
+
+	async fn rescan(&mut self, i: usize) {
+		let folder = &mut self.folders[i];
+		let root = folder.config.path.clone();
+		let me = self.me.clone();
+		let mut index = folder.index.clone();
+		let res = tokio::task::spawn_blocking(move || {
+			scan(&root, &mut index, &me).map(|changed| (index, changed))
+		}).await.map_err(anyhow::Error::from).and_then(|res| res);
+		let res = res.map(|(index, changed)| {
+			folder.index = index;
+			changed
+		});
+		match res {
+			Ok(changed) if !changed.is_empty() => {
+				log::info!("{}: {} local changes", folder.config.id, changed.len());
+				self.save(i).await;
+			},
+			Ok(_) => {},
+			Err(err) => {
+				let id = folder.config.id.clone();
+				self.report(err.context(format!("scanning {} failed", id)));
+			},
+		}
+	}
+
+	async fn save(&self, i: usize) {
+		let folder = &self.folders[i];
+		if let Err(err) = save_index(&folder.index_path, &folder.index).await {
+			self.report(err.context(format!("saving the index of {} failed", folder.config.id)));
+		}
+	}
+
+	async fn reconcile(&mut self, pupynet: &Pupynet, i: usize, peer: &str, remote: BTreeMap<String, FileEntry>) {
+		let mut changed = false;
+		for (rel, theirs) in remote {
+			let action = match plan(self.folders[i].index.files.get(&rel), &theirs) {
+				Some(action) => action,
+				None => continue,
+			};
+			match self.apply(pupynet, i, peer, &rel, theirs, action).await {
+				Ok(()) => changed = true,
+				Err(err) => log::info!("{}: not syncing {} yet: {:#}", self.folders[i].config.id, rel, err),
+			}
+		}
+		if changed {
+			self.save(i).await;
+		}
+	}
+
+	async fn apply(&mut self, pupynet: &Pupynet, i: usize, peer: &str, rel: &str, mut theirs: FileEntry, action: Action) -> anyhow::Result<()> {
+		let folder = &self.folders[i];
+		let id = folder.config.id.clone();
+		let path = resolve(&folder.config.path, rel)?;
+		let ours = folder.index.files.get(rel).cloned();
+		// Changes made since the last scan are picked up by the next one
+		// before anything is overwritten.
+		let on_disk = stat(&path);
+		let expected = ours.as_ref().filter(|e| !e.deleted).map(|e| (e.size, e.modified));
+		if on_disk != expected {
+			anyhow::bail!("changed locally");
+		}
+		if let Some(ours) = &ours {
+			theirs.version.merge(&ours.version);
+		}
+
+		match action {
+			Action::Adopt => {
+				if let Some((size, modified)) = on_disk {
+					theirs.size = size;
+					theirs.modified = modified;
+				}
+			},
+			Action::Delete => {
+				tokio::fs::remove_file(&path).await?;
+				self.emit(PupynetEvent::FileSynced { folder: id, path: rel.to_string() });
+			},
+			Action::Fetch | Action::Conflict => {
+				if let (Action::Conflict, Some(ours)) = (&action, &ours) {
+					if !ours.deleted {
+						let copy = conflict_name(rel, &self.me);
+						tokio::fs::rename(&path, resolve(&self.folders[i].config.path, &copy)?).await?;
+						self.emit(PupynetEvent::SyncConflict { folder: id.clone(), path: rel.to_string(), copy });
+					}
+				}
+				if theirs.deleted {
+					if let Some(ours) = ours.filter(|o| !o.deleted) {
+						theirs.modified = ours.modified;
+					}
+				} else {
+					let (size, modified) = fetch(pupynet, peer, &id, rel, &path, &theirs).await?;
+					theirs.size = size;
+					theirs.modified = modified;
+				}
+				self.emit(PupynetEvent::FileSynced { folder: id, path: rel.to_string() });
+			},
+		}
+		self.folders[i].index.files.insert(rel.to_string(), theirs);
+		// The conflict copy, if any, is indexed as a new file by the next scan.
+		Ok(())
+	}
+}
+
+/// Downloads `rel` from `peer` next to `path`, checking it against `entry`
+/// as it arrives, and moves it into place. Returns the size and
+/// modification time of the written file.
+async fn fetch(pupynet: &Pupynet, peer: &str, folder: &str, rel: &str, path: &Path, entry: &FileEntry) -> anyhow::Result<(u64, u64)> {
+	let compression = pupynet.compression(peer).await?;
+	let (mut stream, codec) = pupynet.open_read(PeerCmd::SyncFetch {
+		node_id: peer.to_string(),
+		folder: folder.to_string(),
+		path: rel.to_string(),
+		compression,
+	}, compression, None).await?;
+	if let Some(parent) = path.parent() {
+		tokio::fs::create_dir_all(parent).await?;
+	}
+	let temp = delta::temp_path(path);
+	let res = async {
+		let mut file = tokio::fs::File::create(&temp).await?;
+		let mut hasher = Sha256::new();
+		let mut size = 0;
+		while let Some(chunk) = remote_fs::read_chunk(&mut stream, codec).await? {
+			size += chunk.len() as u64;
+			if size > entry.size {
+				anyhow::bail!("changed on {} during the transfer: longer than {} bytes", peer, entry.size);
+			}
+			hasher.update(&chunk);
+			file.write_all(&chunk).await?;
+		}
+		if Hash::from(hasher.finalize()) != entry.hash {
+			anyhow::bail!("changed on {} during the transfer", peer);
+		}
+		file.sync_all().await?;
+		drop(file);
+		tokio::fs::rename(&temp, path).await?;
+		Ok(())
+	}.await;
+	if res.is_err() {
+		let _ = tokio::fs::remove_file(&temp).await;
+	}
+	res?;
+	stat(path).ok_or_else(|| anyhow::anyhow!("{} vanished after writing it", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::folder;
+	use crate::testing::node;
+	use crate::testing::wait_for_peer;
+
+	fn version(counts: &[(&str, u64)]) -> VersionVector {
+		VersionVector(counts.iter().map(|(n, c)| (n.to_string(), *c)).collect())
+	}
+
+	fn entry(counts: &[(&str, u64)], hash: u8, modified: u64) -> FileEntry {
+		FileEntry {
+			size: 1,
+			modified,
+			hash: [hash; 32],
+			deleted: false,
+			version: version(counts),
+			modified_by: counts.last().map(|(n, _)| n.to_string()).unwrap_or_default(),
+		}
+	}
+
+	#[test]
+	fn test_version_vectors() {
+		let a = version(&[("a", 2), ("b", 1)]);
+		assert_eq!(a.compare(&a.clone()), Causality::Equal);
+		assert_eq!(a.compare(&version(&[("a", 1), ("b", 1)])), Causality::Newer);
+		assert_eq!(a.compare(&version(&[("a", 2), ("b", 1), ("c", 1)])), Causality::Older);
+		assert_eq!(a.compare(&version(&[("b", 2)])), Causality::Concurrent);
+
+		let mut merged = a.clone();
+		merged.merge(&version(&[("b", 3), ("c", 1)]));
+		assert_eq!(merged, version(&[("a", 2), ("b", 3), ("c", 1)]));
+	}
+
+	#[test]
+	fn test_plan() {
+		let ours = entry(&[("a", 1)], 1, 100);
+		assert_eq!(plan(None, &ours), Some(Action::Fetch));
+		assert_eq!(plan(Some(&ours), &ours), None);
+		assert_eq!(plan(Some(&ours), &entry(&[("a", 2)], 2, 200)), Some(Action::Fetch));
+		assert_eq!(plan(Some(&ours), &entry(&[("a", 2)], 1, 200)), Some(Action::Adopt));
+		let mut deleted = entry(&[("a", 2)], 0, 200);
+		deleted.deleted = true;
+		assert_eq!(plan(Some(&ours), &deleted), Some(Action::Delete));
+
+		// Both changed it: the later change keeps the name on both nodes.
+		let later = entry(&[("a", 1), ("b", 1)], 2, 300);
+		let mut earlier = entry(&[("a", 2)], 3, 200);
+		assert_eq!(plan(Some(&earlier), &later), Some(Action::Conflict));
+		assert_eq!(plan(Some(&later), &earlier), None);
+		earlier.hash = later.hash;
+		assert_eq!(plan(Some(&earlier), &later), Some(Action::Adopt));
+	}
+
+	#[test]
+	fn test_scan() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = dir.path().to_path_buf();
+		std::fs::create_dir_all(root.join("sub")).unwrap();
+		std::fs::write(root.join("a.txt"), b"one").unwrap();
+		std::fs::write(root.join("sub/b.txt"), b"two").unwrap();
+		std::fs::write(root.join(".b.txt.pupynet-1234abcd"), b"partial").unwrap();
+
+		let mut index = Index::default();
+		let mut changed = scan(&root, &mut index, "me").unwrap();
+		changed.sort();
+		assert_eq!(changed, vec!["a.txt", "sub/b.txt"]);
+		assert!(scan(&root, &mut index, "me").unwrap().is_empty());
+
+		std::fs::write(root.join("a.txt"), b"three").unwrap();
+		std::fs::remove_file(root.join("sub/b.txt")).unwrap();
+		let mut changed = scan(&root, &mut index, "me").unwrap();
+		changed.sort();
+		assert_eq!(changed, vec!["a.txt", "sub/b.txt"]);
+		assert_eq!(index.files["a.txt"].version, version(&[("me", 2)]));
+		assert!(index.files["sub/b.txt"].deleted);
+	}
+
+	#[test]
+	fn test_paths() {
+		assert_eq!(conflict_name("docs/notes.txt", "0123456789abcdef"), "docs/notes.sync-conflict-01234567.txt");
+		assert_eq!(conflict_name("Makefile", "ab"), "Makefile.sync-conflict-ab");
+		assert!(resolve(Path::new("/sync"), "../etc/passwd").is_err());
+		assert!(resolve(Path::new("/sync"), "/etc/passwd").is_err());
+		assert_eq!(resolve(Path::new("/sync"), "a/b").unwrap(), PathBuf::from("/sync/a/b"));
+	}
+
+	#[tokio::test]
+	async fn test_folder_sync() {
+		let dir = tempfile::tempdir().unwrap();
+		let (a_dir, b_dir) = (folder(dir.path(), "docs-a"), folder(dir.path(), "docs-b"));
+		let synced = |name: &str, path: &PathBuf| node(dir.path(), name)
+			.owner("alice")
+			.sync_folder("docs", path)
+			.sync_interval(Duration::from_millis(100));
+		let a = synced("a", &a_dir).build();
+		let a_info = a.info().await.unwrap();
+		let b = synced("b", &b_dir).peer(&a_info.listen_addrs[0]).build();
+		let b_info = b.info().await.unwrap();
+		wait_for_peer(&a, &b_info.id).await;
+		wait_for_peer(&b, &a_info.id).await;
+		a.set_trust(b_info.id.clone(), TrustState::Trusted).unwrap();
+		b.set_trust(a_info.id.clone(), TrustState::Trusted).unwrap();
+
+		async fn eventually(f: impl Fn() -> bool) {
+			let wait = async {
+				while !f() {
+					tokio::time::sleep(Duration::from_millis(20)).await;
+				}
+			};
+			tokio::time::timeout(Duration::from_secs(10), wait).await.unwrap();
+		}
+
+		std::fs::create_dir_all(a_dir.join("notes")).unwrap();
+		std::fs::write(a_dir.join("notes/todo.txt"), b"milk").unwrap();
+		let on_b = b_dir.join("notes/todo.txt");
+		eventually(|| std::fs::read(&on_b).ok().as_deref() == Some(b"milk")).await;
+
+		std::fs::write(&on_b, b"milk, eggs").unwrap();
+		let on_a = a_dir.join("notes/todo.txt");
+		eventually(|| std::fs::read(&on_a).ok().as_deref() == Some(b"milk, eggs")).await;
+
+		std::fs::remove_file(&on_a).unwrap();
+		eventually(|| !on_b.exists()).await;
+		assert_eq!(std::fs::read_dir(b_dir.join("notes")).unwrap().count(), 0);
+
+		// Claiming our owner is not enough to read the folder.
+		let c = node(dir.path(), "c").owner("alice").peer(&a_info.listen_addrs[0]).build();
+		wait_for_peer(&c, &a_info.id).await;
+		assert!(fetch_index(&c, &a_info.id, "docs").await.is_err());
+	}
+
+	#[tokio::test]
+	async fn test_fetch_checks_before_moving_into_place() {
+		let dir = tempfile::tempdir().unwrap();
+		let (a_dir, b_dir) = (folder(dir.path(), "docs-a"), folder(dir.path(), "docs-b"));
+		std::fs::write(a_dir.join("a.txt"), b"0123456789").unwrap();
+		let synced = |name: &str, path: &PathBuf| node(dir.path(), name)
+			.owner("alice")
+			.sync_folder("docs", path)
+			.sync_interval(Duration::from_secs(3600));
+		let a = synced("a", &a_dir).build();
+		let a_info = a.info().await.unwrap();
+		let b = synced("b", &b_dir).peer(&a_info.listen_addrs[0]).build();
+		let b_info = b.info().await.unwrap();
+		wait_for_peer(&b, &a_info.id).await;
+		wait_for_peer(&a, &b_info.id).await;
+		a.set_trust(b_info.id.clone(), TrustState::Trusted).unwrap();
+		b.set_trust(a_info.id.clone(), TrustState::Trusted).unwrap();
+		let theirs = fetch_index(&b, &a_info.id, "docs").await.unwrap()["a.txt"].clone();
+
+		let out = folder(dir.path(), "out");
+		let path = out.join("a.txt");
+		let wrong_hash = FileEntry { hash: [0; 32], ..theirs.clone() };
+		assert!(fetch(&b, &a_info.id, "docs", "a.txt", &path, &wrong_hash).await.is_err());
+		let shorter = FileEntry { size: 4, ..theirs.clone() };
+		assert!(fetch(&b, &a_info.id, "docs", "a.txt", &path, &shorter).await.is_err());
+		assert_eq!(std::fs::read_dir(&out).unwrap().count(), 0);
+
+		assert_eq!(fetch(&b, &a_info.id, "docs", "a.txt", &path, &theirs).await.unwrap().0, 10);
+		assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+		assert_eq!(std::fs::read_dir(&out).unwrap().count(), 1);
+	}
+
+	#[tokio::test]
+	async fn test_engine_lets_node_stop() {
+		let dir = tempfile::tempdir().unwrap();
+		let docs = folder(dir.path(), "docs");
+		let a = node(dir.path(), "a")
+			.sync_folder("docs", &docs)
+			.sync_interval(Duration::from_millis(50))
+			.build();
+		let addr = a.info().await.unwrap().listen_addrs[0].trim_start_matches("tcp://").to_string();
+		// Dropped between rounds of the engine.
+		tokio::time::sleep(Duration::from_millis(275)).await;
+		drop(a);
+		let stopped = async {
+			while tokio::net::TcpStream::connect(&addr).await.is_ok() {
+				tokio::time::sleep(Duration::from_millis(20)).await;
+			}
+		};
+		tokio::time::timeout(Duration::from_secs(10), stopped).await.unwrap();
+	}
+}
@@ -1,7 +1,13 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::oneshot;
+use transfer::TransferManager;
 use types::InternalCommand;
-use worker::Worker;
 
 //mod ws;
 mod types;
@@ -12,6 +18,52 @@ mod stream;
 mod multiplex;
 mod tcp;
 mod connection;
+mod storage;
+mod mesh;
+mod routing;
+mod remote_fs;
+mod reconnect;
+mod builder;
+mod flow;
+mod compress;
+mod chunk;
+mod delta;
+mod block_store;
+mod folder_sync;
+mod watch;
+mod swarm;
+mod transfer;
+mod rate;
+#[cfg(test)]
+mod testing;
+
+pub use protocol::FolderEntry;
+pub use protocol::FEATURE_BLOCKS;
+pub use protocol::FEATURE_COMPRESSION;
+pub use protocol::FEATURE_DELTA;
+pub use protocol::FEATURE_ENCRYPTION;
+pub use protocol::FEATURE_EXECUTE;
+pub use protocol::FEATURE_PAUSE;
+pub use protocol::FEATURE_SWARM;
+pub use protocol::FEATURE_SYNC;
+pub use protocol::FEATURE_WATCH;
+pub use protocol::FEATURE_WINDOW;
+pub use protocol::FEATURE_WRITE_MODES;
+pub use protocol::PROTOCOL_VERSION;
+pub use protocol::WriteMode;
+pub use builder::PupynetBuilder;
+pub use builder::Share;
+pub use builder::SyncFolder;
+pub use compress::Compression;
+pub use rate::RateConfig;
+pub use rate::RateLimit;
+pub use rate::RateSchedule;
+pub use transfer::Priority;
+pub use transfer::TransferInfo;
+pub use transfer::TransferKind;
+pub use transfer::TransferState;
+pub use types::TrustState;
+pub use watch::Watch;
 
 #[derive(Debug, Clone)]
 pub enum PupynetEvent {
@@ -24,47 +76,337 @@ pub enum PupynetEvent {
 	PeerData {
 		addr: String,
 		data: Vec<u8>
+	},
+	/// A supervised outbound connection dropped; the next dial is in `delay_ms`.
+	Reconnecting {
+		addr: String,
+		attempt: u32,
+		delay_ms: u64
+	},
+	Reconnected {
+		addr: String
+	},
+	/// A synced folder took a change from another node.
+	FileSynced {
+		folder: String,
+		path: String
+	},
+	/// Both we and another node changed `path`; our version was kept as
+	/// `copy` next to it.
+	SyncConflict {
+		folder: String,
+		path: String,
+		copy: String
+	},
+	/// A transfer was queued, changed state or made progress. Progress is
+	/// reported about twice a second.
+	TransferUpdated {
+		transfer: TransferInfo
+	},
+	/// Something failed in the background; the node keeps running. `addr`
+	/// names the connection or listener involved, if any.
+	Error {
+		addr: Option<String>,
+		message: String
 	}
 }
 
+/// Identity and listen addresses of the local node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeInfo {
+	pub id: String,
+	pub name: String,
+	pub owner: Option<String>,
+	pub listen_addrs: Vec<String>,
+}
+
+/// A known peer and how it can currently be reached.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+	pub id: String,
+	pub name: String,
+	pub owner: Option<String>,
+	/// Addresses the peer was last seen at, most recent first.
+	pub addrs: Vec<String>,
+	/// Unix timestamp in seconds.
+	pub last_seen: u64,
+	pub trust: TrustState,
+	/// Whether we have a direct connection to it.
+	pub connected: bool,
+	/// Number of links a stream to it crosses, `None` when unreachable.
+	pub hops: Option<u8>,
+	pub rtt_ms: Option<u64>,
+}
+
+/// A change under a path watched with `Pupynet::watch`. Paths are as seen
+/// by the watched node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FsEvent {
+	Created {
+		path: String
+	},
+	Modified {
+		path: String
+	},
+	Removed {
+		path: String
+	},
+	/// Only reported when both ends are inside the watched tree; otherwise
+	/// it shows up as `Removed` or `Created`.
+	Renamed {
+		from: String,
+		to: String
+	}
+}
+
+/// How much of a file `sync_file` sent and how much the receiver could
+/// take from its old copy.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeltaStats {
+	pub sent: u64,
+	pub reused: u64,
+}
+
+/// A file fetched by `swarm_download`.
+#[derive(Debug, Clone, Default)]
+pub struct SwarmDownload {
+	/// Bytes written to the destination.
+	pub size: u64,
+	/// Bytes each node served, by node id.
+	pub sources: HashMap<String, u64>,
+}
+
+/// Queue depths of a running node, for spotting where data piles up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metrics {
+	/// Commands waiting for the worker.
+	pub commands_queued: usize,
+	/// Transport events waiting for the worker.
+	pub events_queued: usize,
+	/// Streams being served or forwarded.
+	pub transfers: usize,
+	pub connections: Vec<ConnectionMetrics>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionMetrics {
+	pub addr: String,
+	pub peer_id: Option<String>,
+	/// Bytes waiting to be written to the socket.
+	pub send_queued: usize,
+	/// Bytes received but not yet read by their stream.
+	pub recv_queued: usize,
+	/// Protocol version agreed on, `None` until the peer introduced itself.
+	pub version: Option<u16>,
+	/// `FEATURE_*` bits both ends support.
+	pub features: u64,
+}
+
 pub struct Pupynet {
-	tx: mpsc::UnboundedSender<InternalCommand>,
+	tx: mpsc::Sender<InternalCommand>,
 	event_tx: broadcast::Sender<PupynetEvent>,
 	event_rx: broadcast::Receiver<PupynetEvent>,
+	transfers: Arc<TransferManager>,
+	/// Given to the transfers started through this handle.
+	priority: Priority,
 }
 
 impl Pupynet {
+	/// Starts a node with default settings.
 	pub fn new() -> Pupynet {
-		let (event_tx, event_rx) = broadcast::channel(1024);
-		let (tx, rx) = mpsc::unbounded_channel();
-		{
-			let event_tx = event_tx.clone();
-			tokio::spawn(async move {
-				Worker::new(rx, event_tx).await.run().await;
-			});
+		PupynetBuilder::new().build()
+	}
+
+	pub fn builder() -> PupynetBuilder {
+		PupynetBuilder::new()
+	}
+
+	/// Every known peer, sorted by name.
+	pub async fn peers(&self) -> anyhow::Result<Vec<PeerInfo>> {
+		let (reply, rx) = oneshot::channel();
+		self.send_wait(InternalCommand::Peers { reply }).await?;
+		Ok(rx.await?)
+	}
+
+	pub async fn info(&self) -> anyhow::Result<NodeInfo> {
+		let (reply, rx) = oneshot::channel();
+		self.send_wait(InternalCommand::Info { reply }).await?;
+		Ok(rx.await?)
+	}
+
+	pub async fn metrics(&self) -> anyhow::Result<Metrics> {
+		let (reply, rx) = oneshot::channel();
+		self.send_wait(InternalCommand::Metrics { reply }).await?;
+		Ok(rx.await?)
+	}
+
+	/// Queues a command without waiting, failing when the worker is gone
+	/// or too far behind.
+	fn send(&self, cmd: InternalCommand) -> anyhow::Result<()> {
+		self.tx.try_send(cmd).map_err(|err| match err {
+			mpsc::error::TrySendError::Full(_) => anyhow::anyhow!("command queue full"),
+			mpsc::error::TrySendError::Closed(_) => anyhow::anyhow!("worker stopped"),
+		})
+	}
+
+	/// Queues a command, waiting for room.
+	async fn send_wait(&self, cmd: InternalCommand) -> anyhow::Result<()> {
+		self.tx.send(cmd).await.map_err(|_| anyhow::anyhow!("worker stopped"))
+	}
+
+	pub fn bind(&self, addr: String) -> anyhow::Result<()> {
+		if !addr.starts_with("tcp://") {
+			anyhow::bail!("unsupported bind address: {}", addr);
 		}
+		self.send(InternalCommand::Bind { addr })
+	}
 
-		Pupynet {
-			tx,
-			event_tx,
-			event_rx
+	pub fn connect(&self, addr: String) -> anyhow::Result<()> {
+		if !addr.starts_with("tcp://") {
+			anyhow::bail!("unsupported peer address: {}", addr);
 		}
+		self.send(InternalCommand::Connect { addr })
+	}
+
+	/// Sets how often connections are pinged and how long a silent
+	/// connection is kept before it is closed as dead.
+	pub fn set_keepalive(&self, interval: std::time::Duration, timeout: std::time::Duration) -> anyhow::Result<()> {
+		self.send(InternalCommand::SetKeepalive { interval, timeout })
 	}
 
-	pub fn bind(&self, addr: String) {
-		if addr.starts_with("ws://") {
+	/// Applies the settings of `builder` to the running node without
+	/// dropping its connections. Identity, data dir and discovery port only
+	/// take effect after a restart.
+	pub fn reload(&self, builder: PupynetBuilder) -> anyhow::Result<()> {
+		self.transfers.set_limit(builder.config.max_transfers);
+		self.send(InternalCommand::Reload { config: Box::new(builder.config) })
+	}
 
-		}
+	/// Another handle to the node whose transfers are queued with `priority`.
+	pub fn with_priority(&self, priority: Priority) -> Pupynet {
+		Pupynet { priority, ..self.clone() }
+	}
+
+	/// Ongoing transfers and the last ones that ended, oldest first.
+	pub fn transfers(&self) -> Vec<TransferInfo> {
+		self.transfers.list()
 	}
 
-	pub fn connect(&self, addr: String) {
-		if addr.starts_with("ws://") {
-			self.tx.send(InternalCommand::Connect { addr }).unwrap();
+	/// Pauses a transfer, asking the remote to stop sending as well when it
+	/// supports `FEATURE_PAUSE`. Its slot goes to the next queued transfer.
+	pub fn pause_transfer(&self, id: u64) -> anyhow::Result<()> {
+		self.transfers.pause(id)
+	}
+
+	/// Queues a paused transfer again.
+	pub fn resume_transfer(&self, id: u64) -> anyhow::Result<()> {
+		self.transfers.resume(id)
+	}
+
+	/// Cancels a transfer; the call that started it fails.
+	pub fn cancel_transfer(&self, id: u64) -> anyhow::Result<()> {
+		self.transfers.cancel(id)
+	}
+
+	/// Moves a transfer within the queue; running transfers keep running.
+	pub fn set_transfer_priority(&self, id: u64, priority: Priority) -> anyhow::Result<()> {
+		self.transfers.set_priority(id, priority)
+	}
+
+	/// Stops the node: listeners and discovery stop, peers are told we are
+	/// leaving and in-flight transfers get until the configured shutdown
+	/// timeout to finish before every connection is closed. Returns once
+	/// the node's tasks have ended; calling it again is harmless.
+	pub async fn shutdown(&self) {
+		let (reply, rx) = oneshot::channel();
+		if self.send_wait(InternalCommand::Shutdown { reply }).await.is_ok() {
+			let _ = rx.await;
 		}
 	}
 
+	/// Removes a peer from the local peer store, closes our links to it and
+	/// refuses it until it is trusted again with `set_trust`. With
+	/// `propagate` the trusted nodes of the same owner we are connected to
+	/// are asked to forget it too, and pass it on to theirs.
+	pub fn forget_peer(&self, id: String, propagate: bool) -> anyhow::Result<()> {
+		self.send(InternalCommand::ForgetPeer { id, propagate })
+	}
+
+	/// Records how much we trust a known peer. Stored with the peer store.
+	/// Blocked peers are disconnected, refused when they connect again and
+	/// never routed to or through.
+	pub fn set_trust(&self, id: String, trust: TrustState) -> anyhow::Result<()> {
+		self.send(InternalCommand::SetTrust { id, trust })
+	}
+
+	/// Next event, `None` once the node has shut down.
 	pub async fn next(&mut self) -> Option<PupynetEvent> {
-		println!("next");
-		None
+		loop {
+			let res = tokio::select! {
+				biased;
+				res = self.event_rx.recv() => res,
+				_ = self.tx.closed() => match self.event_rx.try_recv() {
+					Ok(event) => return Some(event),
+					Err(_) => return None,
+				},
+			};
+			match res {
+				Ok(event) => return Some(event),
+				Err(broadcast::error::RecvError::Lagged(n)) => {
+					log::warn!("missed {} events", n);
+				},
+				Err(broadcast::error::RecvError::Closed) => return None,
+			}
+		}
+	}
+
+	/// A handle that does not keep the node running, for tasks of the node
+	/// itself.
+	pub(crate) fn downgrade(&self) -> WeakPupynet {
+		WeakPupynet {
+			tx: self.tx.downgrade(),
+			event_tx: self.event_tx.clone(),
+			transfers: self.transfers.clone(),
+			priority: self.priority,
+		}
+	}
+}
+/// Another handle to the same node, with its own event subscription.
+impl Clone for Pupynet {
+	fn clone(&self) -> Self {
+		Pupynet {
+			tx: self.tx.clone(),
+			event_tx: self.event_tx.clone(),
+			event_rx: self.event_tx.subscribe(),
+			transfers: self.transfers.clone(),
+			priority: self.priority,
+		}
 	}
-}
\ No newline at end of file
+}
+
+/// See `Pupynet::downgrade`. Events can still be sent through it.
+pub(crate) struct WeakPupynet {
+	tx: mpsc::WeakSender<InternalCommand>,
+	event_tx: broadcast::Sender<PupynetEvent>,
+	transfers: Arc<TransferManager>,
+	priority: Priority,
+}
+
+impl WeakPupynet {
+	/// A full handle, `None` once every user handle is dropped or the node
+	/// was shut down.
+	pub(crate) fn upgrade(&self) -> Option<Pupynet> {
+		let tx = self.tx.upgrade().filter(|tx| !tx.is_closed())?;
+		Some(Pupynet {
+			tx,
+			event_tx: self.event_tx.clone(),
+			event_rx: self.event_tx.subscribe(),
+			transfers: self.transfers.clone(),
+			priority: self.priority,
+		})
+	}
+
+	pub(crate) fn emit(&self, event: PupynetEvent) {
+		let _ = self.event_tx.send(event);
+	}
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Hop budget for membership gossip before it is dropped.
+pub const GOSSIP_TTL: u8 = 16;
+
+/// Most links learned through gossip, so peers cannot grow the mesh
+/// without bound.
+pub const MAX_LINKS: usize = 4096;
+
+/// How long tombstones and the links of unreachable nodes are kept; long
+/// enough for announcements still on their way to have died out.
+pub const LINK_EXPIRY: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Link {
+	pub seq: u64,
+	pub up: bool,
+	/// When the link last changed.
+	pub changed: Instant,
+}
+
+/// What a node we only know through gossip says about itself. Kept in
+/// memory alone and dropped once no link mentions the node.
+#[derive(Debug, Clone, Default)]
+pub struct MeshNode {
+	pub name: String,
+	pub owner: Option<String>,
+	pub features: u64,
+}
+
+#[derive(Debug)]
+struct Origin {
+	links: HashMap<String, Link>,
+	/// When the node was last reachable from us.
+	seen: Instant,
+}
+
+/// View of which node has a direct connection to which, built from
+/// `CMD_PEER_CONNECTED` / `CMD_PEER_DISCONNECTED` gossip.
+///
+/// Every announcement is stamped with a sequence number from its origin. A
+/// link only changes when a newer announcement arrives, which both orders
+/// updates arriving over different paths and tells the worker whether to
+/// forward the announcement, so floods die out once every node has seen them.
+#[derive(Debug)]
+pub struct Mesh {
+	origins: HashMap<String, Origin>,
+	nodes: HashMap<String, MeshNode>,
+	/// Links learned through gossip, our own not counted.
+	gossiped: usize,
+	next_seq: u64,
+}
+
+impl Mesh {
+	pub fn new() -> Mesh {
+		// Start from wall clock so a restarted node does not reuse old sequence numbers.
+		let next_seq = std::time::SystemTime::now()
+			.duration_since(std::time::UNIX_EPOCH)
+			.map(|d| d.as_millis() as u64)
+			.unwrap_or_default();
+		Mesh {
+			origins: HashMap::new(),
+			nodes: HashMap::new(),
+			gossiped: 0,
+			next_seq,
+		}
+	}
+
+	/// Records a change in our own links, which the cap does not apply to,
+	/// and returns the sequence number to announce it with.
+	pub fn announce(&mut self, me: &str, peer: &str, up: bool) -> u64 {
+		self.next_seq += 1;
+		self.set(me, peer, Link { seq: self.next_seq, up, changed: Instant::now() });
+		self.next_seq
+	}
+
+	/// Applies an announcement. Returns `false` when it is a duplicate, older
+	/// than what is already known, or a new link while the mesh is full.
+	pub fn update(&mut self, origin: &str, peer: &str, seq: u64, up: bool) -> bool {
+		match self.origins.get(origin).and_then(|o| o.links.get(peer)) {
+			Some(link) if link.seq >= seq => return false,
+			Some(_) => {},
+			None if self.gossiped >= MAX_LINKS => return false,
+			None => self.gossiped += 1,
+		}
+		self.set(origin, peer, Link { seq, up, changed: Instant::now() });
+		true
+	}
+
+	fn set(&mut self, origin: &str, peer: &str, link: Link) {
+		let now = Instant::now();
+		let origin = self.origins.entry(origin.to_string()).or_insert_with(|| Origin { links: HashMap::new(), seen: now });
+		origin.links.insert(peer.to_string(), link);
+	}
+
+	/// Remembers what `id` says about itself, for as long as it is in the mesh.
+	pub fn learn(&mut self, id: &str, node: MeshNode) {
+		if self.nodes.contains_key(id) || self.nodes.len() < MAX_LINKS {
+			self.nodes.insert(id.to_string(), node);
+		}
+	}
+
+	pub fn node(&self, id: &str) -> Option<&MeshNode> {
+		self.nodes.get(id)
+	}
+
+	pub fn nodes(&self) -> impl Iterator<Item = (&String, &MeshNode)> {
+		self.nodes.iter()
+	}
+
+	/// Drops tombstones older than `expiry` and every link of nodes that were
+	/// not `reachable` for that long, then what gossip told us about nodes no
+	/// link mentions any more.
+	pub fn prune(&mut self, me: &str, expiry: Duration, reachable: impl Fn(&str) -> bool) {
+		let now = Instant::now();
+		self.origins.retain(|id, origin| {
+			origin.links.retain(|_, link| link.up || now.duration_since(link.changed) < expiry);
+			let reachable = id == me || reachable(id);
+			if reachable {
+				origin.seen = now;
+			}
+			!origin.links.is_empty() && (reachable || now.duration_since(origin.seen) < expiry)
+		});
+		self.gossiped = self.origins.iter()
+			.filter(|(id, _)| *id != me)
+			.map(|(_, origin)| origin.links.len())
+			.sum();
+		let origins = &self.origins;
+		self.nodes.retain(|id, _| origins.contains_key(id) || origins.values().any(|o| o.links.contains_key(id)));
+	}
+
+	/// Every known link including tombstones, used to bring a new neighbour up to date.
+	pub fn links(&self) -> impl Iterator<Item = (&String, &String, Link)> {
+		self.origins.iter().flat_map(|(id, origin)| {
+			origin.links.iter().map(move |(peer, link)| (id, peer, *link))
+		})
+	}
+
+	/// Nodes `id` currently announces a direct connection to.
+	pub fn neighbours<'a>(&'a self, id: &str) -> impl Iterator<Item = &'a String> {
+		self.origins.get(id)
+			.into_iter()
+			.flat_map(|origin| origin.links.iter().filter(|(_, link)| link.up).map(|(peer, _)| peer))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_duplicate_and_stale_updates_are_ignored() {
+		let mut mesh = Mesh::new();
+		assert!(mesh.update("a", "b", 5, true));
+		assert!(!mesh.update("a", "b", 5, true));
+		assert!(mesh.update("a", "b", 7, false));
+		// The older "up" arriving late over another path must not revive the link.
+		assert!(!mesh.update("a", "b", 6, true));
+		assert_eq!(mesh.neighbours("a").count(), 0);
+	}
+
+	#[test]
+	fn test_neighbours() {
+		let mut mesh = Mesh::new();
+		mesh.update("a", "b", 1, true);
+		mesh.update("a", "c", 1, true);
+		mesh.update("b", "c", 1, true);
+		let mut neighbours: Vec<_> = mesh.neighbours("a").cloned().collect();
+		neighbours.sort();
+		assert_eq!(neighbours, vec!["b".to_string(), "c".to_string()]);
+	}
+
+	#[test]
+	fn test_gossip_is_capped() {
+		let mut mesh = Mesh::new();
+		for i in 0..MAX_LINKS {
+			assert!(mesh.update("a", &i.to_string(), 1, true));
+		}
+		assert!(!mesh.update("b", "c", 1, true));
+		// Known links still change, and our own are always recorded.
+		assert!(mesh.update("a", "0", 2, false));
+		mesh.announce("me", "a", true);
+		assert_eq!(mesh.neighbours("me").count(), 1);
+	}
+
+	#[test]
+	fn test_prune() {
+		let mut mesh = Mesh::new();
+		mesh.announce("me", "a", true);
+		mesh.announce("me", "gone", true);
+		mesh.announce("me", "gone", false);
+		mesh.update("a", "b", 1, true);
+		mesh.update("x", "y", 1, true);
+		mesh.learn("b", MeshNode::default());
+		mesh.learn("y", MeshNode::default());
+
+		mesh.prune("me", LINK_EXPIRY, |id| id != "x");
+		assert_eq!(mesh.links().count(), 4);
+		// Once expired, tombstones and links of unreachable nodes go.
+		mesh.prune("me", Duration::ZERO, |id| id != "x");
+		let mut links: Vec<_> = mesh.links().map(|(origin, peer, _)| format!("{}-{}", origin, peer)).collect();
+		links.sort();
+		assert_eq!(links, vec!["a-b", "me-a"]);
+		assert!(mesh.node("b").is_some());
+		assert!(mesh.node("y").is_none());
+		assert!(mesh.update("x", "y", 1, true));
+	}
+}
@@ -1,3 +1,10 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::chunk::Hash;
+use crate::compress::Compression;
+use crate::multiplex::StreamClass;
+
 pub const INTRODUCE_CMD: u16 = 1;
 pub const CMD_WRITE_FILE: u16 = 2;
 pub const CMD_READ_FILE: u16 = 3;
@@ -9,22 +16,174 @@ pub const CMD_PEER_CONNECTED: u16 = 8;
 pub const CMD_PEER_DISCONNECTED: u16 = 9;
 pub const CMD_EXECUTE: u16 = 10;
 pub const CMD_FORGET_PEER: u16 = 11;
+pub const CMD_HELLO: u16 = 12;
+pub const CMD_GOODBYE: u16 = 13;
+pub const CMD_WRITE_DELTA: u16 = 14;
+pub const CMD_SYNC_INDEX: u16 = 15;
+pub const CMD_SYNC_FETCH: u16 = 16;
+pub const CMD_WATCH: u16 = 17;
+pub const CMD_WRITE_BLOCKS: u16 = 18;
+pub const CMD_FIND_CONTENT: u16 = 19;
+
+/// Largest command payload a node accepts. Only `WriteFile` carries data,
+/// larger files go through `sync_file`.
+pub const MAX_COMMAND_SIZE: usize = 16 * 1024 * 1024;
 
 pub const STREAM_START: u8 = 0x01;
 pub const STREAM_END: u8 = 0x02;
 pub const STREAM_CONTINUE: u8 = 0x03;
+/// Asks the other end to stop writing to the stream until `STREAM_PULL`.
 pub const STREAM_PAUSE: u8 = 0x04;
 pub const STREAM_PULL: u8 = 0x05;
 pub const STREAM_DIED: u8 = 0x06;
+/// Lets the other end write more to the stream. Carries no payload, the
+/// length in its header is the number of bytes granted.
+pub const STREAM_WINDOW: u8 = 0x07;
+/// Bytes either end may write to a stream before the reader granted more.
+pub const INITIAL_WINDOW: usize = 256 * 1024;
 
 pub const SUCCES: u8 = 0x00;
 pub const ERR_REMOVE_FOLDER_RECURSIVE_NOT_ENABLED: u8 = 0x01;
+pub const ERR_IO: u8 = 0x02;
+pub const ERR_UNREACHABLE: u8 = 0x03;
+pub const ERR_HOP_LIMIT: u8 = 0x04;
+/// The path is outside every folder the node shares.
+pub const ERR_ACCESS_DENIED: u8 = 0x05;
+pub const ERR_SHUTTING_DOWN: u8 = 0x06;
+
+/// Hop budget given to a new stream. Every forwarding node decrements it.
+pub const MAX_HOPS: u8 = 8;
+
+/// Version of the peer protocol this node speaks. Bump it whenever the
+/// encoding of a `PeerCmd` changes.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// Oldest version we can still talk to.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+/// Version of nodes whose `Introduce` carries no version.
+pub const LEGACY_VERSION: u16 = 1;
+
+/// Optional capabilities advertised in `Introduce`. A connection only uses
+/// the features both ends advertise.
+pub const FEATURE_COMPRESSION: u64 = 1 << 0;
+pub const FEATURE_ENCRYPTION: u64 = 1 << 1;
+pub const FEATURE_EXECUTE: u64 = 1 << 2;
+/// Understands `WriteDelta`.
+pub const FEATURE_DELTA: u64 = 1 << 3;
+/// Understands `SyncIndex` and `SyncFetch`.
+pub const FEATURE_SYNC: u64 = 1 << 4;
+/// Understands `Watch`.
+pub const FEATURE_WATCH: u64 = 1 << 5;
+/// Understands the write mode and hash of `WriteFile`. Older nodes ignore
+/// them and overwrite in place.
+pub const FEATURE_WRITE_MODES: u64 = 1 << 6;
+/// Understands `WriteBlocks`.
+pub const FEATURE_BLOCKS: u64 = 1 << 7;
+/// Understands `FindContent`.
+pub const FEATURE_SWARM: u64 = 1 << 8;
+/// Understands `STREAM_PAUSE` and `STREAM_PULL` frames. Older nodes close
+/// the connection on frames with an unknown stage.
+pub const FEATURE_PAUSE: u64 = 1 << 9;
+/// Writes to a stream stay within what its reader granted with
+/// `STREAM_WINDOW`. Older nodes are only held back by the socket.
+pub const FEATURE_WINDOW: u64 = 1 << 10;
+/// Features this build implements.
+pub const FEATURES: u64 = FEATURE_COMPRESSION | FEATURE_DELTA | FEATURE_SYNC | FEATURE_WATCH | FEATURE_WRITE_MODES | FEATURE_BLOCKS | FEATURE_SWARM | FEATURE_PAUSE | FEATURE_WINDOW;
+
+/// What a `WriteFile` does with the existing contents of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum WriteMode {
+	/// Writes at the offset and keeps whatever follows.
+	#[default]
+	Overwrite,
+	/// Writes at the offset and drops whatever follows.
+	Truncate,
+	/// Writes at the end; the offset is ignored.
+	Append,
+}
+
+impl WriteMode {
+	pub fn from_u8(value: u8) -> anyhow::Result<WriteMode> {
+		match value {
+			0 => Ok(WriteMode::Overwrite),
+			1 => Ok(WriteMode::Truncate),
+			2 => Ok(WriteMode::Append),
+			_ => anyhow::bail!("unknown write mode {}", value),
+		}
+	}
+
+	pub fn as_u8(self) -> u8 {
+		match self {
+			WriteMode::Overwrite => 0,
+			WriteMode::Truncate => 1,
+			WriteMode::Append => 2,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FolderEntry {
+	pub path: String,
+	pub is_dir: bool,
+	pub size: u64,
+	/// Unix timestamp in seconds.
+	pub modified: u64,
+}
 
+/// First bytes sent on every substream: the request and the remaining hop budget.
 #[derive(Debug)]
+pub struct StreamHeader {
+	pub ttl: u8,
+	pub cmd: PeerCmd,
+}
+
+impl StreamHeader {
+	pub fn serialize(&self) -> Vec<u8> {
+		let mut res = vec![self.ttl];
+		res.extend_from_slice(&self.cmd.serialize());
+		res
+	}
+
+	pub fn parse(buffer: &[u8]) -> anyhow::Result<Option<(StreamHeader, usize)>> {
+		if buffer.is_empty() {
+			return Ok(None);
+		}
+		match PeerCmd::parse(&buffer[1..])? {
+			Some((cmd, used)) => Ok(Some((StreamHeader { ttl: buffer[0], cmd }, used + 1))),
+			None => Ok(None),
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
 pub struct Introduce {
 	pub id: String,
 	pub name: String,
 	pub owner: String,
+	/// TCP ports the node accepts connections on, so discovery can dial it.
+	pub ports: Vec<u16>,
+	/// Largest wide frame the node accepts, 0 for nodes that only decode
+	/// the original frames with a `u16` length.
+	pub max_frame: u32,
+	/// Newest and oldest protocol version the node speaks.
+	pub version: u16,
+	pub min_version: u16,
+	/// `FEATURE_*` bits the node supports.
+	pub features: u64,
+}
+
+impl Introduce {
+	/// The protocol version and features a connection between us and
+	/// `peer` uses, or why the two cannot talk.
+	pub fn negotiate(&self, peer: &Introduce) -> anyhow::Result<(u16, u64)> {
+		let version = self.version.min(peer.version);
+		if version < self.min_version.max(peer.min_version) {
+			anyhow::bail!(
+				"incompatible protocol: we speak versions {}-{}, {} speaks {}-{}",
+				self.min_version, self.version, peer.id, peer.min_version, peer.version
+			);
+		}
+		Ok((version, self.features & peer.features))
+	}
 }
 
 #[derive(Debug)]
@@ -34,12 +193,32 @@ pub enum PeerCmd {
         path: String,
         offset: u64,
         length: u64,
+        /// Codec the requester would like; only sent to nodes advertising
+        /// `FEATURE_COMPRESSION`.
+        compression: Compression,
     },
+    /// The file is only replaced once all data arrived and, when `hash`
+    /// is set, the data sent hashes to it.
     WriteFile {
         node_id: String,
         path: String,
         offset: u64,
         data: Vec<u8>,
+        mode: WriteMode,
+        hash: Option<Hash>,
+    },
+    /// Replaces a file, sending only the chunks the receiver lacks.
+    WriteDelta {
+        node_id: String,
+        path: String,
+    },
+    /// Replaces a file with `size` bytes hashing to `hash`, sending only the
+    /// chunks the receiver has neither stored nor in its current copy.
+    WriteBlocks {
+        node_id: String,
+        path: String,
+        size: u64,
+        hash: Hash,
     },
     RemoveFile {
         node_id: String,
@@ -65,35 +244,409 @@ pub enum PeerCmd {
         length: u64,
         recursive: bool,
     },
+	/// Asks for the index of the synced folder `folder`.
+	SyncIndex {
+		node_id: String,
+		folder: String,
+	},
+	/// Reads a whole file of a synced folder, `path` being relative to it.
+	SyncFetch {
+		node_id: String,
+		folder: String,
+		path: String,
+		compression: Compression,
+	},
+	/// Subscribes to changes under `path`; they are sent until either side
+	/// ends the stream.
+	Watch {
+		node_id: String,
+		path: String,
+		recursive: bool,
+	},
+	/// Asks whether the node has a file whose contents hash to `hash`, and
+	/// the chunks it is made of.
+	FindContent {
+		node_id: String,
+		hash: Hash,
+	},
 	Introduce(Introduce),
-	Hello
+	/// Keepalive. Every `Hello` is answered with `reply` set, echoing `seq`,
+	/// which lets the sender measure the round trip time.
+	Hello {
+		seq: u64,
+		reply: bool,
+	},
+	/// Sent before a node closes the connection on purpose.
+	Goodbye,
+	ForgetPeer {
+		id: String,
+	},
+	/// Gossip: `origin` gained a direct connection to `peer`.
+	PeerConnected {
+		origin: String,
+		seq: u64,
+		ttl: u8,
+		peer: Introduce,
+	},
+	/// Gossip: `origin` lost its direct connection to `peer_id`.
+	PeerDisconnected {
+		origin: String,
+		seq: u64,
+		ttl: u8,
+		peer_id: String,
+	},
 }
 
 impl PeerCmd {
+	/// The node a filesystem command is meant for.
+	pub fn node_id(&self) -> Option<&str> {
+		match self {
+			PeerCmd::ReadFile { node_id, .. } |
+			PeerCmd::WriteFile { node_id, .. } |
+			PeerCmd::WriteDelta { node_id, .. } |
+			PeerCmd::WriteBlocks { node_id, .. } |
+			PeerCmd::RemoveFile { node_id, .. } |
+			PeerCmd::CreateFolder { node_id, .. } |
+			PeerCmd::RenameFolder { node_id, .. } |
+			PeerCmd::RemoveFolder { node_id, .. } |
+			PeerCmd::ListFolderContents { node_id, .. } |
+			PeerCmd::SyncIndex { node_id, .. } |
+			PeerCmd::SyncFetch { node_id, .. } |
+			PeerCmd::Watch { node_id, .. } |
+			PeerCmd::FindContent { node_id, .. } => Some(node_id),
+			_ => None
+		}
+	}
+
+	/// How the writer schedules a stream carrying this command.
+	pub fn stream_class(&self) -> StreamClass {
+		match self {
+			PeerCmd::ReadFile { .. } |
+			PeerCmd::WriteFile { .. } |
+			PeerCmd::WriteDelta { .. } |
+			PeerCmd::WriteBlocks { .. } |
+			PeerCmd::SyncFetch { .. } => StreamClass::Bulk,
+			_ => StreamClass::Interactive,
+		}
+	}
+
 	pub fn serialize(&self) -> Vec<u8> {
 		match self {
+			PeerCmd::ReadFile { node_id, path, offset, length, compression } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, node_id);
+				put_string(&mut payload, path);
+				payload.extend_from_slice(&offset.to_le_bytes());
+				payload.extend_from_slice(&length.to_le_bytes());
+				payload.push(compression.as_u8());
+				frame(CMD_READ_FILE, payload)
+			}
+			PeerCmd::WriteFile { node_id, path, offset, data, mode, hash } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, node_id);
+				put_string(&mut payload, path);
+				payload.extend_from_slice(&offset.to_le_bytes());
+				payload.extend_from_slice(&(data.len() as u32).to_le_bytes());
+				payload.extend_from_slice(data);
+				payload.push(mode.as_u8());
+				if let Some(hash) = hash {
+					payload.extend_from_slice(hash);
+				}
+				frame(CMD_WRITE_FILE, payload)
+			}
+			PeerCmd::WriteBlocks { node_id, path, size, hash } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, node_id);
+				put_string(&mut payload, path);
+				payload.extend_from_slice(&size.to_le_bytes());
+				payload.extend_from_slice(hash);
+				frame(CMD_WRITE_BLOCKS, payload)
+			}
+			PeerCmd::WriteDelta { node_id, path } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, node_id);
+				put_string(&mut payload, path);
+				frame(CMD_WRITE_DELTA, payload)
+			}
+			PeerCmd::RemoveFile { node_id, path } | PeerCmd::RemoveFolder { node_id, path } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, node_id);
+				put_string(&mut payload, path);
+				payload.push(matches!(self, PeerCmd::RemoveFolder { .. }) as u8);
+				frame(CMD_REMOVE, payload)
+			}
+			PeerCmd::CreateFolder { node_id, path } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, node_id);
+				put_string(&mut payload, path);
+				frame(CMD_CREATE_FOLDER, payload)
+			}
+			PeerCmd::RenameFolder { node_id, path, new_name } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, node_id);
+				put_string(&mut payload, path);
+				put_string(&mut payload, new_name);
+				frame(CMD_MOVE, payload)
+			}
+			PeerCmd::ListFolderContents { node_id, path, offset, length, recursive } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, node_id);
+				put_string(&mut payload, path);
+				payload.extend_from_slice(&offset.to_le_bytes());
+				payload.extend_from_slice(&length.to_le_bytes());
+				payload.push(*recursive as u8);
+				frame(CMD_LIST_FOLDER_CONTENTS, payload)
+			}
+			PeerCmd::SyncIndex { node_id, folder } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, node_id);
+				put_string(&mut payload, folder);
+				frame(CMD_SYNC_INDEX, payload)
+			}
+			PeerCmd::SyncFetch { node_id, folder, path, compression } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, node_id);
+				put_string(&mut payload, folder);
+				put_string(&mut payload, path);
+				payload.push(compression.as_u8());
+				frame(CMD_SYNC_FETCH, payload)
+			}
+			PeerCmd::Watch { node_id, path, recursive } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, node_id);
+				put_string(&mut payload, path);
+				payload.push(*recursive as u8);
+				frame(CMD_WATCH, payload)
+			}
+			PeerCmd::FindContent { node_id, hash } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, node_id);
+				payload.extend_from_slice(hash);
+				frame(CMD_FIND_CONTENT, payload)
+			}
 			PeerCmd::Introduce(args) => {
-				let id_len_bytes = (args.id.len() as u16).to_le_bytes();
-				let id_bytes = args.id.as_bytes();
-				let name_len_bytes = (args.name.len() as u16).to_le_bytes();
-				let name_bytes = args.name.as_bytes();
-				let owner_len_bytes = (args.owner.len() as u16).to_le_bytes();
-				let owner_bytes = args.owner.as_bytes();
-				let payload_size = 2 + id_bytes.len() + 2 + name_bytes.len() + 2 + owner_bytes.len();
-				let mut res = Vec::with_capacity(6 + payload_size);
-				res.extend_from_slice(&INTRODUCE_CMD.to_le_bytes());
-				res.extend_from_slice(&(payload_size as u32).to_le_bytes());
-				res.extend_from_slice(&id_len_bytes);
-				res.extend_from_slice(id_bytes);
-				res.extend_from_slice(&name_len_bytes);
-				res.extend_from_slice(name_bytes);
-				res.extend_from_slice(&owner_len_bytes);
-				res.extend_from_slice(owner_bytes);
-				res
-			}
-			_ => todo!()
+				let mut payload = Vec::new();
+				put_string(&mut payload, &args.id);
+				put_string(&mut payload, &args.name);
+				put_string(&mut payload, &args.owner);
+				payload.extend_from_slice(&(args.ports.len() as u16).to_le_bytes());
+				for port in &args.ports {
+					payload.extend_from_slice(&port.to_le_bytes());
+				}
+				payload.extend_from_slice(&args.max_frame.to_le_bytes());
+				payload.extend_from_slice(&args.version.to_le_bytes());
+				payload.extend_from_slice(&args.min_version.to_le_bytes());
+				payload.extend_from_slice(&args.features.to_le_bytes());
+				frame(INTRODUCE_CMD, payload)
+			}
+			PeerCmd::Hello { seq, reply } => {
+				let mut payload = Vec::new();
+				payload.extend_from_slice(&seq.to_le_bytes());
+				payload.push(*reply as u8);
+				frame(CMD_HELLO, payload)
+			}
+			PeerCmd::Goodbye => frame(CMD_GOODBYE, Vec::new()),
+			PeerCmd::ForgetPeer { id } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, id);
+				frame(CMD_FORGET_PEER, payload)
+			}
+			PeerCmd::PeerConnected { origin, seq, ttl, peer } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, origin);
+				payload.extend_from_slice(&seq.to_le_bytes());
+				payload.push(*ttl);
+				put_string(&mut payload, &peer.id);
+				put_string(&mut payload, &peer.name);
+				put_string(&mut payload, &peer.owner);
+				payload.extend_from_slice(&peer.features.to_le_bytes());
+				frame(CMD_PEER_CONNECTED, payload)
+			}
+			PeerCmd::PeerDisconnected { origin, seq, ttl, peer_id } => {
+				let mut payload = Vec::new();
+				put_string(&mut payload, origin);
+				payload.extend_from_slice(&seq.to_le_bytes());
+				payload.push(*ttl);
+				put_string(&mut payload, peer_id);
+				frame(CMD_PEER_DISCONNECTED, payload)
+			}
 		}
 	}
+
+	/// Parses one command from the start of `buffer`. Returns `None` when the
+	/// buffer does not yet hold a complete command.
+	pub fn parse(buffer: &[u8]) -> anyhow::Result<Option<(PeerCmd, usize)>> {
+		if buffer.len() < 6 {
+			return Ok(None);
+		}
+		let cmd_type = u16::from_le_bytes(buffer[0..2].try_into()?);
+		let payload_size = u32::from_le_bytes(buffer[2..6].try_into()?) as usize;
+		if payload_size > MAX_COMMAND_SIZE {
+			anyhow::bail!("command of {} bytes is too large", payload_size);
+		}
+		if buffer.len() < 6 + payload_size {
+			return Ok(None);
+		}
+		let mut eater = ByteEater::new(&buffer[6..6 + payload_size]);
+		let cmd = match cmd_type {
+			CMD_READ_FILE => PeerCmd::ReadFile {
+				node_id: eater.get_string()?,
+				path: eater.get_string()?,
+				offset: eater.get_u64()?,
+				length: eater.get_u64()?,
+				compression: match eater.is_empty() {
+					true => Compression::None,
+					false => Compression::from_u8(eater.get_u8()?)?,
+				},
+			},
+			CMD_WRITE_FILE => PeerCmd::WriteFile {
+				node_id: eater.get_string()?,
+				path: eater.get_string()?,
+				offset: eater.get_u64()?,
+				data: eater.get_bytes()?,
+				mode: match eater.is_empty() {
+					true => WriteMode::Overwrite,
+					false => WriteMode::from_u8(eater.get_u8()?)?,
+				},
+				hash: match eater.is_empty() {
+					true => None,
+					false => Some(eater.get_hash()?),
+				},
+			},
+			CMD_WRITE_DELTA => PeerCmd::WriteDelta {
+				node_id: eater.get_string()?,
+				path: eater.get_string()?,
+			},
+			CMD_WRITE_BLOCKS => PeerCmd::WriteBlocks {
+				node_id: eater.get_string()?,
+				path: eater.get_string()?,
+				size: eater.get_u64()?,
+				hash: eater.get_hash()?,
+			},
+			CMD_REMOVE => {
+				let node_id = eater.get_string()?;
+				let path = eater.get_string()?;
+				if eater.get_u8()? != 0 {
+					PeerCmd::RemoveFolder { node_id, path }
+				} else {
+					PeerCmd::RemoveFile { node_id, path }
+				}
+			},
+			CMD_CREATE_FOLDER => PeerCmd::CreateFolder {
+				node_id: eater.get_string()?,
+				path: eater.get_string()?,
+			},
+			CMD_MOVE => PeerCmd::RenameFolder {
+				node_id: eater.get_string()?,
+				path: eater.get_string()?,
+				new_name: eater.get_string()?,
+			},
+			CMD_LIST_FOLDER_CONTENTS => PeerCmd::ListFolderContents {
+				node_id: eater.get_string()?,
+				path: eater.get_string()?,
+				offset: eater.get_u64()?,
+				length: eater.get_u64()?,
+				recursive: eater.get_u8()? != 0,
+			},
+			CMD_SYNC_INDEX => PeerCmd::SyncIndex {
+				node_id: eater.get_string()?,
+				folder: eater.get_string()?,
+			},
+			CMD_SYNC_FETCH => PeerCmd::SyncFetch {
+				node_id: eater.get_string()?,
+				folder: eater.get_string()?,
+				path: eater.get_string()?,
+				compression: Compression::from_u8(eater.get_u8()?)?,
+			},
+			CMD_WATCH => PeerCmd::Watch {
+				node_id: eater.get_string()?,
+				path: eater.get_string()?,
+				recursive: eater.get_u8()? != 0,
+			},
+			CMD_FIND_CONTENT => PeerCmd::FindContent {
+				node_id: eater.get_string()?,
+				hash: eater.get_hash()?,
+			},
+			INTRODUCE_CMD => {
+				let mut introduce = Introduce {
+					id: eater.get_string()?,
+					name: eater.get_string()?,
+					owner: eater.get_string()?,
+					ports: Vec::new(),
+					max_frame: 0,
+					version: LEGACY_VERSION,
+					min_version: LEGACY_VERSION,
+					features: 0,
+				};
+				// Everything after the owner was added later; older nodes
+				// do not send it.
+				if !eater.is_empty() {
+					let count = eater.get_u16()?;
+					for _ in 0..count {
+						introduce.ports.push(eater.get_u16()?);
+					}
+				}
+				if !eater.is_empty() {
+					introduce.max_frame = eater.get_u32()?;
+				}
+				if !eater.is_empty() {
+					introduce.version = eater.get_u16()?;
+					introduce.min_version = eater.get_u16()?;
+					introduce.features = eater.get_u64()?;
+				}
+				PeerCmd::Introduce(introduce)
+			},
+			CMD_HELLO => PeerCmd::Hello {
+				seq: eater.get_u64()?,
+				reply: eater.get_u8()? != 0,
+			},
+			CMD_GOODBYE => PeerCmd::Goodbye,
+			CMD_FORGET_PEER => PeerCmd::ForgetPeer {
+				id: eater.get_string()?,
+			},
+			CMD_PEER_CONNECTED => {
+				let origin = eater.get_string()?;
+				let seq = eater.get_u64()?;
+				let ttl = eater.get_u8()?;
+				let mut peer = Introduce {
+					id: eater.get_string()?,
+					name: eater.get_string()?,
+					owner: eater.get_string()?,
+					ports: Vec::new(),
+					max_frame: 0,
+					version: LEGACY_VERSION,
+					min_version: LEGACY_VERSION,
+					features: 0,
+				};
+				// Gossip from older nodes does not carry features.
+				if !eater.is_empty() {
+					peer.features = eater.get_u64()?;
+				}
+				PeerCmd::PeerConnected { origin, seq, ttl, peer }
+			},
+			CMD_PEER_DISCONNECTED => PeerCmd::PeerDisconnected {
+				origin: eater.get_string()?,
+				seq: eater.get_u64()?,
+				ttl: eater.get_u8()?,
+				peer_id: eater.get_string()?,
+			},
+			_ => anyhow::bail!("unknown command type: {}", cmd_type),
+		};
+		Ok(Some((cmd, 6 + payload_size)))
+	}
+}
+
+fn frame(cmd_type: u16, payload: Vec<u8>) -> Vec<u8> {
+	let mut res = Vec::with_capacity(6 + payload.len());
+	res.extend_from_slice(&cmd_type.to_le_bytes());
+	res.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+	res.extend_from_slice(&payload);
+	res
+}
+
+pub(crate) fn put_string(buffer: &mut Vec<u8>, s: &str) {
+	buffer.extend_from_slice(&(s.len() as u16).to_le_bytes());
+	buffer.extend_from_slice(s.as_bytes());
 }
 
 struct ByteEater<'a> {
@@ -106,15 +659,225 @@ impl<'a> ByteEater<'a> {
 			buffer
 		}
 	}
+
+	pub fn is_empty(&self) -> bool {
+		self.buffer.is_empty()
+	}
+
+	fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+		if self.buffer.len() < len {
+			anyhow::bail!("unexpected end of buffer");
+		}
+		let (head, tail) = self.buffer.split_at(len);
+		self.buffer = tail;
+		Ok(head)
+	}
+	
+	pub fn get_u8(&mut self) -> anyhow::Result<u8> {
+		Ok(self.take(1)?[0])
+	}
 	
-	pub fn get_u16(&mut self) -> u16 {
-		u16::from_le_bytes(self.buffer[0..2].try_into().unwrap())
+	pub fn get_u16(&mut self) -> anyhow::Result<u16> {
+		Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+	}
+
+	pub fn get_u32(&mut self) -> anyhow::Result<u32> {
+		Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
 	}
 
-	pub fn get_string(&mut self) -> String {
-		let len = self.get_u16();
-		let s = self.buffer[2..len as usize + 2].to_vec();
-		self.buffer = &self.buffer[len as usize + 2..];
-		String::from_utf8(s).unwrap()
+	pub fn get_u64(&mut self) -> anyhow::Result<u64> {
+		Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
 	}
-}
\ No newline at end of file
+
+	pub fn get_hash(&mut self) -> anyhow::Result<Hash> {
+		Ok(self.take(32)?.try_into()?)
+	}
+
+	pub fn get_bytes(&mut self) -> anyhow::Result<Vec<u8>> {
+		let len = self.get_u32()?;
+		Ok(self.take(len as usize)?.to_vec())
+	}
+
+	pub fn get_string(&mut self) -> anyhow::Result<String> {
+		let len = self.get_u16()?;
+		let s = self.take(len as usize)?.to_vec();
+		Ok(String::from_utf8(s)?)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_introduce_roundtrip() {
+		let cmd = PeerCmd::Introduce(Introduce {
+			id: "node1".to_string(),
+			name: "laptop".to_string(),
+			owner: "alice".to_string(),
+			ports: vec![7765],
+			max_frame: 1 << 20,
+			version: PROTOCOL_VERSION,
+			min_version: MIN_PROTOCOL_VERSION,
+			features: FEATURE_COMPRESSION | FEATURE_EXECUTE,
+		});
+		let data = cmd.serialize();
+		let (parsed, used) = PeerCmd::parse(&data).unwrap().unwrap();
+		assert_eq!(used, data.len());
+		match parsed {
+			PeerCmd::Introduce(introduce) => {
+				assert_eq!(introduce.id, "node1");
+				assert_eq!(introduce.name, "laptop");
+				assert_eq!(introduce.owner, "alice");
+				assert_eq!(introduce.ports, vec![7765]);
+				assert_eq!(introduce.max_frame, 1 << 20);
+				assert_eq!(introduce.version, PROTOCOL_VERSION);
+				assert_eq!(introduce.min_version, MIN_PROTOCOL_VERSION);
+				assert_eq!(introduce.features, FEATURE_COMPRESSION | FEATURE_EXECUTE);
+			}
+			_ => panic!("unexpected command {:?}", parsed),
+		}
+	}
+
+	#[test]
+	fn test_introduce_from_older_node() {
+		let mut payload = Vec::new();
+		put_string(&mut payload, "node1");
+		put_string(&mut payload, "laptop");
+		put_string(&mut payload, "alice");
+		payload.extend_from_slice(&1u16.to_le_bytes());
+		payload.extend_from_slice(&7765u16.to_le_bytes());
+		let data = frame(INTRODUCE_CMD, payload);
+		match PeerCmd::parse(&data).unwrap().unwrap().0 {
+			PeerCmd::Introduce(introduce) => {
+				assert_eq!(introduce.ports, vec![7765]);
+				assert_eq!(introduce.max_frame, 0);
+				assert_eq!(introduce.version, LEGACY_VERSION);
+				assert_eq!(introduce.features, 0);
+			}
+			other => panic!("unexpected command {:?}", other),
+		}
+	}
+
+	#[test]
+	fn test_negotiate() {
+		let node = |version, min_version, features| Introduce {
+			id: "node".to_string(),
+			name: String::new(),
+			owner: String::new(),
+			ports: Vec::new(),
+			max_frame: 0,
+			version,
+			min_version,
+			features,
+		};
+		let me = node(3, 2, FEATURE_COMPRESSION | FEATURE_EXECUTE);
+		assert_eq!(me.negotiate(&node(5, 1, FEATURE_COMPRESSION | FEATURE_ENCRYPTION)).unwrap(), (3, FEATURE_COMPRESSION));
+		assert_eq!(me.negotiate(&node(2, 1, 0)).unwrap(), (2, 0));
+		// Too old for us, and too new for us.
+		assert!(me.negotiate(&node(1, 1, 0)).is_err());
+		let err = me.negotiate(&node(6, 4, 0)).unwrap_err();
+		assert!(err.to_string().contains("incompatible protocol"));
+	}
+
+	#[test]
+	fn test_peer_disconnected_roundtrip() {
+		let cmd = PeerCmd::PeerDisconnected {
+			origin: "node1".to_string(),
+			seq: 42,
+			ttl: 3,
+			peer_id: "node2".to_string(),
+		};
+		let data = cmd.serialize();
+		match PeerCmd::parse(&data).unwrap().unwrap().0 {
+			PeerCmd::PeerDisconnected { origin, seq, ttl, peer_id } => {
+				assert_eq!(origin, "node1");
+				assert_eq!(seq, 42);
+				assert_eq!(ttl, 3);
+				assert_eq!(peer_id, "node2");
+			}
+			other => panic!("unexpected command {:?}", other),
+		}
+	}
+
+	#[test]
+	fn test_stream_header_roundtrip() {
+		let header = StreamHeader {
+			ttl: 5,
+			cmd: PeerCmd::WriteFile {
+				node_id: "nas".to_string(),
+				path: "/tmp/a.txt".to_string(),
+				offset: 10,
+				data: b"hello".to_vec(),
+				mode: WriteMode::Overwrite,
+				hash: None,
+			},
+		};
+		let mut data = header.serialize();
+		data.extend_from_slice(b"trailing file data");
+		let (parsed, used) = StreamHeader::parse(&data).unwrap().unwrap();
+		assert_eq!(parsed.ttl, 5);
+		assert_eq!(&data[used..], b"trailing file data");
+		assert_eq!(parsed.cmd.node_id(), Some("nas"));
+		match parsed.cmd {
+			PeerCmd::WriteFile { path, offset, data, .. } => {
+				assert_eq!(path, "/tmp/a.txt");
+				assert_eq!(offset, 10);
+				assert_eq!(data, b"hello");
+			}
+			other => panic!("unexpected command {:?}", other),
+		}
+	}
+
+	#[test]
+	fn test_read_file_compression() {
+		let cmd = PeerCmd::ReadFile {
+			node_id: "nas".to_string(),
+			path: "/srv/notes.txt".to_string(),
+			offset: 0,
+			length: 100,
+			compression: Compression::Zstd,
+		};
+		let data = cmd.serialize();
+		assert!(matches!(PeerCmd::parse(&data).unwrap().unwrap().0, PeerCmd::ReadFile { compression: Compression::Zstd, .. }));
+
+		// Requests from older nodes end before the codec.
+		let mut old = data[..data.len() - 1].to_vec();
+		let payload_len = (old.len() - 6) as u32;
+		old[2..6].copy_from_slice(&payload_len.to_le_bytes());
+		assert!(matches!(PeerCmd::parse(&old).unwrap().unwrap().0, PeerCmd::ReadFile { compression: Compression::None, .. }));
+	}
+
+	#[test]
+	fn test_write_file_mode() {
+		let cmd = PeerCmd::WriteFile {
+			node_id: "nas".to_string(),
+			path: "/srv/notes.txt".to_string(),
+			offset: 0,
+			data: b"hi".to_vec(),
+			mode: WriteMode::Truncate,
+			hash: Some([7; 32]),
+		};
+		let data = cmd.serialize();
+		match PeerCmd::parse(&data).unwrap().unwrap().0 {
+			PeerCmd::WriteFile { mode, hash, .. } => assert_eq!((mode, hash), (WriteMode::Truncate, Some([7; 32]))),
+			other => panic!("parsed {:?}", other),
+		}
+
+		// Requests from older nodes end after the data.
+		let mut old = data[..data.len() - 33].to_vec();
+		let payload_len = (old.len() - 6) as u32;
+		old[2..6].copy_from_slice(&payload_len.to_le_bytes());
+		match PeerCmd::parse(&old).unwrap().unwrap().0 {
+			PeerCmd::WriteFile { mode, hash, .. } => assert_eq!((mode, hash), (WriteMode::Overwrite, None)),
+			other => panic!("parsed {:?}", other),
+		}
+	}
+
+	#[test]
+	fn test_parse_incomplete() {
+		let data = PeerCmd::ForgetPeer { id: "node2".to_string() }.serialize();
+		assert!(PeerCmd::parse(&data[..data.len() - 1]).unwrap().is_none());
+		assert!(PeerCmd::parse(&data[..3]).unwrap().is_none());
+	}
+}
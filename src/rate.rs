@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Buckets never hold less than this, so a slow limit still lets a whole
+/// read or frame through at once.
+const MIN_BURST: f64 = 64.0 * 1024.0;
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+	Upload,
+	Download,
+}
+
+/// Bytes per second in each direction, `None` for no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+	pub upload: Option<u64>,
+	pub download: Option<u64>,
+}
+
+impl RateLimit {
+	fn get(&self, direction: Direction) -> Option<u64> {
+		match direction {
+			Direction::Upload => self.upload,
+			Direction::Download => self.download,
+		}
+	}
+}
+
+/// Replaces the global limit during part of the day, e.g. lifting it at
+/// night.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateSchedule {
+	/// Time after midnight UTC the schedule starts. A schedule ending
+	/// before it starts runs past midnight.
+	pub from: Duration,
+	pub to: Duration,
+	pub limit: RateLimit,
+}
+
+impl RateSchedule {
+	fn is_active(&self, time_of_day: Duration) -> bool {
+		if self.from <= self.to {
+			self.from <= time_of_day && time_of_day < self.to
+		} else {
+			self.from <= time_of_day || time_of_day < self.to
+		}
+	}
+}
+
+/// How fast a node sends and receives stream data. Control traffic is
+/// never held back.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateConfig {
+	/// Shared by all connections.
+	pub global: RateLimit,
+	/// Per connected node id, on top of the global limit.
+	pub peers: HashMap<String, RateLimit>,
+	/// The first active one replaces `global`.
+	pub schedules: Vec<RateSchedule>,
+}
+
+impl RateConfig {
+	fn global(&self, time_of_day: Duration) -> RateLimit {
+		self.schedules.iter()
+			.find(|schedule| schedule.is_active(time_of_day))
+			.map(|schedule| schedule.limit)
+			.unwrap_or(self.global)
+	}
+}
+
+/// Tokens are bytes. They may go negative: data is let through while any
+/// are left and the debt is paid off before the next.
+#[derive(Debug)]
+struct Bucket {
+	rate: u64,
+	tokens: f64,
+	last: Instant,
+}
+
+impl Bucket {
+	fn new(rate: u64, now: Instant) -> Bucket {
+		let mut bucket = Bucket { rate, tokens: 0.0, last: now };
+		bucket.tokens = bucket.burst();
+		bucket
+	}
+
+	fn burst(&self) -> f64 {
+		(self.rate as f64).max(MIN_BURST)
+	}
+
+	fn refill(&mut self, rate: u64, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+		self.last = now;
+		self.rate = rate.max(1);
+		self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst());
+	}
+
+	fn delay(&self) -> Option<Duration> {
+		match self.tokens > 0.0 {
+			true => None,
+			false => Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate as f64)),
+		}
+	}
+}
+
+/// The buckets of one limit, one per direction.
+#[derive(Debug, Default)]
+struct Buckets {
+	upload: Option<Bucket>,
+	download: Option<Bucket>,
+}
+
+impl Buckets {
+	/// Brings the bucket for `direction` up to date with `rate`, dropping
+	/// it when there is no limit.
+	fn get(&mut self, direction: Direction, rate: Option<u64>, now: Instant) -> Option<&mut Bucket> {
+		let bucket = match direction {
+			Direction::Upload => &mut self.upload,
+			Direction::Download => &mut self.download,
+		};
+		match rate {
+			Some(rate) => {
+				let bucket = bucket.get_or_insert_with(|| Bucket::new(rate, now));
+				bucket.refill(rate, now);
+				Some(bucket)
+			},
+			None => {
+				*bucket = None;
+				None
+			},
+		}
+	}
+}
+
+struct Inner {
+	config: RateConfig,
+	global: Buckets,
+	peers: HashMap<String, Buckets>,
+}
+
+impl Inner {
+	fn buckets(&mut self, direction: Direction, peer: Option<&str>) -> Vec<&mut Bucket> {
+		let now = Instant::now();
+		let global = self.config.global(time_of_day()).get(direction);
+		let mut buckets: Vec<_> = self.global.get(direction, global, now).into_iter().collect();
+		let limit = peer.and_then(|peer| Some((peer, self.config.peers.get(peer)?.get(direction))));
+		if let Some((peer, rate)) = limit {
+			let peer = self.peers.entry(peer.to_string()).or_default();
+			buckets.extend(peer.get(direction, rate, now));
+		}
+		buckets
+	}
+}
+
+/// Token buckets shared by the connections of a node.
+pub struct RateLimiter {
+	inner: Mutex<Inner>,
+}
+
+impl RateLimiter {
+	pub fn new(config: RateConfig) -> RateLimiter {
+		RateLimiter {
+			inner: Mutex::new(Inner {
+				config,
+				global: Buckets::default(),
+				peers: HashMap::new(),
+			}),
+		}
+	}
+
+	pub fn set_config(&self, config: RateConfig) {
+		let mut inner = self.inner.lock().unwrap();
+		inner.peers.retain(|peer, _| config.peers.contains_key(peer));
+		inner.config = config;
+	}
+
+	/// How long to wait before moving more data to or from `peer`, the
+	/// node id of the other end of a connection once known.
+	pub fn delay(&self, direction: Direction, peer: Option<&str>) -> Option<Duration> {
+		let mut inner = self.inner.lock().unwrap();
+		inner.buckets(direction, peer).into_iter().filter_map(|bucket| bucket.delay()).max()
+	}
+
+	/// Counts bytes that were moved.
+	pub fn charge(&self, direction: Direction, peer: Option<&str>, bytes: usize) {
+		let mut inner = self.inner.lock().unwrap();
+		for bucket in inner.buckets(direction, peer) {
+			bucket.tokens -= bytes as f64;
+		}
+	}
+
+	/// The tightest limit on data moved to or from `peer`.
+	pub fn rate(&self, direction: Direction, peer: Option<&str>) -> Option<u64> {
+		let mut inner = self.inner.lock().unwrap();
+		inner.buckets(direction, peer).into_iter().map(|bucket| bucket.rate).min()
+	}
+}
+
+fn time_of_day() -> Duration {
+	let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+	Duration::from_secs(since_epoch.as_secs() % DAY.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_schedules() {
+		let hour = |h: u64| Duration::from_secs(h * 60 * 60);
+		let night = RateSchedule { from: hour(22), to: hour(6), limit: RateLimit::default() };
+		assert!(night.is_active(hour(23)));
+		assert!(night.is_active(hour(2)));
+		assert!(!night.is_active(hour(6)));
+		assert!(!night.is_active(hour(12)));
+
+		let limited = RateLimit { upload: Some(1000), download: None };
+		let config = RateConfig { global: limited, peers: HashMap::new(), schedules: vec![night] };
+		assert_eq!(config.global(hour(1)), RateLimit::default());
+		assert_eq!(config.global(hour(12)), limited);
+	}
+
+	#[test]
+	fn test_limits() {
+		let mut peers = HashMap::new();
+		peers.insert("slow".to_string(), RateLimit { upload: Some(100_000), download: None });
+		let limiter = RateLimiter::new(RateConfig {
+			global: RateLimit { upload: Some(1_000_000), download: None },
+			peers,
+			schedules: Vec::new(),
+		});
+		assert_eq!(limiter.rate(Direction::Upload, Some("slow")), Some(100_000));
+		assert_eq!(limiter.rate(Direction::Upload, Some("other")), Some(1_000_000));
+		assert_eq!(limiter.rate(Direction::Download, Some("slow")), None);
+
+		// A full bucket lets data through, then the debt has to be paid off.
+		assert_eq!(limiter.delay(Direction::Upload, Some("slow")), None);
+		limiter.charge(Direction::Upload, Some("slow"), 300_000);
+		let delay = limiter.delay(Direction::Upload, Some("slow")).unwrap();
+		assert!(delay > Duration::from_millis(1900) && delay < Duration::from_millis(2100), "{:?}", delay);
+		// The global bucket was charged as well, but still has tokens left.
+		assert_eq!(limiter.delay(Direction::Upload, Some("other")), None);
+		assert_eq!(limiter.delay(Direction::Download, Some("slow")), None);
+
+		limiter.set_config(RateConfig::default());
+		assert_eq!(limiter.delay(Direction::Upload, Some("slow")), None);
+		assert_eq!(limiter.rate(Direction::Upload, Some("slow")), None);
+	}
+}
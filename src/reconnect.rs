@@ -0,0 +1,157 @@
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::tcp;
+use crate::types::Context;
+use crate::types::InternalEvent;
+
+pub const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A connection has to stay up this long before the backoff starts over, so
+/// a peer that accepts and then drops us is not redialed in a tight loop.
+pub const STABLE_UPTIME: Duration = Duration::from_secs(10);
+/// Failed dials in a row after which a discovered address is given up on.
+pub const MAX_DIAL_FAILURES: u32 = 8;
+
+/// Exponential backoff with equal jitter: every delay is at least half of
+/// the exponential step so retries from many nodes spread out without
+/// collapsing to zero.
+#[derive(Debug)]
+pub struct Backoff {
+	attempt: u32,
+	initial: Duration,
+	max: Duration,
+}
+
+impl Backoff {
+	pub fn new() -> Backoff {
+		Backoff::with_limits(INITIAL_BACKOFF, MAX_BACKOFF)
+	}
+
+	pub fn with_limits(initial: Duration, max: Duration) -> Backoff {
+		Backoff {
+			attempt: 0,
+			initial,
+			max,
+		}
+	}
+
+	pub fn attempt(&self) -> u32 {
+		self.attempt
+	}
+
+	pub fn reset(&mut self) {
+		self.attempt = 0;
+	}
+
+	pub fn next_delay(&mut self) -> Duration {
+		let step = self.initial
+			.saturating_mul(1u32 << self.attempt.min(16))
+			.min(self.max);
+		self.attempt += 1;
+		let half = step / 2;
+		half + half.mul_f64(rand::random::<f64>())
+	}
+}
+
+/// Keeps an outbound connection to `addr` alive, redialing with backoff
+/// whenever it fails or drops. Runs until the worker goes away, the task is
+/// aborted or, with `max_failures`, dialing failed that many times in a row.
+pub async fn supervise(addr: String, max_failures: Option<u32>, ctx: Context) {
+	let mut backoff = Backoff::new();
+	let mut failures = 0;
+	loop {
+		let started = Instant::now();
+		match tcp::connect(&addr, ctx.clone()).await {
+			Ok(()) => {
+				log::info!("connection to {} closed", addr);
+				failures = 0;
+				if started.elapsed() >= STABLE_UPTIME {
+					backoff.reset();
+				}
+			},
+			Err(err) => {
+				log::warn!("failed to connect {}: {}", addr, err);
+				failures += 1;
+			},
+		}
+		if *ctx.shutdown.borrow() {
+			return;
+		}
+		if max_failures.is_some_and(|max| failures >= max) {
+			ctx.report(Some(&addr), anyhow::anyhow!("giving up after {} failed attempts", failures));
+			return;
+		}
+		let delay = backoff.next_delay();
+		let event = InternalEvent::Reconnecting {
+			addr: addr.clone(),
+			attempt: backoff.attempt(),
+			delay,
+		};
+		if ctx.internal_event_tx.send(event).await.is_err() {
+			return;
+		}
+		let mut shutdown = ctx.shutdown.clone();
+		tokio::select! {
+			_ = tokio::time::sleep(delay) => {},
+			_ = shutdown.changed() => return,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_backoff_grows_and_caps() {
+		let mut backoff = Backoff::with_limits(Duration::from_millis(100), Duration::from_secs(1));
+		let delays: Vec<Duration> = (0..8).map(|_| backoff.next_delay()).collect();
+		for (i, delay) in delays.iter().enumerate() {
+			let step = Duration::from_millis(100 * (1 << i)).min(Duration::from_secs(1));
+			assert!(*delay >= step / 2 && *delay <= step, "attempt {}: {:?}", i, delay);
+		}
+		backoff.reset();
+		assert!(backoff.next_delay() <= Duration::from_millis(100));
+	}
+
+	#[tokio::test]
+	async fn test_short_connections_keep_backing_off() {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = format!("tcp://{}", listener.local_addr().unwrap());
+		tokio::spawn(async move {
+			while let Ok((socket, _)) = listener.accept().await {
+				drop(socket);
+			}
+		});
+		let (internal_event_tx, mut rx) = tokio::sync::mpsc::channel(16);
+		let (_shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+		let limiter = std::sync::Arc::new(crate::rate::RateLimiter::new(Default::default()));
+		let ctx = Context { internal_event_tx, shutdown, peer_buffer: 1 << 20, max_frame: 0, limiter };
+		let task = tokio::spawn(supervise(addr, None, ctx));
+
+		let mut attempts = Vec::new();
+		while attempts.len() < 3 {
+			if let InternalEvent::Reconnecting { attempt, .. } = rx.recv().await.unwrap() {
+				attempts.push(attempt);
+			}
+		}
+		task.abort();
+		assert_eq!(attempts, vec![1, 2, 3]);
+	}
+
+	#[tokio::test]
+	async fn test_gives_up_on_unreachable_address() {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = format!("tcp://{}", listener.local_addr().unwrap());
+		drop(listener);
+		let (internal_event_tx, mut rx) = tokio::sync::mpsc::channel(16);
+		let (_shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+		let limiter = std::sync::Arc::new(crate::rate::RateLimiter::new(Default::default()));
+		let ctx = Context { internal_event_tx, shutdown, peer_buffer: 1 << 20, max_frame: 0, limiter };
+
+		tokio::time::timeout(Duration::from_secs(5), supervise(addr, Some(2), ctx)).await.unwrap();
+		assert!(matches!(rx.recv().await, Some(InternalEvent::Reconnecting { attempt: 1, .. })));
+		assert!(matches!(rx.recv().await, Some(InternalEvent::Error { .. })));
+	}
+}
@@ -0,0 +1,386 @@
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::Bytes;
+use tokio::sync::oneshot;
+
+use crate::block_store;
+use crate::chunk;
+use crate::chunk::Hash;
+use crate::compress::Compression;
+use crate::delta;
+use crate::protocol::FolderEntry;
+use crate::protocol::FEATURE_BLOCKS;
+use crate::protocol::FEATURE_DELTA;
+use crate::protocol::FEATURE_WATCH;
+use crate::protocol::FEATURE_WRITE_MODES;
+use crate::protocol::MAX_COMMAND_SIZE;
+use crate::protocol::PeerCmd;
+use crate::protocol::WriteMode;
+use crate::reconnect::Backoff;
+use crate::stream::Stream;
+use crate::transfer::Job;
+use crate::transfer::TransferKind;
+use crate::types::can_resume;
+use crate::types::InternalCommand;
+use crate::watch::Watch;
+use crate::DeltaStats;
+use crate::Pupynet;
+
+/// How long a transfer keeps retrying after its link dropped before giving up.
+pub const RESUME_TIMEOUT: Duration = Duration::from_secs(120);
+
+impl Pupynet {
+	/// Opens a stream for `cmd` towards the node named by its `node_id`,
+	/// routed through intermediate nodes when there is no direct connection.
+	pub(crate) async fn request(&self, cmd: PeerCmd) -> anyhow::Result<Stream> {
+		let (reply, rx) = oneshot::channel();
+		self.send_wait(InternalCommand::OpenStream { cmd, reply }).await?;
+		rx.await?
+	}
+
+	/// Sends `cmd`, closes our side and waits for the status reply.
+	async fn simple_request(&self, cmd: PeerCmd) -> anyhow::Result<()> {
+		let mut stream = self.request(cmd).await?;
+		stream.finish()?;
+		stream.read_status().await?;
+		stream.read_to_end().await?;
+		Ok(())
+	}
+
+	/// The codec to ask `node_id` for, `None` when it cannot compress.
+	pub(crate) async fn compression(&self, node_id: &str) -> anyhow::Result<Compression> {
+		let (reply, rx) = oneshot::channel();
+		self.send_wait(InternalCommand::Compression { node_id: node_id.to_string(), reply }).await?;
+		Ok(rx.await?)
+	}
+
+	/// Features both we and `node_id` support.
+	pub(crate) async fn features(&self, node_id: &str) -> anyhow::Result<u64> {
+		let (reply, rx) = oneshot::channel();
+		self.send_wait(InternalCommand::Features { node_id: node_id.to_string(), reply }).await?;
+		Ok(rx.await?)
+	}
+
+	/// Reads into `data`, appending as chunks arrive so a retry can continue
+	/// from what was already received.
+	pub(crate) async fn read_file_into(&self, node_id: &str, path: &str, offset: u64, length: u64, data: &mut Vec<u8>, job: Option<&Job>) -> anyhow::Result<()> {
+		let compression = self.compression(node_id).await?;
+		self.read_into(PeerCmd::ReadFile {
+			node_id: node_id.to_string(),
+			path: path.to_string(),
+			offset,
+			length,
+			compression,
+		}, compression, length, data, job).await
+	}
+
+	/// Sends a file read and appends the reply to `data`, decoding blocks
+	/// when `compression` was asked for. Fails once the reply goes past
+	/// `limit` bytes. The stream pauses with `job`.
+	pub(crate) async fn read_into(&self, cmd: PeerCmd, compression: Compression, limit: u64, data: &mut Vec<u8>, job: Option<&Job>) -> anyhow::Result<()> {
+		let end = (data.len() as u64).saturating_add(limit);
+		let (mut stream, codec) = self.open_read(cmd, compression, job).await?;
+		while let Some(chunk) = read_chunk(&mut stream, codec).await? {
+			data.extend_from_slice(&chunk);
+			if data.len() as u64 > end {
+				anyhow::bail!("reply is longer than the {} bytes asked for", limit);
+			}
+			if let Some(job) = job {
+				job.progress(chunk.len() as u64);
+			}
+		}
+		Ok(())
+	}
+
+	/// Sends a file read and returns its reply stream, positioned at the
+	/// data, with the codec it is sent in. See `read_chunk`.
+	pub(crate) async fn open_read(&self, cmd: PeerCmd, compression: Compression, job: Option<&Job>) -> anyhow::Result<(Stream, Compression)> {
+		let mut stream = self.request(cmd).await?;
+		if let Some(job) = job {
+			job.attach(&stream);
+		}
+		stream.finish()?;
+		stream.read_status().await?;
+		let codec = match compression {
+			Compression::None => Compression::None,
+			_ => Compression::from_u8(stream.read_u8().await?)?,
+		};
+		Ok((stream, codec))
+	}
+
+	/// Starts tracking a transfer, queued behind the running ones.
+	pub(crate) fn job(&self, kind: TransferKind, node_id: Option<&str>, path: &str, total: Option<u64>) -> Job {
+		self.transfers.add(kind, node_id, path, total, self.priority)
+	}
+
+	/// Reads a file range. If the link drops mid-transfer the read resumes
+	/// from the last received byte once a route to the node is back. Waits
+	/// while the transfer is queued or paused. Fails at once when there is
+	/// no route to the node to begin with.
+	pub async fn read_file(&self, node_id: &str, path: &str, offset: u64, length: u64) -> anyhow::Result<Vec<u8>> {
+		let total = (length != u64::MAX).then_some(length);
+		let job = self.job(TransferKind::Read, Some(node_id), path, total);
+		let res = job.run(self.read_file_job(&job, node_id, path, offset, length)).await;
+		job.finish(&res);
+		res
+	}
+
+	async fn read_file_job(&self, job: &Job, node_id: &str, path: &str, offset: u64, length: u64) -> anyhow::Result<Vec<u8>> {
+		let mut data = Vec::new();
+		let mut backoff = Backoff::new();
+		let deadline = Instant::now() + RESUME_TIMEOUT;
+		loop {
+			job.ready().await?;
+			let received = data.len() as u64;
+			match self.read_file_into(node_id, path, offset + received, length.saturating_sub(received), &mut data, Some(job)).await {
+				Ok(()) => return Ok(data),
+				Err(err) if can_resume(&err, backoff.attempt() > 0) && Instant::now() < deadline => {
+					log::info!("read of {} interrupted at {} bytes, resuming: {}", path, data.len(), err);
+					tokio::time::sleep(backoff.next_delay()).await;
+				},
+				Err(err) => return Err(err),
+			}
+		}
+	}
+
+	/// Writes `data` at `offset`, keeping the rest of the file. Writes at a
+	/// fixed offset are idempotent, so an interrupted write is simply sent
+	/// again once the link is back. `data` travels in one command, so it
+	/// may be at most `MAX_COMMAND_SIZE`.
+	pub async fn write_file(&self, node_id: &str, path: &str, offset: u64, data: Vec<u8>) -> anyhow::Result<()> {
+		self.write_file_with_mode(node_id, path, offset, data, WriteMode::Overwrite).await
+	}
+
+	/// Like `write_file`, with `mode` deciding what becomes of the rest of
+	/// the file. The file only changes once all of `data` arrived intact.
+	/// Appends are not retried, as a retry could append twice.
+	pub async fn write_file_with_mode(&self, node_id: &str, path: &str, offset: u64, data: Vec<u8>, mode: WriteMode) -> anyhow::Result<()> {
+		// The other fields of the command take less than 64 bytes.
+		if node_id.len() + path.len() + data.len() + 64 > MAX_COMMAND_SIZE {
+			anyhow::bail!("{} bytes is too much for one write, use sync_file", data.len());
+		}
+		let job = self.job(TransferKind::Write, Some(node_id), path, Some(data.len() as u64));
+		let res = job.run(self.write_file_job(&job, node_id, path, offset, data, mode)).await;
+		job.finish(&res);
+		res
+	}
+
+	async fn write_file_job(&self, job: &Job, node_id: &str, path: &str, offset: u64, data: Vec<u8>, mode: WriteMode) -> anyhow::Result<()> {
+		let supported = self.features(node_id).await? & FEATURE_WRITE_MODES != 0;
+		if !supported && mode != WriteMode::Overwrite {
+			anyhow::bail!("{} can only overwrite files in place", node_id);
+		}
+		let (data, hash) = match supported {
+			true => {
+				let (data, hash) = tokio::task::spawn_blocking(move || {
+					let hash = chunk::hash(&data);
+					(data, hash)
+				}).await?;
+				(data, Some(hash))
+			},
+			false => (data, None),
+		};
+		let mut backoff = Backoff::new();
+		let deadline = Instant::now() + RESUME_TIMEOUT;
+		loop {
+			job.ready().await?;
+			let res = self.simple_request(PeerCmd::WriteFile {
+				node_id: node_id.to_string(),
+				path: path.to_string(),
+				offset,
+				data: data.clone(),
+				mode,
+				hash,
+			}).await;
+			match res {
+				Err(err) if mode != WriteMode::Append && can_resume(&err, backoff.attempt() > 0) && Instant::now() < deadline => {
+					log::info!("write of {} interrupted, retrying: {}", path, err);
+					tokio::time::sleep(backoff.next_delay()).await;
+				},
+				res => {
+					if res.is_ok() {
+						job.progress(data.len() as u64);
+					}
+					return res;
+				},
+			}
+		}
+	}
+
+	async fn sync_file_once(&self, job: &Job, node_id: &str, path: &str, data: Vec<u8>) -> anyhow::Result<DeltaStats> {
+		let mut stream = self.request(PeerCmd::WriteDelta {
+			node_id: node_id.to_string(),
+			path: path.to_string(),
+		}).await?;
+		job.attach(&stream);
+		stream.read_status().await?;
+		let known = delta::read_signatures(&mut stream).await?;
+		let stats = delta::send_delta(&mut stream, data, known).await?;
+		stream.finish()?;
+		stream.read_status().await?;
+		stream.read_to_end().await?;
+		Ok(stats)
+	}
+
+	async fn send_blocks_once(&self, job: &Job, node_id: &str, path: &str, data: &[u8], parts: &[block_store::Part], hash: Hash) -> anyhow::Result<DeltaStats> {
+		let mut stream = self.request(PeerCmd::WriteBlocks {
+			node_id: node_id.to_string(),
+			path: path.to_string(),
+			size: data.len() as u64,
+			hash,
+		}).await?;
+		job.attach(&stream);
+		let stats = block_store::send(&mut stream, data, parts).await?;
+		stream.finish()?;
+		stream.read_status().await?;
+		stream.read_to_end().await?;
+		Ok(stats)
+	}
+
+	/// Replaces the contents of a remote file with `data`, sending only the
+	/// chunks the remote node has neither stored nor in its current copy.
+	/// The file is swapped in atomically once complete. Nodes without delta
+	/// support get `data` written at offset 0 like `write_file` does.
+	pub async fn sync_file(&self, node_id: &str, path: &str, data: Vec<u8>) -> anyhow::Result<DeltaStats> {
+		let job = self.job(TransferKind::Sync, Some(node_id), path, Some(data.len() as u64));
+		let res = job.run(self.sync_file_job(&job, node_id, path, data)).await;
+		job.finish(&res);
+		res
+	}
+
+	async fn sync_file_job(&self, job: &Job, node_id: &str, path: &str, data: Vec<u8>) -> anyhow::Result<DeltaStats> {
+		let features = self.features(node_id).await?;
+		if features & (FEATURE_BLOCKS | FEATURE_DELTA) == 0 {
+			let sent = data.len() as u64;
+			self.write_file_job(job, node_id, path, 0, data, WriteMode::Overwrite).await?;
+			return Ok(DeltaStats { sent, reused: 0 });
+		}
+		let (data, recipe) = match features & FEATURE_BLOCKS {
+			0 => (data, None),
+			_ => tokio::task::spawn_blocking(move || {
+				let recipe = block_store::recipe(&data);
+				(data, Some(recipe))
+			}).await?,
+		};
+		let mut backoff = Backoff::new();
+		let deadline = Instant::now() + RESUME_TIMEOUT;
+		loop {
+			job.ready().await?;
+			let res = match &recipe {
+				Some((parts, hash)) => self.send_blocks_once(job, node_id, path, &data, parts, *hash).await,
+				None => self.sync_file_once(job, node_id, path, data.clone()).await,
+			};
+			match res {
+				Err(err) if can_resume(&err, backoff.attempt() > 0) && Instant::now() < deadline => {
+					log::info!("sync of {} interrupted, retrying: {}", path, err);
+					tokio::time::sleep(backoff.next_delay()).await;
+				},
+				res => {
+					if res.is_ok() {
+						job.progress(data.len() as u64);
+					}
+					return res;
+				},
+			}
+		}
+	}
+
+	/// Subscribes to changes under `path` on a remote node, below it too
+	/// when `recursive` is set.
+	pub async fn watch(&self, node_id: &str, path: &str, recursive: bool) -> anyhow::Result<Watch> {
+		if self.features(node_id).await? & FEATURE_WATCH == 0 {
+			anyhow::bail!("{} cannot watch for changes", node_id);
+		}
+		let mut stream = self.request(PeerCmd::Watch {
+			node_id: node_id.to_string(),
+			path: path.to_string(),
+			recursive,
+		}).await?;
+		stream.read_status().await?;
+		Ok(Watch::new(stream))
+	}
+
+	pub async fn remove_file(&self, node_id: &str, path: &str) -> anyhow::Result<()> {
+		self.simple_request(PeerCmd::RemoveFile {
+			node_id: node_id.to_string(),
+			path: path.to_string(),
+		}).await
+	}
+
+	pub async fn remove_folder(&self, node_id: &str, path: &str) -> anyhow::Result<()> {
+		self.simple_request(PeerCmd::RemoveFolder {
+			node_id: node_id.to_string(),
+			path: path.to_string(),
+		}).await
+	}
+
+	pub async fn create_folder(&self, node_id: &str, path: &str) -> anyhow::Result<()> {
+		self.simple_request(PeerCmd::CreateFolder {
+			node_id: node_id.to_string(),
+			path: path.to_string(),
+		}).await
+	}
+
+	pub async fn rename(&self, node_id: &str, path: &str, new_name: &str) -> anyhow::Result<()> {
+		self.simple_request(PeerCmd::RenameFolder {
+			node_id: node_id.to_string(),
+			path: path.to_string(),
+			new_name: new_name.to_string(),
+		}).await
+	}
+
+	/// Lists `path` on a remote node. A `length` of 0 returns every entry.
+	pub async fn list_folder(&self, node_id: &str, path: &str, offset: u64, length: u64, recursive: bool) -> anyhow::Result<Vec<FolderEntry>> {
+		let mut stream = self.request(PeerCmd::ListFolderContents {
+			node_id: node_id.to_string(),
+			path: path.to_string(),
+			offset,
+			length,
+			recursive,
+		}).await?;
+		stream.finish()?;
+		stream.read_status().await?;
+		let mut entries = Vec::new();
+		loop {
+			let is_dir = match stream.try_read_u8().await? {
+				Some(is_dir) => is_dir != 0,
+				None => break,
+			};
+			entries.push(FolderEntry {
+				is_dir,
+				size: stream.read_u64().await?,
+				modified: stream.read_u64().await?,
+				path: stream.read_str().await?,
+			});
+		}
+		Ok(entries)
+	}
+}
+
+/// Next piece of a reply opened with `open_read`, `None` at its end.
+pub(crate) async fn read_chunk(stream: &mut Stream, codec: Compression) -> anyhow::Result<Option<Bytes>> {
+	if codec == Compression::None {
+		stream.read_bytes().await
+	} else {
+		Ok(stream.read_block(codec).await?.map(Bytes::from))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::node;
+
+	#[tokio::test]
+	async fn test_unreachable_node_fails_at_once() {
+		let dir = tempfile::tempdir().unwrap();
+		let a = node(dir.path(), "a").build();
+		let requests = async {
+			assert!(a.read_file("nobody", "/a", 0, 10).await.is_err());
+			assert!(a.write_file("nobody", "/a", 0, b"a".to_vec()).await.is_err());
+			assert!(a.sync_file("nobody", "/a", b"a".to_vec()).await.is_err());
+		};
+		tokio::time::timeout(Duration::from_secs(5), requests).await.unwrap();
+	}
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
+use crate::mesh::Mesh;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+	/// Directly connected node to hand the stream to.
+	pub next_hop: String,
+	pub hops: u8,
+}
+
+/// Shortest-path next hops to every node in the mesh, rebuilt whenever our
+/// own connections or the gossiped mesh change. Blocked nodes are neither
+/// reached nor passed through.
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+	routes: HashMap<String, Route>,
+}
+
+impl RoutingTable {
+	pub fn build<'a>(me: &str, direct: impl Iterator<Item = &'a String>, mesh: &Mesh, blocked: &HashSet<&str>) -> RoutingTable {
+		let mut routes: HashMap<String, Route> = HashMap::new();
+		let mut queue = VecDeque::new();
+		for id in direct {
+			if id != me && !routes.contains_key(id) && !blocked.contains(id.as_str()) {
+				routes.insert(id.clone(), Route { next_hop: id.clone(), hops: 1 });
+				queue.push_back(id.clone());
+			}
+		}
+		while let Some(id) = queue.pop_front() {
+			let route = routes[&id].clone();
+			for neighbour in mesh.neighbours(&id) {
+				if neighbour == me || routes.contains_key(neighbour) || blocked.contains(neighbour.as_str()) {
+					continue;
+				}
+				routes.insert(neighbour.clone(), Route {
+					next_hop: route.next_hop.clone(),
+					hops: route.hops.saturating_add(1),
+				});
+				queue.push_back(neighbour.clone());
+			}
+		}
+		RoutingTable { routes }
+	}
+
+	pub fn get(&self, id: &str) -> Option<&Route> {
+		self.routes.get(id)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::folder;
+	use crate::testing::node;
+	use crate::testing::wait_for_peer;
+	use crate::PROTOCOL_VERSION;
+
+	#[test]
+	fn test_routes_through_intermediary() {
+		// laptop - server - nas, plus a longer detour laptop - a - b - nas
+		let mut mesh = Mesh::new();
+		mesh.update("server", "nas", 1, true);
+		mesh.update("server", "laptop", 1, true);
+		mesh.update("a", "b", 1, true);
+		mesh.update("b", "nas", 1, true);
+		let direct = ["server".to_string(), "a".to_string()];
+		let table = RoutingTable::build("laptop", direct.iter(), &mesh, &HashSet::new());
+
+		assert_eq!(table.get("nas"), Some(&Route { next_hop: "server".to_string(), hops: 2 }));
+		assert_eq!(table.get("b"), Some(&Route { next_hop: "a".to_string(), hops: 2 }));
+		assert!(table.get("laptop").is_none());
+	}
+
+	#[test]
+	fn test_link_down_removes_route() {
+		let mut mesh = Mesh::new();
+		mesh.update("server", "nas", 1, true);
+		mesh.update("server", "nas", 2, false);
+		let direct = ["server".to_string()];
+		let table = RoutingTable::build("laptop", direct.iter(), &mesh, &HashSet::new());
+		assert!(table.get("nas").is_none());
+	}
+
+	#[test]
+	fn test_blocked_nodes_are_avoided() {
+		let mut mesh = Mesh::new();
+		mesh.update("server", "nas", 1, true);
+		mesh.update("a", "b", 1, true);
+		mesh.update("b", "nas", 1, true);
+		let direct = ["server".to_string(), "a".to_string()];
+		let blocked = HashSet::from(["server", "b"]);
+		let table = RoutingTable::build("laptop", direct.iter(), &mesh, &blocked);
+
+		assert!(table.get("server").is_none());
+		assert!(table.get("b").is_none());
+		assert!(table.get("nas").is_none());
+		assert!(table.get("a").is_some());
+	}
+
+	#[tokio::test]
+	async fn test_nodes_in_one_process() {
+		let dir = tempfile::tempdir().unwrap();
+		let share = folder(dir.path(), "share");
+		std::fs::write(share.join("hello.txt"), b"hello").unwrap();
+		let outside = folder(dir.path(), "outside");
+		std::fs::write(outside.join("secret.txt"), b"secret").unwrap();
+
+		let c = node(dir.path(), "c").share(&share).build();
+		let c_info = c.info().await.unwrap();
+		let b = node(dir.path(), "b").peer(&c_info.listen_addrs[0]).build();
+		let b_info = b.info().await.unwrap();
+		let a = node(dir.path(), "a").peer(&b_info.listen_addrs[0]).build();
+		let a_info = a.info().await.unwrap();
+
+		assert_eq!(c_info.name, "c");
+		assert_ne!(a_info.id, b_info.id);
+		assert_ne!(b_info.id, c_info.id);
+
+		// a reaches c through b once the mesh has converged.
+		wait_for_peer(&a, &c_info.id).await;
+		let path = share.join("hello.txt");
+		let data = a.read_file(&c_info.id, path.to_str().unwrap(), 0, 5).await.unwrap();
+		assert_eq!(data, b"hello");
+
+		let path = outside.join("secret.txt");
+		assert!(a.read_file(&c_info.id, path.to_str().unwrap(), 0, 6).await.is_err());
+
+		let metrics = b.metrics().await.unwrap();
+		assert_eq!(metrics.connections.len(), 2);
+		assert_eq!(metrics.commands_queued, 0);
+		assert!(metrics.connections.iter().all(|c| c.version == Some(PROTOCOL_VERSION)));
+	}
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::builder::Config;
+use crate::types::Peer;
+
+pub fn app_dir() -> anyhow::Result<PathBuf> {
+	let home = match homedir::my_home()? {
+		Some(home) => home,
+		None => anyhow::bail!("could not resolve home directory"),
+	};
+	Ok(home.join(".pupynet"))
+}
+
+/// The configured data dir, `~/.pupynet` when unset.
+pub fn data_dir(config: &Config) -> anyhow::Result<PathBuf> {
+	match &config.data_dir {
+		Some(dir) => Ok(dir.clone()),
+		None => app_dir(),
+	}
+}
+
+pub fn peers_path(dir: &Path) -> PathBuf {
+	dir.join("peers.json")
+}
+
+/// Ids of forgotten peers, which stay refused.
+pub fn forgotten_path(dir: &Path) -> PathBuf {
+	dir.join("forgotten.json")
+}
+
+pub fn node_id_path(dir: &Path) -> PathBuf {
+	dir.join("node_id")
+}
+
+/// Where the chunks of received files are kept.
+pub fn blocks_dir(dir: &Path) -> PathBuf {
+	dir.join("blocks")
+}
+
+/// Where the index of synced folder `id` is kept.
+pub fn sync_index_path(dir: &Path, id: &str) -> PathBuf {
+	let name: String = id.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+	dir.join("sync").join(format!("{}.json", name))
+}
+
+/// Name used when none is configured: the host name, if it can be found.
+pub fn default_name() -> String {
+	std::env::var("HOSTNAME")
+		.or_else(|_| std::env::var("COMPUTERNAME"))
+		.or_else(|_| std::fs::read_to_string("/etc/hostname"))
+		.map(|name| name.trim().to_string())
+		.unwrap_or_default()
+}
+
+pub fn new_node_id() -> String {
+	rand::random::<[u8; 16]>().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Reads the node id from `path`, generating and storing one on first start.
+pub async fn load_or_create_node_id(path: &Path) -> anyhow::Result<String> {
+	match tokio::fs::read_to_string(path).await {
+		Ok(id) if !id.trim().is_empty() => return Ok(id.trim().to_string()),
+		Ok(_) => {},
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => {},
+		Err(err) => return Err(err.into()),
+	}
+	let id = new_node_id();
+	if let Some(parent) = path.parent() {
+		tokio::fs::create_dir_all(parent).await?;
+	}
+	tokio::fs::write(path, &id).await?;
+	Ok(id)
+}
+
+pub async fn load_peers(path: &Path) -> anyhow::Result<HashMap<String, Peer>> {
+	let data = match tokio::fs::read(path).await {
+		Ok(data) => data,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+		Err(err) => return Err(err.into()),
+	};
+	let peers: Vec<Peer> = serde_json::from_slice(&data)?;
+	Ok(peers.into_iter().map(|p| (p.id.clone(), p)).collect())
+}
+
+pub async fn save_peers(path: &Path, peers: &HashMap<String, Peer>) -> anyhow::Result<()> {
+	if let Some(parent) = path.parent() {
+		tokio::fs::create_dir_all(parent).await?;
+	}
+	let mut peers: Vec<&Peer> = peers.values().collect();
+	peers.sort_by(|a, b| a.id.cmp(&b.id));
+	let data = serde_json::to_vec_pretty(&peers)?;
+	let tmp = path.with_extension("json.tmp");
+	tokio::fs::write(&tmp, data).await?;
+	tokio::fs::rename(&tmp, path).await?;
+	Ok(())
+}
+
+pub async fn load_forgotten(path: &Path) -> anyhow::Result<HashSet<String>> {
+	let data = match tokio::fs::read(path).await {
+		Ok(data) => data,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+		Err(err) => return Err(err.into()),
+	};
+	Ok(serde_json::from_slice(&data)?)
+}
+
+pub async fn save_forgotten(path: &Path, ids: &HashSet<String>) -> anyhow::Result<()> {
+	if let Some(parent) = path.parent() {
+		tokio::fs::create_dir_all(parent).await?;
+	}
+	let mut ids: Vec<&String> = ids.iter().collect();
+	ids.sort();
+	let data = serde_json::to_vec_pretty(&ids)?;
+	let tmp = path.with_extension("json.tmp");
+	tokio::fs::write(&tmp, data).await?;
+	tokio::fs::rename(&tmp, path).await?;
+	Ok(())
+}
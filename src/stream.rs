@@ -1,129 +1,786 @@
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Weak;
+
+use bytes::Buf;
+use bytes::Bytes;
+use bytes::BufMut;
+use bytes::BytesMut;
+use sha2::Digest;
+use sha2::Sha256;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncSeekExt;
 use tokio::io::AsyncWriteExt;
-use tokio::io::BufReader;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
 
+use crate::builder::Share;
+use crate::chunk::Hash;
+use crate::compress;
+use crate::compress::Compression;
+use crate::delta;
+use crate::flow::Credit;
 use crate::protocol::*;
+use crate::types::PeerConnCmd;
+use crate::types::PeerTx;
+use crate::types::TransientError;
 
-// struct Parser {
-// 	stream_id: u64,
-// 	streams: 
-// }
+/// How much of a file is read per chunk sent when serving a read.
+const READ_CHUNK: usize = 256 * 1024;
 
-// impl Parser {
+/// How much is read from a stream before the connection hears of it.
+const GRANT_STEP: usize = INITIAL_WINDOW / 4;
 
-// }
+/// Writes wait for the window to fit this much of them, so they do not go
+/// out in slivers. Readers never hold back enough of a window to keep it
+/// this small.
+const MIN_PIECE: usize = INITIAL_WINDOW / 2;
 
-pub trait Transport {
+#[derive(Debug)]
+pub enum StreamMsg {
+	/// Incoming data and the window or connection budget it occupies until
+	/// read.
+	Data(Bytes, Credit),
+	End,
+	Died,
+}
 
+/// Why writes to a stream wait.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Hold {
+	/// The remote sent `STREAM_PAUSE`.
+	pub remote: bool,
+	/// The transfer using the stream was paused here.
+	pub local: bool,
+	/// Bytes the remote still lets us write, `None` when it sets no limit.
+	/// Zero until the connection has taken the stream.
+	pub window: Option<u64>,
 }
 
-struct Stream {
-	transport: Box<dyn Transport>
+impl Hold {
+	fn held(&self) -> bool {
+		self.remote || self.local
+	}
+
+	/// Whether a write of `len` bytes may go on. Relays ignore pauses, not
+	/// windows.
+	fn ready(&self, pausable: bool, len: usize) -> bool {
+		let want = len.min(MIN_PIECE) as u64;
+		!(pausable && self.held()) && self.window.is_none_or(|window| window >= want)
+	}
+}
+
+/// One multiplexed substream of a connection. Reads come from the
+/// connection's read loop, writes are framed by the connection's writer.
+#[derive(Debug)]
+pub struct Stream {
+	id: u64,
+	rx: mpsc::UnboundedReceiver<StreamMsg>,
+	tx: PeerTx,
+	/// Received but not yet read.
+	buffer: Bytes,
+	/// Read but not yet handed to the connection to grant back.
+	ungranted: usize,
+	read_closed: bool,
+	write_closed: bool,
+	/// Shared with the connection, which sets `remote`, and with any
+	/// `StreamControl`, which sets `local`.
+	hold: Arc<watch::Sender<Hold>>,
 }
 
 impl Stream {
-	pub async fn read_bool(&mut self) -> bool {
-		false
+	pub fn new(id: u64, rx: mpsc::UnboundedReceiver<StreamMsg>, tx: PeerTx) -> Stream {
+		Stream {
+			id,
+			rx,
+			tx,
+			buffer: Bytes::new(),
+			ungranted: 0,
+			read_closed: false,
+			write_closed: false,
+			hold: Arc::new(watch::channel(Hold { window: Some(0), ..Hold::default() }).0),
+		}
+	}
+
+	pub fn id(&self) -> u64 {
+		self.id
+	}
+
+	/// For the connection to hold our writes while the remote paused us.
+	/// Gone once the stream is dropped.
+	pub fn hold(&self) -> Weak<watch::Sender<Hold>> {
+		Arc::downgrade(&self.hold)
+	}
+
+	/// A handle pausing the stream from outside the task using it.
+	pub fn control(&self) -> StreamControl {
+		StreamControl { id: self.id, tx: self.tx.clone(), hold: self.hold.clone() }
+	}
+
+	/// Asks the remote to stop writing to the stream, or to go on. Links
+	/// to nodes without `FEATURE_PAUSE` drop the request.
+	fn pause_remote(&self, paused: bool) -> anyhow::Result<()> {
+		let stage = if paused { STREAM_PAUSE } else { STREAM_PULL };
+		self.tx.send(PeerConnCmd::StreamData { stream_id: self.id, stage, data: Bytes::new() })
+	}
+
+	/// Receives the next message, `None` once the remote has ended its side.
+	async fn recv(&mut self) -> anyhow::Result<Option<Bytes>> {
+		if self.read_closed {
+			return Ok(None);
+		}
+		match self.rx.recv().await {
+			Some(StreamMsg::Data(data, credit)) => {
+				// The window only reopens once the data left the budget.
+				drop(credit);
+				self.grant(data.len());
+				Ok(Some(data))
+			},
+			Some(StreamMsg::End) => {
+				self.read_closed = true;
+				Ok(None)
+			},
+			Some(StreamMsg::Died) | None => {
+				self.read_closed = true;
+				self.write_closed = true;
+				Err(TransientError::StreamDied(self.id).into())
+			}
+		}
+	}
+
+	/// Hands what was read to the connection to grant back to the remote.
+	/// Connections without windows drop grants.
+	fn grant(&mut self, len: usize) {
+		self.ungranted += len;
+		if self.ungranted >= GRANT_STEP {
+			let bytes = std::mem::take(&mut self.ungranted) as u32;
+			let _ = self.tx.send(PeerConnCmd::Grant { stream_id: self.id, bytes });
+		}
+	}
+
+	/// Buffers at least `len` bytes. Only values split across chunks are
+	/// copied to join them.
+	async fn fill(&mut self, len: usize) -> anyhow::Result<()> {
+		while self.buffer.len() < len {
+			let data = match self.recv().await? {
+				Some(data) => data,
+				None => anyhow::bail!("stream {} ended early", self.id),
+			};
+			if self.buffer.is_empty() {
+				self.buffer = data;
+			} else {
+				let mut joined = BytesMut::with_capacity(self.buffer.len() + data.len());
+				joined.extend_from_slice(&self.buffer);
+				joined.extend_from_slice(&data);
+				self.buffer = joined.freeze();
+			}
+		}
+		Ok(())
+	}
+
+	pub async fn read_exact(&mut self, len: usize) -> anyhow::Result<Bytes> {
+		self.fill(len).await?;
+		Ok(self.buffer.split_to(len))
 	}
 
-	pub async fn read_u16(&mut self) -> u16 {
-		0
+	pub async fn read_u8(&mut self) -> anyhow::Result<u8> {
+		Ok(self.read_exact(1).await?[0])
 	}
 
-	pub async fn read_u64(&mut self) -> u64 {
-		0
+	/// Whether the remote ended the stream cleanly with nothing left to read.
+	pub async fn at_end(&mut self) -> anyhow::Result<bool> {
+		while self.buffer.is_empty() {
+			match self.recv().await? {
+				Some(data) => self.buffer = data,
+				None => return Ok(true),
+			}
+		}
+		Ok(false)
+	}
+
+	/// Like `read_u8` but returns `None` when the remote ended the stream cleanly.
+	pub async fn try_read_u8(&mut self) -> anyhow::Result<Option<u8>> {
+		if self.at_end().await? {
+			return Ok(None);
+		}
+		Ok(Some(self.buffer.get_u8()))
 	}
 
-	pub async fn read_str(&mut self) -> &str {
-		"lol"
+	pub async fn read_u16(&mut self) -> anyhow::Result<u16> {
+		Ok(self.read_exact(2).await?.get_u16_le())
 	}
 
-	pub async fn read_bytes(&mut self) -> Option<&[u8]> {
-		Some(&[])
+	pub async fn read_u32(&mut self) -> anyhow::Result<u32> {
+		Ok(self.read_exact(4).await?.get_u32_le())
 	}
 
-	pub async fn write_bytes(&mut self, data: &[u8]) {
+	pub async fn read_u64(&mut self) -> anyhow::Result<u64> {
+		Ok(self.read_exact(8).await?.get_u64_le())
 	}
 
-	pub async fn write_byte(&mut self, byte: u8) {
+	pub async fn read_str(&mut self) -> anyhow::Result<String> {
+		let len = self.read_u16().await? as usize;
+		Ok(String::from_utf8(self.read_exact(len).await?.to_vec())?)
 	}
+
+	/// Returns the next chunk of data as received, `None` once the remote
+	/// has finished.
+	pub async fn read_bytes(&mut self) -> anyhow::Result<Option<Bytes>> {
+		if !self.buffer.is_empty() {
+			return Ok(Some(std::mem::take(&mut self.buffer)));
+		}
+		self.recv().await
+	}
+
+	/// Reads one block written with `compress::encode_block`, `None` once
+	/// the remote has finished.
+	pub async fn read_block(&mut self, codec: Compression) -> anyhow::Result<Option<Vec<u8>>> {
+		if self.at_end().await? {
+			return Ok(None);
+		}
+		let raw_len = self.read_u32().await? as usize;
+		let stored_len = self.read_u32().await? as usize;
+		if raw_len > compress::MAX_BLOCK_LEN || stored_len > raw_len {
+			anyhow::bail!("invalid block of {} bytes stored in {}", raw_len, stored_len);
+		}
+		let stored = self.read_exact(stored_len).await?;
+		let data = tokio::task::spawn_blocking(move || compress::decode_block(codec, raw_len, &stored)).await??;
+		Ok(Some(data))
+	}
+
+	pub async fn read_to_end(&mut self) -> anyhow::Result<Vec<u8>> {
+		let mut res = std::mem::take(&mut self.buffer).to_vec();
+		while let Some(data) = self.recv().await? {
+			res.extend_from_slice(&data);
+		}
+		Ok(res)
+	}
+
+	fn check_writable(&self) -> anyhow::Result<()> {
+		if self.write_closed {
+			anyhow::bail!("stream {} is closed for writing", self.id);
+		}
+		Ok(())
+	}
+
+	/// Waits while the connection's send queue is full.
+	pub async fn write_bytes(&mut self, data: &[u8]) -> anyhow::Result<()> {
+		self.write_chunk(Bytes::copy_from_slice(data)).await
+	}
+
+	/// Like `write_bytes` but hands `data` to the connection without copying.
+	/// Waits while the stream is paused by either end.
+	pub async fn write_chunk(&mut self, data: Bytes) -> anyhow::Result<()> {
+		self.write_within(data, true).await
+	}
+
+	/// Writes regardless of pauses, for relays that passed the pause on
+	/// instead.
+	async fn forward_chunk(&mut self, data: Bytes) -> anyhow::Result<()> {
+		self.write_within(data, false).await
+	}
+
+	/// Writes `data` in pieces that fit the window the remote granted.
+	async fn write_within(&mut self, mut data: Bytes, pausable: bool) -> anyhow::Result<()> {
+		self.check_writable()?;
+		while !data.is_empty() {
+			if !self.hold.borrow().ready(pausable, data.len()) {
+				let mut hold = self.hold.subscribe();
+				// We hold a sender ourselves, so the channel stays open. A
+				// connection closing before it took the stream never opens
+				// its window.
+				tokio::select! {
+					_ = hold.wait_for(|hold| hold.ready(pausable, data.len())) => {},
+					_ = self.tx.closed() => anyhow::bail!("connection closed"),
+				}
+			}
+			let mut len = data.len();
+			// Only grants need to wake writers, so taking from the window
+			// notifies nobody.
+			self.hold.send_if_modified(|hold| {
+				if let Some(window) = &mut hold.window {
+					len = len.min(*window as usize);
+					*window -= len as u64;
+				}
+				false
+			});
+			self.tx.send_data(self.id, data.split_to(len)).await?;
+		}
+		Ok(())
+	}
+
+	pub async fn write_byte(&mut self, byte: u8) -> anyhow::Result<()> {
+		self.write_bytes(&[byte]).await
+	}
+
+	pub async fn write_u64(&mut self, value: u64) -> anyhow::Result<()> {
+		self.write_bytes(&value.to_le_bytes()).await
+	}
+
+	pub async fn write_str(&mut self, s: &str) -> anyhow::Result<()> {
+		let mut data = (s.len() as u16).to_le_bytes().to_vec();
+		data.extend_from_slice(s.as_bytes());
+		self.write_bytes(&data).await
+	}
+
+	/// Ends our side of the stream. The remote may still be writing.
+	pub fn finish(&mut self) -> anyhow::Result<()> {
+		self.check_writable()?;
+		self.write_closed = true;
+		self.tx.send(PeerConnCmd::StreamData { stream_id: self.id, stage: STREAM_END, data: Bytes::new() })
+	}
+
+	/// Tears the stream down in both directions.
+	pub fn abort(&mut self) {
+		if !self.write_closed || !self.read_closed {
+			let _ = self.tx.send(PeerConnCmd::StreamData { stream_id: self.id, stage: STREAM_DIED, data: Bytes::new() });
+		}
+		self.write_closed = true;
+		self.read_closed = true;
+	}
+
+	pub async fn write_status(&mut self, res: anyhow::Result<()>) -> anyhow::Result<()> {
+		match res {
+			Ok(()) => self.write_byte(SUCCES).await,
+			Err(err) => self.write_error(ERR_IO, &err.to_string()).await,
+		}
+	}
+
+	pub async fn write_error(&mut self, code: u8, msg: &str) -> anyhow::Result<()> {
+		self.write_byte(code).await?;
+		self.write_str(msg).await
+	}
+
+	/// Reads a status written by `write_status`/`write_error`.
+	pub async fn read_status(&mut self) -> anyhow::Result<()> {
+		let code = self.read_u8().await?;
+		if code == SUCCES {
+			return Ok(());
+		}
+		let msg = self.read_str().await.unwrap_or_default();
+		match code {
+			ERR_REMOVE_FOLDER_RECURSIVE_NOT_ENABLED => anyhow::bail!("path is a folder, recursive remove not enabled"),
+			ERR_UNREACHABLE => anyhow::bail!("node unreachable: {}", msg),
+			ERR_HOP_LIMIT => anyhow::bail!("hop limit exceeded: {}", msg),
+			ERR_ACCESS_DENIED => anyhow::bail!("access denied: {}", msg),
+			_ => anyhow::bail!("remote error {}: {}", code, msg),
+		}
+	}
+}
+
+impl Drop for Stream {
+	fn drop(&mut self) {
+		self.abort();
+	}
+}
+
+/// Pauses a stream on behalf of the transfer using it.
+#[derive(Debug, Clone)]
+pub struct StreamControl {
+	id: u64,
+	tx: PeerTx,
+	hold: Arc<watch::Sender<Hold>>,
+}
+
+impl StreamControl {
+	/// Holds our writes and asks the remote to hold its own, or releases both.
+	pub fn pause(&self, paused: bool) {
+		self.hold.send_modify(|hold| hold.local = paused);
+		let stage = if paused { STREAM_PAUSE } else { STREAM_PULL };
+		let _ = self.tx.send(PeerConnCmd::StreamData { stream_id: self.id, stage, data: Bytes::new() });
+	}
+}
+
+/// Copies data both ways between two streams until both sides have finished.
+/// A pause from either end is passed on to the other.
+pub async fn pipe(mut a: Stream, mut b: Stream) {
+	let mut a_done = false;
+	let mut b_done = false;
+	let (mut a_hold, mut b_hold) = (a.hold.subscribe(), b.hold.subscribe());
+	// Holds also change with every grant, only pauses are passed on.
+	let (mut a_paused, mut b_paused) = (false, false);
+	while !a_done || !b_done {
+		let res = tokio::select! {
+			res = a.read_bytes(), if !a_done => match res {
+				Ok(Some(data)) => b.forward_chunk(data).await,
+				Ok(None) => {
+					a_done = true;
+					b.finish()
+				},
+				Err(err) => Err(err),
+			},
+			res = b.read_bytes(), if !b_done => match res {
+				Ok(Some(data)) => a.forward_chunk(data).await,
+				Ok(None) => {
+					b_done = true;
+					a.finish()
+				},
+				Err(err) => Err(err),
+			},
+			Ok(()) = a_hold.changed() => {
+				let paused = a_hold.borrow_and_update().remote;
+				match std::mem::replace(&mut a_paused, paused) == paused {
+					true => Ok(()),
+					false => b.pause_remote(paused),
+				}
+			},
+			Ok(()) = b_hold.changed() => {
+				let paused = b_hold.borrow_and_update().remote;
+				match std::mem::replace(&mut b_paused, paused) == paused {
+					true => Ok(()),
+					false => a.pause_remote(paused),
+				}
+			},
+		};
+		if let Err(err) = res {
+			log::info!("pipe {} <-> {} closed: {}", a.id(), b.id(), err);
+			a.abort();
+			b.abort();
+			return;
+		}
+	}
+}
+
+/// Answers a stream that cannot be served with an error and closes it.
+pub async fn reject(mut stream: Stream, code: u8, msg: String) {
+	if stream.write_error(code, &msg).await.is_err() || stream.finish().is_err() {
+		return;
+	}
+	// Drain what the requester still sends so the stream closes cleanly.
+	while let Ok(Some(_)) = stream.read_bytes().await {}
 }
 
-async fn handle_read_file(stream: &mut Stream) -> anyhow::Result<()> {
-	let mut file = {
-		let path = stream.read_str().await;
-		tokio::fs::OpenOptions::new().read(true).open(path).await.unwrap()
+/// Serves a file range. When the requester asked for compression, the
+/// codec picked for the file follows the status and the data is sent as
+/// blocks.
+pub(crate) async fn handle_read_file(stream: &mut Stream, path: &str, offset: u64, length: u64, compression: Compression) -> anyhow::Result<()> {
+	let mut file = match tokio::fs::OpenOptions::new().read(true).open(path).await {
+		Ok(file) => file,
+		Err(err) => return stream.write_status(Err(err.into())).await,
 	};
-	let offset = stream.read_u64().await;
-	let length = stream.read_u64().await;
-	file.seek(std::io::SeekFrom::Start(offset)).await.unwrap();
-	let mut reader = BufReader::new(file);
-	let mut buff = [0u8; 8192];
+	if let Err(err) = file.seek(std::io::SeekFrom::Start(offset)).await {
+		return stream.write_status(Err(err.into())).await;
+	}
+	stream.write_status(Ok(())).await?;
+	let codec = compress::for_path(compression, Path::new(path));
+	if compression != Compression::None {
+		stream.write_byte(codec.as_u8()).await?;
+	}
+	let mut buff = BytesMut::new();
 	let mut total_read = 0;
 	while total_read < length {
-		let to_read = std::cmp::min(buff.len() as u64, length - total_read) as usize;
-		let read = reader.read(&mut buff[..to_read]).await.unwrap();
+		let to_read = std::cmp::min(READ_CHUNK as u64, length - total_read) as usize;
+		buff.reserve(to_read);
+		let read = file.read_buf(&mut (&mut buff).limit(to_read)).await?;
 		if read == 0 {
 			break;
 		}
-		stream.write_bytes(&buff[..read]).await;
+		let chunk = buff.split().freeze();
+		if codec == Compression::None {
+			// The chunk goes out as is; the next read reuses the allocation
+			// once the connection has written it.
+			stream.write_chunk(chunk).await?;
+		} else {
+			let block = tokio::task::spawn_blocking(move || compress::encode_block(codec, &chunk)).await?;
+			stream.write_chunk(block.into()).await?;
+		}
 		total_read += read as u64;
 	}
 	Ok(())
 }
 
-async fn handle_write_file(stream: &mut Stream) -> anyhow::Result<()> {
-	let mut file = {
-		let path = stream.read_str().await;
-		tokio::fs::OpenOptions::new().write(true).create(true).open(path).await.unwrap()
+/// Builds the new contents of `path` in `temp`, next to it, and renames it
+/// into place once the sender has finished and the data matches `hash`, so
+/// an interrupted write leaves the file as it was.
+async fn write_file(stream: &mut Stream, path: &Path, temp: &Path, offset: u64, data: &[u8], mode: WriteMode, hash: Option<Hash>) -> anyhow::Result<()> {
+	let existing = match tokio::fs::metadata(path).await {
+		Ok(metadata) if metadata.is_file() => Some(metadata),
+		Ok(_) => anyhow::bail!("{} is not a file", path.display()),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+		Err(err) => return Err(err.into()),
+	};
+	// How much of the current contents the new file starts with.
+	let keep = match (&existing, mode) {
+		(None, _) => 0,
+		(Some(metadata), WriteMode::Truncate) => offset.min(metadata.len()),
+		(Some(metadata), _) => metadata.len(),
 	};
-	let offset = stream.read_u64().await;
-	file.seek(std::io::SeekFrom::Start(offset)).await.unwrap();
-	while let Some(data) = stream.read_bytes().await {
-		file.write_all(data).await.unwrap();
+	let mut file = tokio::fs::File::create(temp).await?;
+	if keep > 0 {
+		let mut old = tokio::fs::File::open(path).await?;
+		tokio::io::copy(&mut (&mut old).take(keep), &mut file).await?;
 	}
+	let offset = match mode {
+		WriteMode::Append => keep,
+		_ => offset,
+	};
+	file.seek(std::io::SeekFrom::Start(offset)).await?;
 
+	let mut out = tokio::io::BufWriter::new(file);
+	let mut hasher = Sha256::new();
+	hasher.update(data);
+	out.write_all(data).await?;
+	while let Some(data) = stream.read_bytes().await? {
+		hasher.update(&data);
+		out.write_all(&data).await?;
+	}
+	out.flush().await?;
+	if hash.is_some_and(|hash| hash != <Hash>::from(hasher.finalize())) {
+		anyhow::bail!("data for {} arrived corrupted", path.display());
+	}
+	let file = out.into_inner();
+	if let Some(metadata) = existing {
+		file.set_permissions(metadata.permissions()).await?;
+	}
+	file.sync_all().await?;
+	tokio::fs::rename(temp, path).await?;
 	Ok(())
 }
 
-async fn handle_remove(stream: &mut Stream) -> anyhow::Result<()> {
-	let path = stream.read_str().await.to_string();
-	let recursive = stream.read_bool().await;
-	let metadata = tokio::fs::metadata(&path).await?;
+async fn handle_write_file(stream: &mut Stream, path: &str, offset: u64, data: &[u8], mode: WriteMode, hash: Option<Hash>) -> anyhow::Result<()> {
+	let path = PathBuf::from(path);
+	let temp = delta::temp_path(&path);
+	let res = write_file(stream, &path, &temp, offset, data, mode, hash).await;
+	if res.is_err() {
+		let _ = tokio::fs::remove_file(&temp).await;
+	}
+	stream.write_status(res).await
+}
+
+async fn handle_remove(stream: &mut Stream, path: &str, recursive: bool) -> anyhow::Result<()> {
+	let metadata = match tokio::fs::metadata(path).await {
+		Ok(metadata) => metadata,
+		Err(err) => return stream.write_status(Err(err.into())).await,
+	};
 
 	if metadata.is_dir() {
 		if !recursive {
-			stream.write_byte(ERR_REMOVE_FOLDER_RECURSIVE_NOT_ENABLED).await;
+			stream.write_error(ERR_REMOVE_FOLDER_RECURSIVE_NOT_ENABLED, path).await
 		} else {
-			tokio::fs::remove_dir_all(&path).await;
+			let res = tokio::fs::remove_dir_all(path).await;
+			stream.write_status(res.map_err(Into::into)).await
 		}
 	} else {
-		tokio::fs::remove_file(&path).await;
+		let res = tokio::fs::remove_file(path).await;
+		stream.write_status(res.map_err(Into::into)).await
 	}
+}
 
-	Ok(())
+async fn handle_create_folder(stream: &mut Stream, path: &str) -> anyhow::Result<()> {
+	let res = tokio::fs::create_dir_all(path).await;
+	stream.write_status(res.map_err(Into::into)).await
+}
+
+fn rename_target(path: &str, new_name: &str) -> PathBuf {
+	match Path::new(path).parent() {
+		Some(parent) => parent.join(new_name),
+		None => PathBuf::from(new_name),
+	}
+}
+
+async fn handle_rename(stream: &mut Stream, path: &str, new_name: &str) -> anyhow::Result<()> {
+	let res = tokio::fs::rename(path, rename_target(path, new_name)).await;
+	stream.write_status(res.map_err(Into::into)).await
 }
 
-async fn handle_create_folder(stream: &mut Stream) -> anyhow::Result<()> {
-	let path = stream.read_str().await;
-	tokio::fs::create_dir_all(path).await?;
+pub async fn list_folder(root: &Path, recursive: bool) -> anyhow::Result<Vec<FolderEntry>> {
+	let mut entries = Vec::new();
+	let mut dirs = vec![root.to_path_buf()];
+	while let Some(dir) = dirs.pop() {
+		let mut read_dir = tokio::fs::read_dir(&dir).await?;
+		while let Some(entry) = read_dir.next_entry().await? {
+			let metadata = entry.metadata().await?;
+			let path = entry.path();
+			if recursive && metadata.is_dir() {
+				dirs.push(path.clone());
+			}
+			let modified = metadata.modified().ok()
+				.and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+				.map(|d| d.as_secs())
+				.unwrap_or_default();
+			entries.push(FolderEntry {
+				path: path.strip_prefix(root).unwrap_or(&path).to_string_lossy().to_string(),
+				is_dir: metadata.is_dir(),
+				size: metadata.len(),
+				modified,
+			});
+		}
+	}
+	entries.sort_by(|a, b| a.path.cmp(&b.path));
+	Ok(entries)
+}
+
+async fn handle_list_folder(stream: &mut Stream, path: &str, offset: u64, length: u64, recursive: bool) -> anyhow::Result<()> {
+	let entries = match list_folder(Path::new(path), recursive).await {
+		Ok(entries) => entries,
+		Err(err) => return stream.write_status(Err(err)).await,
+	};
+	stream.write_status(Ok(())).await?;
+	let length = if length == 0 { u64::MAX } else { length };
+	for entry in entries.iter().skip(offset as usize).take(length as usize) {
+		stream.write_byte(entry.is_dir as u8).await?;
+		stream.write_u64(entry.size).await?;
+		stream.write_u64(entry.modified).await?;
+		stream.write_str(&entry.path).await?;
+	}
 	Ok(())
 }
 
-pub async fn handle_stream(mut stream: Stream) -> anyhow::Result<()> {
-	let cmd_type = stream.read_u16().await;
-	match cmd_type {
-		// INTRODUCE_CMD => {
-		// 	let id = stream.read_str();
-		// 	let name = stream.read_str();
-		// }
-		CMD_READ_FILE => handle_read_file(&mut stream).await?,
-		CMD_WRITE_FILE => handle_write_file(&mut stream).await?,
-		CMD_REMOVE => handle_remove(&mut stream).await?,
-		CMD_CREATE_FOLDER => handle_create_folder(&mut stream).await?,
-		_ => {}
+/// Resolves the symlinks in `path`. The parts that do not exist yet, like
+/// the name of a file about to be written, are kept as they are.
+async fn real_path(path: &Path) -> PathBuf {
+	let mut missing = Vec::new();
+	let mut existing = path;
+	loop {
+		if let Ok(real) = tokio::fs::canonicalize(existing).await {
+			return missing.iter().rev().fold(real, |path, name| path.join(name));
+		}
+		match (existing.file_name(), existing.parent()) {
+			(Some(name), Some(parent)) => {
+				missing.push(name.to_owned());
+				existing = parent;
+			},
+			_ => return path.to_path_buf(),
+		}
 	}
+}
 
+/// Whether `path` lies inside one of `shares`, writable ones only when
+/// `write` is set. Nothing is shared unless configured. Paths containing
+/// `..` are refused and symlinks are followed, so neither can lead out of
+/// a share.
+pub async fn is_shared(shares: &[Share], path: &Path, write: bool) -> bool {
+	if path.components().any(|c| c == Component::ParentDir) {
+		return false;
+	}
+	let path = real_path(path).await;
+	for share in shares.iter().filter(|share| !(write && share.read_only)) {
+		if path.starts_with(real_path(&share.path).await) {
+			return true;
+		}
+	}
+	false
+}
+
+async fn is_allowed(shares: &[Share], cmd: &PeerCmd) -> bool {
+	match cmd {
+		PeerCmd::ReadFile { path, .. } |
+		PeerCmd::ListFolderContents { path, .. } => is_shared(shares, Path::new(path), false).await,
+		PeerCmd::WriteFile { path, .. } |
+		PeerCmd::WriteDelta { path, .. } |
+		PeerCmd::RemoveFile { path, .. } |
+		PeerCmd::RemoveFolder { path, .. } |
+		PeerCmd::CreateFolder { path, .. } => is_shared(shares, Path::new(path), true).await,
+		PeerCmd::RenameFolder { path, new_name, .. } => {
+			is_shared(shares, Path::new(path), true).await && is_shared(shares, &rename_target(path, new_name), true).await
+		},
+		_ => true,
+	}
+}
+
+/// Serves a filesystem request that arrived on `stream` for this node,
+/// refusing paths outside `shares`.
+pub async fn handle_stream(mut stream: Stream, cmd: PeerCmd, shares: &[Share]) -> anyhow::Result<()> {
+	if !is_allowed(shares, &cmd).await {
+		reject(stream, ERR_ACCESS_DENIED, "path is not shared".to_string()).await;
+		return Ok(());
+	}
+	match cmd {
+		PeerCmd::ReadFile { path, offset, length, compression, .. } => {
+			handle_read_file(&mut stream, &path, offset, length, compression).await?
+		},
+		PeerCmd::WriteFile { path, offset, data, mode, hash, .. } => {
+			handle_write_file(&mut stream, &path, offset, &data, mode, hash).await?
+		},
+		PeerCmd::WriteDelta { path, .. } => delta::handle_write_delta(&mut stream, &path).await?,
+		PeerCmd::RemoveFile { path, .. } => handle_remove(&mut stream, &path, false).await?,
+		PeerCmd::RemoveFolder { path, .. } => handle_remove(&mut stream, &path, true).await?,
+		PeerCmd::CreateFolder { path, .. } => handle_create_folder(&mut stream, &path).await?,
+		PeerCmd::RenameFolder { path, new_name, .. } => handle_rename(&mut stream, &path, &new_name).await?,
+		PeerCmd::ListFolderContents { path, offset, length, recursive, .. } => {
+			handle_list_folder(&mut stream, &path, offset, length, recursive).await?
+		},
+		_ => stream.write_error(ERR_IO, "unsupported stream command").await?,
+	}
+	stream.finish()?;
+	// Wait for the requester to close its side so it sees our END first.
+	while stream.read_bytes().await?.is_some() {}
 	Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::folder;
+	use crate::testing::node;
+	use crate::testing::wait_for_peer;
+
+	#[tokio::test]
+	async fn test_is_shared() {
+		let shares = vec![
+			Share { path: PathBuf::from("/srv/media"), read_only: false },
+			Share { path: PathBuf::from("/srv/backup"), read_only: true },
+		];
+		assert!(is_shared(&shares, Path::new("/srv/media/movie.mkv"), true).await);
+		assert!(!is_shared(&shares, Path::new("/srv/mediaold/movie.mkv"), false).await);
+		assert!(!is_shared(&shares, Path::new("/srv/media/../../etc/passwd"), false).await);
+		assert!(is_shared(&shares, Path::new("/srv/backup/db.sql"), false).await);
+		assert!(!is_shared(&shares, Path::new("/srv/backup/db.sql"), true).await);
+		assert!(!is_shared(&[], Path::new("/etc/passwd"), false).await);
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn test_symlink_out_of_share() {
+		let dir = tempfile::tempdir().unwrap();
+		let share = dir.path().join("share");
+		let outside = dir.path().join("outside");
+		std::fs::create_dir_all(&share).unwrap();
+		std::fs::create_dir_all(&outside).unwrap();
+		std::os::unix::fs::symlink(&outside, share.join("link")).unwrap();
+		let shares = vec![Share { path: share.clone(), read_only: false }];
+
+		assert!(is_shared(&shares, &share.join("new.txt"), true).await);
+		assert!(!is_shared(&shares, &share.join("link/secret.txt"), false).await);
+		assert!(!is_shared(&shares, &share.join("link/new/file.txt"), true).await);
+	}
+
+	#[tokio::test]
+	async fn test_write_modes() {
+		use crate::protocol::PeerCmd;
+		use crate::WriteMode;
+
+		let dir = tempfile::tempdir().unwrap();
+		let share = folder(dir.path(), "share");
+		let a = node(dir.path(), "a").share(&share).build();
+		let a_info = a.info().await.unwrap();
+		let b = node(dir.path(), "b").peer(&a_info.listen_addrs[0]).build();
+		wait_for_peer(&b, &a_info.id).await;
+		let path = share.join("notes.txt");
+		let remote = path.to_str().unwrap();
+
+		b.write_file(&a_info.id, remote, 0, b"hello world".to_vec()).await.unwrap();
+		b.write_file(&a_info.id, remote, 0, b"HELLO".to_vec()).await.unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), b"HELLO world");
+		b.write_file_with_mode(&a_info.id, remote, 5, b"!".to_vec(), WriteMode::Truncate).await.unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), b"HELLO!");
+		b.write_file_with_mode(&a_info.id, remote, 0, b"?".to_vec(), WriteMode::Append).await.unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), b"HELLO!?");
+
+		// Data that does not match its hash never reaches the file.
+		let mut stream = b.request(PeerCmd::WriteFile {
+			node_id: a_info.id.clone(),
+			path: remote.to_string(),
+			offset: 0,
+			data: b"garbage".to_vec(),
+			mode: WriteMode::Truncate,
+			hash: Some([0; 32]),
+		}).await.unwrap();
+		stream.finish().unwrap();
+		assert!(stream.read_status().await.is_err());
+		assert_eq!(std::fs::read(&path).unwrap(), b"HELLO!?");
+		assert_eq!(std::fs::read_dir(&share).unwrap().count(), 1);
+	}
+}
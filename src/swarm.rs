@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::io::AsyncSeekExt;
+use tokio::io::AsyncWriteExt;
+
+use crate::block_store;
+use crate::block_store::BlockStore;
+use crate::builder::Share;
+use crate::builder::SyncFolder;
+use crate::chunk;
+use crate::chunk::Hash;
+use crate::delta;
+use crate::folder_sync;
+use crate::protocol::PeerCmd;
+use crate::protocol::FEATURE_SWARM;
+use crate::storage;
+use crate::stream;
+use crate::stream::Stream;
+use crate::transfer::Job;
+use crate::transfer::TransferKind;
+use crate::Pupynet;
+use crate::SwarmDownload;
+
+/// Files are fetched in pieces of whole chunks adding up to about this size.
+const PIECE_SIZE: u64 = 1024 * 1024;
+/// Pieces requested from one node at a time.
+const PIECES_PER_NODE: usize = 4;
+/// A node that failed this many pieces is not asked again.
+const MAX_FAILURES: u32 = 3;
+/// Recipes are sent in writes of about this size.
+const BATCH: usize = 64 * 1024;
+
+/// A range of the file made of the chunks `chunks` of the recipe.
+struct Piece {
+	offset: u64,
+	len: u64,
+	chunks: std::ops::Range<usize>,
+}
+
+fn pieces(recipe: &[(u32, Hash)]) -> Vec<Piece> {
+	let mut pieces: Vec<Piece> = Vec::new();
+	let mut offset = 0;
+	for (i, (len, _)) in recipe.iter().enumerate() {
+		match pieces.last_mut() {
+			Some(piece) if piece.len < PIECE_SIZE => {
+				piece.len += *len as u64;
+				piece.chunks.end = i + 1;
+			},
+			_ => pieces.push(Piece { offset, len: *len as u64, chunks: i..i + 1 }),
+		}
+		offset += *len as u64;
+	}
+	pieces
+}
+
+/// Checks that `data` is made of exactly the chunks of `recipe`.
+fn verify(data: &[u8], recipe: &[(u32, Hash)]) -> anyhow::Result<()> {
+	let mut offset = 0;
+	for (len, hash) in recipe {
+		let end = offset + *len as usize;
+		if end > data.len() || chunk::hash(&data[offset..end]) != *hash {
+			anyhow::bail!("chunk at {} does not match its hash", offset);
+		}
+		offset = end;
+	}
+	if offset != data.len() {
+		anyhow::bail!("got {} bytes instead of {}", data.len(), offset);
+	}
+	Ok(())
+}
+
+/// Chunks `path` the way `block_store::recipe` does and hashes it whole.
+fn file_recipe(path: &Path) -> std::io::Result<(Vec<(u32, Hash)>, Hash)> {
+	let file = std::fs::File::open(path)?;
+	let mut chunker = chunk::Chunker::new(std::io::BufReader::new(file));
+	let mut hasher = Sha256::new();
+	let mut recipe = Vec::new();
+	while let Some(chunk) = chunker.next_chunk()? {
+		hasher.update(&chunk);
+		recipe.push((chunk.len() as u32, chunk::hash(&chunk)));
+	}
+	Ok((recipe, hasher.finalize().into()))
+}
+
+/// Files that may hash to `hash`: the received ones the block store knows
+/// of and those of synced folders.
+async fn candidates(hash: &Hash, store: Option<&BlockStore>, folders: &[SyncFolder], data_dir: Option<&Path>) -> Vec<PathBuf> {
+	let mut files = match store {
+		Some(store) => store.files_with(hash).await,
+		None => Vec::new(),
+	};
+	let data_dir = match data_dir {
+		Some(dir) => dir,
+		None => return files,
+	};
+	for folder in folders {
+		let index = match folder_sync::load_index(&storage::sync_index_path(data_dir, &folder.id)).await {
+			Ok(index) => index,
+			Err(err) => {
+				log::warn!("could not load the index of {}: {:#}", folder.id, err);
+				continue;
+			},
+		};
+		for (rel, entry) in index.files {
+			if entry.deleted || entry.hash != *hash {
+				continue;
+			}
+			if let Ok(path) = folder_sync::resolve(&folder.path, &rel) {
+				files.push(path);
+			}
+		}
+	}
+	files
+}
+
+/// Serves a `FindContent`: a status, then whether we have a shared file
+/// with that content and, if so, its path, size and chunks. Candidates are
+/// hashed again before answering, as they may have changed since indexed.
+pub async fn handle_stream(mut stream: Stream, cmd: PeerCmd, shares: &[Share], store: Option<&BlockStore>, folders: &[SyncFolder], data_dir: Option<&Path>) -> anyhow::Result<()> {
+	let hash = match cmd {
+		PeerCmd::FindContent { hash, .. } => hash,
+		_ => anyhow::bail!("not a content lookup"),
+	};
+	let mut found = None;
+	for path in candidates(&hash, store, folders, data_dir).await {
+		if !stream::is_shared(shares, &path, false).await {
+			continue;
+		}
+		let file = path.clone();
+		match tokio::task::spawn_blocking(move || file_recipe(&file)).await? {
+			Ok((recipe, whole)) if whole == hash => {
+				found = Some((path, recipe));
+				break;
+			},
+			Ok(_) => {},
+			Err(err) => log::info!("could not hash {}: {}", path.display(), err),
+		}
+	}
+	stream.write_status(Ok(())).await?;
+	match found {
+		Some((path, recipe)) => {
+			stream.write_bytes(&[1]).await?;
+			stream.write_str(&path.to_string_lossy()).await?;
+			let size: u64 = recipe.iter().map(|(len, _)| *len as u64).sum();
+			stream.write_bytes(&size.to_le_bytes()).await?;
+			let mut batch = Vec::with_capacity(BATCH + 36);
+			for (len, hash) in &recipe {
+				batch.extend_from_slice(&len.to_le_bytes());
+				batch.extend_from_slice(hash);
+				if batch.len() >= BATCH {
+					stream.write_bytes(&batch).await?;
+					batch.clear();
+				}
+			}
+			batch.extend_from_slice(&0u32.to_le_bytes());
+			stream.write_bytes(&batch).await?;
+		},
+		None => stream.write_bytes(&[0]).await?,
+	}
+	stream.finish()?;
+	while stream.read_bytes().await?.is_some() {}
+	Ok(())
+}
+
+/// A node holding the file being downloaded.
+struct Holder {
+	node_id: String,
+	path: String,
+	busy: usize,
+	failures: u32,
+}
+
+impl Pupynet {
+	/// Where `node_id` keeps a file hashing to `hash` and the chunks it is
+	/// made of, `None` when it has none.
+	async fn find_content(&self, node_id: &str, hash: Hash) -> anyhow::Result<Option<(String, Vec<(u32, Hash)>)>> {
+		let mut stream = self.request(PeerCmd::FindContent { node_id: node_id.to_string(), hash }).await?;
+		stream.finish()?;
+		stream.read_status().await?;
+		if stream.read_u8().await? == 0 {
+			stream.read_to_end().await?;
+			return Ok(None);
+		}
+		let path = stream.read_str().await?;
+		let size = stream.read_u64().await?;
+		let recipe = block_store::read_recipe(&mut stream, size).await?;
+		stream.read_to_end().await?;
+		Ok(Some((path, recipe)))
+	}
+
+	async fn fetch_piece(&self, node_id: &str, path: &str, piece: &Piece, recipe: &[(u32, Hash)]) -> anyhow::Result<Vec<u8>> {
+		let mut data = Vec::with_capacity(piece.len as usize);
+		self.read_file_into(node_id, path, piece.offset, piece.len, &mut data, None).await?;
+		let chunks = recipe[piece.chunks.clone()].to_vec();
+		tokio::task::spawn_blocking(move || verify(&data, &chunks).map(|()| data)).await?
+	}
+
+	/// Downloads the file whose contents hash to `hash` (SHA-256) from every
+	/// reachable node that has it into `dest`, fetching pieces from several
+	/// of them at once. Every chunk is checked against its hash as it
+	/// arrives, and a piece that fails is fetched again from another node.
+	/// `dest` is only replaced once the whole file matches `hash`. While the
+	/// transfer is paused no new pieces are asked for.
+	pub async fn swarm_download(&self, hash: [u8; 32], dest: impl AsRef<Path>) -> anyhow::Result<SwarmDownload> {
+		let job = self.job(TransferKind::Download, None, &block_store::hex(&hash), None);
+		let res = job.run(self.swarm_download_job(&job, hash, dest.as_ref())).await;
+		job.finish(&res);
+		res
+	}
+
+	async fn swarm_download_job(&self, job: &Job, hash: Hash, dest: &Path) -> anyhow::Result<SwarmDownload> {
+		job.ready().await?;
+		let mut nodes = Vec::new();
+		for peer in self.peers().await? {
+			if peer.hops.is_some() && self.features(&peer.id).await? & FEATURE_SWARM != 0 {
+				nodes.push(peer.id);
+			}
+		}
+		let replies = futures_util::future::join_all(nodes.iter().map(|id| self.find_content(id, hash))).await;
+		let mut holders = Vec::new();
+		let mut recipes = Vec::new();
+		for (node_id, reply) in nodes.into_iter().zip(replies) {
+			match reply {
+				Ok(Some((path, chunks))) => {
+					// Equal contents are cut into equal chunks, so one recipe
+					// describes every copy. Others are only tried if it fails.
+					if !recipes.contains(&chunks) {
+						recipes.push(chunks);
+					}
+					holders.push(Holder { node_id, path, busy: 0, failures: 0 });
+				},
+				Ok(None) => {},
+				Err(err) => log::info!("could not ask {} for content: {}", node_id, err),
+			}
+		}
+		if recipes.is_empty() {
+			anyhow::bail!("no reachable node has {}", block_store::hex(&hash));
+		}
+		self.download(job, hash, &mut holders, recipes, dest).await
+	}
+
+	/// Fetches the file as described by the first recipe that yields
+	/// contents hashing to `hash`.
+	async fn download(&self, job: &Job, hash: Hash, holders: &mut [Holder], recipes: Vec<Vec<(u32, Hash)>>, dest: &Path) -> anyhow::Result<SwarmDownload> {
+		let mut error = None;
+		for recipe in recipes {
+			let temp = delta::temp_path(dest);
+			let res = self.fetch_into(job, hash, holders, &recipe, &temp).await;
+			match res {
+				Ok(sources) => {
+					tokio::fs::rename(&temp, dest).await?;
+					let size = recipe.iter().map(|(len, _)| *len as u64).sum();
+					return Ok(SwarmDownload { size, sources });
+				},
+				Err(err) => {
+					let _ = tokio::fs::remove_file(&temp).await;
+					log::info!("download of {} failed, trying the next recipe: {:#}", block_store::hex(&hash), err);
+					error = Some(err);
+				},
+			}
+		}
+		Err(error.unwrap_or_else(|| anyhow::anyhow!("no recipe for {}", block_store::hex(&hash))))
+	}
+
+	/// Writes the pieces of `recipe` to `path` and checks the result hashes
+	/// to `hash`. Returns how many bytes each node served.
+	async fn fetch_into(&self, job: &Job, hash: Hash, holders: &mut [Holder], recipe: &[(u32, Hash)], path: &Path) -> anyhow::Result<HashMap<String, u64>> {
+		for holder in holders.iter_mut() {
+			holder.failures = 0;
+		}
+		let pieces = pieces(recipe);
+		let size: u64 = recipe.iter().map(|(len, _)| *len as u64).sum();
+		job.set_total(size);
+		let mut file = tokio::fs::File::create(path).await?;
+		file.set_len(size).await?;
+		let mut sources: HashMap<String, u64> = HashMap::new();
+		let mut pending: VecDeque<usize> = (0..pieces.len()).collect();
+		let mut in_flight = FuturesUnordered::new();
+		let mut done = 0;
+		loop {
+			let usable = |holder: &Holder| holder.failures < MAX_FAILURES;
+			if in_flight.is_empty() && (pending.is_empty() || !holders.iter().any(usable)) {
+				break;
+			}
+			if in_flight.is_empty() {
+				job.ready().await?;
+			}
+			for (h, holder) in holders.iter_mut().enumerate() {
+				while job.is_running() && holder.busy < PIECES_PER_NODE && usable(holder) {
+					let Some(p) = pending.pop_front() else { break };
+					holder.busy += 1;
+					let (node_id, path) = (holder.node_id.clone(), holder.path.clone());
+					let piece = &pieces[p];
+					in_flight.push(async move { (h, p, self.fetch_piece(&node_id, &path, piece, recipe).await) });
+				}
+			}
+			let Some((h, p, res)) = in_flight.next().await else { continue };
+			let holder = &mut holders[h];
+			holder.busy -= 1;
+			match res {
+				Ok(bytes) => {
+					let piece = &pieces[p];
+					file.seek(SeekFrom::Start(piece.offset)).await?;
+					file.write_all(&bytes).await?;
+					*sources.entry(holder.node_id.clone()).or_default() += piece.len;
+					job.progress(piece.len);
+					done += 1;
+				},
+				Err(err) => {
+					log::info!("piece at {} from {} failed: {:#}", pieces[p].offset, holder.node_id, err);
+					holder.failures += 1;
+					pending.push_back(p);
+				},
+			}
+		}
+		if done != pieces.len() {
+			anyhow::bail!("{} of {} pieces could not be fetched from any node", pieces.len() - done, pieces.len());
+		}
+		file.sync_all().await?;
+		drop(file);
+		let file = path.to_path_buf();
+		let (_, whole) = tokio::task::spawn_blocking(move || file_recipe(&file)).await??;
+		if whole != hash {
+			anyhow::bail!("downloaded file does not match its hash");
+		}
+		Ok(sources)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::folder;
+	use crate::testing::node;
+	use crate::testing::wait_for_peer;
+
+	#[test]
+	fn test_pieces() {
+		let data: Vec<u8> = (0..3 * PIECE_SIZE as usize + 1234).map(|i| (i * 7 % 251) as u8).collect();
+		let recipe: Vec<(u32, Hash)> = chunk::chunks(&data).map(|c| (c.len() as u32, chunk::hash(c))).collect();
+		let pieces = pieces(&recipe);
+		assert!(pieces.len() >= 3);
+		let mut offset = 0;
+		for piece in &pieces {
+			assert_eq!(piece.offset, offset);
+			let bytes = &data[piece.offset as usize..(piece.offset + piece.len) as usize];
+			verify(bytes, &recipe[piece.chunks.clone()]).unwrap();
+			offset += piece.len;
+		}
+		assert_eq!(offset, data.len() as u64);
+
+		let mut bad = data[..pieces[0].len as usize].to_vec();
+		bad[10] ^= 1;
+		assert!(verify(&bad, &recipe[pieces[0].chunks.clone()]).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_falls_back_to_another_recipe() {
+		let dir = tempfile::tempdir().unwrap();
+		let share = folder(dir.path(), "share");
+		let a = node(dir.path(), "a").share(&share).build();
+		let a_info = a.info().await.unwrap();
+		let c = node(dir.path(), "c").peer(&a_info.listen_addrs[0]).build();
+		wait_for_peer(&c, &a_info.id).await;
+
+		let data: Vec<u8> = (0..3 * PIECE_SIZE as usize).map(|_| rand::random()).collect();
+		let path = share.join("file.bin");
+		std::fs::write(&path, &data).unwrap();
+		let real: Vec<(u32, Hash)> = chunk::chunks(&data).map(|c| (c.len() as u32, chunk::hash(c))).collect();
+		// Chunks that never match, and a recipe missing the last chunk whose
+		// pieces all verify but whose file does not hash right.
+		let garbage = vec![(real[0].0, [0; 32])];
+		let truncated = real[..real.len() - 1].to_vec();
+
+		let mut holders = vec![Holder { node_id: a_info.id.clone(), path: path.to_string_lossy().to_string(), busy: 0, failures: 0 }];
+		let job = c.job(TransferKind::Download, None, "file.bin", None);
+		let dest = dir.path().join("download.bin");
+		let download = c.download(&job, chunk::hash(&data), &mut holders, vec![garbage, truncated, real], &dest).await.unwrap();
+		assert_eq!(download.size, data.len() as u64);
+		assert!(std::fs::read(&dest).unwrap() == data);
+		// The failed attempts left nothing behind.
+		let names: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+		assert_eq!(names.len(), 4, "{:?}", names);
+	}
+
+	#[tokio::test]
+	async fn test_swarm_download() {
+		let dir = tempfile::tempdir().unwrap();
+		let (a_dir, b_dir) = (folder(dir.path(), "swarm-a"), folder(dir.path(), "swarm-b"));
+		let a = node(dir.path(), "a").share(&a_dir).build();
+		let b = node(dir.path(), "b").share(&b_dir).build();
+		let a_info = a.info().await.unwrap();
+		let b_info = b.info().await.unwrap();
+		let c = node(dir.path(), "c").peer(&a_info.listen_addrs[0]).peer(&b_info.listen_addrs[0]).build();
+		wait_for_peer(&c, &a_info.id).await;
+		wait_for_peer(&c, &b_info.id).await;
+		let data: Vec<u8> = (0..8 << 20).map(|_| rand::random()).collect();
+		let hash = crate::chunk::hash(&data);
+		let dest = folder(dir.path(), "swarm-c").join("download.bin");
+		assert!(c.swarm_download(hash, &dest).await.is_err());
+		assert!(!dest.exists());
+
+		c.sync_file(&a_info.id, a_dir.join("movie.bin").to_str().unwrap(), data.clone()).await.unwrap();
+		c.sync_file(&b_info.id, b_dir.join("film.bin").to_str().unwrap(), data.clone()).await.unwrap();
+		let download = c.swarm_download(hash, &dest).await.unwrap();
+		assert!(std::fs::read(&dest).unwrap() == data);
+		assert_eq!(download.size, data.len() as u64);
+		assert_eq!(download.sources.len(), 2);
+		assert_eq!(download.sources.values().sum::<u64>(), data.len() as u64);
+
+		// A copy that changed since it was received is no longer offered.
+		std::fs::write(a_dir.join("movie.bin"), b"changed").unwrap();
+		let download = c.swarm_download(hash, &dest).await.unwrap();
+		assert_eq!(download.sources.keys().collect::<Vec<_>>(), vec![&b_info.id]);
+	}
+}
@@ -1,21 +1,52 @@
+use std::time::Duration;
 
-use hyper::server::conn::http1;
-use hyper::service::service_fn;
-use tokio::io::AsyncReadExt;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
 
 use crate::connection::Connection;
-use crate::multiplex::Multiplexer;
 use crate::types::Context;
 
 
-pub async fn bind(addr: &str, ctx: Context) {
-	let listener = TcpListener::bind(addr).await.expect("Failed to bind");
-	while let Ok((stream, _)) = listener.accept().await {
+pub async fn bind(addr: &str) -> anyhow::Result<TcpListener> {
+	let listener = TcpListener::bind(addr.trim_start_matches("tcp://")).await?;
+	log::info!("listening on tcp://{}", listener.local_addr()?);
+	Ok(listener)
+}
+
+/// Pause after a failed accept, so running out of file descriptors does
+/// not turn into a busy loop.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Accepts connections until the node shuts down. Failed accepts are
+/// reported and retried.
+pub async fn accept(listener: TcpListener, ctx: Context) {
+	let mut shutdown = ctx.shutdown.clone();
+	let local = listener.local_addr().map(|addr| format!("tcp://{}", addr)).ok();
+	loop {
+		let res = tokio::select! {
+			res = listener.accept() => res,
+			_ = shutdown.changed() => return,
+		};
+		let (stream, peer_addr) = match res {
+			Ok(res) => res,
+			Err(err) => {
+				ctx.report(local.as_deref(), anyhow::Error::new(err).context("accept failed"));
+				tokio::time::sleep(ACCEPT_BACKOFF).await;
+				continue;
+			}
+		};
 		let ctx = ctx.clone();
 		tokio::spawn(async move {
-			Connection::new(stream, ctx).run().await;
+			Connection::new(stream, format!("tcp://{}", peer_addr), false, ctx).run().await;
 		});
 	}
-}
\ No newline at end of file
+}
+
+/// Dials `addr` and runs the connection until it closes. An error means the
+/// connection could not be established at all.
+pub async fn connect(addr: &str, ctx: Context) -> anyhow::Result<()> {
+	let stream = TcpStream::connect(addr.trim_start_matches("tcp://")).await?;
+	log::info!("connected to {}", addr);
+	Connection::new(stream, addr.to_string(), true, ctx).run().await;
+	Ok(())
+}
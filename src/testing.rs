@@ -0,0 +1,49 @@
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::Pupynet;
+use crate::PupynetBuilder;
+use crate::PupynetEvent;
+
+/// How long a test waits on a node before failing.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A node listening on a free local port, keeping its data in `dir`.
+pub fn node(dir: &Path, name: &str) -> PupynetBuilder {
+	Pupynet::builder()
+		.name(name)
+		.data_dir(dir.join(name))
+		.no_discovery()
+		.listen("tcp://127.0.0.1:0")
+}
+
+/// Creates the folder `name` in `dir`.
+pub fn folder(dir: &Path, name: &str) -> PathBuf {
+	let path = dir.join(name);
+	std::fs::create_dir_all(&path).unwrap();
+	path
+}
+
+/// Waits for an event of `node` matching `f`.
+pub async fn wait_for(node: &mut Pupynet, f: impl Fn(&PupynetEvent) -> bool) {
+	let wait = async {
+		while let Some(event) = node.next().await {
+			if f(&event) {
+				return;
+			}
+		}
+		panic!("node stopped");
+	};
+	tokio::time::timeout(TIMEOUT, wait).await.unwrap();
+}
+
+/// Waits until `node` has a route to `id`.
+pub async fn wait_for_peer(node: &Pupynet, id: &str) {
+	let wait = async {
+		while !node.peers().await.unwrap().iter().any(|p| p.id == id && p.hops.is_some()) {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+	};
+	tokio::time::timeout(TIMEOUT, wait).await.unwrap();
+}
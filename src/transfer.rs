@@ -0,0 +1,483 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::sync::watch;
+
+use crate::stream::Stream;
+use crate::stream::StreamControl;
+use crate::PupynetEvent;
+
+/// Transfers running at once unless configured otherwise.
+pub const MAX_TRANSFERS: usize = 4;
+/// Progress of a running transfer is reported at most this often.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+/// The rate is measured over windows of about this length.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+/// Finished transfers kept around for `Pupynet::transfers`.
+const FINISHED_KEPT: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferKind {
+	Read,
+	Write,
+	Sync,
+	/// Fetched by content hash from every node that has it.
+	Download,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferState {
+	/// Waiting for one of the running transfers to end.
+	Queued,
+	Running,
+	Paused,
+	Done,
+	Failed,
+	Cancelled,
+}
+
+impl TransferState {
+	pub fn is_finished(&self) -> bool {
+		matches!(self, TransferState::Done | TransferState::Failed | TransferState::Cancelled)
+	}
+}
+
+/// Queued transfers start in order of priority, then in the order they
+/// were started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+	Low,
+	#[default]
+	Normal,
+	High,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferInfo {
+	pub id: u64,
+	pub kind: TransferKind,
+	/// `None` for downloads from several nodes.
+	pub node_id: Option<String>,
+	/// Path on the remote node, or the content hash of a download.
+	pub path: String,
+	pub priority: Priority,
+	pub state: TransferState,
+	/// Bytes transferred so far.
+	pub done: u64,
+	/// `None` until known.
+	pub total: Option<u64>,
+	/// Bytes per second over the last second or so.
+	pub rate: u64,
+	/// Seconds left at the current rate.
+	pub eta_secs: Option<u64>,
+	/// Why a failed transfer failed.
+	pub error: Option<String>,
+}
+
+struct Entry {
+	info: TransferInfo,
+	/// The stream carrying the transfer, paused along with it.
+	stream: Option<StreamControl>,
+	/// Start and bytes done of the current rate window.
+	window: (Instant, u64),
+	reported: Instant,
+}
+
+struct Inner {
+	next_id: u64,
+	limit: usize,
+	entries: BTreeMap<u64, Entry>,
+}
+
+/// Every transfer of a node. Runs at most `limit` of them at once, the
+/// rest wait in order of priority.
+pub struct TransferManager {
+	inner: Mutex<Inner>,
+	/// Bumped on every change of state, for jobs waiting on theirs.
+	changed: watch::Sender<()>,
+	event_tx: broadcast::Sender<PupynetEvent>,
+}
+
+impl TransferManager {
+	pub fn new(limit: usize, event_tx: broadcast::Sender<PupynetEvent>) -> TransferManager {
+		TransferManager {
+			inner: Mutex::new(Inner { next_id: 1, limit: limit.max(1), entries: BTreeMap::new() }),
+			changed: watch::channel(()).0,
+			event_tx,
+		}
+	}
+
+	fn lock(&self) -> MutexGuard<'_, Inner> {
+		self.inner.lock().unwrap_or_else(|err| err.into_inner())
+	}
+
+	fn emit(&self, entry: &mut Entry) {
+		entry.reported = Instant::now();
+		let _ = self.event_tx.send(PupynetEvent::TransferUpdated { transfer: entry.info.clone() });
+	}
+
+	/// Moves `entry` to `state`, pausing or resuming its stream when it
+	/// stops or starts running.
+	fn set_state(&self, entry: &mut Entry, state: TransferState) {
+		let was_running = entry.info.state == TransferState::Running;
+		entry.info.state = state;
+		let running = state == TransferState::Running;
+		if was_running != running && !state.is_finished() {
+			if let Some(stream) = &entry.stream {
+				stream.pause(!running);
+			}
+		}
+		if running {
+			entry.window = (Instant::now(), entry.info.done);
+		} else {
+			entry.info.rate = 0;
+			entry.info.eta_secs = None;
+		}
+		self.emit(entry);
+	}
+
+	/// Starts queued transfers while there is room, highest priority first.
+	fn schedule(&self, inner: &mut Inner) {
+		let running = inner.entries.values().filter(|e| e.info.state == TransferState::Running).count();
+		for _ in running..inner.limit {
+			let next = inner.entries.values_mut()
+				.filter(|e| e.info.state == TransferState::Queued)
+				.max_by_key(|e| (e.info.priority, Reverse(e.info.id)));
+			match next {
+				Some(entry) => self.set_state(entry, TransferState::Running),
+				None => break,
+			}
+		}
+		self.changed.send_replace(());
+	}
+
+	pub fn set_limit(&self, limit: usize) {
+		let mut inner = self.lock();
+		inner.limit = limit.max(1);
+		self.schedule(&mut inner);
+	}
+
+	/// Registers a transfer, queued until `schedule` lets it run.
+	pub fn add(self: &Arc<Self>, kind: TransferKind, node_id: Option<&str>, path: &str, total: Option<u64>, priority: Priority) -> Job {
+		let mut inner = self.lock();
+		let id = inner.next_id;
+		inner.next_id += 1;
+		let now = Instant::now();
+		let mut entry = Entry {
+			info: TransferInfo {
+				id,
+				kind,
+				node_id: node_id.map(str::to_string),
+				path: path.to_string(),
+				priority,
+				state: TransferState::Queued,
+				done: 0,
+				total,
+				rate: 0,
+				eta_secs: None,
+				error: None,
+			},
+			stream: None,
+			window: (now, 0),
+			reported: now,
+		};
+		self.emit(&mut entry);
+		inner.entries.insert(id, entry);
+		self.schedule(&mut inner);
+		Job { id, manager: self.clone(), finished: false }
+	}
+
+	pub fn list(&self) -> Vec<TransferInfo> {
+		self.lock().entries.values().map(|e| e.info.clone()).collect()
+	}
+
+	fn update(&self, id: u64, f: impl FnOnce(&Self, &mut Entry) -> anyhow::Result<()>) -> anyhow::Result<()> {
+		let mut inner = self.lock();
+		let entry = match inner.entries.get_mut(&id) {
+			Some(entry) => entry,
+			None => anyhow::bail!("no transfer {}", id),
+		};
+		if entry.info.state.is_finished() {
+			anyhow::bail!("transfer {} already ended", id);
+		}
+		f(self, entry)?;
+		self.schedule(&mut inner);
+		Ok(())
+	}
+
+	/// Stops a transfer until `resume`, letting a queued one take its place.
+	pub fn pause(&self, id: u64) -> anyhow::Result<()> {
+		self.update(id, |manager, entry| {
+			if entry.info.state != TransferState::Paused {
+				manager.set_state(entry, TransferState::Paused);
+			}
+			Ok(())
+		})
+	}
+
+	/// Queues a paused transfer again.
+	pub fn resume(&self, id: u64) -> anyhow::Result<()> {
+		self.update(id, |manager, entry| {
+			if entry.info.state == TransferState::Paused {
+				manager.set_state(entry, TransferState::Queued);
+			}
+			Ok(())
+		})
+	}
+
+	pub fn cancel(&self, id: u64) -> anyhow::Result<()> {
+		self.update(id, |manager, entry| {
+			manager.set_state(entry, TransferState::Cancelled);
+			Ok(())
+		})
+	}
+
+	/// Only changes the order of queued transfers; running ones keep running.
+	pub fn set_priority(&self, id: u64, priority: Priority) -> anyhow::Result<()> {
+		self.update(id, |manager, entry| {
+			entry.info.priority = priority;
+			manager.emit(entry);
+			Ok(())
+		})
+	}
+
+	fn end(&self, id: u64, state: TransferState, error: Option<String>) {
+		let mut inner = self.lock();
+		if let Some(entry) = inner.entries.get_mut(&id) {
+			entry.stream = None;
+			if !entry.info.state.is_finished() {
+				entry.info.error = error;
+				self.set_state(entry, state);
+			}
+		}
+		let finished: Vec<u64> = inner.entries.values()
+			.filter(|e| e.info.state.is_finished())
+			.map(|e| e.info.id)
+			.collect();
+		for id in finished.iter().take(finished.len().saturating_sub(FINISHED_KEPT)) {
+			inner.entries.remove(id);
+		}
+		self.schedule(&mut inner);
+	}
+}
+
+/// The side of a transfer carrying it out. Dropping it before `finish`
+/// counts as cancelled.
+pub struct Job {
+	id: u64,
+	manager: Arc<TransferManager>,
+	finished: bool,
+}
+
+impl Job {
+	fn state(&self) -> TransferState {
+		match self.manager.lock().entries.get(&self.id) {
+			Some(entry) => entry.info.state,
+			None => TransferState::Cancelled,
+		}
+	}
+
+	pub fn is_running(&self) -> bool {
+		self.state() == TransferState::Running
+	}
+
+	fn cancelled(&self) -> anyhow::Error {
+		anyhow::anyhow!("transfer {} was cancelled", self.id)
+	}
+
+	/// Waits until the transfer may run, failing once it is cancelled.
+	pub async fn ready(&self) -> anyhow::Result<()> {
+		let mut changed = self.manager.changed.subscribe();
+		loop {
+			match self.state() {
+				TransferState::Running => return Ok(()),
+				TransferState::Cancelled => return Err(self.cancelled()),
+				_ => {},
+			}
+			// The manager outlives us, so the channel stays open.
+			let _ = changed.changed().await;
+		}
+	}
+
+	/// Runs `fut` unless the transfer gets cancelled first. Dropping `fut`
+	/// drops its streams, which tells the remote with `STREAM_DIED`.
+	pub async fn run<T>(&self, fut: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+		let mut changed = self.manager.changed.subscribe();
+		tokio::pin!(fut);
+		loop {
+			if self.state() == TransferState::Cancelled {
+				return Err(self.cancelled());
+			}
+			tokio::select! {
+				res = &mut fut => return res,
+				_ = changed.changed() => {},
+			}
+		}
+	}
+
+	/// Pauses `stream` whenever the transfer is not running.
+	pub fn attach(&self, stream: &Stream) {
+		let control = stream.control();
+		let mut inner = self.manager.lock();
+		if let Some(entry) = inner.entries.get_mut(&self.id) {
+			if entry.info.state != TransferState::Running {
+				control.pause(true);
+			}
+			entry.stream = Some(control);
+		}
+	}
+
+	pub fn set_total(&self, total: u64) {
+		if let Some(entry) = self.manager.lock().entries.get_mut(&self.id) {
+			entry.info.total = Some(total);
+		}
+	}
+
+	/// Counts `bytes` more as transferred.
+	pub fn progress(&self, bytes: u64) {
+		let mut inner = self.manager.lock();
+		let entry = match inner.entries.get_mut(&self.id) {
+			Some(entry) => entry,
+			None => return,
+		};
+		entry.info.done += bytes;
+		let now = Instant::now();
+		let elapsed = now - entry.window.0;
+		if elapsed >= RATE_WINDOW {
+			entry.info.rate = ((entry.info.done - entry.window.1) as f64 / elapsed.as_secs_f64()) as u64;
+			entry.window = (now, entry.info.done);
+			entry.info.eta_secs = match (entry.info.total, entry.info.rate) {
+				(Some(total), rate) if rate > 0 => Some(total.saturating_sub(entry.info.done) / rate),
+				_ => None,
+			};
+		}
+		if now - entry.reported >= PROGRESS_INTERVAL {
+			self.manager.emit(entry);
+		}
+	}
+
+	/// Records how the transfer ended.
+	pub fn finish<T>(mut self, res: &anyhow::Result<T>) {
+		self.finished = true;
+		match res {
+			Ok(_) => self.manager.end(self.id, TransferState::Done, None),
+			Err(err) => self.manager.end(self.id, TransferState::Failed, Some(format!("{:#}", err))),
+		}
+	}
+}
+
+impl Drop for Job {
+	fn drop(&mut self) {
+		if !self.finished {
+			self.manager.end(self.id, TransferState::Cancelled, None);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::compress::Compression;
+	use crate::testing::folder;
+	use crate::testing::node;
+	use crate::testing::wait_for_peer;
+	use crate::Pupynet;
+
+	fn states(manager: &TransferManager) -> Vec<TransferState> {
+		manager.list().iter().map(|t| t.state).collect()
+	}
+
+	#[tokio::test]
+	async fn test_queue() {
+		use TransferState::*;
+
+		let (event_tx, _) = broadcast::channel(64);
+		let manager = Arc::new(TransferManager::new(1, event_tx));
+		let first = manager.add(TransferKind::Read, Some("a"), "/1", None, Priority::Normal);
+		let low = manager.add(TransferKind::Read, Some("a"), "/2", None, Priority::Low);
+		let high = manager.add(TransferKind::Read, Some("a"), "/3", None, Priority::High);
+		assert_eq!(states(&manager), vec![Running, Queued, Queued]);
+
+		// A paused transfer makes room for the next one by priority.
+		manager.pause(1).unwrap();
+		assert_eq!(states(&manager), vec![Paused, Queued, Running]);
+		high.ready().await.unwrap();
+		manager.resume(1).unwrap();
+		high.finish(&Ok(()));
+		assert_eq!(states(&manager), vec![Running, Queued, Done]);
+		first.ready().await.unwrap();
+
+		manager.cancel(2).unwrap();
+		assert!(low.ready().await.is_err());
+		assert!(low.run(std::future::pending::<anyhow::Result<()>>()).await.is_err());
+		drop(low);
+		drop(first);
+		assert_eq!(states(&manager), vec![Cancelled, Cancelled, Done]);
+		assert!(manager.pause(3).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_transfer_control() {
+		use crate::TransferState;
+
+		let dir = tempfile::tempdir().unwrap();
+		let share = folder(dir.path(), "transfers");
+		let file = share.join("big.bin");
+		let mut data = vec![0; 48 << 20];
+		rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut data);
+		std::fs::write(&file, &data).unwrap();
+		let a = node(dir.path(), "a").share(&share).build();
+		let a_info = a.info().await.unwrap();
+		let b = node(dir.path(), "b").peer(&a_info.listen_addrs[0]).build();
+		let b_info = b.info().await.unwrap();
+		let c = node(dir.path(), "c").peer(&b_info.listen_addrs[0]).compression(Compression::None).build();
+		wait_for_peer(&c, &a_info.id).await;
+		let remote = file.to_str().unwrap().to_string();
+		let done = |c: &Pupynet| c.transfers().last().map(|t| (t.state, t.done)).unwrap_or((TransferState::Queued, 0));
+
+		// Paused mid-stream, the sender two hops away stops until resumed.
+		let read = tokio::spawn({
+			let (c, id, remote) = (c.clone(), a_info.id.clone(), remote.clone());
+			async move { c.read_file(&id, &remote, 0, u64::MAX).await }
+		});
+		while done(&c).1 == 0 {
+			tokio::time::sleep(Duration::from_millis(1)).await;
+		}
+		let id = c.transfers().last().unwrap().id;
+		c.pause_transfer(id).unwrap();
+		tokio::time::sleep(Duration::from_millis(300)).await;
+		let paused = done(&c);
+		tokio::time::sleep(Duration::from_millis(300)).await;
+		assert_eq!(done(&c), paused);
+		assert_eq!(paused.0, TransferState::Paused);
+		assert!(paused.1 < data.len() as u64);
+		c.resume_transfer(id).unwrap();
+		assert!(read.await.unwrap().unwrap() == data);
+		assert_eq!(done(&c), (TransferState::Done, data.len() as u64));
+
+		// A cancelled read fails, and the links stay usable.
+		let read = tokio::spawn({
+			let (c, id, remote) = (c.clone(), a_info.id.clone(), remote.clone());
+			async move { c.read_file(&id, &remote, 0, u64::MAX).await }
+		});
+		while done(&c).0 != TransferState::Running {
+			tokio::time::sleep(Duration::from_millis(1)).await;
+		}
+		c.cancel_transfer(c.transfers().last().unwrap().id).unwrap();
+		assert!(read.await.unwrap().is_err());
+		assert_eq!(done(&c).0, TransferState::Cancelled);
+		assert_eq!(c.read_file(&a_info.id, &remote, 0, 4).await.unwrap(), data[..4]);
+	}
+}
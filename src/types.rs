@@ -1,18 +1,171 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use std::time::Instant;
 
+use bytes::Bytes;
+use serde::Deserialize;
+use serde::Serialize;
 use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
 
+use crate::builder::Config;
+use crate::compress::Compression;
+use crate::flow::Budget;
+use crate::flow::Credit;
+use crate::multiplex::FrameFormat;
+use crate::multiplex::StreamClass;
 use crate::protocol::PeerCmd;
+use crate::protocol::StreamHeader;
+use crate::protocol::LEGACY_VERSION;
+use crate::protocol::STREAM_CONTINUE;
+use crate::rate::RateLimiter;
+use crate::stream::Hold;
+use crate::stream::Stream;
+use crate::stream::StreamMsg;
+use crate::Metrics;
+use crate::NodeInfo;
+use crate::PeerInfo;
 
+#[derive(Debug)]
 pub enum PeerConnCmd {
 	Close,
-	Send(Vec<u8>)
+	Send(Vec<u8>),
+	/// Opens an outbound substream; incoming data for it goes to `data_tx`,
+	/// as much as its window or the connection's inbound budget allows.
+	OpenStream {
+		stream_id: u64,
+		header: Vec<u8>,
+		data_tx: mpsc::UnboundedSender<StreamMsg>,
+		hold: Weak<watch::Sender<Hold>>,
+		class: StreamClass
+	},
+	StreamData {
+		stream_id: u64,
+		stage: u8,
+		data: Bytes
+	},
+	/// Lets the remote write `bytes` more to a stream we have read from.
+	Grant {
+		stream_id: u64,
+		bytes: u32
+	},
+	/// Switches how later frames are written, once the peer told us what
+	/// it can decode.
+	SetFrameFormat(FrameFormat),
+	/// `FEATURE_*` bits both ends support, once the peer introduced itself.
+	SetFeatures(u64),
+	/// Node id of the peer, once it introduced itself.
+	SetPeer(String)
+}
+
+impl PeerConnCmd {
+	fn len(&self) -> usize {
+		match self {
+			PeerConnCmd::Send(data) => data.len(),
+			PeerConnCmd::StreamData { data, .. } => data.len(),
+			PeerConnCmd::OpenStream { header, .. } => header.len(),
+			PeerConnCmd::Close | PeerConnCmd::Grant { .. } | PeerConnCmd::SetFrameFormat(_) | PeerConnCmd::SetFeatures(_) | PeerConnCmd::SetPeer(_) => 0,
+		}
+	}
+}
+
+/// Sending half of a connection's outgoing queue, with its byte budgets.
+/// Stream data waits for room, control messages fail once the peer has
+/// stopped draining them. The budgets bound the queue rather than its
+/// length, as a message may be a byte or a whole frame.
+#[derive(Debug, Clone)]
+pub struct PeerTx {
+	tx: mpsc::UnboundedSender<(PeerConnCmd, Credit)>,
+	control: Budget,
+	data: Budget,
+	/// Incoming data not yet read, for peers without `FEATURE_WINDOW`.
+	/// Others are held to the window of each stream.
+	pub inbound: Budget,
+	/// Incoming data not yet read, on all streams.
+	unread: Arc<AtomicUsize>,
+	/// When anything was last read from the socket.
+	received: Arc<Mutex<Instant>>,
+}
+
+impl PeerTx {
+	/// A queue allowing `buffer` bytes in each of its budgets.
+	pub fn new(buffer: usize) -> (PeerTx, mpsc::UnboundedReceiver<(PeerConnCmd, Credit)>) {
+		let (tx, rx) = mpsc::unbounded_channel();
+		let unread = Arc::new(AtomicUsize::new(0));
+		let tx = PeerTx {
+			tx,
+			control: Budget::new(buffer),
+			data: Budget::new(buffer),
+			inbound: Budget::metered(buffer, unread.clone()),
+			unread,
+			received: Arc::new(Mutex::new(Instant::now())),
+		};
+		(tx, rx)
+	}
+
+	pub fn mark_received(&self) {
+		*self.received.lock().unwrap() = Instant::now();
+	}
+
+	pub fn last_received(&self) -> Instant {
+		*self.received.lock().unwrap()
+	}
+
+	/// Queues `cmd` without waiting. Closing, ending and opening streams and
+	/// granting windows are not charged: their number is bounded by the
+	/// streams themselves and the data read from them.
+	pub fn send(&self, cmd: PeerConnCmd) -> anyhow::Result<()> {
+		let credit = match &cmd {
+			PeerConnCmd::Send(_) | PeerConnCmd::StreamData { stage: STREAM_CONTINUE, .. } => {
+				match self.control.try_take(cmd.len()) {
+					Some(credit) => credit,
+					None => anyhow::bail!("send queue full"),
+				}
+			},
+			_ => Credit::default(),
+		};
+		self.tx.send((cmd, credit)).map_err(|_| anyhow::anyhow!("connection closed"))
+	}
+
+	/// Queues data for a stream, waiting while the connection is backed up.
+	pub async fn send_data(&self, stream_id: u64, data: Bytes) -> anyhow::Result<()> {
+		let credit = self.data.take(data.len()).await;
+		let cmd = PeerConnCmd::StreamData { stream_id, stage: STREAM_CONTINUE, data };
+		self.tx.send((cmd, credit)).map_err(|_| anyhow::anyhow!("connection closed"))
+	}
+
+	/// Resolves once the connection has stopped taking commands.
+	pub async fn closed(&self) {
+		self.tx.closed().await
+	}
+
+	/// Bytes waiting to be written to the socket.
+	pub fn queued(&self) -> usize {
+		self.control.used() + self.data.used()
+	}
+
+	/// Receive window of `size` bytes for one stream.
+	pub fn window(&self, size: usize) -> Budget {
+		Budget::metered(size, self.unread.clone())
+	}
+
+	/// Bytes received but not yet read by their stream.
+	pub fn unread(&self) -> usize {
+		self.unread.load(Ordering::Relaxed)
+	}
 }
 
 pub enum InternalEvent {
 	PeerConnected {
 		addr: String,
-		tx: mpsc::UnboundedSender<PeerConnCmd>
+		outbound: bool,
+		tx: PeerTx
 	},
 	PeerDisconnected {
 		addr: String
@@ -20,6 +173,23 @@ pub enum InternalEvent {
 	PeerCmd {
 		addr: String,
 		cmd: PeerCmd
+	},
+	StreamOpened {
+		addr: String,
+		header: StreamHeader,
+		stream: Stream
+	},
+	/// A supervised outbound connection failed or dropped and will be redialed.
+	Reconnecting {
+		addr: String,
+		attempt: u32,
+		delay: std::time::Duration
+	},
+	/// A background task hit an error it recovered from or that ended a
+	/// single connection; the worker logs it and tells the application.
+	Error {
+		addr: Option<String>,
+		error: anyhow::Error
 	}
 }
 
@@ -30,36 +200,225 @@ pub enum InternalCommand {
 	Connect {
 		addr: String
 	},
-	PeerCmd {
-		addr: String,
-		cmd: PeerCmd
+	ForgetPeer {
+		id: String,
+		propagate: bool
+	},
+	SetTrust {
+		id: String,
+		trust: TrustState
+	},
+	SetKeepalive {
+		interval: std::time::Duration,
+		timeout: std::time::Duration
+	},
+	/// Opens a substream towards the node named by the command's `node_id`.
+	OpenStream {
+		cmd: PeerCmd,
+		reply: oneshot::Sender<anyhow::Result<Stream>>
+	},
+	Info {
+		reply: oneshot::Sender<NodeInfo>
+	},
+	/// Features both we and `node_id` support.
+	Features {
+		node_id: String,
+		reply: oneshot::Sender<u64>
+	},
+	/// The codec to ask for when reading from `node_id`.
+	Compression {
+		node_id: String,
+		reply: oneshot::Sender<Compression>
+	},
+	Metrics {
+		reply: oneshot::Sender<Metrics>
+	},
+	Peers {
+		reply: oneshot::Sender<Vec<PeerInfo>>
+	},
+	/// Stops the node, replying once everything has wound down.
+	Shutdown {
+		reply: oneshot::Sender<()>
+	},
+	/// Applies a changed configuration to the running node.
+	Reload {
+		config: Box<Config>
 	}
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrustState {
+	#[default]
+	Unknown,
+	Trusted,
+	Blocked,
+}
+
+/// How many of the most recently seen addresses are remembered per peer.
+pub const MAX_PEER_ADDRS: usize = 8;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Peer {
 	pub id: String,
 	pub name: String,
 	pub owner: Option<String>,
+	#[serde(skip)]
 	pub introduced: bool,
+	/// Addresses the peer was last seen at, most recent first.
+	#[serde(default)]
+	pub addrs: Vec<String>,
+	/// Unix timestamp in seconds.
+	#[serde(default)]
+	pub last_seen: u64,
+	#[serde(default)]
+	pub trust: TrustState,
+	/// `FEATURE_*` bits the peer advertised, directly or through gossip.
+	#[serde(default)]
+	pub features: u64,
+}
+
+impl Peer {
+	pub fn seen_at(&mut self, addr: &str) {
+		self.addrs.retain(|a| a != addr);
+		self.addrs.insert(0, addr.to_string());
+		self.addrs.truncate(MAX_PEER_ADDRS);
+		self.last_seen = unix_now();
+	}
 }
 
 #[derive(Debug, Default)]
 pub struct State {
 	pub me: Peer,
-	pub peers: HashMap<String, Peer>
+	pub peers: HashMap<String, Peer>,
+	/// Peers we were told to forget. They are refused until trusted again,
+	/// so neither gossip nor their own introduction brings them back.
+	pub forgotten: HashSet<String>
 }
 
 impl State {
+	pub fn peer_by_addr(&self, addr: &str) -> Option<&Peer> {
+		self.peers.values().find(|p| p.addrs.iter().any(|a| a == addr))
+	}
 
+	pub fn same_owner(&self, peer: &Peer) -> bool {
+		match (&self.me.owner, &peer.owner) {
+			(Some(me), Some(other)) => !me.is_empty() && me == other,
+			_ => false
+		}
+	}
+
+	/// Whether `peer` may act as one of our owner's nodes. Any node can
+	/// claim our owner, so it must also have been trusted explicitly.
+	pub fn is_own_node(&self, peer: &Peer) -> bool {
+		peer.trust == TrustState::Trusted && self.same_owner(peer)
+	}
 }
 
-pub struct Context {
+pub fn unix_now() -> u64 {
+	std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or_default()
+}
+
+/// Failures that may go away once a dropped link is re-established, as
+/// opposed to errors reported by the remote node itself.
+#[derive(Debug)]
+pub enum TransientError {
+	NoRoute(String),
+	StreamDied(u64),
+}
+
+impl std::fmt::Display for TransientError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			TransientError::NoRoute(id) => write!(f, "no route to {}", id),
+			TransientError::StreamDied(id) => write!(f, "stream {} died", id),
+		}
+	}
+}
+
+impl std::error::Error for TransientError {}
+
+/// Whether a transfer that failed with `err` should be tried again. Having
+/// no route only counts once an earlier attempt got through, so requests to
+/// unreachable nodes fail at once.
+pub fn can_resume(err: &anyhow::Error, started: bool) -> bool {
+	match err.downcast_ref::<TransientError>() {
+		Some(TransientError::NoRoute(_)) => started,
+		Some(TransientError::StreamDied(_)) => true,
+		None => false,
+	}
+}
+
+/// A live transport connection as seen by the worker.
+pub struct PeerConn {
+	pub tx: PeerTx,
+	/// Set once the remote side has introduced itself.
+	pub peer_id: Option<String>,
+	/// The dialing side uses odd stream ids and the accepting side even ones,
+	/// so both ends can open streams without coordinating.
+	pub next_stream_id: u64,
+	/// Keepalives not answered yet by seq, and when they were sent.
+	pub hellos: HashMap<u64, Instant>,
+	pub rtt: Option<std::time::Duration>,
+	/// The remote said goodbye and is about to close the connection.
+	pub leaving: bool,
+	/// Protocol version and `FEATURE_*` bits agreed on at introduction.
+	pub version: u16,
+	pub features: u64,
+}
+
+impl PeerConn {
+	pub fn new(tx: PeerTx, outbound: bool) -> PeerConn {
+		PeerConn {
+			tx,
+			peer_id: None,
+			next_stream_id: if outbound { 1 } else { 2 },
+			hellos: HashMap::new(),
+			rtt: None,
+			leaving: false,
+			version: LEGACY_VERSION,
+			features: 0,
+		}
+	}
 
+	pub fn open_stream(&mut self, header: &StreamHeader) -> anyhow::Result<Stream> {
+		let stream_id = self.next_stream_id;
+		self.next_stream_id += 2;
+		let (data_tx, data_rx) = mpsc::unbounded_channel();
+		let stream = Stream::new(stream_id, data_rx, self.tx.clone());
+		self.tx.send(PeerConnCmd::OpenStream {
+			stream_id,
+			header: header.serialize(),
+			data_tx,
+			hold: stream.hold(),
+			class: header.cmd.stream_class()
+		})?;
+		Ok(stream)
+	}
+}
+
+#[derive(Clone)]
+pub struct Context {
+	pub internal_event_tx: mpsc::Sender<InternalEvent>,
+	/// Flips to `true` when the node shuts down.
+	pub shutdown: watch::Receiver<bool>,
+	/// Bytes each connection may queue to write and each of its streams
+	/// may hold unread.
+	pub peer_buffer: usize,
+	/// Largest wide frame connections accept.
+	pub max_frame: u32,
+	pub limiter: Arc<RateLimiter>,
 }
 
-impl Clone for Context {
-	fn clone(&self) -> Self {
-		Context {}
+impl Context {
+	/// Hands an error to the worker, to be surfaced as `PupynetEvent::Error`.
+	/// Dropped when the worker is too far behind to take it.
+	pub fn report(&self, addr: Option<&str>, error: anyhow::Error) {
+		let event = InternalEvent::Error { addr: addr.map(str::to_string), error };
+		if let Err(err) = self.internal_event_tx.try_send(event) {
+			log::debug!("dropping error report: {}", err);
+		}
 	}
 }
\ No newline at end of file
@@ -1,50 +1,84 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use crate::protocol::PeerCmd;
+use crate::types::Context;
 use crate::types::InternalEvent;
-use crate::PupynetEvent;
 
-fn process_socket(socket: Arc<UdpSocket>, tx: mpsc::UnboundedSender<InternalEvent>) {
+pub const DISCOVERY_PORT: u16 = 7764;
+
+/// First pause after a failed receive, doubled on each failure in a row.
+const RECEIVE_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Failed receives in a row after which discovery gives up.
+const MAX_RECEIVE_FAILURES: u32 = 8;
+
+fn process_socket(socket: Arc<UdpSocket>, mut ctx: Context) -> JoinHandle<()> {
 	tokio::spawn(async move {
-		// let mut parser = PupynetParser::new();
-		// loop {
-		// 	let mut buf = [0; 1024];
-		// 	let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
-		// 	log::info!("received {} bytes from {}", len, addr);
-		// 	parser.parse(&buf[0..len]);
-		// 	while let Some(cmd) = parser.next() {
-		// 		let addr = format!("udp://{}", addr);
-		// 		match tx.send(InternalEvent::PeerCmd { addr, cmd }) {
-		// 			Ok(_) => {},
-		// 			Err(err) => {
-		// 				log::error!("error sending event: {}", err);
-		// 				return;
-		// 			}
-		// 		}
-		// 	}
-		// }
-	});
+		let mut buf = [0; 65536];
+		let mut failures = 0;
+		loop {
+			let res = tokio::select! {
+				res = socket.recv_from(&mut buf) => res,
+				_ = ctx.shutdown.changed() => return,
+			};
+			let (len, addr) = match res {
+				Ok(res) => res,
+				Err(err) => {
+					failures += 1;
+					if failures >= MAX_RECEIVE_FAILURES {
+						ctx.report(None, anyhow::Error::new(err).context("discovery receive failed, giving up"));
+						return;
+					}
+					ctx.report(None, anyhow::Error::new(err).context("discovery receive failed"));
+					tokio::select! {
+						_ = tokio::time::sleep(RECEIVE_BACKOFF * 2u32.pow(failures - 1)) => continue,
+						_ = ctx.shutdown.changed() => return,
+					}
+				}
+			};
+			failures = 0;
+			log::info!("received {} bytes from {}", len, addr);
+			let addr = format!("udp://{}", addr);
+			let mut data = &buf[0..len];
+			while !data.is_empty() {
+				let (cmd, used) = match PeerCmd::parse(data) {
+					Ok(Some(res)) => res,
+					Ok(None) => break,
+					Err(err) => {
+						log::warn!("invalid datagram from {}: {}", addr, err);
+						break;
+					}
+				};
+				data = &data[used..];
+				if let Err(err) = ctx.internal_event_tx.send(InternalEvent::PeerCmd { addr: addr.clone(), cmd }).await {
+					log::error!("error sending event: {}", err);
+					return;
+				}
+			}
+		}
+	})
 }
 
-pub async fn bind_default(tx: mpsc::UnboundedSender<InternalEvent>) -> Arc<UdpSocket> {
-	match UdpSocket::bind("0.0.0.0:7764").await {
+/// Binds the discovery socket on `port`, falling back to a random port
+/// (send only) when it is taken. Also returns the receiving task, which
+/// ends on shutdown.
+pub async fn bind(port: u16, ctx: Context) -> anyhow::Result<(Arc<UdpSocket>, Option<JoinHandle<()>>)> {
+	match UdpSocket::bind(("0.0.0.0", port)).await {
 		Result::Ok(socket) => {
 			log::info!("bound broadcast socket");
 			let socket: Arc<UdpSocket> = Arc::new(socket);
-			let tx = tx.clone();
-			socket.set_broadcast(true).unwrap();
-			{
-				let broadcast_socket = socket.clone();
-				process_socket(broadcast_socket, tx);
-			}
-			socket
+			socket.set_broadcast(true)?;
+			let task = process_socket(socket.clone(), ctx);
+			Ok((socket, Some(task)))
 		},
 		Err(err) => {
 			log::error!("error binding broadcast socket: {}", err);
-			let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
-			socket.set_broadcast(true).unwrap();
-			Arc::new(socket)
+			let socket = UdpSocket::bind("0.0.0.0:0").await?;
+			socket.set_broadcast(true)?;
+			Ok((Arc::new(socket), None))
 		},
 	}
-}
\ No newline at end of file
+}
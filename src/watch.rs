@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+
+use crate::builder::Share;
+use crate::protocol::put_string;
+use crate::protocol::PeerCmd;
+use crate::protocol::ERR_ACCESS_DENIED;
+use crate::stream;
+use crate::stream::Stream;
+use crate::FsEvent;
+
+/// How often the polling fallback compares the tree with its last snapshot.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Changes that may queue up for a slow subscriber before the watcher waits.
+const EVENT_BUFFER: usize = 1024;
+
+const EVENT_CREATED: u8 = 1;
+const EVENT_MODIFIED: u8 = 2;
+const EVENT_REMOVED: u8 = 3;
+const EVENT_RENAMED: u8 = 4;
+
+fn encode(event: &FsEvent) -> Vec<u8> {
+	let mut buf = Vec::new();
+	match event {
+		FsEvent::Created { path } => {
+			buf.push(EVENT_CREATED);
+			put_string(&mut buf, path);
+		},
+		FsEvent::Modified { path } => {
+			buf.push(EVENT_MODIFIED);
+			put_string(&mut buf, path);
+		},
+		FsEvent::Removed { path } => {
+			buf.push(EVENT_REMOVED);
+			put_string(&mut buf, path);
+		},
+		FsEvent::Renamed { from, to } => {
+			buf.push(EVENT_RENAMED);
+			put_string(&mut buf, from);
+			put_string(&mut buf, to);
+		},
+	}
+	buf
+}
+
+/// A subscription to changes on another node, from `Pupynet::watch`.
+/// Dropping it unsubscribes.
+#[derive(Debug)]
+pub struct Watch {
+	stream: Stream,
+}
+
+impl Watch {
+	pub(crate) fn new(stream: Stream) -> Watch {
+		Watch { stream }
+	}
+
+	/// The next change, `None` once the node ended the subscription: the
+	/// watched path went away, the node shut down or changes came in faster
+	/// than they could be tracked. Re-list the path to catch up.
+	pub async fn next(&mut self) -> anyhow::Result<Option<FsEvent>> {
+		let kind = match self.stream.try_read_u8().await? {
+			Some(kind) => kind,
+			None => return Ok(None),
+		};
+		let path = self.stream.read_str().await?;
+		let event = match kind {
+			EVENT_CREATED => FsEvent::Created { path },
+			EVENT_MODIFIED => FsEvent::Modified { path },
+			EVENT_REMOVED => FsEvent::Removed { path },
+			EVENT_RENAMED => FsEvent::Renamed { from: path, to: self.stream.read_str().await? },
+			_ => anyhow::bail!("unknown watch event {}", kind),
+		};
+		Ok(Some(event))
+	}
+}
+
+impl Drop for Watch {
+	fn drop(&mut self) {
+		let _ = self.stream.finish();
+	}
+}
+
+fn path_string(path: &Path) -> String {
+	path.to_string_lossy().to_string()
+}
+
+/// Sends changes under `root` to `tx` until `tx` closes or `root` goes away.
+/// Uses inotify where there is one and polls otherwise.
+async fn watch_tree(root: PathBuf, recursive: bool, tx: mpsc::Sender<FsEvent>) -> anyhow::Result<()> {
+	#[cfg(target_os = "linux")]
+	match Notifier::new(&root, recursive) {
+		Ok(notifier) => return notifier.run(&root, &tx).await,
+		Err(err) => log::info!("cannot use inotify for {}, polling instead: {}", root.display(), err),
+	}
+	poll(&root, recursive, &tx, POLL_INTERVAL).await
+}
+
+#[cfg(target_os = "linux")]
+const WATCH_MASK: inotify::WatchMask = inotify::WatchMask::CREATE
+	.union(inotify::WatchMask::MODIFY)
+	.union(inotify::WatchMask::CLOSE_WRITE)
+	.union(inotify::WatchMask::DELETE)
+	.union(inotify::WatchMask::MOVED_FROM)
+	.union(inotify::WatchMask::MOVED_TO)
+	.union(inotify::WatchMask::DELETE_SELF)
+	.union(inotify::WatchMask::MOVE_SELF);
+
+/// How long the two halves of a rename may be apart.
+#[cfg(target_os = "linux")]
+const MOVE_WAIT: Duration = Duration::from_millis(50);
+/// Writes to a file this soon after the last reported one are folded into
+/// a single `Modified`, sent when the writer closes the file at the latest.
+#[cfg(target_os = "linux")]
+const MODIFY_COALESCE: Duration = Duration::from_millis(200);
+
+#[cfg(target_os = "linux")]
+struct Notifier {
+	events: inotify::EventStream<Vec<u8>>,
+	/// The folder, or file, each watch is for.
+	dirs: HashMap<inotify::WatchDescriptor, PathBuf>,
+	recursive: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl Notifier {
+	fn new(root: &Path, recursive: bool) -> std::io::Result<Notifier> {
+		let events = inotify::Inotify::init()?.into_event_stream(vec![0; 64 * 1024])?;
+		let mut notifier = Notifier { events, dirs: HashMap::new(), recursive };
+		notifier.add(root)?;
+		Ok(notifier)
+	}
+
+	/// Watches `dir` and, when recursive, every folder below it. Returns
+	/// what is below it, which may have appeared before the watches did.
+	fn add(&mut self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+		let wd = self.events.watches().add(dir, WATCH_MASK)?;
+		self.dirs.insert(wd, dir.to_path_buf());
+		let mut found = Vec::new();
+		if !self.recursive {
+			return Ok(found);
+		}
+		let mut pending = vec![dir.to_path_buf()];
+		while let Some(dir) = pending.pop() {
+			for entry in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+				let path = entry.path();
+				if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+					if let Ok(wd) = self.events.watches().add(&path, WATCH_MASK) {
+						self.dirs.insert(wd, path.clone());
+						pending.push(path.clone());
+					}
+				}
+				found.push(path);
+			}
+		}
+		Ok(found)
+	}
+
+	/// Drops the watches of a folder that left the watched tree.
+	fn forget(&mut self, dir: &Path) {
+		let gone: Vec<_> = self.dirs.iter().filter(|(_, d)| d.starts_with(dir)).map(|(wd, _)| wd.clone()).collect();
+		for wd in gone {
+			self.dirs.remove(&wd);
+			let _ = self.events.watches().remove(wd);
+		}
+	}
+
+	fn renamed(&mut self, from: &Path, to: &Path) {
+		for dir in self.dirs.values_mut() {
+			if let Ok(rest) = dir.strip_prefix(from) {
+				*dir = match rest.as_os_str().is_empty() {
+					true => to.to_path_buf(),
+					false => to.join(rest),
+				};
+			}
+		}
+	}
+
+	async fn run(mut self, root: &Path, tx: &mpsc::Sender<FsEvent>) -> anyhow::Result<()> {
+		use futures_util::StreamExt;
+		use inotify::EventMask;
+
+		// A rename within the tree is a MOVED_FROM right followed by a
+		// MOVED_TO with the same cookie. A MOVED_FROM on its own means the
+		// path left the tree.
+		let mut moved: Option<(u32, PathBuf)> = None;
+		// Files with reported writes: when the last was reported and
+		// whether more came after it.
+		let mut writes: HashMap<PathBuf, (tokio::time::Instant, bool)> = HashMap::new();
+		loop {
+			let wait = if moved.is_some() { MOVE_WAIT } else { Duration::MAX };
+			let event = match tokio::time::timeout(wait, self.events.next()).await {
+				Ok(Some(event)) => event?,
+				Ok(None) => return Ok(()),
+				Err(_) => {
+					if let Some((_, from)) = moved.take() {
+						self.forget(&from);
+						tx.send(FsEvent::Removed { path: path_string(&from) }).await?;
+					}
+					continue;
+				},
+			};
+			let mask = event.mask;
+			if mask.contains(EventMask::Q_OVERFLOW) {
+				anyhow::bail!("changes under {} came in too fast, some were lost", root.display());
+			}
+			if mask.contains(EventMask::IGNORED) {
+				// The watch went away with its folder, or the whole tree did.
+				if self.dirs.remove(&event.wd).as_deref() == Some(root) {
+					return Ok(());
+				}
+				continue;
+			}
+			let dir = match self.dirs.get(&event.wd) {
+				Some(dir) => dir.clone(),
+				None => continue,
+			};
+			let path = match &event.name {
+				Some(name) => dir.join(name),
+				// Events on a watched folder itself are reported by its
+				// parent, except for the root.
+				None if dir == root && mask.intersects(EventMask::DELETE_SELF | EventMask::MOVE_SELF) => {
+					tx.send(FsEvent::Removed { path: path_string(root) }).await?;
+					return Ok(());
+				},
+				None if dir == root && !mask.contains(EventMask::ISDIR) => dir,
+				None => continue,
+			};
+
+			if let Some((cookie, from)) = moved.take() {
+				if mask.contains(EventMask::MOVED_TO) && event.cookie == cookie {
+					self.renamed(&from, &path);
+					writes.remove(&from);
+					tx.send(FsEvent::Renamed { from: path_string(&from), to: path_string(&path) }).await?;
+					continue;
+				}
+				self.forget(&from);
+				tx.send(FsEvent::Removed { path: path_string(&from) }).await?;
+			}
+
+			if mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+				tx.send(FsEvent::Created { path: path_string(&path) }).await?;
+				if mask.contains(EventMask::ISDIR) && self.recursive {
+					for found in self.add(&path).unwrap_or_default() {
+						tx.send(FsEvent::Created { path: path_string(&found) }).await?;
+					}
+				}
+			} else if mask.contains(EventMask::MODIFY) {
+				let now = tokio::time::Instant::now();
+				match writes.get_mut(&path) {
+					Some((at, more)) if now - *at < MODIFY_COALESCE => *more = true,
+					_ => {
+						writes.insert(path.clone(), (now, false));
+						tx.send(FsEvent::Modified { path: path_string(&path) }).await?;
+					},
+				}
+			} else if mask.contains(EventMask::CLOSE_WRITE) {
+				if let Some((_, true)) = writes.remove(&path) {
+					tx.send(FsEvent::Modified { path: path_string(&path) }).await?;
+				}
+			} else if mask.contains(EventMask::DELETE) {
+				writes.remove(&path);
+				tx.send(FsEvent::Removed { path: path_string(&path) }).await?;
+			} else if mask.contains(EventMask::MOVED_FROM) {
+				moved = Some((event.cookie, path));
+			}
+		}
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Stat {
+	is_dir: bool,
+	size: u64,
+	modified: Option<SystemTime>,
+}
+
+/// Everything below `root`, or `root` itself when it is a file.
+fn snapshot(root: &Path, recursive: bool) -> HashMap<PathBuf, Stat> {
+	let mut res = HashMap::new();
+	let mut pending = vec![root.to_path_buf()];
+	while let Some(dir) = pending.pop() {
+		let entries = match std::fs::read_dir(&dir) {
+			Ok(entries) => entries,
+			Err(_) if dir == root => {
+				if let Ok(metadata) = std::fs::metadata(root) {
+					res.insert(dir, Stat { is_dir: false, size: metadata.len(), modified: metadata.modified().ok() });
+				}
+				break;
+			},
+			Err(_) => continue,
+		};
+		for entry in entries.flatten() {
+			let metadata = match entry.metadata() {
+				Ok(metadata) => metadata,
+				Err(_) => continue,
+			};
+			let path = entry.path();
+			if metadata.is_dir() && recursive {
+				pending.push(path.clone());
+			}
+			res.insert(path, Stat { is_dir: metadata.is_dir(), size: metadata.len(), modified: metadata.modified().ok() });
+		}
+	}
+	res
+}
+
+/// What changed between two snapshots, parents created before their
+/// children and removed after them.
+fn diff(old: &HashMap<PathBuf, Stat>, new: &HashMap<PathBuf, Stat>) -> Vec<FsEvent> {
+	let mut created = Vec::new();
+	let mut modified = Vec::new();
+	let mut removed = Vec::new();
+	for (path, stat) in new {
+		match old.get(path) {
+			None => created.push(path),
+			Some(old) if old.is_dir != stat.is_dir => {
+				removed.push(path);
+				created.push(path);
+			},
+			Some(old) if !stat.is_dir && old != stat => modified.push(path),
+			_ => {},
+		}
+	}
+	removed.extend(old.keys().filter(|path| !new.contains_key(*path)));
+	created.sort();
+	modified.sort();
+	removed.sort_by(|a, b| b.cmp(a));
+	let removed = removed.into_iter().map(|p| FsEvent::Removed { path: path_string(p) });
+	let created = created.into_iter().map(|p| FsEvent::Created { path: path_string(p) });
+	let modified = modified.into_iter().map(|p| FsEvent::Modified { path: path_string(p) });
+	removed.chain(created).chain(modified).collect()
+}
+
+async fn poll(root: &Path, recursive: bool, tx: &mpsc::Sender<FsEvent>, interval: Duration) -> anyhow::Result<()> {
+	let take = |root: PathBuf| tokio::task::spawn_blocking(move || snapshot(&root, recursive));
+	let mut old = take(root.to_path_buf()).await?;
+	loop {
+		tokio::time::sleep(interval).await;
+		if tokio::fs::symlink_metadata(root).await.is_err() {
+			tx.send(FsEvent::Removed { path: path_string(root) }).await?;
+			return Ok(());
+		}
+		let new = take(root.to_path_buf()).await?;
+		for event in diff(&old, &new) {
+			tx.send(event).await?;
+		}
+		old = new;
+	}
+}
+
+async fn serve(stream: &mut Stream, root: PathBuf, recursive: bool, shutdown: &mut watch::Receiver<bool>) -> anyhow::Result<()> {
+	let (tx, mut rx) = mpsc::channel(EVENT_BUFFER);
+	let mut watcher = tokio::spawn(watch_tree(root, recursive, tx));
+	let res = loop {
+		tokio::select! {
+			event = rx.recv() => match event {
+				Some(event) => {
+					if let Err(err) = stream.write_bytes(&encode(&event)).await {
+						break Err(err);
+					}
+				},
+				None => break (&mut watcher).await.map_err(anyhow::Error::from).and_then(|res| res),
+			},
+			// The requester unsubscribes by ending its side.
+			data = stream.read_bytes() => if !matches!(data, Ok(Some(_))) {
+				break Ok(());
+			},
+			_ = shutdown.changed() => break Ok(()),
+		}
+	};
+	watcher.abort();
+	res
+}
+
+/// Serves a `Watch` until the requester unsubscribes, the watched path goes
+/// away or the node shuts down.
+pub async fn handle_stream(mut stream: Stream, cmd: PeerCmd, shares: &[Share], mut shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+	let (path, recursive) = match cmd {
+		PeerCmd::Watch { path, recursive, .. } => (PathBuf::from(path), recursive),
+		_ => anyhow::bail!("not a watch command"),
+	};
+	if !stream::is_shared(shares, &path, false).await {
+		stream::reject(stream, ERR_ACCESS_DENIED, "path is not shared".to_string()).await;
+		return Ok(());
+	}
+	match tokio::fs::metadata(&path).await {
+		Ok(_) => {
+			stream.write_status(Ok(())).await?;
+			let res = serve(&mut stream, path, recursive, &mut shutdown).await;
+			if let Err(err) = &res {
+				log::info!("watch ended: {:#}", err);
+			}
+		},
+		Err(err) => stream.write_status(Err(err.into())).await?,
+	}
+	stream.finish()?;
+	if !*shutdown.borrow() {
+		while stream.read_bytes().await?.is_some() {}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::folder;
+	use crate::testing::node;
+	use crate::testing::wait_for_peer;
+
+	async fn expect(rx: &mut mpsc::Receiver<FsEvent>, expected: FsEvent) {
+		let wait = async {
+			loop {
+				match rx.recv().await {
+					Some(event) if event == expected => return,
+					Some(_) => {},
+					None => panic!("watcher ended waiting for {:?}", expected),
+				}
+			}
+		};
+		tokio::time::timeout(Duration::from_secs(5), wait).await.unwrap();
+	}
+
+	async fn check_watcher(dir: &Path, rx: &mut mpsc::Receiver<FsEvent>, renames: bool) {
+		let path = |name: &str| path_string(&dir.join(name));
+		std::fs::create_dir(dir.join("sub")).unwrap();
+		expect(rx, FsEvent::Created { path: path("sub") }).await;
+		std::fs::write(dir.join("sub/a.txt"), b"a").unwrap();
+		expect(rx, FsEvent::Created { path: path("sub/a.txt") }).await;
+		tokio::time::sleep(Duration::from_millis(300)).await;
+		std::fs::write(dir.join("sub/a.txt"), b"changed").unwrap();
+		expect(rx, FsEvent::Modified { path: path("sub/a.txt") }).await;
+		std::fs::rename(dir.join("sub/a.txt"), dir.join("b.txt")).unwrap();
+		if renames {
+			expect(rx, FsEvent::Renamed { from: path("sub/a.txt"), to: path("b.txt") }).await;
+		} else {
+			expect(rx, FsEvent::Created { path: path("b.txt") }).await;
+		}
+		std::fs::remove_file(dir.join("b.txt")).unwrap();
+		expect(rx, FsEvent::Removed { path: path("b.txt") }).await;
+		std::fs::remove_dir_all(dir).unwrap();
+		expect(rx, FsEvent::Removed { path: path_string(dir) }).await;
+		tokio::time::timeout(Duration::from_secs(5), async {
+			while rx.recv().await.is_some() {}
+		}).await.unwrap();
+	}
+
+	#[cfg(target_os = "linux")]
+	#[tokio::test]
+	async fn test_inotify() {
+		let tmp = tempfile::tempdir().unwrap();
+		let dir = tmp.path().to_path_buf();
+		let (tx, mut rx) = mpsc::channel(EVENT_BUFFER);
+		let notifier = Notifier::new(&dir, true).unwrap();
+		let root = dir.clone();
+		tokio::spawn(async move { notifier.run(&root, &tx).await });
+		check_watcher(&dir, &mut rx, true).await;
+	}
+
+	#[tokio::test]
+	async fn test_polling() {
+		let tmp = tempfile::tempdir().unwrap();
+		let dir = tmp.path().to_path_buf();
+		let (tx, mut rx) = mpsc::channel(EVENT_BUFFER);
+		let root = dir.clone();
+		tokio::spawn(async move { poll(&root, true, &tx, Duration::from_millis(20)).await });
+		// Let it take the snapshot changes are compared with.
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		check_watcher(&dir, &mut rx, false).await;
+	}
+
+	#[tokio::test]
+	async fn test_watch() {
+		let dir = tempfile::tempdir().unwrap();
+		let share = folder(dir.path(), "share");
+		let outside = folder(dir.path(), "outside");
+		let a = node(dir.path(), "a").share(&share).build();
+		let a_info = a.info().await.unwrap();
+		let b = node(dir.path(), "b").peer(&a_info.listen_addrs[0]).build();
+		wait_for_peer(&b, &a_info.id).await;
+		assert!(b.watch(&a_info.id, outside.to_str().unwrap(), true).await.is_err());
+
+		let mut watch = b.watch(&a_info.id, share.to_str().unwrap(), true).await.unwrap();
+		let path = share.join("new.txt");
+		std::fs::write(&path, b"hello").unwrap();
+		let event = tokio::time::timeout(Duration::from_secs(5), watch.next()).await.unwrap().unwrap();
+		assert_eq!(event, Some(FsEvent::Created { path: path.to_str().unwrap().to_string() }));
+
+		// Unsubscribing ends the watch on a, which then shuts down promptly.
+		drop(watch);
+		let metrics = a.metrics().await.unwrap();
+		assert!(metrics.transfers <= 1);
+		tokio::time::timeout(Duration::from_secs(2), a.shutdown()).await.unwrap();
+	}
+}
@@ -1,27 +1,218 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::task::JoinSet;
+use crate::block_store;
+use crate::block_store::BlockStore;
+use crate::block_store::GC_INTERVAL;
+use crate::builder::Config;
+use crate::builder::Share;
+use crate::builder::SyncFolder;
+use crate::compress::Compression;
+use crate::folder_sync;
+use crate::mesh::Mesh;
+use crate::mesh::MeshNode;
+use crate::mesh::GOSSIP_TTL;
+use crate::mesh::LINK_EXPIRY;
+use crate::multiplex::FrameFormat;
 use crate::protocol::Introduce;
 use crate::protocol::PeerCmd;
+use crate::protocol::StreamHeader;
+use crate::protocol::ERR_ACCESS_DENIED;
+use crate::protocol::ERR_HOP_LIMIT;
+use crate::protocol::ERR_SHUTTING_DOWN;
+use crate::protocol::ERR_UNREACHABLE;
+use crate::protocol::FEATURES;
+use crate::protocol::FEATURE_COMPRESSION;
+use crate::protocol::LEGACY_VERSION;
+use crate::protocol::MIN_PROTOCOL_VERSION;
+use crate::protocol::PROTOCOL_VERSION;
+use crate::rate::RateLimiter;
+use crate::reconnect;
+use crate::routing::RoutingTable;
+use crate::stream;
+use crate::stream::Stream;
+use crate::swarm;
+use crate::types::Context;
 use crate::types::InternalCommand;
 use crate::types::InternalEvent;
+use crate::types::Peer;
+use crate::types::PeerConn;
+use crate::types::PeerConnCmd;
 use crate::types::State;
-use crate::udp::bind_default;
+use crate::types::TransientError;
+use crate::types::TrustState;
+use crate::storage;
+use crate::tcp;
+use crate::udp;
+use crate::ConnectionMetrics;
+use crate::Metrics;
+use crate::NodeInfo;
+use crate::PeerInfo;
 use crate::PupynetEvent;
 
+/// How often we announce ourselves on the local network.
+pub const DISCOVERY_INTERVAL: Duration = Duration::from_secs(5);
+
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// A connection silent for this long is considered dead and closed.
+pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `Pupynet::shutdown` waits for in-flight transfers by default.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const QUEUE_CAPACITY: usize = 1024;
+/// Default bytes a connection may queue per direction.
+pub const PEER_BUFFER: usize = 1 << 20;
+
+/// An outbound connection kept alive by `reconnect::supervise`.
+struct Supervised {
+	task: JoinHandle<()>,
+	reconnecting: bool,
+	/// The peer that announced a discovered address.
+	peer: Option<String>,
+}
+
+/// A shutdown waiting for transfers to finish.
+struct Stopping {
+	deadline: tokio::time::Instant,
+	replies: Vec<oneshot::Sender<()>>,
+}
+
 pub struct Worker {
 	event_tx: broadcast::Sender<PupynetEvent>,
-	internal_event_tx: mpsc::UnboundedSender<InternalEvent>,
-	internal_event_rx: mpsc::UnboundedReceiver<InternalEvent>,
-	rx: mpsc::UnboundedReceiver<InternalCommand>,
-	udp_socket: std::sync::Arc<tokio::net::UdpSocket>,
+	internal_event_tx: mpsc::Sender<InternalEvent>,
+	internal_event_rx: mpsc::Receiver<InternalEvent>,
+	rx: mpsc::Receiver<InternalCommand>,
+	udp_socket: Option<Arc<tokio::net::UdpSocket>>,
+	peers_path: Option<PathBuf>,
+	forgotten_path: Option<PathBuf>,
+	/// Peers were seen again but nothing else about them changed. Saved on
+	/// the next keepalive tick rather than on every introduction.
+	peers_dirty: bool,
+	config: Config,
+	shares: Arc<Vec<Share>>,
+	limiter: Arc<RateLimiter>,
+	sync_folders: Arc<Vec<SyncFolder>>,
+	blocks: Option<Arc<BlockStore>>,
+	/// Where the indexes of synced folders are kept.
+	sync_dir: Option<PathBuf>,
+	conns: HashMap<String, PeerConn>,
+	mesh: Mesh,
+	routes: RoutingTable,
+	listen_addrs: Vec<SocketAddr>,
+	supervised: HashMap<String, Supervised>,
+	/// Listener and discovery tasks, which end once `shutdown_tx` fires.
+	tasks: Vec<JoinHandle<()>>,
+	/// Streams being served or forwarded.
+	transfers: JoinSet<()>,
+	shutdown_tx: watch::Sender<bool>,
+	stopping: Option<Stopping>,
+	discovery_timer: tokio::time::Interval,
+	keepalive_timer: tokio::time::Interval,
+	keepalive_timeout: Duration,
+	hello_seq: u64,
 	state: State
 }
 
 impl Worker {
-	pub async fn new(rx: mpsc::UnboundedReceiver<InternalCommand>, event_tx: broadcast::Sender<PupynetEvent>) -> Self {
-		let (internal_event_tx, internal_event_rx) = mpsc::unbounded_channel();
+	pub async fn new(config: Config, rx: mpsc::Receiver<InternalCommand>, event_tx: broadcast::Sender<PupynetEvent>) -> Self {
+		let (internal_event_tx, internal_event_rx) = mpsc::channel(config.queue_capacity);
+		let (shutdown_tx, shutdown) = watch::channel(false);
 
-		let udp_socket = bind_default(internal_event_tx.clone()).await;
+		let limiter = Arc::new(RateLimiter::new(config.rate_limits.clone()));
+
+		let mut tasks = Vec::new();
+		let udp_socket = match config.discovery_port {
+			Some(port) => {
+				let ctx = Context {
+					internal_event_tx: internal_event_tx.clone(),
+					shutdown,
+					peer_buffer: config.peer_buffer,
+					max_frame: config.max_frame_size,
+					limiter: limiter.clone(),
+				};
+				match udp::bind(port, ctx).await {
+					Ok((socket, task)) => {
+						tasks.extend(task);
+						Some(socket)
+					},
+					Err(err) => {
+						log::error!("discovery disabled: {}", err);
+						let _ = event_tx.send(PupynetEvent::Error {
+							addr: None,
+							message: format!("discovery disabled: {}", err)
+						});
+						None
+					}
+				}
+			},
+			None => None,
+		};
+
+		let data_dir = storage::data_dir(&config);
+		let (peers_path, forgotten_path) = match &data_dir {
+			Ok(dir) => (Some(storage::peers_path(dir)), Some(storage::forgotten_path(dir))),
+			Err(err) => {
+				log::error!("peer persistence disabled: {}", err);
+				(None, None)
+			}
+		};
+		let identity_path = match (&config.identity_path, &data_dir) {
+			(Some(path), _) => Some(path.clone()),
+			(None, Ok(dir)) => Some(storage::node_id_path(dir)),
+			(None, Err(_)) => None,
+		};
+		let blocks = match (&data_dir, config.block_store) {
+			(Ok(dir), true) => {
+				let store = Arc::new(BlockStore::new(storage::blocks_dir(dir)));
+				tasks.push(tokio::spawn(block_store::collect_garbage_every(store.clone(), GC_INTERVAL, shutdown_tx.subscribe())));
+				Some(store)
+			},
+			_ => None,
+		};
+		let mut state = State::default();
+		state.me.id = match identity_path {
+			Some(path) => match storage::load_or_create_node_id(&path).await {
+				Ok(id) => id,
+				Err(err) => {
+					log::error!("failed to load node id from {}: {}", path.display(), err);
+					storage::new_node_id()
+				}
+			},
+			None => storage::new_node_id()
+		};
+		state.me.name = if config.name.is_empty() {
+			storage::default_name()
+		} else {
+			config.name.clone()
+		};
+		state.me.owner = config.owner.clone();
+		log::info!("node id: {} name: {}", state.me.id, state.me.name);
+		if let Some(path) = &peers_path {
+			match storage::load_peers(path).await {
+				Ok(peers) => {
+					log::info!("loaded {} known peers", peers.len());
+					state.peers = peers;
+				},
+				Err(err) => log::error!("failed to load peers from {}: {}", path.display(), err),
+			}
+		}
+		if let Some(path) = &forgotten_path {
+			match storage::load_forgotten(path).await {
+				Ok(forgotten) => state.forgotten = forgotten,
+				Err(err) => log::error!("failed to load forgotten peers from {}: {}", path.display(), err),
+			}
+		}
 
 		Self {
 			event_tx,
@@ -29,80 +220,991 @@ impl Worker {
 			internal_event_rx,
 			rx,
 			udp_socket,
-			state: State::default()
+			peers_path,
+			forgotten_path,
+			peers_dirty: false,
+			shares: Arc::new(config.shares.clone()),
+			limiter,
+			sync_folders: Arc::new(config.sync_folders.clone()),
+			blocks,
+			sync_dir: data_dir.ok(),
+			discovery_timer: tokio::time::interval(config.discovery_interval),
+			keepalive_timer: tokio::time::interval(config.keepalive_interval),
+			keepalive_timeout: config.keepalive_timeout,
+			config,
+			conns: HashMap::new(),
+			mesh: Mesh::new(),
+			routes: RoutingTable::default(),
+			listen_addrs: Vec::new(),
+			supervised: HashMap::new(),
+			tasks,
+			transfers: JoinSet::new(),
+			shutdown_tx,
+			stopping: None,
+			hello_seq: 0,
+			state
+		}
+	}
+
+	fn ctx(&self) -> Context {
+		Context {
+			internal_event_tx: self.internal_event_tx.clone(),
+			shutdown: self.shutdown_tx.subscribe(),
+			peer_buffer: self.config.peer_buffer,
+			max_frame: self.config.max_frame_size,
+			limiter: self.limiter.clone(),
+		}
+	}
+
+	fn me(&self) -> Introduce {
+		Introduce {
+			id: self.state.me.id.clone(),
+			name: self.state.me.name.clone(),
+			owner: self.state.me.owner.clone().unwrap_or_default(),
+			ports: self.listen_addrs.iter().map(|addr| addr.port()).collect(),
+			max_frame: self.config.max_frame_size,
+			version: PROTOCOL_VERSION,
+			min_version: MIN_PROTOCOL_VERSION,
+			features: FEATURES,
+		}
+	}
+
+	fn emit(&self, event: PupynetEvent) {
+		// Sending only fails when nobody is listening, which is fine.
+		let _ = self.event_tx.send(event);
+	}
+
+	/// Logs a failure the node can carry on after and tells the application.
+	fn report(&self, addr: Option<&str>, err: &anyhow::Error) {
+		match addr {
+			Some(addr) => log::error!("{}: {:#}", addr, err),
+			None => log::error!("{:#}", err),
+		}
+		self.emit(PupynetEvent::Error {
+			addr: addr.map(str::to_string),
+			message: format!("{:#}", err)
+		});
+	}
+
+	/// Starts keeping an outbound connection to `addr` alive. Addresses
+	/// `peer` was discovered at are given up on once they stop answering,
+	/// configured ones are redialed until removed.
+	fn supervise(&mut self, addr: String, peer: Option<String>) {
+		if self.supervised.get(&addr).is_some_and(|s| !s.task.is_finished()) || self.stopping.is_some() {
+			return;
+		}
+		let max_failures = peer.as_ref().map(|_| reconnect::MAX_DIAL_FAILURES);
+		let task = tokio::spawn(reconnect::supervise(addr.clone(), max_failures, self.ctx()));
+		self.supervised.insert(addr, Supervised { task, reconnecting: false, peer });
+	}
+
+	fn is_connected(&self, peer_id: &str) -> bool {
+		self.conns.values().any(|c| !c.leaving && c.peer_id.as_deref() == Some(peer_id))
+	}
+
+	/// Dials a peer found through UDP discovery. Only the node with the
+	/// smaller id dials so two nodes finding each other open a single link.
+	fn connect_discovered(&mut self, addr: &str, introduce: &Introduce) {
+		if introduce.ports.is_empty() || self.state.me.id >= introduce.id || self.is_connected(&introduce.id) {
+			return;
+		}
+		let ip = match addr.trim_start_matches("udp://").parse::<std::net::SocketAddr>() {
+			Ok(addr) => addr.ip(),
+			Err(_) => return,
+		};
+		let addr = format!("tcp://{}", std::net::SocketAddr::new(ip, introduce.ports[0]));
+		// The peer moved, so stop dialing where it used to be.
+		let moved: Vec<String> = self.supervised.iter()
+			.filter(|(a, s)| s.peer.as_deref() == Some(introduce.id.as_str()) && **a != addr)
+			.map(|(a, _)| a.clone())
+			.collect();
+		for old in moved {
+			if let Some(supervised) = self.supervised.remove(&old) {
+				log::info!("{} moved from {} to {}", introduce.id, old, addr);
+				supervised.task.abort();
+			}
+		}
+		log::info!("discovered {} at {}", introduce.id, addr);
+		self.supervise(addr, Some(introduce.id.clone()));
+	}
+
+	/// Closes connections that stopped answering and pings the rest.
+	fn keepalive(&mut self) {
+		let now = std::time::Instant::now();
+		let dead: Vec<String> = self.conns.iter()
+			.filter(|(_, c)| now.duration_since(c.tx.last_received()) > self.keepalive_timeout)
+			.map(|(addr, _)| addr.clone())
+			.collect();
+		for addr in dead {
+			log::warn!("{} stopped responding, closing", addr);
+			if let Some(conn) = self.conns.get(&addr) {
+				let _ = conn.tx.send(PeerConnCmd::Close);
+			}
+			self.remove_conn(&addr);
+		}
+
+		self.hello_seq += 1;
+		let data = PeerCmd::Hello { seq: self.hello_seq, reply: false }.serialize();
+		let mut stuck = Vec::new();
+		for (addr, conn) in self.conns.iter_mut() {
+			// Fails once the peer stopped reading and its queue filled up.
+			if let Err(err) = conn.tx.send(PeerConnCmd::Send(data.clone())) {
+				stuck.push((addr.clone(), err));
+				continue;
+			}
+			// Replies may take longer than the interval, but not than the timeout.
+			conn.hellos.retain(|_, sent| now.duration_since(*sent) <= self.keepalive_timeout);
+			conn.hellos.insert(self.hello_seq, now);
+		}
+		for (addr, err) in stuck {
+			self.report(Some(&addr), &err.context("closing connection"));
+			if let Some(conn) = self.conns.get(&addr) {
+				let _ = conn.tx.send(PeerConnCmd::Close);
+			}
+			self.remove_conn(&addr);
+		}
+	}
+
+	fn metrics(&self) -> Metrics {
+		let mut connections: Vec<ConnectionMetrics> = self.conns.iter().map(|(addr, conn)| ConnectionMetrics {
+			addr: addr.clone(),
+			peer_id: conn.peer_id.clone(),
+			send_queued: conn.tx.queued(),
+			recv_queued: conn.tx.unread(),
+			version: conn.peer_id.as_ref().map(|_| conn.version),
+			features: conn.features,
+		}).collect();
+		connections.sort_by(|a, b| a.addr.cmp(&b.addr));
+		Metrics {
+			commands_queued: self.rx.len(),
+			events_queued: self.internal_event_rx.len(),
+			transfers: self.transfers.len(),
+			connections,
+		}
+	}
+
+	fn handle_hello(&mut self, addr: &str, seq: u64, reply: bool) {
+		let conn = match self.conns.get_mut(addr) {
+			Some(conn) => conn,
+			None => return,
+		};
+		if !reply {
+			let _ = conn.tx.send(PeerConnCmd::Send(PeerCmd::Hello { seq, reply: true }.serialize()));
+			return;
+		}
+		if let Some(sent_at) = conn.hellos.remove(&seq) {
+			conn.rtt = Some(sent_at.elapsed());
+			log::debug!("rtt to {}: {:?}", addr, conn.rtt);
+		}
+	}
+
+	/// The remote is about to close the connection, so route around it already.
+	fn handle_goodbye(&mut self, addr: &str) {
+		let conn = match self.conns.get_mut(addr) {
+			Some(conn) => conn,
+			None => return,
+		};
+		log::info!("{} is shutting down", addr);
+		conn.leaving = true;
+		if let Some(peer_id) = conn.peer_id.clone() {
+			if !self.is_connected(&peer_id) {
+				self.announce_link(&peer_id, false);
+			}
+			self.rebuild_routes();
+		}
+	}
+
+	/// Stops accepting new work and tells peers we are leaving. Transfers
+	/// already running get until the deadline to finish.
+	fn begin_shutdown(&mut self, reply: oneshot::Sender<()>) {
+		if let Some(stopping) = &mut self.stopping {
+			stopping.replies.push(reply);
+			return;
+		}
+		log::info!("shutting down, waiting for {} transfers", self.transfers.len());
+		let _ = self.shutdown_tx.send(true);
+		let goodbye = PeerCmd::Goodbye.serialize();
+		for conn in self.conns.values() {
+			let _ = conn.tx.send(PeerConnCmd::Send(goodbye.clone()));
+		}
+		self.stopping = Some(Stopping {
+			deadline: tokio::time::Instant::now() + self.config.shutdown_timeout,
+			replies: vec![reply],
+		});
+	}
+
+	/// Closes every connection and waits for the tasks we spawned to end.
+	async fn finish_shutdown(&mut self) {
+		let _ = self.shutdown_tx.send(true);
+		if !self.transfers.is_empty() {
+			log::warn!("aborting {} unfinished transfers", self.transfers.len());
+		}
+		self.transfers.shutdown().await;
+		if self.peers_dirty {
+			self.save_peers().await;
+		}
+		for conn in self.conns.values() {
+			let _ = conn.tx.send(PeerConnCmd::Close);
+		}
+		let tasks = self.tasks.drain(..).chain(self.supervised.drain().map(|(_, s)| s.task));
+		for mut task in tasks {
+			// Supervisors end once their connection has closed; one still
+			// dialing an unreachable address is cut short.
+			if tokio::time::timeout(Duration::from_secs(1), &mut task).await.is_err() {
+				task.abort();
+			}
+		}
+		log::info!("shut down");
+		if let Some(stopping) = self.stopping.take() {
+			for reply in stopping.replies {
+				let _ = reply.send(());
+			}
+		}
+	}
+
+	/// Forgets a connection, updating the mesh if it was our last link to that peer.
+	fn remove_conn(&mut self, addr: &str) {
+		let conn = match self.conns.remove(addr) {
+			Some(conn) => conn,
+			None => return,
+		};
+		if let Some(peer_id) = conn.peer_id {
+			if !self.is_connected(&peer_id) {
+				self.announce_link(&peer_id, false);
+			}
+			self.rebuild_routes();
+		}
+		self.emit(PupynetEvent::PeerDisconnected { addr: addr.to_string() });
+	}
+
+	async fn announce(&self) {
+		let (socket, port) = match (&self.udp_socket, self.config.discovery_port) {
+			(Some(socket), Some(port)) => (socket, port),
+			_ => return,
+		};
+		let data = PeerCmd::Introduce(self.me()).serialize();
+		if let Err(err) = socket.send_to(&data, ("255.255.255.255", port)).await {
+			log::debug!("discovery broadcast failed: {}", err);
+		}
+	}
+
+	fn peer_info(&self, id: &str) -> Introduce {
+		match self.state.peers.get(id) {
+			Some(peer) => Introduce {
+				id: peer.id.clone(),
+				name: peer.name.clone(),
+				owner: peer.owner.clone().unwrap_or_default(),
+				ports: Vec::new(),
+				max_frame: 0,
+				version: LEGACY_VERSION,
+				min_version: LEGACY_VERSION,
+				features: peer.features,
+			},
+			None if id == self.state.me.id => self.me(),
+			None => {
+				let node = self.mesh.node(id).cloned().unwrap_or_default();
+				Introduce {
+					id: id.to_string(),
+					name: node.name,
+					owner: node.owner.unwrap_or_default(),
+					ports: Vec::new(),
+					max_frame: 0,
+					version: LEGACY_VERSION,
+					min_version: LEGACY_VERSION,
+					features: node.features,
+				}
+			}
+		}
+	}
+
+	/// Features `id` advertised, directly or through gossip.
+	fn features_of(&self, id: &str) -> u64 {
+		match self.state.peers.get(id) {
+			Some(peer) => peer.features,
+			None => self.mesh.node(id).map(|node| node.features).unwrap_or_default(),
+		}
+	}
+
+	fn peer_list(&self) -> Vec<PeerInfo> {
+		let mut peers: Vec<PeerInfo> = self.state.peers.values().map(|peer| {
+			let conns = self.conns.values().filter(|c| c.peer_id.as_deref() == Some(peer.id.as_str()));
+			let rtt = conns.clone().filter_map(|c| c.rtt).min();
+			PeerInfo {
+				id: peer.id.clone(),
+				name: peer.name.clone(),
+				owner: peer.owner.clone(),
+				addrs: peer.addrs.clone(),
+				last_seen: peer.last_seen,
+				trust: peer.trust,
+				connected: conns.count() > 0,
+				hops: self.routes.get(&peer.id).map(|route| route.hops),
+				rtt_ms: rtt.map(|rtt| rtt.as_millis() as u64),
+			}
+		}).collect();
+		// Nodes only known through gossip.
+		for (id, node) in self.mesh.nodes() {
+			if self.state.peers.contains_key(id) || self.state.forgotten.contains(id) || *id == self.state.me.id {
+				continue;
+			}
+			peers.push(PeerInfo {
+				id: id.clone(),
+				name: node.name.clone(),
+				owner: node.owner.clone(),
+				addrs: Vec::new(),
+				last_seen: 0,
+				trust: TrustState::Unknown,
+				connected: false,
+				hops: self.routes.get(id).map(|route| route.hops),
+				rtt_ms: None,
+			});
+		}
+		peers.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+		peers
+	}
+
+	/// Sends raw command bytes to every introduced connection except `except`.
+	fn broadcast(&self, data: &[u8], except: Option<&str>) {
+		for (addr, conn) in &self.conns {
+			if conn.peer_id.is_none() || Some(addr.as_str()) == except {
+				continue;
+			}
+			if let Err(err) = conn.tx.send(PeerConnCmd::Send(data.to_vec())) {
+				log::error!("failed to gossip to {}: {}", addr, err);
+			}
+		}
+	}
+
+	fn rebuild_routes(&mut self) {
+		let direct = self.conns.values().filter(|c| !c.leaving).filter_map(|c| c.peer_id.as_ref());
+		let blocked: HashSet<&str> = self.state.peers.values()
+			.filter(|p| p.trust == TrustState::Blocked)
+			.map(|p| p.id.as_str())
+			.chain(self.state.forgotten.iter().map(String::as_str))
+			.collect();
+		self.routes = RoutingTable::build(&self.state.me.id, direct, &self.mesh, &blocked);
+	}
+
+	/// Whether `id` is blocked or forgotten, and so kept off the network.
+	fn is_refused(&self, id: &str) -> bool {
+		self.state.forgotten.contains(id) || self.state.peers.get(id).is_some_and(|p| p.trust == TrustState::Blocked)
+	}
+
+	/// Drops the links to a refused peer and stops redialing it.
+	fn disconnect_peer(&mut self, id: &str) {
+		let addrs: Vec<String> = self.conns.iter()
+			.filter(|(_, c)| c.peer_id.as_deref() == Some(id))
+			.map(|(addr, _)| addr.clone())
+			.collect();
+		for addr in addrs {
+			self.close_refused(&addr);
+		}
+		self.rebuild_routes();
+	}
+
+	fn close_refused(&mut self, addr: &str) {
+		if let Some(conn) = self.conns.get(addr) {
+			let _ = conn.tx.send(PeerConnCmd::Close);
+		}
+		if let Some(supervised) = self.supervised.remove(addr) {
+			supervised.task.abort();
+		}
+		self.remove_conn(addr);
+	}
+
+	/// Opens a substream towards `target` through the next hop on its route.
+	fn open_stream(&mut self, target: &str, header: &StreamHeader) -> anyhow::Result<Stream> {
+		let route = match self.routes.get(target) {
+			Some(route) => route,
+			None => return Err(TransientError::NoRoute(target.to_string()).into()),
+		};
+		let conn = self.conns.values_mut()
+			.find(|c| c.peer_id.as_deref() == Some(route.next_hop.as_str()));
+		match conn {
+			Some(conn) => conn.open_stream(header),
+			None => anyhow::bail!("next hop {} for {} is not connected", route.next_hop, target),
+		}
+	}
+
+	/// Relays a stream meant for another node one hop closer to it.
+	fn forward_stream(&mut self, addr: &str, target: &str, header: StreamHeader, stream: Stream) {
+		if header.ttl == 0 {
+			log::warn!("dropping stream from {} to {}: hop limit exceeded", addr, target);
+			self.transfers.spawn(stream::reject(stream, ERR_HOP_LIMIT, target.to_string()));
+			return;
+		}
+		let header = StreamHeader {
+			ttl: header.ttl - 1,
+			cmd: header.cmd,
+		};
+		match self.open_stream(target, &header) {
+			Ok(upstream) => {
+				log::info!("forwarding stream from {} to {}", addr, target);
+				self.transfers.spawn(stream::pipe(stream, upstream));
+			},
+			Err(err) => {
+				log::warn!("cannot forward stream from {}: {}", addr, err);
+				self.transfers.spawn(stream::reject(stream, ERR_UNREACHABLE, target.to_string()));
+			}
+		}
+	}
+
+	/// Announces a change in our own direct connections to the mesh.
+	fn announce_link(&mut self, peer_id: &str, up: bool) {
+		let origin = self.state.me.id.clone();
+		let seq = self.mesh.announce(&origin, peer_id, up);
+		self.rebuild_routes();
+		let cmd = if up {
+			PeerCmd::PeerConnected { origin, seq, ttl: GOSSIP_TTL, peer: self.peer_info(peer_id) }
+		} else {
+			PeerCmd::PeerDisconnected { origin, seq, ttl: GOSSIP_TTL, peer_id: peer_id.to_string() }
+		};
+		self.broadcast(&cmd.serialize(), None);
+	}
+
+	/// Replays everything we know about the mesh to a newly introduced neighbour.
+	fn sync_mesh(&self, addr: &str) {
+		let conn = match self.conns.get(addr) {
+			Some(conn) => conn,
+			None => return,
+		};
+		for (origin, peer_id, link) in self.mesh.links() {
+			let cmd = if link.up {
+				PeerCmd::PeerConnected { origin: origin.clone(), seq: link.seq, ttl: GOSSIP_TTL, peer: self.peer_info(peer_id) }
+			} else {
+				PeerCmd::PeerDisconnected { origin: origin.clone(), seq: link.seq, ttl: GOSSIP_TTL, peer_id: peer_id.clone() }
+			};
+			if conn.tx.send(PeerConnCmd::Send(cmd.serialize())).is_err() {
+				return;
+			}
+		}
+	}
+
+	async fn handle_link_update(&mut self, addr: &str, cmd: PeerCmd) {
+		let (origin, seq, ttl, peer_id, up) = match &cmd {
+			PeerCmd::PeerConnected { origin, seq, ttl, peer } => (origin, *seq, *ttl, &peer.id, true),
+			PeerCmd::PeerDisconnected { origin, seq, ttl, peer_id } => (origin, *seq, *ttl, peer_id, false),
+			_ => return,
+		};
+		// We never forward gossip whose ttl ran out, so only a broken or
+		// malicious peer sends it.
+		if ttl == 0 {
+			log::warn!("dropping gossip from {} with a ttl of 0", addr);
+			return;
+		}
+		if *origin == self.state.me.id || !self.mesh.update(origin, peer_id, seq, up) {
+			return;
+		}
+		log::info!("mesh: {} {} {}", origin, if up { "connected to" } else { "disconnected from" }, peer_id);
+		self.rebuild_routes();
+
+		let forward = match cmd {
+			PeerCmd::PeerConnected { origin, seq, ttl, peer } => {
+				if let Some(known) = self.state.peers.get_mut(&peer.id) {
+					// Gossip from older nodes carries no features.
+					if peer.features != 0 && known.features != peer.features {
+						known.features = peer.features;
+						self.save_peers().await;
+					}
+				} else if peer.id != self.state.me.id && !self.state.forgotten.contains(&peer.id) {
+					// Only peers we talked to ourselves are stored.
+					self.mesh.learn(&peer.id, MeshNode {
+						name: peer.name.clone(),
+						owner: Some(peer.owner.clone()).filter(|o| !o.is_empty()),
+						features: peer.features,
+					});
+				}
+				PeerCmd::PeerConnected { origin, seq, ttl: ttl - 1, peer }
+			},
+			PeerCmd::PeerDisconnected { origin, seq, ttl, peer_id } => {
+				PeerCmd::PeerDisconnected { origin, seq, ttl: ttl - 1, peer_id }
+			},
+			_ => return,
+		};
+		if ttl > 1 {
+			self.broadcast(&forward.serialize(), Some(addr));
+		}
+	}
+
+	async fn save_peers(&mut self) {
+		self.peers_dirty = false;
+		if let Some(path) = &self.peers_path {
+			if let Err(err) = storage::save_peers(path, &self.state.peers).await {
+				log::error!("failed to save peers to {}: {}", path.display(), err);
+			}
+		}
+	}
+
+	async fn save_forgotten(&self) {
+		if let Some(path) = &self.forgotten_path {
+			if let Err(err) = storage::save_forgotten(path, &self.state.forgotten).await {
+				log::error!("failed to save forgotten peers to {}: {}", path.display(), err);
+			}
+		}
+	}
+
+	/// Removes a peer, closes our links to it and refuses it from then on.
+	/// With `propagate` our own nodes we are connected to are told as well,
+	/// and pass it on to theirs.
+	async fn forget_peer(&mut self, id: &str, propagate: bool) {
+		if id == self.state.me.id || !self.state.forgotten.insert(id.to_string()) {
+			return;
+		}
+		log::info!("forgot peer {}", id);
+		if let Some(peer) = self.state.peers.remove(id) {
+			for addr in &peer.addrs {
+				if let Some(supervised) = self.supervised.remove(addr) {
+					supervised.task.abort();
+				}
+			}
+			self.save_peers().await;
+		}
+		self.save_forgotten().await;
+		self.disconnect_peer(id);
+
+		if !propagate {
+			return;
+		}
+		let data = PeerCmd::ForgetPeer { id: id.to_string() }.serialize();
+		for (addr, conn) in &self.conns {
+			let own = conn.peer_id.as_ref()
+				.and_then(|peer_id| self.state.peers.get(peer_id))
+				.is_some_and(|peer| self.state.is_own_node(peer));
+			if !own {
+				continue;
+			}
+			if let Err(err) = conn.tx.send(PeerConnCmd::Send(data.clone())) {
+				log::error!("failed to send forget to {}: {}", addr, err);
+			}
 		}
 	}
 
 	async fn send(&self, addr: &str, cmd: PeerCmd) -> anyhow::Result<()> {
-		if addr.starts_with("udp://") {
+		if let Some(conn) = self.conns.get(addr) {
+			conn.tx.send(PeerConnCmd::Send(cmd.serialize()))?;
+			return Ok(());
+		}
+		if let (Some(socket), true) = (&self.udp_socket, addr.starts_with("udp://")) {
 			let addr = addr.trim_start_matches("udp://");
-			self.udp_socket.send_to(&cmd.serialize(), &addr).await.unwrap();
+			socket.send_to(&cmd.serialize(), &addr).await?;
 			return Ok(());
 		}
+		anyhow::bail!("no connection to {}", addr)
+	}
 
-		Ok(())
+	async fn bind(&mut self, addr: String) {
+		if self.stopping.is_some() {
+			return;
+		}
+		let listener = match tcp::bind(&addr).await {
+			Ok(listener) => listener,
+			Err(err) => {
+				self.report(Some(&addr), &err.context("bind failed"));
+				return;
+			}
+		};
+		if let Ok(local) = listener.local_addr() {
+			self.listen_addrs.push(local);
+		}
+		self.tasks.push(tokio::spawn(tcp::accept(listener, self.ctx())));
+	}
+
+	/// Applies what can change at runtime and keeps existing connections.
+	async fn reload(&mut self, config: Config) {
+		let old = std::mem::replace(&mut self.config, config);
+		if old.data_dir != self.config.data_dir || old.identity_path != self.config.identity_path {
+			log::warn!("data dir and identity changes take effect after a restart");
+		}
+		if old.discovery_port != self.config.discovery_port {
+			log::warn!("discovery port changes take effect after a restart");
+		}
+
+		let name = if self.config.name.is_empty() { storage::default_name() } else { self.config.name.clone() };
+		if name != self.state.me.name || self.config.owner != self.state.me.owner {
+			self.state.me.name = name;
+			self.state.me.owner = self.config.owner.clone();
+			self.broadcast(&PeerCmd::Introduce(self.me()).serialize(), None);
+		}
+		self.shares = Arc::new(self.config.shares.clone());
+		self.limiter.set_config(self.config.rate_limits.clone());
+		if old.discovery_interval != self.config.discovery_interval {
+			self.discovery_timer = tokio::time::interval(self.config.discovery_interval);
+		}
+		if old.keepalive_interval != self.config.keepalive_interval {
+			self.keepalive_timer = tokio::time::interval(self.config.keepalive_interval);
+		}
+		self.keepalive_timeout = self.config.keepalive_timeout;
+
+		for addr in self.config.listen.clone() {
+			if !old.listen.contains(&addr) {
+				self.bind(addr).await;
+			}
+		}
+		for addr in old.listen.iter().filter(|addr| !self.config.listen.contains(addr)) {
+			log::warn!("still listening on {} until restart", addr);
+		}
+		for addr in self.config.peers.clone() {
+			self.supervise(addr, None);
+		}
+		for addr in old.peers.iter().filter(|addr| !self.config.peers.contains(addr)) {
+			if let Some(supervised) = self.supervised.remove(addr) {
+				log::info!("no longer connecting to {}", addr);
+				supervised.task.abort();
+			}
+		}
+		log::info!("configuration reloaded");
 	}
 
 	async fn handle_cmd(&mut self, cmd: InternalCommand) {
 		match cmd {
-			InternalCommand::Bind { addr } => todo!(),
-			InternalCommand::Connect { addr } => todo!(),
-			InternalCommand::PeerCmd { addr, cmd } => todo!(),
+			InternalCommand::Bind { addr } => {
+				self.bind(addr).await;
+			},
+			InternalCommand::Connect { addr } => {
+				self.supervise(addr, None);
+			},
+			InternalCommand::ForgetPeer { id, propagate } => {
+				self.forget_peer(&id, propagate).await;
+			},
+			InternalCommand::SetTrust { id, trust } => {
+				// Setting the trust of a forgotten peer takes it back, and one
+				// known through gossip only is remembered from then on.
+				let forgotten = self.state.forgotten.remove(&id);
+				if forgotten {
+					self.save_forgotten().await;
+				}
+				if !self.state.peers.contains_key(&id) && (forgotten || self.mesh.node(&id).is_some()) {
+					let node = self.mesh.node(&id).cloned().unwrap_or_default();
+					self.state.peers.insert(id.clone(), Peer {
+						id: id.clone(),
+						name: node.name,
+						owner: node.owner,
+						features: node.features,
+						..Default::default()
+					});
+				}
+				match self.state.peers.get_mut(&id) {
+					Some(peer) => {
+						peer.trust = trust;
+						log::info!("{} is now {:?}", id, trust);
+						self.save_peers().await;
+						match trust {
+							TrustState::Blocked => self.disconnect_peer(&id),
+							_ => self.rebuild_routes(),
+						}
+					},
+					None => log::info!("trust: unknown peer {}", id),
+				}
+			},
+			InternalCommand::SetKeepalive { interval, timeout } => {
+				self.keepalive_timer = tokio::time::interval(interval);
+				self.keepalive_timeout = timeout;
+			},
+			InternalCommand::OpenStream { cmd, reply } => {
+				let res = match cmd.node_id().map(|id| id.to_string()) {
+					_ if self.stopping.is_some() => Err(anyhow::anyhow!("node is shutting down")),
+					Some(target) if target == self.state.me.id => Err(anyhow::anyhow!("{} is the local node", target)),
+					Some(target) => self.open_stream(&target, &StreamHeader { ttl: self.config.max_hops, cmd }),
+					None => Err(anyhow::anyhow!("command has no target node")),
+				};
+				let _ = reply.send(res);
+			},
+			InternalCommand::Shutdown { reply } => {
+				self.begin_shutdown(reply);
+			},
+			InternalCommand::Reload { config } => {
+				self.reload(*config).await;
+			},
+			InternalCommand::Peers { reply } => {
+				let _ = reply.send(self.peer_list());
+			},
+			InternalCommand::Metrics { reply } => {
+				let _ = reply.send(self.metrics());
+			},
+			InternalCommand::Compression { node_id, reply } => {
+				let codec = match self.features_of(&node_id) & FEATURES & FEATURE_COMPRESSION {
+					0 => Compression::None,
+					_ => self.config.compression,
+				};
+				let _ = reply.send(codec);
+			},
+			InternalCommand::Features { node_id, reply } => {
+				let _ = reply.send(self.features_of(&node_id) & FEATURES);
+			},
+			InternalCommand::Info { reply } => {
+				let _ = reply.send(NodeInfo {
+					id: self.state.me.id.clone(),
+					name: self.state.me.name.clone(),
+					owner: self.state.me.owner.clone(),
+					listen_addrs: self.listen_addrs.iter().map(|addr| format!("tcp://{}", addr)).collect(),
+				});
+			},
 		}
 	}
 
 	async fn handle_interal_event(&mut self, event: InternalEvent) {
 		match event {
-			InternalEvent::PeerConnected { addr, tx } => {
-				self.event_tx.send(PupynetEvent::PeerConnected { addr }).unwrap();
+			InternalEvent::PeerConnected { addr, outbound, tx } => {
+				if self.stopping.is_some() {
+					let _ = tx.send(PeerConnCmd::Close);
+					return;
+				}
+				if self.conns.len() >= self.config.max_connections {
+					log::warn!("closing {}: connection limit of {} reached", addr, self.config.max_connections);
+					let _ = tx.send(PeerConnCmd::Close);
+					return;
+				}
+				if let Err(err) = tx.send(PeerConnCmd::Send(PeerCmd::Introduce(self.me()).serialize())) {
+					log::error!("failed to introduce to {}: {}", addr, err);
+				}
+				self.conns.insert(addr.clone(), PeerConn::new(tx, outbound));
+				if let Some(supervised) = self.supervised.get_mut(&addr) {
+					if std::mem::replace(&mut supervised.reconnecting, false) {
+						log::info!("reconnected to {}", addr);
+						self.emit(PupynetEvent::Reconnected { addr: addr.clone() });
+					}
+				}
+				self.emit(PupynetEvent::PeerConnected { addr });
 			},
 			InternalEvent::PeerDisconnected { addr } => {
-				self.event_tx.send(PupynetEvent::PeerDisconnected { addr }).unwrap();
+				self.remove_conn(&addr);
+			},
+			InternalEvent::Error { addr, error } => {
+				self.report(addr.as_deref(), &error);
+			},
+			InternalEvent::Reconnecting { addr, attempt, delay } => {
+				if let Some(supervised) = self.supervised.get_mut(&addr) {
+					supervised.reconnecting = true;
+				}
+				log::info!("reconnecting to {} in {:?} (attempt {})", addr, delay, attempt);
+				self.emit(PupynetEvent::Reconnecting { addr, attempt, delay_ms: delay.as_millis() as u64 });
+			},
+			InternalEvent::StreamOpened { addr, header, stream } => {
+				if self.stopping.is_some() {
+					self.transfers.spawn(stream::reject(stream, ERR_SHUTTING_DOWN, self.state.me.id.clone()));
+					return;
+				}
+				// Links to refused peers are dropped as soon as they introduce
+				// themselves, so streams may still arrive behind that.
+				let blocked = match self.conns.get(&addr) {
+					Some(conn) => conn.peer_id.as_deref().is_some_and(|id| self.is_refused(id)),
+					None => true,
+				};
+				if blocked {
+					log::warn!("refusing stream from {}", addr);
+					self.transfers.spawn(stream::reject(stream, ERR_ACCESS_DENIED, "blocked".to_string()));
+					return;
+				}
+				match header.cmd.node_id().map(|id| id.to_string()) {
+					Some(target) if target != self.state.me.id => {
+						self.forward_stream(&addr, &target, header, stream);
+					},
+					_ if matches!(header.cmd, PeerCmd::SyncIndex { .. } | PeerCmd::SyncFetch { .. }) => {
+						// Synced folders are only shared with our owner's nodes.
+						let allowed = match self.state.peer_by_addr(&addr) {
+							Some(peer) => self.state.is_own_node(peer),
+							None => false
+						};
+						if !allowed {
+							log::warn!("refusing sync stream from {}", addr);
+							self.transfers.spawn(stream::reject(stream, ERR_ACCESS_DENIED, "not a trusted node of our owner".to_string()));
+							return;
+						}
+						let folders = self.sync_folders.clone();
+						let sync_dir = self.sync_dir.clone();
+						let ctx = self.ctx();
+						self.transfers.spawn(async move {
+							if let Err(err) = folder_sync::handle_stream(stream, header.cmd, &folders, sync_dir.as_deref()).await {
+								ctx.report(Some(&addr), err.context("sync stream failed"));
+							}
+						});
+					},
+					_ if matches!(header.cmd, PeerCmd::WriteBlocks { .. }) => {
+						let shares = self.shares.clone();
+						let blocks = self.blocks.clone();
+						let ctx = self.ctx();
+						self.transfers.spawn(async move {
+							if let Err(err) = block_store::handle_stream(stream, header.cmd, &shares, blocks.as_deref()).await {
+								ctx.report(Some(&addr), err.context("stream failed"));
+							}
+						});
+					},
+					_ if matches!(header.cmd, PeerCmd::FindContent { .. }) => {
+						let shares = self.shares.clone();
+						let blocks = self.blocks.clone();
+						let folders = self.sync_folders.clone();
+						let sync_dir = self.sync_dir.clone();
+						let ctx = self.ctx();
+						self.transfers.spawn(async move {
+							if let Err(err) = swarm::handle_stream(stream, header.cmd, &shares, blocks.as_deref(), &folders, sync_dir.as_deref()).await {
+								ctx.report(Some(&addr), err.context("content lookup failed"));
+							}
+						});
+					},
+					_ if matches!(header.cmd, PeerCmd::Watch { .. }) => {
+						let shares = self.shares.clone();
+						let ctx = self.ctx();
+						self.transfers.spawn(async move {
+							if let Err(err) = crate::watch::handle_stream(stream, header.cmd, &shares, ctx.shutdown.clone()).await {
+								ctx.report(Some(&addr), err.context("watch stream failed"));
+							}
+						});
+					},
+					_ => {
+						let shares = self.shares.clone();
+						let ctx = self.ctx();
+						self.transfers.spawn(async move {
+							if let Err(err) = stream::handle_stream(stream, header.cmd, &shares).await {
+								ctx.report(Some(&addr), err.context("stream failed"));
+							}
+						});
+					}
+				}
 			},
 			InternalEvent::PeerCmd { addr, cmd } => {
 				match cmd {
-						PeerCmd::ReadFile { node_id, path, offset, length } => todo!(),
-						PeerCmd::WriteFile { node_id, path, offset, data } => todo!(),
-						PeerCmd::RemoveFile { node_id, path } => todo!(),
-						PeerCmd::CreateFolder { node_id, path } => todo!(),
-						PeerCmd::RenameFolder { node_id, path, new_name } => todo!(),
-						PeerCmd::RemoveFolder { node_id, path } => todo!(),
-						PeerCmd::ListFolderContents { node_id, path, offset, length, recursive } => todo!(),
+						PeerCmd::ReadFile { .. } |
+						PeerCmd::WriteFile { .. } |
+						PeerCmd::WriteDelta { .. } |
+						PeerCmd::WriteBlocks { .. } |
+						PeerCmd::SyncIndex { .. } |
+						PeerCmd::SyncFetch { .. } |
+						PeerCmd::Watch { .. } |
+						PeerCmd::FindContent { .. } |
+						PeerCmd::RemoveFile { .. } |
+						PeerCmd::CreateFolder { .. } |
+						PeerCmd::RenameFolder { .. } |
+						PeerCmd::RemoveFolder { .. } |
+						PeerCmd::ListFolderContents { .. } => {
+							log::warn!("{} sent a filesystem command outside of a stream", addr);
+						},
 						PeerCmd::Introduce(introduce) => {
 							if introduce.id == self.state.me.id {
 								log::info!("it is me");
 								return;
 							}
-	
+							if self.is_refused(&introduce.id) {
+								log::info!("refusing {} at {}", introduce.id, addr);
+								self.close_refused(&addr);
+								return;
+							}
+							let negotiated = match self.me().negotiate(&introduce) {
+								Ok(negotiated) => negotiated,
+								Err(err) => {
+									if let Some(conn) = self.conns.get(&addr) {
+										self.report(Some(&addr), &err);
+										let _ = conn.tx.send(PeerConnCmd::Close);
+									} else {
+										log::debug!("ignoring {}: {}", addr, err);
+									}
+									return;
+								}
+							};
+
+							let introduce_info = introduce.clone();
 							let peer = self.state.peers.entry(introduce.id.clone()).or_default();
+							let stored = (peer.id.clone(), peer.name.clone(), peer.owner.clone(), peer.addrs.clone(), peer.features);
 							peer.id = introduce.id;
 							peer.name = introduce.name;
-							if !peer.introduced {
-								peer.introduced = true;
-								let cmd = PeerCmd::Introduce(Introduce {
-									id: self.state.me.id.clone(),
-									name: self.state.me.name.clone(),
-									owner: self.state.me.owner.clone().unwrap_or_default(),
-								});
-								self.send(&addr, cmd).await;
+							peer.owner = Some(introduce.owner).filter(|o| !o.is_empty());
+							peer.features = introduce.features;
+							peer.seen_at(&addr);
+							let changed = stored != (peer.id.clone(), peer.name.clone(), peer.owner.clone(), peer.addrs.clone(), peer.features);
+							let introduced = std::mem::replace(&mut peer.introduced, true);
+							let id = peer.id.clone();
+							match changed {
+								true => self.save_peers().await,
+								false => self.peers_dirty = true,
+							}
+
+							if let Some(conn) = self.conns.get_mut(&addr) {
+								(conn.version, conn.features) = negotiated;
+								let _ = conn.tx.send(PeerConnCmd::SetFeatures(conn.features));
+								let _ = conn.tx.send(PeerConnCmd::SetPeer(id.clone()));
+								if introduce_info.max_frame > 0 {
+									let max = introduce_info.max_frame.min(self.config.max_frame_size);
+									let _ = conn.tx.send(PeerConnCmd::SetFrameFormat(FrameFormat::Wide { max }));
+								}
+								// Connections introduce themselves on connect, so only
+								// the first introduction over a link changes the mesh.
+								if conn.peer_id.is_none() {
+									conn.peer_id = Some(id.clone());
+									self.announce_link(&id, true);
+									self.sync_mesh(&addr);
+								}
+							} else {
+								self.connect_discovered(&addr, &introduce_info);
+								if !introduced {
+									if let Err(err) = self.send(&addr, PeerCmd::Introduce(self.me())).await {
+										self.report(Some(&addr), &err.context("introduction failed"));
+									}
+								}
 							}
 						},
-						PeerCmd::Hello => todo!(),
+						PeerCmd::Hello { seq, reply } => {
+							self.handle_hello(&addr, seq, reply);
+						},
+						PeerCmd::Goodbye => {
+							self.handle_goodbye(&addr);
+						},
+						PeerCmd::ForgetPeer { id } => {
+							// Only our owner's nodes may make us forget a peer.
+							let allowed = match self.state.peer_by_addr(&addr) {
+								Some(sender) => self.state.is_own_node(sender),
+								None => false
+							};
+							if !allowed {
+								log::warn!("ignoring forget of {} from {}", id, addr);
+								return;
+							}
+							self.forget_peer(&id, true).await;
+						},
+						cmd @ (PeerCmd::PeerConnected { .. } | PeerCmd::PeerDisconnected { .. }) => {
+							self.handle_link_update(&addr, cmd).await;
+						},
 					}
 			}
 		}
 	}
 
 	pub async fn run(mut self) {
+		for addr in self.config.listen.clone() {
+			self.bind(addr).await;
+		}
+		for addr in self.config.peers.clone() {
+			self.supervise(addr, None);
+		}
 		loop {
+			let deadline = match &self.stopping {
+				Some(stopping) if self.transfers.is_empty() || tokio::time::Instant::now() >= stopping.deadline => break,
+				Some(stopping) => Some(stopping.deadline),
+				None => None,
+			};
 			tokio::select! {
+				_ = self.discovery_timer.tick(), if deadline.is_none() => {
+					self.announce().await;
+				}
+				Some(res) = self.transfers.join_next() => {
+					if let Err(err) = res {
+						self.report(None, &anyhow::Error::new(err).context("transfer task failed"));
+					}
+				}
+				_ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if deadline.is_some() => {}
+				_ = self.keepalive_timer.tick() => {
+					self.keepalive();
+					self.supervised.retain(|_, s| !s.task.is_finished());
+					let routes = &self.routes;
+					self.mesh.prune(&self.state.me.id, LINK_EXPIRY, |id| routes.get(id).is_some());
+					if self.peers_dirty {
+						self.save_peers().await;
+					}
+				}
 				cmd = self.rx.recv() => {
 					match cmd {
 						Some(cmd) => {
 							self.handle_cmd(cmd).await;
 						},
 						None => {
-							log::error!("command channel closed");
+							log::info!("all handles dropped");
 							break;
 						}
 					}
@@ -120,5 +1222,293 @@ impl Worker {
 				}
 			}
 		}
+		self.finish_shutdown().await;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::folder;
+	use crate::testing::node;
+	use crate::testing::wait_for;
+	use crate::testing::wait_for_peer;
+	use crate::types::PeerTx;
+	use crate::Pupynet;
+
+	async fn worker() -> (Worker, tempfile::TempDir) {
+		let dir = tempfile::tempdir().unwrap();
+		let config = Config {
+			data_dir: Some(dir.path().to_path_buf()),
+			discovery_port: None,
+			..Config::default()
+		};
+		let (_tx, rx) = mpsc::channel(1);
+		let (event_tx, _) = broadcast::channel(16);
+		(Worker::new(config, rx, event_tx).await, dir)
+	}
+
+	#[tokio::test]
+	async fn test_expired_gossip_is_dropped() {
+		let (mut worker, _dir) = worker().await;
+		let mut peer = worker.me();
+		peer.id = "b".to_string();
+		worker.handle_link_update("tcp://peer", PeerCmd::PeerConnected { origin: "a".to_string(), seq: 1, ttl: 0, peer }).await;
+		worker.handle_link_update("tcp://peer", PeerCmd::PeerDisconnected { origin: "a".to_string(), seq: 2, ttl: 0, peer_id: "b".to_string() }).await;
+		assert_eq!(worker.mesh.links().count(), 0);
+		assert!(!worker.state.peers.contains_key("b"));
+	}
+
+	#[tokio::test]
+	async fn test_gossiped_nodes_are_not_stored() {
+		let (mut worker, dir) = worker().await;
+		let mut peer = worker.me();
+		peer.id = "c".to_string();
+		peer.name = "far".to_string();
+		worker.handle_link_update("tcp://peer", PeerCmd::PeerConnected { origin: "b".to_string(), seq: 1, ttl: 1, peer }).await;
+		assert!(!worker.state.peers.contains_key("c"));
+		assert!(!storage::peers_path(dir.path()).exists());
+		assert!(worker.peer_list().iter().any(|p| p.id == "c" && p.name == "far"));
+		assert_eq!(worker.features_of("c"), FEATURES);
+
+		// Trusting it is what makes it stored.
+		worker.handle_cmd(InternalCommand::SetTrust { id: "c".to_string(), trust: TrustState::Trusted }).await;
+		assert_eq!(worker.state.peers["c"].name, "far");
+		assert!(storage::peers_path(dir.path()).exists());
+	}
+
+	#[tokio::test]
+	async fn test_peers_saved_on_change() {
+		let (mut worker, dir) = worker().await;
+		let path = storage::peers_path(dir.path());
+		let mut introduce = worker.me();
+		introduce.id = "b".to_string();
+		introduce.ports = Vec::new();
+		let event = |introduce: &Introduce| InternalEvent::PeerCmd { addr: "udp://10.0.0.2:9999".to_string(), cmd: PeerCmd::Introduce(introduce.clone()) };
+
+		worker.handle_interal_event(event(&introduce)).await;
+		assert!(path.exists());
+		// Hearing from it again only refreshes when it was last seen.
+		std::fs::remove_file(&path).unwrap();
+		worker.handle_interal_event(event(&introduce)).await;
+		assert!(!path.exists());
+		assert!(worker.peers_dirty);
+
+		introduce.name = "renamed".to_string();
+		worker.handle_interal_event(event(&introduce)).await;
+		assert!(path.exists());
+		assert!(!worker.peers_dirty);
+	}
+
+	#[tokio::test]
+	async fn test_forgotten_peer_stays_forgotten() {
+		let (mut worker, dir) = worker().await;
+		let mut introduce = worker.me();
+		introduce.id = "b".to_string();
+		introduce.ports = Vec::new();
+		let event = InternalEvent::PeerCmd { addr: "udp://10.0.0.2:9999".to_string(), cmd: PeerCmd::Introduce(introduce.clone()) };
+		worker.handle_interal_event(event).await;
+		assert!(worker.state.peers.contains_key("b"));
+
+		worker.forget_peer("b", true).await;
+		assert!(!worker.state.peers.contains_key("b"));
+		// Neither its introduction nor gossip about it brings it back.
+		let event = InternalEvent::PeerCmd { addr: "udp://10.0.0.2:9999".to_string(), cmd: PeerCmd::Introduce(introduce.clone()) };
+		worker.handle_interal_event(event).await;
+		worker.handle_link_update("tcp://peer", PeerCmd::PeerConnected { origin: "a".to_string(), seq: 1, ttl: 1, peer: introduce }).await;
+		assert!(!worker.state.peers.contains_key("b"));
+
+		// Nor does a restart.
+		let config = Config {
+			data_dir: Some(dir.path().to_path_buf()),
+			discovery_port: None,
+			..Config::default()
+		};
+		let (_tx, rx) = mpsc::channel(1);
+		let mut worker = Worker::new(config, rx, broadcast::channel(16).0).await;
+		assert!(worker.is_refused("b"));
+		worker.handle_cmd(InternalCommand::SetTrust { id: "b".to_string(), trust: TrustState::Trusted }).await;
+		assert!(!worker.is_refused("b"));
+		assert_eq!(worker.state.peers["b"].trust, TrustState::Trusted);
+	}
+
+	#[tokio::test]
+	async fn test_forget_reaches_own_nodes() {
+		let dir = tempfile::tempdir().unwrap();
+		let a = node(dir.path(), "a").owner("alice").build();
+		let a_info = a.info().await.unwrap();
+		let b = node(dir.path(), "b").owner("alice").peer(&a_info.listen_addrs[0]).build();
+		let b_info = b.info().await.unwrap();
+		let c = node(dir.path(), "c").peer(&b_info.listen_addrs[0]).build();
+		let c_info = c.info().await.unwrap();
+		wait_for_peer(&a, &b_info.id).await;
+		wait_for_peer(&a, &c_info.id).await;
+		wait_for_peer(&b, &a_info.id).await;
+		a.set_trust(b_info.id.clone(), TrustState::Trusted).unwrap();
+		b.set_trust(a_info.id.clone(), TrustState::Trusted).unwrap();
+
+		// a only knows c through b, yet drops it and b disconnects it.
+		a.forget_peer(c_info.id.clone(), true).unwrap();
+		let forgotten = |node: &Pupynet| {
+			let (node, id) = (node.clone(), c_info.id.clone());
+			async move {
+				while node.peers().await.unwrap().iter().any(|p| p.id == id) {
+					tokio::time::sleep(Duration::from_millis(10)).await;
+				}
+			}
+		};
+		tokio::time::timeout(Duration::from_secs(10), forgotten(&a)).await.unwrap();
+		tokio::time::timeout(Duration::from_secs(10), forgotten(&b)).await.unwrap();
+		// c keeps redialing b but is refused.
+		tokio::time::sleep(Duration::from_millis(500)).await;
+		assert!(!b.peers().await.unwrap().iter().any(|p| p.id == c_info.id));
+		let metrics = b.metrics().await.unwrap();
+		assert!(metrics.connections.iter().all(|conn| conn.peer_id.as_deref() != Some(c_info.id.as_str())));
+	}
+
+	#[tokio::test]
+	async fn test_moved_peer_is_not_redialed() {
+		let (mut worker, _dir) = worker().await;
+		let mut introduce = worker.me();
+		// Only the node with the smaller id dials.
+		introduce.id = "z".to_string();
+		introduce.ports = vec![1];
+		worker.connect_discovered("udp://127.0.0.1:7764", &introduce);
+		worker.connect_discovered("udp://127.0.0.2:7764", &introduce);
+		let addrs: Vec<&String> = worker.supervised.keys().collect();
+		assert_eq!(addrs, vec!["tcp://127.0.0.2:1"]);
+	}
+
+	#[tokio::test]
+	async fn test_keepalive() {
+		let (mut worker, _dir) = worker().await;
+		worker.keepalive_timeout = Duration::from_millis(300);
+		let (tx, _rx) = PeerTx::new(PEER_BUFFER);
+		worker.conns.insert("tcp://peer".to_string(), PeerConn::new(tx.clone(), true));
+
+		// A reply arriving after the next hello went out still counts.
+		worker.keepalive();
+		let first = worker.hello_seq;
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		worker.keepalive();
+		worker.handle_hello("tcp://peer", first, true);
+		assert!(worker.conns["tcp://peer"].rtt.unwrap() >= Duration::from_millis(100));
+
+		// Any data read keeps the connection, silence closes it.
+		tokio::time::sleep(Duration::from_millis(250)).await;
+		tx.mark_received();
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		worker.keepalive();
+		assert!(worker.conns.contains_key("tcp://peer"));
+		tokio::time::sleep(Duration::from_millis(350)).await;
+		worker.keepalive();
+		assert!(!worker.conns.contains_key("tcp://peer"));
+	}
+
+	#[tokio::test]
+	async fn test_shutdown() {
+		let dir = tempfile::tempdir().unwrap();
+		let a = node(dir.path(), "a").shutdown_timeout(Duration::from_secs(1)).build();
+		let a_info = a.info().await.unwrap();
+		let mut b = node(dir.path(), "b").peer(&a_info.listen_addrs[0]).build();
+		wait_for(&mut b, |e| matches!(e, PupynetEvent::PeerConnected { .. })).await;
+
+		let mut events = a.clone();
+		tokio::time::timeout(Duration::from_secs(5), a.shutdown()).await.unwrap();
+		assert!(events.next().await.is_none());
+		assert!(a.info().await.is_err());
+		let addr = a_info.listen_addrs[0].trim_start_matches("tcp://");
+		assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+		wait_for(&mut b, |e| matches!(e, PupynetEvent::PeerDisconnected { .. })).await;
+	}
+
+	#[tokio::test]
+	async fn test_blocked_peer_is_refused() {
+		use crate::TrustState;
+		let dir = tempfile::tempdir().unwrap();
+		let share = folder(dir.path(), "share");
+		std::fs::write(share.join("hello.txt"), b"hello").unwrap();
+		let mut a = node(dir.path(), "a").share(&share).build();
+		let a_info = a.info().await.unwrap();
+		let b = node(dir.path(), "b").peer(&a_info.listen_addrs[0]).build();
+		let b_info = b.info().await.unwrap();
+		wait_for_peer(&a, &b_info.id).await;
+
+		a.set_trust(b_info.id.clone(), TrustState::Blocked).unwrap();
+		wait_for(&mut a, |e| matches!(e, PupynetEvent::PeerDisconnected { .. })).await;
+		// b keeps redialing, but never gets through.
+		let path = share.join("hello.txt");
+		for _ in 0..3 {
+			assert!(b.read_file(&a_info.id, path.to_str().unwrap(), 0, 5).await.is_err());
+			tokio::time::sleep(Duration::from_millis(300)).await;
+		}
+		let peers = a.peers().await.unwrap();
+		assert!(peers.iter().any(|p| p.id == b_info.id && !p.connected && p.hops.is_none()));
+
+		a.set_trust(b_info.id.clone(), TrustState::Trusted).unwrap();
+		wait_for_peer(&b, &a_info.id).await;
+		assert_eq!(b.read_file(&a_info.id, path.to_str().unwrap(), 0, 5).await.unwrap(), b"hello");
 	}
-}
\ No newline at end of file
+
+	#[tokio::test]
+	async fn test_survives_socket_errors() {
+		use tokio::io::AsyncWriteExt;
+
+		let dir = tempfile::tempdir().unwrap();
+		let share = folder(dir.path(), "share");
+		std::fs::write(share.join("hello.txt"), b"hello").unwrap();
+		let mut a = node(dir.path(), "a").share(&share).build();
+		let a_info = a.info().await.unwrap();
+		let a_addr = a_info.listen_addrs[0].clone();
+
+		// A peer speaking garbage only loses its own connection.
+		let mut raw = tokio::net::TcpStream::connect(a_addr.trim_start_matches("tcp://")).await.unwrap();
+		raw.write_all(&[0xff; 64]).await.unwrap();
+		wait_for(&mut a, |e| matches!(e, PupynetEvent::Error { addr: Some(_), .. })).await;
+
+		// Binding a taken port and dialing a closed one are reported, not fatal.
+		let mut b = node(dir.path(), "b").listen(&a_addr).peer("tcp://127.0.0.1:1").build();
+		wait_for(&mut b, |e| matches!(e, PupynetEvent::Error { message, .. } if message.contains("bind failed"))).await;
+		wait_for(&mut b, |e| matches!(e, PupynetEvent::Reconnecting { .. })).await;
+
+		b.connect(a_addr).unwrap();
+		assert!(b.connect("udp://127.0.0.1:1".to_string()).is_err());
+		wait_for_peer(&b, &a_info.id).await;
+		let path = share.join("hello.txt");
+		let data = b.read_file(&a_info.id, path.to_str().unwrap(), 0, 5).await.unwrap();
+		assert_eq!(data, b"hello");
+		assert!(a.info().await.is_ok());
+	}
+
+	#[tokio::test]
+	async fn test_rejects_incompatible_peer() {
+		use tokio::io::AsyncReadExt;
+		use tokio::io::AsyncWriteExt;
+		use crate::multiplex::encode_frames;
+		use crate::multiplex::CONTROL_STREAM;
+		use crate::protocol::Introduce;
+		use crate::protocol::PeerCmd;
+		use crate::protocol::STREAM_CONTINUE;
+
+		let dir = tempfile::tempdir().unwrap();
+		let mut a = node(dir.path(), "a").build();
+		let a_info = a.info().await.unwrap();
+		let mut raw = tokio::net::TcpStream::connect(a_info.listen_addrs[0].trim_start_matches("tcp://")).await.unwrap();
+		let future = PeerCmd::Introduce(Introduce {
+			id: "future".to_string(),
+			name: "future".to_string(),
+			owner: String::new(),
+			ports: Vec::new(),
+			max_frame: 0,
+			version: PROTOCOL_VERSION + 10,
+			min_version: PROTOCOL_VERSION + 5,
+			features: 0,
+		});
+		raw.write_all(&encode_frames(CONTROL_STREAM, STREAM_CONTINUE, &future.serialize())).await.unwrap();
+
+		wait_for(&mut a, |e| matches!(e, PupynetEvent::Error { message, .. } if message.contains("incompatible protocol"))).await;
+		let mut rest = Vec::new();
+		tokio::time::timeout(Duration::from_secs(5), raw.read_to_end(&mut rest)).await.unwrap().unwrap();
+		assert!(a.peers().await.unwrap().is_empty());
+	}
+}